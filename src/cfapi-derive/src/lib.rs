@@ -0,0 +1,215 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::ext::IdentExt;
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Fields, LitInt};
+
+struct CfField {
+    ident: syn::Ident,
+    ty: syn::Type,
+    token: i32,
+    // None is required, Some(None) is Default::default()
+    default: Option<Option<Expr>>,
+    // read by from_tokens only, apply_tokens skips the token
+    snapshot_only: bool,
+}
+
+/// Implements `cfapi::from_event::FromCfEvent` for a struct with named fields. A field
+/// is read from `#[cf(token = N)]`, `#[cf(token = N, default)]` or
+/// `#[cf(token = N, default = expr)]`, fields without `cf` get `Default::default()`.
+/// `#[cf(token = N, snapshot_only)]` is not overwritten by `apply_tokens`.
+#[proc_macro_derive(FromCfEvent, attributes(cf))]
+pub fn derive_from_cf_event(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "FromCfEvent needs named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "FromCfEvent only supports structs",
+            ))
+        }
+    };
+
+    let mut cf_fields: Vec<CfField> = vec![];
+    let mut plain_fields = vec![];
+    for field in fields {
+        let ident = field.ident.clone().unwrap();
+        let mut token = None;
+        let mut default = None;
+        let mut snapshot_only = false;
+        for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("cf")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("token") {
+                    token = Some(meta.value()?.parse::<LitInt>()?.base10_parse::<i32>()?);
+                    Ok(())
+                } else if meta.path.is_ident("default") {
+                    default = Some(match meta.input.peek(syn::Token![=]) {
+                        true => Some(meta.value()?.parse::<Expr>()?),
+                        false => None,
+                    });
+                    Ok(())
+                } else if meta.path.is_ident("snapshot_only") {
+                    snapshot_only = true;
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `token`, `default` or `snapshot_only`"))
+                }
+            })?;
+        }
+        match token {
+            Some(token) => {
+                if let Some(other) = cf_fields.iter().find(|f| f.token == token) {
+                    return Err(syn::Error::new_spanned(
+                        &ident,
+                        format!("token {} is already read by {}", token, other.ident),
+                    ));
+                }
+                cf_fields.push(CfField {
+                    ident,
+                    ty: field.ty.clone(),
+                    token,
+                    default,
+                    snapshot_only,
+                });
+            }
+            None if default.is_some() || snapshot_only => {
+                return Err(syn::Error::new_spanned(
+                    &ident,
+                    "`default` and `snapshot_only` need a `token`",
+                ))
+            }
+            None => plain_fields.push(ident),
+        }
+    }
+
+    if cf_fields.len() > 64 {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "FromCfEvent reads at most 64 fields from tokens",
+        ));
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let slots: Vec<_> = cf_fields
+        .iter()
+        .map(|f| format_ident!("__cf_{}", f.ident.unraw()))
+        .collect();
+
+    let declare = cf_fields.iter().zip(&slots).map(|(f, slot)| {
+        let ty = &f.ty;
+        quote! { let mut #slot: ::core::option::Option<#ty> = ::core::option::Option::None; }
+    });
+    let decode = cf_fields.iter().zip(&slots).map(|(f, slot)| {
+        let (ty, token) = (&f.ty, f.token);
+        let field = f.ident.unraw().to_string();
+        let has_default = f.default.is_some();
+        quote! {
+            #token => {
+                if let ::core::option::Option::Some(v) =
+                    ::cfapi::from_event::decode_field::<#ty>(value, #field, #token, #has_default)?
+                {
+                    #slot = ::core::option::Option::Some(v);
+                }
+            }
+        }
+    });
+    let build = cf_fields.iter().zip(&slots).map(|(f, slot)| {
+        let (ident, ty, token) = (&f.ident, &f.ty, f.token);
+        let field = ident.unraw().to_string();
+        let otherwise = match &f.default {
+            Some(Some(expr)) => quote! { #expr },
+            Some(None) => quote! { ::core::default::Default::default() },
+            None => quote! {
+                match <#ty as ::cfapi::from_event::FromCfValue>::missing() {
+                    ::core::option::Option::Some(v) => v,
+                    ::core::option::Option::None => {
+                        return ::core::result::Result::Err(
+                            ::cfapi::from_event::DecodeError::Missing {
+                                field: #field,
+                                token: #token,
+                            },
+                        )
+                    }
+                }
+            },
+        };
+        quote! {
+            #ident: match #slot {
+                ::core::option::Option::Some(v) => v,
+                ::core::option::Option::None => #otherwise,
+            }
+        }
+    });
+    let names = cf_fields.iter().map(|f| f.ident.unraw().to_string());
+    let apply = cf_fields
+        .iter()
+        .enumerate()
+        .filter(|(_, f)| !f.snapshot_only)
+        .map(|(index, f)| {
+            let (ident, ty, token) = (&f.ident, &f.ty, f.token);
+            quote! {
+                #token => {
+                    updated.receive(#index);
+                    if let ::core::option::Option::Some(v) =
+                        <#ty as ::cfapi::from_event::FromCfValue>::from_cf_value(&value)
+                    {
+                        self.#ident = v;
+                        updated.insert(#index);
+                    }
+                }
+            }
+        });
+
+    Ok(quote! {
+        impl #impl_generics ::cfapi::from_event::FromCfEvent for #name #ty_generics #where_clause {
+            fn from_tokens<I>(
+                tokens: I,
+            ) -> ::core::result::Result<Self, ::cfapi::from_event::DecodeError>
+            where
+                I: ::core::iter::IntoIterator<Item = (i32, ::cfapi::value::CFValue)>,
+            {
+                #(#declare)*
+                for (token, value) in tokens {
+                    match token {
+                        #(#decode)*
+                        _ => {}
+                    }
+                }
+                ::core::result::Result::Ok(Self {
+                    #(#build,)*
+                    #(#plain_fields: ::core::default::Default::default(),)*
+                })
+            }
+
+            fn apply_tokens<I>(&mut self, tokens: I) -> ::cfapi::from_event::UpdatedFields
+            where
+                I: ::core::iter::IntoIterator<Item = (i32, ::cfapi::value::CFValue)>,
+            {
+                let mut updated = ::cfapi::from_event::UpdatedFields::new(&[#(#names),*]);
+                for (token, value) in tokens {
+                    match token {
+                        #(#apply)*
+                        _ => {}
+                    }
+                }
+                updated
+            }
+        }
+    })
+}
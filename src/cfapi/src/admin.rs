@@ -0,0 +1,481 @@
+use super::binding::{Commands, MessageEvent, MessageEvent_Types};
+use super::event_reader::{EventReader, EventReaderSerConfig};
+use super::message_event::MessageEventHandlerExt;
+use super::value::CFValue;
+use serde::{Deserialize, Serialize};
+use snafu::prelude::Snafu;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// CTF token of the source id (ENUM.SRC.ID)
+pub const TOKEN_ENUM_SRC_ID: i32 = 4;
+/// CTF token of the ticker symbol (SYMBOL.TICKER)
+pub const TOKEN_SYMBOL_TICKER: i32 = 5;
+/// CTF token of the user name (USER.NAME)
+pub const TOKEN_USER_NAME: i32 = 5028;
+
+#[derive(Debug, Snafu)]
+pub enum AdminError {
+    #[snafu(display("Admin request {command} send failed, request queue may be full"))]
+    SendFailed { command: String },
+    #[snafu(display("Admin request {command} tag {tag} timeout after {timeout:?}"))]
+    Timeout {
+        command: String,
+        tag: i64,
+        timeout: Duration,
+    },
+    #[snafu(display("Admin request tag {tag} status {code}: {message}"))]
+    Status {
+        tag: i64,
+        code: i32,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminToken {
+    pub number: i32,
+    pub name: String,
+    pub value: CFValue,
+}
+
+/// One IMAGE_PART of an administrative or listing response.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AdminRecord {
+    pub source: i32,
+    pub symbol: String,
+    pub tokens: Vec<AdminToken>,
+}
+
+impl AdminRecord {
+    pub fn from_event(event: &MessageEvent, reader_config: &EventReaderSerConfig) -> Self {
+        let mut reader = EventReader::new(event, reader_config);
+        let tokens = reader
+            .iter_with_token_num_name()
+            .map(|(number, name, value)| AdminToken {
+                number,
+                name,
+                value,
+            })
+            .collect();
+        AdminRecord {
+            source: i32::from(event.getSource()),
+            symbol: event.getSymbol().to_string(),
+            tokens,
+        }
+    }
+
+    pub fn get(&self, number: i32) -> Option<&CFValue> {
+        self.tokens
+            .iter()
+            .find(|token| token.number == number)
+            .map(|token| &token.value)
+    }
+
+    pub fn get_by_name(&self, name: &str) -> Option<&CFValue> {
+        self.tokens
+            .iter()
+            .find(|token| token.name == name)
+            .map(|token| &token.value)
+    }
+
+    fn get_string(&self, number: i32) -> Option<String> {
+        match self.get(number) {
+            Some(CFValue::String(v)) => Some(v.clone()),
+            Some(CFValue::Int(v)) => Some(v.to_string()),
+            _ => None,
+        }
+    }
+
+    fn get_int(&self, number: i32) -> Option<i64> {
+        match self.get(number) {
+            Some(CFValue::Int(v)) => Some(*v),
+            Some(CFValue::String(v)) => v.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Typed result of an administrative or listing command, built from the
+/// collected IMAGE_PART records of one request.
+pub trait AdminResponse: Sized {
+    const NAME: &'static str;
+
+    fn command() -> Commands;
+    fn from_records(records: Vec<AdminRecord>) -> Self;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscribedSymbol {
+    pub source: i32,
+    pub symbol: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SubscribedSymbols {
+    pub symbols: Vec<SubscribedSymbol>,
+}
+
+impl AdminResponse for SubscribedSymbols {
+    const NAME: &'static str = "LISTSUBSCRIBEDSYMBOLS";
+
+    fn command() -> Commands {
+        Commands::LISTSUBSCRIBEDSYMBOLS
+    }
+
+    fn from_records(records: Vec<AdminRecord>) -> Self {
+        let symbols = records
+            .into_iter()
+            .map(|record| SubscribedSymbol {
+                source: record
+                    .get_int(TOKEN_ENUM_SRC_ID)
+                    .map(|v| v as i32)
+                    .unwrap_or(record.source),
+                symbol: record
+                    .get_string(TOKEN_SYMBOL_TICKER)
+                    .unwrap_or(record.symbol),
+            })
+            .collect();
+        SubscribedSymbols { symbols }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UserPermission {
+    /// source ids the user is permissioned for
+    pub sources: Vec<i32>,
+    pub records: Vec<AdminRecord>,
+}
+
+impl AdminResponse for UserPermission {
+    const NAME: &'static str = "LISTUSERPERMISSION";
+
+    fn command() -> Commands {
+        Commands::LISTUSERPERMISSION
+    }
+
+    fn from_records(records: Vec<AdminRecord>) -> Self {
+        let mut sources: Vec<i32> = records
+            .iter()
+            .filter_map(|record| record.get_int(TOKEN_ENUM_SRC_ID).map(|v| v as i32))
+            .collect();
+        sources.sort_unstable();
+        sources.dedup();
+        UserPermission { sources, records }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ActiveClients {
+    pub clients: Vec<AdminRecord>,
+}
+
+impl AdminResponse for ActiveClients {
+    const NAME: &'static str = "GETACTIVECLIENTSINFO";
+
+    fn command() -> Commands {
+        Commands::GETACTIVECLIENTSINFO
+    }
+
+    fn from_records(records: Vec<AdminRecord>) -> Self {
+        ActiveClients { clients: records }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Users {
+    pub names: Vec<String>,
+    pub records: Vec<AdminRecord>,
+}
+
+impl AdminResponse for Users {
+    const NAME: &'static str = "GETALLUSERS";
+
+    fn command() -> Commands {
+        Commands::GETALLUSERS
+    }
+
+    fn from_records(records: Vec<AdminRecord>) -> Self {
+        let names = records
+            .iter()
+            .map(|record| {
+                record
+                    .get_string(TOKEN_USER_NAME)
+                    .unwrap_or_else(|| record.symbol.clone())
+            })
+            .collect();
+        Users { names, records }
+    }
+}
+
+/// Enumeration tables of a source, one record per enumerated token.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Enumerations {
+    pub tables: Vec<AdminRecord>,
+}
+
+impl AdminResponse for Enumerations {
+    const NAME: &'static str = "LISTENUMERATION";
+
+    fn command() -> Commands {
+        Commands::LISTENUMERATION
+    }
+
+    fn from_records(records: Vec<AdminRecord>) -> Self {
+        Enumerations { tables: records }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AdministrationInfo {
+    pub records: Vec<AdminRecord>,
+}
+
+impl AdminResponse for AdministrationInfo {
+    const NAME: &'static str = "LISTADMINISTRATIONINFO";
+
+    fn command() -> Commands {
+        Commands::LISTADMINISTRATIONINFO
+    }
+
+    fn from_records(records: Vec<AdminRecord>) -> Self {
+        AdministrationInfo { records }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExchangeInfo {
+    pub exchanges: Vec<AdminRecord>,
+}
+
+impl AdminResponse for ExchangeInfo {
+    const NAME: &'static str = "LISTEXTENDEDEXCHANGEINFO";
+
+    fn command() -> Commands {
+        Commands::LISTEXTENDEDEXCHANGEINFO
+    }
+
+    fn from_records(records: Vec<AdminRecord>) -> Self {
+        ExchangeInfo { exchanges: records }
+    }
+}
+
+type AdminResult = Result<Vec<AdminRecord>, AdminError>;
+
+struct PendingRequest {
+    records: Vec<AdminRecord>,
+    done: Sender<AdminResult>,
+}
+
+#[derive(Default)]
+struct AdminResponsesInner {
+    // number of requests waiting for a response, lets market data skip the lock
+    in_flight: AtomicUsize,
+    pending: Mutex<HashMap<i64, PendingRequest>>,
+    reader_config: EventReaderSerConfig,
+}
+
+/// Collects the responses of administrative and listing requests by request tag.
+#[derive(Clone, Default)]
+pub struct AdminResponses {
+    inner: Arc<AdminResponsesInner>,
+}
+
+impl AdminResponses {
+    /// Send a request through `send` and register its tag. The pending lock is
+    /// held while sending so a response can not arrive before the tag is known.
+    pub fn send<F>(&self, name: &str, send: F) -> Result<(i64, Receiver<AdminResult>), AdminError>
+    where
+        F: FnOnce() -> i64,
+    {
+        self.inner.in_flight.fetch_add(1, Ordering::SeqCst);
+        let mut pending = self.inner.pending.lock().unwrap();
+        let tag = send();
+        if tag == 0 {
+            self.inner.in_flight.fetch_sub(1, Ordering::SeqCst);
+            return SendFailedSnafu { command: name }.fail();
+        }
+        let (done, recv) = channel();
+        pending.insert(
+            tag,
+            PendingRequest {
+                records: Vec::new(),
+                done,
+            },
+        );
+        Ok((tag, recv))
+    }
+
+    pub fn wait(
+        &self,
+        name: &str,
+        tag: i64,
+        recv: Receiver<AdminResult>,
+        timeout: Duration,
+    ) -> AdminResult {
+        match recv.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                if self.inner.pending.lock().unwrap().remove(&tag).is_some() {
+                    self.inner.in_flight.fetch_sub(1, Ordering::SeqCst);
+                }
+                // the response may have completed between the timeout and the remove
+                match recv.try_recv() {
+                    Ok(result) => result,
+                    Err(_) => TimeoutSnafu {
+                        command: name,
+                        tag,
+                        timeout,
+                    }
+                    .fail(),
+                }
+            }
+        }
+    }
+
+    /// Returns true when the event belongs to a pending request and was consumed.
+    pub fn collect(&self, event: &MessageEvent) -> bool {
+        if self.inner.in_flight.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+        let tag = event.getTag();
+        let mut pending = self.inner.pending.lock().unwrap();
+        let request = match pending.get_mut(&tag) {
+            Some(request) => request,
+            None => return false,
+        };
+        let event_type = event.getType();
+        debug!("admin response tag: {}, type: {}", tag, event_type);
+        let result = match event_type {
+            MessageEvent_Types::IMAGE_PART
+            | MessageEvent_Types::UPDATE
+            | MessageEvent_Types::REFRESH => {
+                request
+                    .records
+                    .push(AdminRecord::from_event(event, &self.inner.reader_config));
+                return true;
+            }
+            MessageEvent_Types::IMAGE_COMPLETE => {
+                let record = AdminRecord::from_event(event, &self.inner.reader_config);
+                if !record.tokens.is_empty() {
+                    request.records.push(record);
+                }
+                Ok(std::mem::take(&mut request.records))
+            }
+            MessageEvent_Types::STATUS => StatusSnafu {
+                tag,
+                code: i32::from(event.getStatusCode()),
+                message: event.getStatusString().to_string(),
+            }
+            .fail(),
+        };
+        if let Some(request) = pending.remove(&tag) {
+            self.inner.in_flight.fetch_sub(1, Ordering::SeqCst);
+            if request.done.send(result).is_err() {
+                warn!("admin response tag {} completed without waiter", tag);
+            }
+        }
+        true
+    }
+}
+
+impl MessageEventHandlerExt for AdminResponses {
+    fn on_message_event(&mut self, event: &MessageEvent) {
+        self.collect(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(source: i32, symbol: &str, tokens: Vec<(i32, &str, CFValue)>) -> AdminRecord {
+        AdminRecord {
+            source,
+            symbol: symbol.into(),
+            tokens: tokens
+                .into_iter()
+                .map(|(number, name, value)| AdminToken {
+                    number,
+                    name: name.into(),
+                    value,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_user_permission_sources() {
+        let records = vec![
+            record(
+                0,
+                "",
+                vec![(TOKEN_ENUM_SRC_ID, "ENUM.SRC.ID", CFValue::Int(533))],
+            ),
+            record(
+                0,
+                "",
+                vec![(TOKEN_ENUM_SRC_ID, "ENUM.SRC.ID", CFValue::Int(534))],
+            ),
+            record(
+                0,
+                "",
+                vec![(TOKEN_ENUM_SRC_ID, "ENUM.SRC.ID", CFValue::Int(533))],
+            ),
+            record(0, "", vec![(8, "TRADE.PRICE", CFValue::Double(1.0))]),
+        ];
+        let permission = UserPermission::from_records(records);
+        assert_eq!(permission.sources, vec![533, 534]);
+        assert_eq!(permission.records.len(), 4);
+    }
+
+    #[test]
+    fn test_subscribed_symbols_fallback() {
+        let records = vec![
+            record(533, "AAPL", vec![]),
+            record(
+                0,
+                "",
+                vec![
+                    (
+                        TOKEN_ENUM_SRC_ID,
+                        "ENUM.SRC.ID",
+                        CFValue::String("534".into()),
+                    ),
+                    (
+                        TOKEN_SYMBOL_TICKER,
+                        "SYMBOL.TICKER",
+                        CFValue::String("NVDA".into()),
+                    ),
+                ],
+            ),
+        ];
+        let subscribed = SubscribedSymbols::from_records(records);
+        assert_eq!(subscribed.symbols[0].source, 533);
+        assert_eq!(subscribed.symbols[0].symbol, "AAPL");
+        assert_eq!(subscribed.symbols[1].source, 534);
+        assert_eq!(subscribed.symbols[1].symbol, "NVDA");
+    }
+
+    #[test]
+    fn test_admin_responses_send_failed() {
+        let responses = AdminResponses::default();
+        let r = responses.send(Users::NAME, || 0);
+        assert!(matches!(r, Err(AdminError::SendFailed { .. })));
+        assert_eq!(responses.inner.in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_admin_responses_timeout() {
+        let responses = AdminResponses::default();
+        let (tag, recv) = responses.send(Users::NAME, || 42).unwrap();
+        assert_eq!(tag, 42);
+        let r = responses.wait(Users::NAME, tag, recv, Duration::from_millis(1));
+        assert!(matches!(r, Err(AdminError::Timeout { tag: 42, .. })));
+        assert_eq!(responses.inner.in_flight.load(Ordering::SeqCst), 0);
+    }
+}
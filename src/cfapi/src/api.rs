@@ -1,6 +1,11 @@
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::time::Duration;
 
+use super::admin::{
+    ActiveClients, AdminError, AdminResponse, AdminResponses, AdministrationInfo, Enumerations,
+    ExchangeInfo, SubscribedSymbols, UserPermission, Users,
+};
 use super::binding::{
     APIFactoryWrap, BaseMessageEventHandler, BaseSessionEventHandler, BaseStatisticsEventHandler,
     BaseUserEventHandler, Commands,
@@ -306,6 +311,7 @@ pub struct CFAPI {
     _session_event_handler: Rc<RefCell<BaseSessionEventHandler>>,
     _message_event_handler: Rc<RefCell<BaseMessageEventHandler>>,
     _statistics_event_handler: Rc<RefCell<BaseStatisticsEventHandler>>,
+    admin_responses: AdminResponses,
 }
 
 impl CFAPI {
@@ -321,8 +327,10 @@ impl CFAPI {
         let session_event_handler = BaseSessionEventHandler::new_rust_owned(
             BaseSessionEventHandler::new(session_event_handlers),
         );
+        let admin_responses = AdminResponses::default();
         let message_event_handler = BaseMessageEventHandler::new_rust_owned(
-            BaseMessageEventHandler::new(message_event_handlers),
+            BaseMessageEventHandler::new(message_event_handlers)
+                .with_admin_responses(admin_responses.clone()),
         );
         let statistics_event_handler = BaseStatisticsEventHandler::new_rust_owned(
             BaseStatisticsEventHandler::new(statistics_event_handlers),
@@ -356,6 +364,7 @@ impl CFAPI {
             _session_event_handler: session_event_handler,
             _message_event_handler: message_event_handler,
             _statistics_event_handler: statistics_event_handler,
+            admin_responses,
         }
     }
 
@@ -482,4 +491,76 @@ impl CFAPI {
         let_cxx_string!(symbol = symbol);
         self.api.pin_mut().sendRequest(&src_id, &symbol, command);
     }
+
+    /// Send an administrative or listing command and collect its IMAGE_PART
+    /// stream into the typed response, empty arguments are not added to the request.
+    pub fn admin_request<T: AdminResponse>(
+        &mut self,
+        src_id: &str,
+        symbol: &str,
+        user_name: &str,
+        timeout: Duration,
+    ) -> Result<T, AdminError> {
+        let api = &mut self.api;
+        let (tag, recv) = self.admin_responses.send(T::NAME, || {
+            let_cxx_string!(src_id = src_id);
+            let_cxx_string!(symbol = symbol);
+            let_cxx_string!(user_name = user_name);
+            api.pin_mut()
+                .sendAdminRequest(&src_id, &symbol, &user_name, T::command())
+        })?;
+        let records = self.admin_responses.wait(T::NAME, tag, recv, timeout)?;
+        Ok(T::from_records(records))
+    }
+
+    pub fn list_subscribed_symbols(
+        &mut self,
+        src_id: &str,
+        timeout: Duration,
+    ) -> Result<SubscribedSymbols, AdminError> {
+        self.admin_request(src_id, "", "", timeout)
+    }
+
+    pub fn list_user_permission(
+        &mut self,
+        user_name: &str,
+        timeout: Duration,
+    ) -> Result<UserPermission, AdminError> {
+        self.admin_request("", "", user_name, timeout)
+    }
+
+    pub fn get_active_clients_info(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<ActiveClients, AdminError> {
+        self.admin_request("", "", "", timeout)
+    }
+
+    pub fn get_all_users(&mut self, timeout: Duration) -> Result<Users, AdminError> {
+        self.admin_request("", "", "", timeout)
+    }
+
+    pub fn list_enumeration(
+        &mut self,
+        src_id: &str,
+        timeout: Duration,
+    ) -> Result<Enumerations, AdminError> {
+        self.admin_request(src_id, "", "", timeout)
+    }
+
+    pub fn list_administration_info(
+        &mut self,
+        src_id: &str,
+        timeout: Duration,
+    ) -> Result<AdministrationInfo, AdminError> {
+        self.admin_request(src_id, "", "", timeout)
+    }
+
+    pub fn list_extended_exchange_info(
+        &mut self,
+        src_id: &str,
+        timeout: Duration,
+    ) -> Result<ExchangeInfo, AdminError> {
+        self.admin_request(src_id, "", "", timeout)
+    }
 }
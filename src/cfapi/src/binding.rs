@@ -1,5 +1,6 @@
 use std::fmt::{Debug, Display};
 
+use super::admin::AdminResponses;
 use super::message_event::{DefaultMessageEventHandler, MessageEventHandlerExt};
 use super::session_event::{DefaultSessionEventHandler, SessionEventHandlerExt};
 use super::stat_event::{DefaultStatisticsEventHandler, StatisticsEventHandlerExt};
@@ -149,6 +150,7 @@ impl cfapi::SessionEventHandler_methods for BaseSessionEventHandler {
 pub struct BaseMessageEventHandler {
     handlers: Vec<Box<dyn MessageEventHandlerExt + 'static>>,
     with_default: bool,
+    admin_responses: AdminResponses,
 }
 
 impl BaseMessageEventHandler {
@@ -172,6 +174,11 @@ impl BaseMessageEventHandler {
         self
     }
 
+    pub fn with_admin_responses(mut self, admin_responses: AdminResponses) -> Self {
+        self.admin_responses = admin_responses;
+        self
+    }
+
     pub fn add_handler(&mut self, handler: Box<dyn MessageEventHandlerExt + 'static>) {
         if self.with_default {
             self.handlers.pop();
@@ -188,6 +195,9 @@ impl BaseMessageEventHandler {
 
 impl cfapi::MessageEventHandler_methods for BaseMessageEventHandler {
     fn onMessageEvent(&mut self, event: &cfapi::MessageEvent) {
+        if self.admin_responses.collect(event) {
+            return;
+        }
         for handler in &mut self.handlers {
             handler.on_message_event(event);
         }
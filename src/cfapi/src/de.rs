@@ -0,0 +1,464 @@
+use super::value::CFValue;
+use serde::de::{self, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use serde::forward_to_deserialize_any;
+use snafu::prelude::Snafu;
+use std::fmt::Display;
+
+#[derive(Debug, Snafu)]
+pub enum DeError {
+    #[snafu(display("{}", message))]
+    Custom { message: String },
+    #[snafu(display("CFValue {:?} is not a lossless {}", value, expected))]
+    Mistyped {
+        expected: &'static str,
+        value: CFValue,
+    },
+}
+
+impl de::Error for DeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        DeError::Custom {
+            message: msg.to_string(),
+        }
+    }
+}
+
+/// Tokens read one at a time, the name and value only when asked for.
+pub trait TokenSource {
+    /// Moves to the next token, false after the last.
+    fn advance(&mut self) -> bool;
+    fn token_number(&mut self) -> i32;
+    fn token_name(&mut self) -> String;
+    fn value(&mut self) -> CFValue;
+}
+
+impl<S: TokenSource + ?Sized> TokenSource for &mut S {
+    fn advance(&mut self) -> bool {
+        (**self).advance()
+    }
+
+    fn token_number(&mut self) -> i32 {
+        (**self).token_number()
+    }
+
+    fn token_name(&mut self) -> String {
+        (**self).token_name()
+    }
+
+    fn value(&mut self) -> CFValue {
+        (**self).value()
+    }
+}
+
+/// Deserializes a struct or map from the tokens in one pass.
+///
+/// A struct field takes the token whose name it has, or whose number it starts with in
+/// the `to_map` key form, so `#[serde(rename = "(447)")]` and
+/// `#[serde(rename = "(447)TRADE.PRICE")]` both read token 447. Tokens without a field
+/// are skipped without reading their value. A map gets every token under its `to_map`
+/// key.
+///
+/// A repeated token, like 22 in snapshots, is read as its last value, as `to_map` and
+/// `apply_tokens` do. A struct has its fields matched to the end of the tokens before
+/// they are deserialized, so serde does not see a duplicate field.
+pub struct TokenDeserializer<S> {
+    source: S,
+    // tokens before the ones of the source, the symbol and such
+    header: std::vec::IntoIter<(i32, &'static str, CFValue)>,
+}
+
+impl<S: TokenSource> TokenDeserializer<S> {
+    pub fn new(source: S) -> Self {
+        TokenDeserializer {
+            source,
+            header: vec![].into_iter(),
+        }
+    }
+
+    pub fn with_header(mut self, header: Vec<(i32, &'static str, CFValue)>) -> Self {
+        self.header = header.into_iter();
+        self
+    }
+}
+
+// (447) and (447)TRADE.PRICE are token 447
+fn field_token(field: &str) -> Option<i32> {
+    let (number, _) = field.strip_prefix('(')?.split_once(')')?;
+    number.parse().ok()
+}
+
+impl<'de, S: TokenSource> de::Deserializer<'de> for TokenDeserializer<S> {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_map(TokenMap {
+            de: &mut self,
+            fields: None,
+            matched: None,
+            value: None,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        mut self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        let fields = Fields {
+            numbered: fields
+                .iter()
+                .filter_map(|field| Some((field_token(field)?, *field)))
+                .collect(),
+            named: fields
+                .iter()
+                .filter(|field| field_token(field).is_none())
+                .copied()
+                .collect(),
+        };
+        visitor.visit_map(TokenMap {
+            de: &mut self,
+            fields: Some(fields),
+            matched: None,
+            value: None,
+        })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+struct Fields {
+    numbered: Vec<(i32, &'static str)>,
+    named: Vec<&'static str>,
+}
+
+struct TokenMap<'a, S> {
+    de: &'a mut TokenDeserializer<S>,
+    // None for a map, which takes every token
+    fields: Option<Fields>,
+    // the last value of each field, read on the first key
+    matched: Option<std::vec::IntoIter<(&'static str, CFValue)>>,
+    value: Option<CFValue>,
+}
+
+impl Fields {
+    fn find(&self, number: i32, name: impl FnOnce() -> String) -> Option<&'static str> {
+        if let Some((_, field)) = self.numbered.iter().find(|(n, _)| *n == number) {
+            return Some(field);
+        }
+        // the name is only read when a field may want it
+        if self.named.is_empty() {
+            return None;
+        }
+        let name = name();
+        self.named.iter().find(|field| **field == name).copied()
+    }
+}
+
+impl<'a, S: TokenSource> TokenMap<'a, S> {
+    // the number of the next token, with the name and value when it is a header one
+    fn next_token(&mut self) -> Option<(i32, Option<&'static str>, Option<CFValue>)> {
+        match self.de.header.next() {
+            Some((number, name, value)) => Some((number, Some(name), Some(value))),
+            None if self.de.source.advance() => Some((self.de.source.token_number(), None, None)),
+            None => None,
+        }
+    }
+
+    fn match_fields(&mut self) -> Vec<(&'static str, CFValue)> {
+        let mut matched: Vec<(&'static str, CFValue)> = vec![];
+        while let Some((number, header_name, header_value)) = self.next_token() {
+            let Some(fields) = &self.fields else {
+                break;
+            };
+            let source = &mut self.de.source;
+            let name = || match header_name {
+                Some(name) => name.to_string(),
+                None => source.token_name(),
+            };
+            let Some(field) = fields.find(number, name) else {
+                continue;
+            };
+            let value = header_value.unwrap_or_else(|| self.de.source.value());
+            match matched.iter_mut().find(|(f, _)| *f == field) {
+                Some((_, last)) => *last = value,
+                None => matched.push((field, value)),
+            }
+        }
+        matched
+    }
+}
+
+impl<'de, 'a, S: TokenSource> MapAccess<'de> for TokenMap<'a, S> {
+    type Error = DeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, DeError> {
+        if self.fields.is_none() {
+            let Some((number, header_name, header_value)) = self.next_token() else {
+                return Ok(None);
+            };
+            let name = match header_name {
+                Some(name) => name.to_string(),
+                None => self.de.source.token_name(),
+            };
+            let key = format!("({}){}", number, name);
+            self.value = Some(header_value.unwrap_or_else(|| self.de.source.value()));
+            return seed.deserialize(key.into_deserializer()).map(Some);
+        }
+        if self.matched.is_none() {
+            self.matched = Some(self.match_fields().into_iter());
+        }
+        match self.matched.as_mut().and_then(Iterator::next) {
+            Some((field, value)) => {
+                self.value = Some(value);
+                seed.deserialize(field.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, DeError> {
+        let value = self.value.take().unwrap_or(CFValue::Null);
+        seed.deserialize(ValueDeserializer(value))
+    }
+}
+
+/// Deserializes one value. Numbers convert only when lossless, see `coerce_i64` and
+/// `coerce_f64`, and a blank or unknown value is none.
+pub struct ValueDeserializer(pub CFValue);
+
+impl ValueDeserializer {
+    fn mistyped(self, expected: &'static str) -> DeError {
+        DeError::Mistyped {
+            expected,
+            value: self.0,
+        }
+    }
+}
+
+impl<'de> IntoDeserializer<'de, DeError> for CFValue {
+    type Deserializer = ValueDeserializer;
+
+    fn into_deserializer(self) -> ValueDeserializer {
+        ValueDeserializer(self)
+    }
+}
+
+macro_rules! deserialize_int {
+    ($($method:ident)*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+            match self.0.coerce_i64() {
+                Some(v) => visitor.visit_i64(v),
+                None => Err(self.mistyped("integer")),
+            }
+        }
+    )*};
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.0 {
+            CFValue::String(v) => visitor.visit_string(v),
+            CFValue::Double(v) | CFValue::Datetime(v) => visitor.visit_f64(v),
+            CFValue::Int(v) => visitor.visit_i64(v),
+            CFValue::Decimal(v) => visitor.visit_string(v.to_string()),
+            CFValue::Null | CFValue::Unknown => visitor.visit_unit(),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.0 {
+            CFValue::Null | CFValue::Unknown => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    deserialize_int! {
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.0.coerce_f64() {
+            Some(v) => visitor.visit_f64(v),
+            None => Err(self.mistyped("f64")),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.0 {
+            CFValue::String(v) => visitor.visit_string(v),
+            CFValue::Decimal(v) => visitor.visit_string(v.to_string()),
+            _ => Err(self.mistyped("string")),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i128 u128 char bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    struct Tokens {
+        tokens: Vec<(i32, &'static str, CFValue)>,
+        pos: Option<usize>,
+        names_read: usize,
+    }
+
+    impl TokenSource for Tokens {
+        fn advance(&mut self) -> bool {
+            let pos = self.pos.map_or(0, |pos| pos + 1);
+            self.pos = Some(pos);
+            pos < self.tokens.len()
+        }
+
+        fn token_number(&mut self) -> i32 {
+            self.tokens[self.pos.unwrap()].0
+        }
+
+        fn token_name(&mut self) -> String {
+            self.names_read += 1;
+            self.tokens[self.pos.unwrap()].1.to_string()
+        }
+
+        fn value(&mut self) -> CFValue {
+            self.tokens[self.pos.unwrap()].2.clone()
+        }
+    }
+
+    fn tokens() -> Tokens {
+        Tokens {
+            tokens: vec![
+                (8, "TRADE.PRICE", CFValue::Double(167.78)),
+                (9, "TRADE.SIZE", CFValue::Double(651.0)),
+                (854, "TRADE.PART.CODE", CFValue::from("t")),
+                (395, "TRADE.OFFICIAL.LOW", CFValue::Null),
+            ],
+            pos: None,
+            names_read: 0,
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Trade {
+        #[serde(rename = "(2)Symbol")]
+        symbol: String,
+        #[serde(rename = "(8)")]
+        price: f64,
+        #[serde(rename = "(9)TRADE.SIZE")]
+        size: i64,
+        #[serde(rename = "(395)")]
+        low: Option<f64>,
+        #[serde(default)]
+        volume: i64,
+    }
+
+    #[test]
+    fn test_deserialize_tokens() {
+        let header = vec![(2, "Symbol", CFValue::from("AAPL"))];
+        let mut source = tokens();
+        let de = TokenDeserializer::new(&mut source).with_header(header.clone());
+        let trade = Trade::deserialize(de).unwrap();
+        assert_eq!(trade.symbol, "AAPL");
+        assert_eq!((trade.price, trade.size, trade.low), (167.78, 651, None));
+        assert_eq!(trade.volume, 0);
+        // the volume field has a name, so a token without a numbered field has its name read
+        assert_eq!(source.names_read, 1);
+
+        #[derive(Deserialize)]
+        struct Part {
+            #[serde(rename = "TRADE.PART.CODE")]
+            code: String,
+            #[serde(rename = "(8)")]
+            price: f64,
+        }
+        let part = Part::deserialize(TokenDeserializer::new(tokens())).unwrap();
+        assert_eq!((part.code.as_str(), part.price), ("t", 167.78));
+
+        #[derive(Deserialize)]
+        struct Low {
+            #[serde(rename = "(395)")]
+            low: Option<f64>,
+        }
+        let mut source = tokens();
+        let low = Low::deserialize(TokenDeserializer::new(&mut source)).unwrap();
+        assert_eq!((low.low, source.names_read), (None, 0));
+
+        #[derive(Debug, Deserialize)]
+        struct Price {
+            #[serde(rename = "(8)")]
+            _price: i64,
+        }
+        let err = Price::deserialize(TokenDeserializer::new(tokens())).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "CFValue Double(167.78) is not a lossless integer"
+        );
+
+        let map: BTreeMap<String, CFValue> =
+            BTreeMap::deserialize(TokenDeserializer::new(tokens()).with_header(header)).unwrap();
+        assert_eq!(map["(2)Symbol"], CFValue::from("AAPL"));
+        assert_eq!(map["(9)TRADE.SIZE"], CFValue::Double(651.0));
+        assert_eq!(map["(395)TRADE.OFFICIAL.LOW"], CFValue::Null);
+        assert_eq!(map.len(), 5);
+    }
+
+    #[test]
+    fn test_repeated_token() {
+        let repeated = || Tokens {
+            tokens: vec![
+                (22, "TRADE.VOL", CFValue::Double(35490925.0)),
+                (8, "TRADE.PRICE", CFValue::Double(167.78)),
+                (22, "TRADE.VOL", CFValue::Double(35491576.0)),
+            ],
+            pos: None,
+            names_read: 0,
+        };
+
+        #[derive(Deserialize)]
+        struct Volume {
+            #[serde(rename = "(22)")]
+            volume: i64,
+            #[serde(rename = "(8)")]
+            price: f64,
+        }
+        let volume = Volume::deserialize(TokenDeserializer::new(repeated())).unwrap();
+        assert_eq!((volume.volume, volume.price), (35491576, 167.78));
+
+        let map: BTreeMap<String, CFValue> =
+            BTreeMap::deserialize(TokenDeserializer::new(repeated())).unwrap();
+        assert_eq!(map["(22)TRADE.VOL"], CFValue::Double(35491576.0));
+        assert_eq!(map.len(), 2);
+    }
+}
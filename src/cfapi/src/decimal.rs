@@ -0,0 +1,284 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use snafu::prelude::Snafu;
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// 10^18 is the largest power of ten in an i64.
+pub const MAX_SCALE: u8 = 18;
+
+#[derive(Debug, Snafu)]
+pub enum DecimalError {
+    #[snafu(display("{:?} is not a decimal", input))]
+    Parse { input: String },
+    #[snafu(display("{} does not fit a decimal with scale {}", value, scale))]
+    OutOfRange { value: String, scale: u8 },
+}
+
+/// How a decimal is serialized.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecimalRepr {
+    /// "167.78", every digit of the scale
+    #[default]
+    String,
+    /// 16778 for a scale of 2
+    Scaled,
+    /// 167.78, the nearest f64
+    Float,
+}
+
+/// Fixed-point number, `units / 10^scale`. Equality and ordering compare the value, so
+/// neither the scale nor the repr take part.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Decimal {
+    units: i64,
+    scale: u8,
+    repr: DecimalRepr,
+}
+
+fn pow10(scale: u8) -> i64 {
+    10i64.pow(scale as u32)
+}
+
+impl Decimal {
+    pub fn new(units: i64, scale: u8) -> Self {
+        assert!(
+            scale <= MAX_SCALE,
+            "decimal scale {} above {}",
+            scale,
+            MAX_SCALE
+        );
+        Decimal {
+            units,
+            scale,
+            repr: DecimalRepr::default(),
+        }
+    }
+
+    /// Rounds half away from zero to the scale, so 167.78000000000003 is 167.78 at
+    /// scale 2. None for nan, infinity or a value too large for the scale.
+    pub fn from_f64(value: f64, scale: u8) -> Option<Self> {
+        if scale > MAX_SCALE {
+            return None;
+        }
+        let units = (value * pow10(scale) as f64).round();
+        // i64::MAX as f64 rounds up to 2^63, which does not fit
+        if units.is_finite() && units >= i64::MIN as f64 && units < i64::MAX as f64 {
+            Some(Decimal::new(units as i64, scale))
+        } else {
+            None
+        }
+    }
+
+    pub fn with_repr(mut self, repr: DecimalRepr) -> Self {
+        self.repr = repr;
+        self
+    }
+
+    pub fn units(&self) -> i64 {
+        self.units
+    }
+
+    pub fn scale(&self) -> u8 {
+        self.scale
+    }
+
+    pub fn repr(&self) -> DecimalRepr {
+        self.repr
+    }
+
+    /// The nearest f64, exact as long as the units fit 2^53.
+    pub fn to_f64(&self) -> f64 {
+        self.units as f64 / pow10(self.scale) as f64
+    }
+
+    /// Rounds half away from zero when the scale shrinks, None when the units overflow.
+    pub fn rescale(&self, scale: u8) -> Option<Self> {
+        if scale > MAX_SCALE {
+            return None;
+        }
+        let units = if scale >= self.scale {
+            self.units.checked_mul(pow10(scale - self.scale))?
+        } else {
+            let div = pow10(self.scale - scale);
+            let (q, r) = (self.units / div, self.units % div);
+            if r.abs() * 2 >= div {
+                q + self.units.signum()
+            } else {
+                q
+            }
+        };
+        Some(Decimal {
+            units,
+            scale,
+            repr: self.repr,
+        })
+    }
+
+    // units at scale 18 do not always fit an i64, in an i128 they do
+    fn cmp_units(&self, other: &Self) -> (i128, i128) {
+        let scale = self.scale.max(other.scale);
+        (
+            self.units as i128 * pow10(scale - self.scale) as i128,
+            other.units as i128 * pow10(scale - other.scale) as i128,
+        )
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool {
+        let (a, b) = self.cmp_units(other);
+        a == b
+    }
+}
+
+impl Eq for Decimal {}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (a, b) = self.cmp_units(other);
+        a.cmp(&b)
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.scale == 0 {
+            return write!(f, "{}", self.units);
+        }
+        let div = pow10(self.scale).unsigned_abs();
+        let abs = self.units.unsigned_abs();
+        let sign = if self.units < 0 { "-" } else { "" };
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            abs / div,
+            abs % div,
+            width = self.scale as usize
+        )
+    }
+}
+
+/// The scale is the number of fraction digits, "590.00" has a scale of 2.
+impl FromStr for Decimal {
+    type Err = DecimalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_error = || ParseSnafu { input: s }.build();
+        let (int, frac) = s.split_once('.').unwrap_or((s, ""));
+        let digits = int.trim_start_matches(['-', '+']);
+        if int.len() - digits.len() > 1
+            || digits.is_empty() && frac.is_empty()
+            || !digits
+                .bytes()
+                .chain(frac.bytes())
+                .all(|b| b.is_ascii_digit())
+        {
+            return Err(parse_error());
+        }
+        let scale = u8::try_from(frac.len())
+            .ok()
+            .filter(|scale| *scale <= MAX_SCALE)
+            .ok_or_else(|| {
+                OutOfRangeSnafu {
+                    value: s,
+                    scale: MAX_SCALE,
+                }
+                .build()
+            })?;
+        let units: i64 = format!("{}{}", int, frac)
+            .parse()
+            .map_err(|_| OutOfRangeSnafu { value: s, scale }.build())?;
+        Ok(Decimal::new(units, scale))
+    }
+}
+
+impl Serialize for Decimal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.repr {
+            DecimalRepr::String => serializer.collect_str(self),
+            DecimalRepr::Scaled => serializer.serialize_i64(self.units),
+            DecimalRepr::Float => serializer.serialize_f64(self.to_f64()),
+        }
+    }
+}
+
+struct DecimalVisitor;
+
+impl<'de> de::Visitor<'de> for DecimalVisitor {
+    type Value = Decimal;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a decimal string or number")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Decimal, E> {
+        v.parse().map_err(E::custom)
+    }
+
+    // the scale of a scaled integer is not in the data, so it reads as scale 0
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Decimal, E> {
+        Ok(Decimal::new(v, 0).with_repr(DecimalRepr::Scaled))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Decimal, E> {
+        let v = i64::try_from(v).map_err(E::custom)?;
+        self.visit_i64(v)
+    }
+
+    // the shortest digits that read back as the same f64
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Decimal, E> {
+        let decimal: Decimal = v.to_string().parse().map_err(E::custom)?;
+        Ok(decimal.with_repr(DecimalRepr::Float))
+    }
+}
+
+impl<'de> Deserialize<'de> for Decimal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(DecimalVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decimal() {
+        let price = Decimal::from_f64(167.78000000000003, 2).unwrap();
+        assert_eq!(price.units(), 16778);
+        assert_eq!(price.to_string(), "167.78");
+        assert_eq!(price.to_f64(), 167.78);
+        assert_eq!(price, "167.780".parse().unwrap());
+        assert_eq!(Decimal::from_f64(-0.005, 2).unwrap().to_string(), "-0.01");
+        assert_eq!(Decimal::new(-5, 3).to_string(), "-0.005");
+        assert_eq!(
+            Decimal::new(590, 0).rescale(2).unwrap().to_string(),
+            "590.00"
+        );
+        assert_eq!(Decimal::new(-16775, 2).rescale(1).unwrap().units(), -1678);
+        assert!(Decimal::from_f64(f64::NAN, 2).is_none());
+        assert!(Decimal::new(1, 2) < Decimal::new(1, 1));
+        assert!("1.2.3".parse::<Decimal>().is_err());
+        assert!("--1".parse::<Decimal>().is_err());
+        assert!(".".parse::<Decimal>().is_err());
+
+        assert_eq!(serde_json::to_string(&price).unwrap(), "\"167.78\"");
+        let scaled = price.with_repr(DecimalRepr::Scaled);
+        assert_eq!(serde_json::to_string(&scaled).unwrap(), "16778");
+        let float = price.with_repr(DecimalRepr::Float);
+        assert_eq!(serde_json::to_string(&float).unwrap(), "167.78");
+        let back: Decimal = serde_json::from_str("167.78").unwrap();
+        assert_eq!((back, back.repr()), (price, DecimalRepr::Float));
+        let back: Decimal = serde_json::from_str("\"590.00\"").unwrap();
+        assert_eq!((back.units(), back.scale()), (59000, 2));
+    }
+}
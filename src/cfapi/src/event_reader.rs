@@ -1,6 +1,12 @@
 use super::binding::{GetEventReader, MessageEvent, MessageEvent_Types, MessageReader, ValueTypes};
+use super::de::{DeError, TokenDeserializer, TokenSource};
 use super::value::CFValue;
+use serde::de::{DeserializeOwned, Deserializer, Visitor};
+use serde::forward_to_deserialize_any;
 use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::ptr::NonNull;
 
 pub struct EventReaderSerConfig {
     with_event_type: bool,
@@ -28,33 +34,259 @@ impl EventReaderSerConfig {
     }
 }
 
+// the calls EventReader makes to the sdk reader, which only moves forward or finds a token.
+// MessageReader.h says find "searches the message" and keeps the position when the token
+// is not found. Reset relies on find searching from the first token of the message, not
+// only forward from the current one, which the header does not spell out.
+trait RawReader {
+    fn next(&mut self) -> i32;
+    // token after the current one
+    fn peek(&mut self) -> i32;
+    fn find(&mut self, id: i32) -> bool;
+    fn token_number(&mut self) -> i32;
+    fn token_name(&mut self) -> String;
+    fn value_type(&mut self) -> ValueTypes;
+    fn value(&mut self) -> CFValue;
+}
+
+struct SdkReader<'a> {
+    // owned by the event, so it lives as long as the borrow of the event
+    reader: NonNull<MessageReader>,
+    _event: PhantomData<&'a mut MessageReader>,
+}
+
+impl SdkReader<'_> {
+    fn pin(&mut self) -> Pin<&mut MessageReader> {
+        // SAFETY: the reader is a C++ object owned by the borrowed event, it never moves
+        // and outlives self. The &mut only lives as long as the &mut self and the reader
+        // does not leave its thread, so it is never used by two at once.
+        unsafe { Pin::new_unchecked(self.reader.as_mut()) }
+    }
+}
+
+impl RawReader for SdkReader<'_> {
+    fn next(&mut self) -> i32 {
+        i32::from(self.pin().next())
+    }
+
+    fn peek(&mut self) -> i32 {
+        i32::from(self.pin().peek())
+    }
+
+    fn find(&mut self, id: i32) -> bool {
+        self.pin().find(autocxx::c_int(id))
+    }
+
+    fn token_number(&mut self) -> i32 {
+        i32::from(self.pin().getTokenNumber())
+    }
+
+    fn token_name(&mut self) -> String {
+        self.pin().getTokenName().to_string()
+    }
+
+    fn value_type(&mut self) -> ValueTypes {
+        self.pin().getValueType()
+    }
+
+    fn value(&mut self) -> CFValue {
+        match self.value_type() {
+            ValueTypes::INT64 => CFValue::Int(self.pin().getValueAsInteger()),
+            ValueTypes::DOUBLE => CFValue::Double(self.pin().getValueAsDouble()),
+            ValueTypes::STRING => {
+                let value = self.pin().getValueAsString().to_string();
+                // a blank token has an empty value
+                if value.is_empty() {
+                    CFValue::Null
+                } else {
+                    CFValue::String(value)
+                }
+            }
+            ValueTypes::DATETIME => CFValue::Datetime(self.pin().getValueAsDouble()),
+            ValueTypes::UNKNOWN => CFValue::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    // before the first token, nothing is current
+    Start,
+    Token,
+    End,
+}
+
+// the position of next_token. Reset moves back to the start, the position of the reader at
+// new. There is no rewind in the sdk, so the start is found again by its token number and
+// a token repeated before the start would be found instead.
+struct Cursor<R> {
+    reader: R,
+    // token after the position at new, -1 when there are none
+    first: i32,
+    state: State,
+    // the reader left the start, so the first token is found rather than read next
+    moved: bool,
+}
+
+impl<R: RawReader> Cursor<R> {
+    fn new(mut reader: R) -> Self {
+        let first = reader.peek();
+        Self {
+            reader,
+            first,
+            state: State::Start,
+            moved: false,
+        }
+    }
+
+    fn next_token(&mut self) -> Option<i32> {
+        let token = match self.state {
+            State::End => return None,
+            State::Start if self.first == -1 => -1,
+            State::Start if self.moved => match self.reader.find(self.first) {
+                true => self.first,
+                false => -1,
+            },
+            State::Start | State::Token => self.reader.next(),
+        };
+        self.moved = true;
+        if token == -1 {
+            self.state = State::End;
+            return None;
+        }
+        self.state = State::Token;
+        Some(token)
+    }
+
+    fn peek(&mut self) -> Option<i32> {
+        let token = match self.state {
+            State::Start => self.first,
+            State::Token => self.reader.peek(),
+            State::End => -1,
+        };
+        (token != -1).then_some(token)
+    }
+
+    fn reset(&mut self) {
+        self.state = State::Start;
+    }
+
+    // not found keeps the position, as the sdk reader does
+    fn seek(&mut self, id: i32) -> bool {
+        let found = self.reader.find(id);
+        if found {
+            self.moved = true;
+            self.state = State::Token;
+        }
+        found
+    }
+
+    fn token_number(&mut self) -> i32 {
+        match self.state {
+            State::Token => self.reader.token_number(),
+            _ => -1,
+        }
+    }
+
+    fn token_name(&mut self) -> String {
+        match self.state {
+            State::Token => self.reader.token_name(),
+            _ => "UNKNOWN".to_string(),
+        }
+    }
+
+    fn value_type(&mut self) -> ValueTypes {
+        match self.state {
+            State::Token => self.reader.value_type(),
+            _ => ValueTypes::UNKNOWN,
+        }
+    }
+
+    fn value(&mut self) -> CFValue {
+        match self.state {
+            State::Token => self.reader.value(),
+            _ => CFValue::Unknown,
+        }
+    }
+}
+
+/// Reads the tokens of a MessageEvent. The reader borrows the event, which the SDK only
+/// keeps for the callback, so it can not outlive the callback:
+///
+/// ```compile_fail
+/// use cfapi::binding::MessageEvent;
+/// use cfapi::event_reader::{EventReader, EventReaderSerConfig};
+///
+/// fn keep<'a>(event: &MessageEvent, config: &'a EventReaderSerConfig) -> EventReader<'a> {
+///     EventReader::new(event, config)
+/// }
+/// ```
+///
+/// It is neither Send nor Sync, the SDK reader is not thread safe.
 pub struct EventReader<'a> {
     event: &'a MessageEvent,
-    reader: std::pin::Pin<&'a mut MessageReader>,
+    cursor: Cursor<SdkReader<'a>>,
     ser_config: &'a EventReaderSerConfig,
 }
 
 impl<'a> EventReader<'a> {
+    /// Readers of the same event share the position of the sdk reader. The start that
+    /// reset goes back to is where it was at new, which is the first token only when the
+    /// event was not read before, e.g. a new reader in the callback.
     pub fn new(event: &'a MessageEvent, ser_config: &'a EventReaderSerConfig) -> Self {
         let reader = GetEventReader(event) as *mut MessageReader;
-        let reader = unsafe { std::pin::Pin::new_unchecked(&mut *reader) };
+        let reader = SdkReader {
+            reader: NonNull::new(reader).expect("MessageEvent without a reader"),
+            _event: PhantomData,
+        };
         EventReader {
             event,
-            reader,
+            cursor: Cursor::new(reader),
             ser_config,
         }
     }
+
     pub fn with_ser_config(mut self, ser_config: &'a EventReaderSerConfig) -> Self {
         self.ser_config = ser_config;
         self
     }
+
+    /// Moves to the next token and returns its number, None after the last.
+    pub fn next_token(&mut self) -> Option<i32> {
+        self.cursor.next_token()
+    }
+
+    /// Number of the token next_token moves to, without moving.
+    pub fn peek(&mut self) -> Option<i32> {
+        self.cursor.peek()
+    }
+
+    /// Back to where the reader was at new, next_token returns that token again. Until
+    /// then no token is current. This finds the token by number, see `RawReader`.
+    pub fn reset(&mut self) {
+        self.cursor.reset()
+    }
+
+    /// Type of the current value, without reading it. UNKNOWN when no token is current.
+    pub fn value_type(&mut self) -> ValueTypes {
+        self.cursor.value_type()
+    }
+
+    /// Moves to the token like find, but only returns the type of its value.
+    pub fn find_value_type(&mut self, id: i32) -> Option<ValueTypes> {
+        if self.cursor.seek(id) {
+            Some(self.value_type())
+        } else {
+            None
+        }
+    }
 }
 
-pub struct EventReaderWithTokenNumberIter<'a> {
-    reader: &'a mut EventReader<'a>,
+pub struct EventReaderWithTokenNumberIter<'r, 'a> {
+    reader: &'r mut EventReader<'a>,
 }
 
-impl<'a> Iterator for EventReaderWithTokenNumberIter<'a> {
+impl<'r, 'a> Iterator for EventReaderWithTokenNumberIter<'r, 'a> {
     type Item = (i32, CFValue);
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -62,11 +294,11 @@ impl<'a> Iterator for EventReaderWithTokenNumberIter<'a> {
     }
 }
 
-pub struct EventReaderWithTokenNameIter<'a> {
-    reader: &'a mut EventReader<'a>,
+pub struct EventReaderWithTokenNameIter<'r, 'a> {
+    reader: &'r mut EventReader<'a>,
 }
 
-impl<'a> Iterator for EventReaderWithTokenNameIter<'a> {
+impl<'r, 'a> Iterator for EventReaderWithTokenNameIter<'r, 'a> {
     type Item = (String, CFValue);
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -74,11 +306,11 @@ impl<'a> Iterator for EventReaderWithTokenNameIter<'a> {
     }
 }
 
-pub struct EventReaderWithTokenNumNameIter<'a> {
-    reader: &'a mut EventReader<'a>,
+pub struct EventReaderWithTokenNumNameIter<'r, 'a> {
+    reader: &'r mut EventReader<'a>,
 }
 
-impl<'a> Iterator for EventReaderWithTokenNumNameIter<'a> {
+impl<'r, 'a> Iterator for EventReaderWithTokenNumNameIter<'r, 'a> {
     type Item = (i32, String, CFValue);
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -90,25 +322,23 @@ impl<'a> Iterator for EventReader<'a> {
     type Item = CFValue;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.reader.as_mut().next() != autocxx::c_int(-1) {
-            Some(self.get_value())
-        } else {
-            None
-        }
+        self.next_token()?;
+        Some(self.get_value())
     }
 }
 
 impl<'a> EventReader<'a> {
+    /// Number of the current token, -1 when none is current.
     pub fn get_token_number(&mut self) -> i32 {
-        i32::from(self.reader.as_mut().getTokenNumber())
+        self.cursor.token_number()
     }
 
     pub fn get_token_name(&mut self) -> String {
-        self.reader.as_mut().getTokenName().to_string()
+        self.cursor.token_name()
     }
 
     pub fn find(&mut self, id: i32) -> Option<CFValue> {
-        if self.reader.as_mut().find(autocxx::c_int(id)) {
+        if self.cursor.seek(id) {
             Some(self.get_value())
         } else {
             None
@@ -116,58 +346,44 @@ impl<'a> EventReader<'a> {
     }
 
     pub fn get_value(&mut self) -> CFValue {
-        match self.reader.as_mut().getValueType() {
-            ValueTypes::INT64 => CFValue::Int(self.reader.as_mut().getValueAsInteger()),
-            ValueTypes::DOUBLE => CFValue::Double(self.reader.as_mut().getValueAsDouble()),
-            ValueTypes::STRING => {
-                CFValue::String(self.reader.as_mut().getValueAsString().to_string())
-            }
-            ValueTypes::DATETIME => CFValue::Datetime(self.reader.as_mut().getValueAsDouble()),
-            ValueTypes::UNKNOWN => CFValue::Unknown,
-        }
+        self.cursor.value()
     }
+
     pub fn next_with_token_number(&mut self) -> Option<(i32, CFValue)> {
-        if self.reader.as_mut().next() != autocxx::c_int(-1) {
-            Some((self.get_token_number(), self.get_value()))
-        } else {
-            None
-        }
+        let token = self.next_token()?;
+        Some((token, self.get_value()))
     }
 
     pub fn next_with_token_name(&mut self) -> Option<(String, CFValue)> {
-        if self.reader.as_mut().next() != autocxx::c_int(-1) {
-            Some((self.get_token_name(), self.get_value()))
-        } else {
-            None
-        }
+        self.next_token()?;
+        Some((self.get_token_name(), self.get_value()))
     }
 
     pub fn next_with_token_num_name(&mut self) -> Option<(i32, String, CFValue)> {
-        if self.reader.as_mut().next() != autocxx::c_int(-1) {
-            Some((
-                self.get_token_number(),
-                self.get_token_name(),
-                self.get_value(),
-            ))
-        } else {
-            None
-        }
+        let token = self.next_token()?;
+        Some((token, self.get_token_name(), self.get_value()))
     }
 
-    pub fn iter_with_token_number(&'a mut self) -> EventReaderWithTokenNumberIter {
+    /// Every token from the start, the iter methods can be called again for another walk
+    /// once the iterator is dropped.
+    pub fn iter_with_token_number(&mut self) -> EventReaderWithTokenNumberIter<'_, 'a> {
+        self.reset();
         EventReaderWithTokenNumberIter { reader: self }
     }
 
-    pub fn iter_with_token_name(&'a mut self) -> EventReaderWithTokenNameIter {
+    pub fn iter_with_token_name(&mut self) -> EventReaderWithTokenNameIter<'_, 'a> {
+        self.reset();
         EventReaderWithTokenNameIter { reader: self }
     }
 
-    pub fn iter_with_token_num_name(&'a mut self) -> EventReaderWithTokenNumNameIter {
+    pub fn iter_with_token_num_name(&mut self) -> EventReaderWithTokenNumNameIter<'_, 'a> {
+        self.reset();
         EventReaderWithTokenNumNameIter { reader: self }
     }
 
-    pub fn to_map(&'a mut self) -> BTreeMap<String, CFValue> {
-        let mut map = BTreeMap::new();
+    /// The event type, source and symbol as tokens 0, 1 and 2, as the ser config asks.
+    pub fn header(&self) -> Vec<(i32, &'static str, CFValue)> {
+        let mut header = vec![];
         if self.ser_config.with_event_type {
             let event_type = self.event.getType() as MessageEvent_Types;
             let event_type = match event_type {
@@ -177,19 +393,22 @@ impl<'a> EventReader<'a> {
                 MessageEvent_Types::STATUS => "STATUS",
                 MessageEvent_Types::UPDATE => "UPDATE",
             };
-            map.insert(
-                "(0)EventType".to_owned(),
-                CFValue::String(event_type.to_owned()),
-            );
+            header.push((0, "EventType", CFValue::String(event_type.to_owned())));
         }
         if self.ser_config.with_src {
-            map.insert(
-                "(1)Source".to_owned(),
-                CFValue::Int(i32::from(self.event.getSource()) as i64),
-            );
+            let source = CFValue::Int(i32::from(self.event.getSource()) as i64);
+            header.push((1, "Source", source));
         }
         let symbol = self.event.getSymbol();
-        map.insert("(2)Symbol".to_owned(), CFValue::String(symbol.to_string()));
+        header.push((2, "Symbol", CFValue::String(symbol.to_string())));
+        header
+    }
+
+    pub fn to_map(&mut self) -> BTreeMap<String, CFValue> {
+        let mut map = BTreeMap::new();
+        for (token_number, token_name, value) in self.header() {
+            map.insert(format!("({}){}", token_number, token_name), value);
+        }
 
         for (token_number, token_name, value) in self.iter_with_token_num_name() {
             map.insert(format!("({}){}", token_number, token_name), value);
@@ -198,11 +417,225 @@ impl<'a> EventReader<'a> {
         map
     }
 
-    pub fn to_json(&'a mut self) -> Result<String, serde_json::Error> {
+    pub fn to_json(&mut self) -> Result<String, serde_json::Error> {
         serde_json::to_string(&mut self.to_map())
     }
 
-    pub fn to_msgpack(&'a mut self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    pub fn to_msgpack(&mut self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
         rmp_serde::to_vec_named(&self.to_map())
     }
 }
+
+impl<'a> EventReader<'a> {
+    /// Reads the event from the start into `T`, see [`TokenDeserializer`] for how fields
+    /// match tokens. The header tokens are included, so `(2)Symbol` reads the symbol.
+    pub fn deserialize<T: DeserializeOwned>(&mut self) -> Result<T, DeError> {
+        T::deserialize(self)
+    }
+
+    fn tokens(&mut self) -> TokenDeserializer<&mut Self> {
+        self.reset();
+        let header = self.header();
+        TokenDeserializer::new(self).with_header(header)
+    }
+}
+
+impl<'a> TokenSource for EventReader<'a> {
+    fn advance(&mut self) -> bool {
+        self.next_token().is_some()
+    }
+
+    fn token_number(&mut self) -> i32 {
+        self.get_token_number()
+    }
+
+    fn token_name(&mut self) -> String {
+        self.get_token_name()
+    }
+
+    fn value(&mut self) -> CFValue {
+        self.get_value()
+    }
+}
+
+impl<'de, 'r, 'a> Deserializer<'de> for &'r mut EventReader<'a> {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        self.tokens().deserialize_any(visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.tokens().deserialize_struct(name, fields, visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // moves like the sdk reader: next and find move, peek looks at the token after. find
+    // searches from the first token, which is what reset assumes of the sdk
+    struct FakeReader {
+        tokens: Vec<(i32, CFValue)>,
+        // None before the first token
+        pos: Option<usize>,
+    }
+
+    impl FakeReader {
+        fn current(&self) -> Option<&(i32, CFValue)> {
+            self.tokens.get(self.pos?)
+        }
+    }
+
+    impl RawReader for FakeReader {
+        fn next(&mut self) -> i32 {
+            let pos = self.pos.map_or(0, |pos| pos + 1).min(self.tokens.len());
+            self.pos = Some(pos);
+            self.current().map_or(-1, |t| t.0)
+        }
+
+        fn peek(&mut self) -> i32 {
+            let pos = self.pos.map_or(0, |pos| pos + 1);
+            self.tokens.get(pos).map_or(-1, |t| t.0)
+        }
+
+        fn find(&mut self, id: i32) -> bool {
+            match self.tokens.iter().position(|t| t.0 == id) {
+                Some(pos) => {
+                    self.pos = Some(pos);
+                    true
+                }
+                None => false,
+            }
+        }
+
+        fn token_number(&mut self) -> i32 {
+            self.current().map_or(-1, |t| t.0)
+        }
+
+        fn token_name(&mut self) -> String {
+            format!("TOKEN.{}", self.token_number())
+        }
+
+        fn value_type(&mut self) -> ValueTypes {
+            match self.current() {
+                Some((_, CFValue::Int(_))) => ValueTypes::INT64,
+                Some((_, CFValue::Double(_))) => ValueTypes::DOUBLE,
+                Some((_, CFValue::String(_))) => ValueTypes::STRING,
+                _ => ValueTypes::UNKNOWN,
+            }
+        }
+
+        fn value(&mut self) -> CFValue {
+            self.current().map_or(CFValue::Unknown, |t| t.1.clone())
+        }
+    }
+
+    fn cursor(tokens: Vec<(i32, CFValue)>) -> Cursor<FakeReader> {
+        Cursor::new(FakeReader { tokens, pos: None })
+    }
+
+    fn quote() -> Cursor<FakeReader> {
+        cursor(vec![
+            (10, CFValue::Double(167.78)),
+            (11, CFValue::Int(300)),
+            (3240, CFValue::from("Q")),
+            (16, CFValue::Double(1.5)),
+        ])
+    }
+
+    fn rest(cursor: &mut Cursor<FakeReader>) -> Vec<i32> {
+        std::iter::from_fn(|| cursor.next_token()).collect()
+    }
+
+    #[test]
+    fn test_reset_after_partial_iteration() {
+        let mut cursor = quote();
+        assert_eq!(cursor.next_token(), Some(10));
+        assert_eq!(cursor.next_token(), Some(11));
+        cursor.reset();
+        assert_eq!(cursor.token_number(), -1);
+        assert!(matches!(cursor.value(), CFValue::Unknown));
+        assert_eq!(cursor.next_token(), Some(10));
+        assert!(matches!(cursor.value(), CFValue::Double(v) if v == 167.78));
+        assert_eq!(rest(&mut cursor), vec![11, 3240, 16]);
+        assert_eq!(cursor.next_token(), None);
+        cursor.reset();
+        assert_eq!(cursor.next_token(), Some(10));
+    }
+
+    #[test]
+    fn test_peek_then_next() {
+        let mut cursor = quote();
+        assert_eq!(cursor.peek(), Some(10));
+        assert_eq!(cursor.peek(), Some(10));
+        assert_eq!(cursor.next_token(), Some(10));
+        assert_eq!(cursor.peek(), Some(11));
+        assert_eq!(cursor.next_token(), Some(11));
+        assert_eq!(cursor.token_number(), 11);
+        cursor.reset();
+        assert_eq!(cursor.peek(), Some(10));
+        assert_eq!(cursor.next_token(), Some(10));
+        assert_eq!(rest(&mut cursor), vec![11, 3240, 16]);
+        assert_eq!(cursor.peek(), None);
+    }
+
+    #[test]
+    fn test_value_type_lookups() {
+        let mut cursor = quote();
+        assert!(cursor.value_type() == ValueTypes::UNKNOWN);
+        assert!(cursor.seek(3240));
+        assert!(cursor.value_type() == ValueTypes::STRING);
+        assert_eq!(cursor.next_token(), Some(16));
+        // not found keeps the position
+        assert!(!cursor.seek(999));
+        assert_eq!(cursor.token_number(), 16);
+        assert!(cursor.value_type() == ValueTypes::DOUBLE);
+        cursor.reset();
+        assert!(cursor.value_type() == ValueTypes::UNKNOWN);
+        assert!(cursor.seek(11));
+        assert!(cursor.value_type() == ValueTypes::INT64);
+        assert_eq!(rest(&mut cursor), vec![3240, 16]);
+    }
+
+    #[test]
+    fn test_iterate_twice() {
+        let mut quote = quote();
+        assert_eq!(rest(&mut quote), vec![10, 11, 3240, 16]);
+        quote.reset();
+        assert_eq!(rest(&mut quote), vec![10, 11, 3240, 16]);
+
+        let mut empty = cursor(vec![]);
+        assert_eq!(empty.peek(), None);
+        assert_eq!(rest(&mut empty), Vec::<i32>::new());
+        empty.reset();
+        assert_eq!(empty.peek(), None);
+        assert_eq!(rest(&mut empty), Vec::<i32>::new());
+        assert!(empty.value_type() == ValueTypes::UNKNOWN);
+    }
+
+    #[test]
+    fn test_reset_to_construction_point() {
+        // a reader the event already moved, e.g. by an earlier EventReader
+        let mut cursor = Cursor::new(FakeReader {
+            tokens: quote().reader.tokens,
+            pos: Some(1),
+        });
+        assert_eq!(rest(&mut cursor), vec![3240, 16]);
+        cursor.reset();
+        assert_eq!(cursor.peek(), Some(3240));
+        assert_eq!(rest(&mut cursor), vec![3240, 16]);
+    }
+}
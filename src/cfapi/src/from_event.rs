@@ -0,0 +1,276 @@
+use super::decimal::Decimal;
+use super::event_reader::EventReader;
+use super::value::CFValue;
+use snafu::prelude::Snafu;
+
+pub use cfapi_derive::FromCfEvent;
+
+#[derive(Debug, Snafu)]
+pub enum DecodeError {
+    #[snafu(display("token {} of {} is not in the event", token, field))]
+    Missing { field: &'static str, token: i32 },
+    #[snafu(display("token {} of {} has a mistyped value {:?}", token, field, value))]
+    Mistyped {
+        field: &'static str,
+        token: i32,
+        value: CFValue,
+    },
+}
+
+/// A field type the derive can fill from a token value.
+pub trait FromCfValue: Sized {
+    /// None when the value does not fit the type without loss.
+    fn from_cf_value(value: &CFValue) -> Option<Self>;
+
+    /// Value of a field without a default whose token is absent, None makes it required.
+    fn missing() -> Option<Self> {
+        None
+    }
+}
+
+impl FromCfValue for i64 {
+    fn from_cf_value(value: &CFValue) -> Option<Self> {
+        value.coerce_i64()
+    }
+}
+
+impl FromCfValue for i32 {
+    fn from_cf_value(value: &CFValue) -> Option<Self> {
+        value.coerce_i64().and_then(|v| i32::try_from(v).ok())
+    }
+}
+
+impl FromCfValue for f64 {
+    fn from_cf_value(value: &CFValue) -> Option<Self> {
+        value.coerce_f64()
+    }
+}
+
+impl FromCfValue for Decimal {
+    fn from_cf_value(value: &CFValue) -> Option<Self> {
+        value.as_decimal()
+    }
+}
+
+impl FromCfValue for String {
+    fn from_cf_value(value: &CFValue) -> Option<Self> {
+        value.as_str().map(str::to_string)
+    }
+}
+
+impl FromCfValue for CFValue {
+    fn from_cf_value(value: &CFValue) -> Option<Self> {
+        Some(value.clone())
+    }
+}
+
+/// Absent is None, and so is a blank token, which clears the field on an update.
+impl<T: FromCfValue> FromCfValue for Option<T> {
+    fn from_cf_value(value: &CFValue) -> Option<Self> {
+        match value {
+            CFValue::Null => Some(None),
+            value => T::from_cf_value(value).map(Some),
+        }
+    }
+
+    fn missing() -> Option<Self> {
+        Some(None)
+    }
+}
+
+/// The fields `apply_tokens` overwrote, by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpdatedFields {
+    fields: &'static [&'static str],
+    // bit i is fields[i]
+    bits: u64,
+    // fields whose token was in the update, overwritten or not
+    received: u64,
+}
+
+impl UpdatedFields {
+    // called by the derived apply_tokens, the derive keeps fields at 64
+    #[doc(hidden)]
+    pub fn new(fields: &'static [&'static str]) -> Self {
+        Self {
+            fields,
+            bits: 0,
+            received: 0,
+        }
+    }
+
+    #[doc(hidden)]
+    pub fn insert(&mut self, index: usize) {
+        self.bits |= 1 << index;
+    }
+
+    #[doc(hidden)]
+    pub fn receive(&mut self, index: usize) {
+        self.received |= 1 << index;
+    }
+
+    /// Whether the token of the field was in the update, also when its value was
+    /// blank or mistyped and the field kept its value.
+    pub fn received(&self, field: &str) -> bool {
+        self.fields
+            .iter()
+            .position(|f| *f == field)
+            .is_some_and(|i| self.received & (1 << i) != 0)
+    }
+
+    pub fn contains(&self, field: &str) -> bool {
+        self.iter().any(|f| f == field)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bits.count_ones() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+
+    /// In the order of the struct.
+    pub fn iter(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.fields
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.bits & (1 << i) != 0)
+            .map(|(_, f)| *f)
+    }
+}
+
+/// Decodes a struct from the tokens of an event in one pass, implemented by
+/// `#[derive(FromCfEvent)]`:
+///
+/// ```ignore
+/// #[derive(FromCfEvent)]
+/// struct Quote {
+///     #[cf(token = 10)]
+///     ask_price: f64,
+///     #[cf(token = 1709, default = 1)]
+///     market_phase: i64,
+///     // no token, always the default
+///     code: String,
+/// }
+/// ```
+///
+/// A field with `default` (`Default::default()`) or `default = expr` takes it when its
+/// token is absent, blank or mistyped. Without one the decode fails, unless the field is
+/// an `Option`.
+pub trait FromCfEvent: Sized {
+    fn from_tokens<I: IntoIterator<Item = (i32, CFValue)>>(tokens: I) -> Result<Self, DecodeError>;
+
+    /// Overwrites the fields whose token is in the tokens, a blank or mistyped value
+    /// keeps the field, and so does a `snapshot_only` field. Returns the fields that
+    /// were overwritten.
+    fn apply_tokens<I: IntoIterator<Item = (i32, CFValue)>>(&mut self, tokens: I) -> UpdatedFields;
+
+    /// Reads the event from the start.
+    fn from_event_reader(reader: &mut EventReader) -> Result<Self, DecodeError> {
+        Self::from_tokens(reader.iter_with_token_number())
+    }
+
+    /// Applies the tokens of an update event, read from the start.
+    fn apply_update(&mut self, reader: &mut EventReader) -> UpdatedFields {
+        self.apply_tokens(reader.iter_with_token_number())
+    }
+}
+
+// called by the derived from_tokens, Ok(None) when the token does not count as present
+#[doc(hidden)]
+pub fn decode_field<T: FromCfValue>(
+    value: CFValue,
+    field: &'static str,
+    token: i32,
+    has_default: bool,
+) -> Result<Option<T>, DecodeError> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    match T::from_cf_value(&value) {
+        Some(v) => Ok(Some(v)),
+        None if has_default => Ok(None),
+        None => MistypedSnafu {
+            field,
+            token,
+            value,
+        }
+        .fail(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, FromCfEvent)]
+    struct Quote {
+        #[cf(token = 10)]
+        ask_price: f64,
+        #[cf(token = 11, default)]
+        ask_volume: i64,
+        #[cf(token = 1709, default = 1)]
+        market_phase: i64,
+        #[cf(token = 3240)]
+        exchange: Option<String>,
+        #[cf(token = 460, default, snapshot_only)]
+        total_amount: i64,
+        code: String,
+    }
+
+    #[test]
+    fn test_derive_from_cf_event() {
+        let quote = Quote::from_tokens([
+            (11, CFValue::Double(1.5)),
+            (10, CFValue::Double(167.78)),
+            (22, CFValue::Int(9)),
+            (460, CFValue::Int(500)),
+        ])
+        .unwrap();
+        assert_eq!(quote.ask_price, 167.78);
+        assert_eq!(quote.ask_volume, 0);
+        assert_eq!(quote.market_phase, 1);
+        assert_eq!(quote.exchange, None);
+        assert_eq!(quote.total_amount, 500);
+        assert_eq!(quote.code, "");
+
+        let err = Quote::from_tokens([(11, CFValue::Int(3))]).unwrap_err();
+        assert!(matches!(err, DecodeError::Missing { token: 10, .. }));
+        let err = Quote::from_tokens([(10, CFValue::from("x"))]).unwrap_err();
+        assert!(matches!(
+            err,
+            DecodeError::Mistyped {
+                field: "ask_price",
+                ..
+            }
+        ));
+
+        let mut quote = Quote {
+            exchange: Some("Q".into()),
+            ..quote
+        };
+        let updated = quote.apply_tokens([
+            (10, CFValue::Null),
+            (11, CFValue::Int(300)),
+            (1709, CFValue::from("x")),
+            (3240, CFValue::Null),
+            (460, CFValue::Int(700)),
+        ]);
+        assert_eq!(updated.len(), 2);
+        assert!(updated.contains("ask_volume") && updated.contains("exchange"));
+        assert!(!updated.contains("ask_price") && !updated.contains("market_phase"));
+        assert_eq!(
+            updated.iter().collect::<Vec<_>>(),
+            vec!["ask_volume", "exchange"]
+        );
+        assert!(updated.received("ask_price") && updated.received("market_phase"));
+        assert!(!updated.received("total_amount") && !updated.received("code"));
+        assert!(quote.apply_tokens([(22, CFValue::Int(9))]).is_empty());
+        assert_eq!(quote.ask_price, 167.78);
+        assert_eq!(quote.ask_volume, 300);
+        assert_eq!(quote.market_phase, 1);
+        assert_eq!(quote.exchange, None);
+        assert_eq!(quote.total_amount, 500);
+    }
+}
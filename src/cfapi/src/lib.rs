@@ -1,8 +1,15 @@
+// the derive names paths from the crate root, which inside cfapi is self
+extern crate self as cfapi;
+
 pub mod binding;
 pub mod event_reader;
 pub mod value;
+pub mod de;
+pub mod decimal;
+pub mod from_event;
 pub mod user_event;
 pub mod session_event;
 pub mod message_event;
 pub mod stat_event;
 pub mod api;
+pub mod admin;
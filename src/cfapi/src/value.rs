@@ -1,24 +1,41 @@
+use super::decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use snafu::prelude::Snafu;
+use std::fmt;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Snafu)]
+pub enum ValueError {
+    #[snafu(display("CFValue {:?} is not a lossless {}", value, expected))]
+    Mistyped {
+        expected: &'static str,
+        value: CFValue,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum CFValue {
     String(String),
     Double(f64),
     Int(i64),
     Datetime(f64),
+    /// fixed-point, made from a Double with the scale of the source
+    Decimal(Decimal),
+    /// a blank token, sent without a value to clear the field
+    Null,
     Unknown,
 }
 
+// integers up to 2^53 are exact in a f64
+const MAX_EXACT_F64_INT: i64 = 1 << f64::MANTISSA_DIGITS;
 
 impl CFValue {
+    #[deprecated(note = "zero for the other types, use as_i64 or coerce_i64")]
     pub fn to_i64(self) -> i64 {
-        match self {
-            CFValue::Int(v) => v,
-            _ => 0,
-        }
+        self.as_i64().unwrap_or_default()
     }
 
+    #[deprecated(note = "zero for the other types, use as_f64 or coerce_f64")]
     pub fn to_f64(self) -> f64 {
         match self {
             CFValue::Double(v) => v,
@@ -27,10 +44,222 @@ impl CFValue {
         }
     }
 
-    pub fn to_string(self) -> String {
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            CFValue::Int(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            CFValue::Double(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// Epoch seconds.
+    pub fn as_datetime(&self) -> Option<f64> {
+        match self {
+            CFValue::Datetime(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
         match self {
-            CFValue::String(v) => v,
-            _ => "".to_string(),
+            CFValue::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_decimal(&self) -> Option<Decimal> {
+        match self {
+            CFValue::Decimal(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, CFValue::Null)
+    }
+
+    /// An Int, or a Double or Decimal without a fraction that fits an i64.
+    pub fn coerce_i64(&self) -> Option<i64> {
+        match self {
+            CFValue::Int(v) => Some(*v),
+            CFValue::Decimal(v) => v.rescale(0).filter(|int| int == v).map(|int| int.units()),
+            // i64::MAX as f64 rounds up to 2^63, which does not fit
+            CFValue::Double(v)
+                if v.fract() == 0.0 && *v >= i64::MIN as f64 && *v < i64::MAX as f64 =>
+            {
+                Some(*v as i64)
+            }
+            _ => None,
+        }
+    }
+
+    /// A Double or Datetime, an Int that a f64 holds exactly, or the nearest f64 of a
+    /// Decimal.
+    pub fn coerce_f64(&self) -> Option<f64> {
+        match self {
+            CFValue::Double(v) | CFValue::Datetime(v) => Some(*v),
+            CFValue::Int(v) if v.abs() <= MAX_EXACT_F64_INT => Some(*v as f64),
+            CFValue::Decimal(v) => Some(v.to_f64()),
+            _ => None,
+        }
+    }
+
+    /// A Decimal or Int at the scale, or a Double rounded to it.
+    pub fn coerce_decimal(&self, scale: u8) -> Option<Decimal> {
+        match self {
+            CFValue::Decimal(v) => v.rescale(scale),
+            CFValue::Int(v) => Decimal::new(*v, 0).rescale(scale),
+            CFValue::Double(v) => Decimal::from_f64(*v, scale),
+            _ => None,
+        }
+    }
+}
+
+/// Strings as they are, numbers as rust prints them, blank and unknown as empty.
+impl fmt::Display for CFValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CFValue::String(v) => f.write_str(v),
+            CFValue::Double(v) | CFValue::Datetime(v) => write!(f, "{}", v),
+            CFValue::Int(v) => write!(f, "{}", v),
+            CFValue::Decimal(v) => write!(f, "{}", v),
+            CFValue::Null | CFValue::Unknown => Ok(()),
+        }
+    }
+}
+
+impl TryFrom<&CFValue> for i64 {
+    type Error = ValueError;
+
+    fn try_from(value: &CFValue) -> Result<Self, Self::Error> {
+        value.coerce_i64().ok_or_else(|| mistyped("i64", value))
+    }
+}
+
+impl TryFrom<&CFValue> for f64 {
+    type Error = ValueError;
+
+    fn try_from(value: &CFValue) -> Result<Self, Self::Error> {
+        value.coerce_f64().ok_or_else(|| mistyped("f64", value))
+    }
+}
+
+impl TryFrom<CFValue> for i64 {
+    type Error = ValueError;
+
+    fn try_from(value: CFValue) -> Result<Self, Self::Error> {
+        i64::try_from(&value)
+    }
+}
+
+impl TryFrom<CFValue> for f64 {
+    type Error = ValueError;
+
+    fn try_from(value: CFValue) -> Result<Self, Self::Error> {
+        f64::try_from(&value)
+    }
+}
+
+impl TryFrom<CFValue> for String {
+    type Error = ValueError;
+
+    fn try_from(value: CFValue) -> Result<Self, Self::Error> {
+        match value {
+            CFValue::String(v) => Ok(v),
+            value => MistypedSnafu {
+                expected: "string",
+                value,
+            }
+            .fail(),
         }
     }
-}
\ No newline at end of file
+}
+
+fn mistyped(expected: &'static str, value: &CFValue) -> ValueError {
+    ValueError::Mistyped {
+        expected,
+        value: value.clone(),
+    }
+}
+
+impl From<i64> for CFValue {
+    fn from(v: i64) -> Self {
+        CFValue::Int(v)
+    }
+}
+
+impl From<f64> for CFValue {
+    fn from(v: f64) -> Self {
+        CFValue::Double(v)
+    }
+}
+
+impl From<Decimal> for CFValue {
+    fn from(v: Decimal) -> Self {
+        CFValue::Decimal(v)
+    }
+}
+
+impl From<String> for CFValue {
+    fn from(v: String) -> Self {
+        CFValue::String(v)
+    }
+}
+
+impl From<&str> for CFValue {
+    fn from(v: &str) -> Self {
+        CFValue::String(v.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strict_and_lossless() {
+        assert_eq!(CFValue::Int(3).as_i64(), Some(3));
+        assert_eq!(CFValue::Double(3.0).as_i64(), None);
+        assert_eq!(CFValue::Double(3.0).coerce_i64(), Some(3));
+        assert_eq!(CFValue::Double(3.5).coerce_i64(), None);
+        assert_eq!(CFValue::Double(9.3e18).coerce_i64(), None);
+        assert_eq!(CFValue::Int(2).coerce_f64(), Some(2.0));
+        assert_eq!(CFValue::Int((1 << 53) + 1).coerce_f64(), None);
+        assert_eq!(CFValue::Datetime(1.5).coerce_f64(), Some(1.5));
+        assert_eq!(CFValue::String("1".into()).coerce_i64(), None);
+        assert_eq!(CFValue::Null.coerce_f64(), None);
+
+        assert_eq!(i64::try_from(CFValue::Double(-4.0)).unwrap(), -4);
+        assert!(f64::try_from(CFValue::Null).is_err());
+        let s: Result<String, _> = CFValue::Int(1).try_into();
+        assert_eq!(
+            s.unwrap_err().to_string(),
+            "CFValue Int(1) is not a lossless string"
+        );
+
+        assert_eq!(CFValue::Double(590.5).to_string(), "590.5");
+        assert_eq!(CFValue::Int(-3).to_string(), "-3");
+        assert_eq!(CFValue::from("AAPL").to_string(), "AAPL");
+        assert_eq!(CFValue::Null.to_string(), "");
+        let price = CFValue::Double(167.78000000000003)
+            .coerce_decimal(2)
+            .unwrap();
+        assert_eq!(CFValue::from(price).to_string(), "167.78");
+        assert_eq!(CFValue::Decimal(Decimal::new(300, 2)).coerce_i64(), Some(3));
+        assert_eq!(CFValue::Decimal(Decimal::new(301, 2)).coerce_i64(), None);
+        assert_ne!(CFValue::Null, CFValue::Unknown);
+        assert_ne!(CFValue::Int(1), CFValue::Double(1.0));
+
+        assert_eq!(serde_json::to_string(&CFValue::Null).unwrap(), "null");
+        assert_eq!(
+            serde_json::from_str::<CFValue>("null").unwrap(),
+            CFValue::Null
+        );
+    }
+}
@@ -26,6 +26,8 @@ public:
                              long jit_conflation_threshold_percent);
     bool startSession();
     std::int64_t sendRequest(const std::string &src_id, const std::string &symbol, cfapi::Commands command);
+    std::int64_t sendAdminRequest(const std::string &src_id, const std::string &symbol,
+                                  const std::string &user_name, cfapi::Commands command);
     // void registerMessageEventHandler(cfapi::MessageEventHandler *messageHandler);
     void registerMessageEventHandler(const cfapi::MessageEventHandler &messageHandler);
     void registerStatisticsEventHandler(const cfapi::StatisticsEventHandler &statsEH, int interval);
@@ -74,6 +74,28 @@ std::int64_t APIFactoryWrap::sendRequest(const std::string &src_id, const std::s
     // std::int64_t ret = ;
     return (*session).send(req);
 };
+
+std::int64_t APIFactoryWrap::sendAdminRequest(const std::string &src_id, const std::string &symbol,
+                                              const std::string &user_name, cfapi::Commands command)
+{
+    cfapi::Request &req = (*session).createRequest();
+    req.clearRequest();
+    if (!src_id.empty())
+    {
+        req.add(cfapi::ENUM_SRC_ID, src_id);
+    }
+    if (!symbol.empty())
+    {
+        req.add(cfapi::SYMBOL_TICKER, symbol);
+    }
+    if (!user_name.empty())
+    {
+        req.add(cfapi::USER_NAME, user_name);
+    }
+    req.setCommand(command);
+    return (*session).send(req);
+};
+
 bool APIFactoryWrap::startSession()
 {
     std::string failReason;
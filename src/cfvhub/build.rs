@@ -0,0 +1,12 @@
+fn main() -> std::io::Result<()> {
+    // the vendored protoc, so building does not need one installed
+    if std::env::var_os("PROTOC").is_none() {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+    }
+    prost_build::compile_protos(
+        &["proto/nasdaq_basic.proto", "proto/envelope.proto"],
+        &["proto"],
+    )?;
+    println!("cargo:rerun-if-changed=proto");
+    Ok(())
+}
@@ -0,0 +1,626 @@
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+use super::formater::{BatchSnafu, CompressSnafu, Encoding, FormatError, Formated, FormaterExt};
+use super::queue::RateLimiter;
+use super::sink::shutdown::{Close, Shutdown};
+use super::sink::{Dest, SinkError, SinkExt};
+
+fn default_max_messages() -> usize {
+    100
+}
+
+fn default_max_ms() -> u64 {
+    50
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchLayout {
+    /// a json or msgpack array of the messages
+    #[default]
+    Array,
+    /// every message of a text format on its own line, ndjson for json
+    Lines,
+    /// every message after its length as a varint, like protobuf writeDelimitedTo
+    Delimited,
+}
+
+impl BatchLayout {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BatchLayout::Array => "array",
+            BatchLayout::Lines => "lines",
+            BatchLayout::Delimited => "delimited",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    /// lz4 frame format
+    Lz4,
+    Zstd {
+        level: i32,
+    },
+}
+
+/// Sends the messages of a destination together, e.g. in toml `[sinks.batch]`
+/// `max_messages = 500` `layout = "lines"` and `[sinks.batch.compression]` `type = "zstd"`
+/// `level = 3`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchConfig {
+    #[serde(default = "default_max_messages")]
+    pub max_messages: usize,
+    /// the first message of a batch waits at most this long
+    #[serde(default = "default_max_ms")]
+    pub max_ms: u64,
+    #[serde(default)]
+    pub layout: BatchLayout,
+    #[serde(default)]
+    pub compression: Compression,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: default_max_messages(),
+            max_ms: default_max_ms(),
+            layout: BatchLayout::default(),
+            compression: Compression::default(),
+        }
+    }
+}
+
+/// Messages of one destination, serialized as their sequence.
+#[derive(Debug, Clone, Serialize)]
+#[serde(transparent)]
+pub struct Batch<In> {
+    #[serde(skip)]
+    dest: String,
+    pub items: Vec<In>,
+}
+
+impl<In> Batch<In> {
+    pub fn new(dest: &str, items: Vec<In>) -> Self {
+        Self {
+            dest: dest.to_string(),
+            items,
+        }
+    }
+}
+
+impl<In> Dest for Batch<In> {
+    fn get_dest(&self) -> &str {
+        &self.dest
+    }
+}
+
+fn write_array_len(buf: &mut Vec<u8>, len: usize) {
+    match len {
+        0..=15 => buf.push(0x90 | len as u8),
+        16..=0xffff => {
+            buf.push(0xdc);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            buf.push(0xdd);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+/// Formats a batch message by message with the inner formater, so it composes with
+/// any of them. The content type is the inner one with the layout, e.g. `json+lines`.
+#[derive(Debug)]
+pub struct BatchFormater<F> {
+    inner: F,
+    layout: BatchLayout,
+    content_type: OnceCell<String>,
+}
+
+impl<F> BatchFormater<F> {
+    pub fn new(inner: F, layout: BatchLayout) -> Self {
+        Self {
+            inner,
+            layout,
+            content_type: OnceCell::new(),
+        }
+    }
+}
+
+impl<In, F> FormaterExt<Batch<In>> for BatchFormater<F>
+where
+    In: Serialize,
+    F: FormaterExt<In>,
+{
+    fn format(&self, input: &Batch<In>) -> Result<Formated, FormatError> {
+        let mut buf = vec![];
+        let encoding = self.format_into(input, &mut buf)?;
+        Ok(Formated::from_encoded(buf, encoding))
+    }
+
+    fn content_type(&self) -> &str {
+        self.content_type
+            .get_or_init(|| format!("{}+{}", self.inner.content_type(), self.layout.as_str()))
+    }
+
+    fn format_into(&self, input: &Batch<In>, buf: &mut Vec<u8>) -> Result<Encoding, FormatError> {
+        let content_type = self.inner.content_type();
+        match (self.layout, content_type) {
+            (BatchLayout::Array, "json") => {
+                buf.push(b'[');
+                for (i, item) in input.items.iter().enumerate() {
+                    if i > 0 {
+                        buf.push(b',');
+                    }
+                    self.inner.format_into(item, buf)?;
+                }
+                buf.push(b']');
+                Ok(Encoding::Text)
+            }
+            (BatchLayout::Array, "msgpack") => {
+                write_array_len(buf, input.items.len());
+                for item in input.items.iter() {
+                    self.inner.format_into(item, buf)?;
+                }
+                Ok(Encoding::Binary)
+            }
+            (BatchLayout::Lines, _) => {
+                for item in input.items.iter() {
+                    if self.inner.format_into(item, buf)? == Encoding::Binary {
+                        return BatchSnafu {
+                            content_type,
+                            layout: self.layout.as_str(),
+                        }
+                        .fail();
+                    }
+                    buf.push(b'\n');
+                }
+                Ok(Encoding::Text)
+            }
+            (BatchLayout::Delimited, _) => {
+                let mut item_buf = vec![];
+                for item in input.items.iter() {
+                    item_buf.clear();
+                    self.inner.format_into(item, &mut item_buf)?;
+                    prost::encoding::encode_varint(item_buf.len() as u64, buf);
+                    buf.extend_from_slice(&item_buf);
+                }
+                Ok(Encoding::Binary)
+            }
+            (layout, content_type) => BatchSnafu {
+                content_type,
+                layout: layout.as_str(),
+            }
+            .fail(),
+        }
+    }
+}
+
+/// Compresses the output of the inner formater, the content type gets the compression,
+/// e.g. `msgpack+array+zstd`.
+#[derive(Debug)]
+pub struct CompressFormater<F> {
+    inner: F,
+    compression: Compression,
+    content_type: OnceCell<String>,
+}
+
+impl<F> CompressFormater<F> {
+    pub fn new(inner: F, compression: Compression) -> Self {
+        Self {
+            inner,
+            compression,
+            content_type: OnceCell::new(),
+        }
+    }
+}
+
+impl<In, F> FormaterExt<In> for CompressFormater<F>
+where
+    In: Serialize,
+    F: FormaterExt<In>,
+{
+    fn format(&self, input: &In) -> Result<Formated, FormatError> {
+        let mut buf = vec![];
+        let encoding = self.format_into(input, &mut buf)?;
+        Ok(Formated::from_encoded(buf, encoding))
+    }
+
+    fn content_type(&self) -> &str {
+        let suffix = match self.compression {
+            Compression::None => return self.inner.content_type(),
+            Compression::Lz4 => "lz4",
+            Compression::Zstd { .. } => "zstd",
+        };
+        self.content_type
+            .get_or_init(|| format!("{}+{}", self.inner.content_type(), suffix))
+    }
+
+    fn format_into(&self, input: &In, buf: &mut Vec<u8>) -> Result<Encoding, FormatError> {
+        if self.compression == Compression::None {
+            return self.inner.format_into(input, buf);
+        }
+        let mut raw = vec![];
+        self.inner.format_into(input, &mut raw)?;
+        match self.compression {
+            Compression::None => unreachable!(),
+            Compression::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(buf);
+                encoder.write_all(&raw).context(CompressSnafu)?;
+                encoder
+                    .finish()
+                    .map_err(std::io::Error::from)
+                    .context(CompressSnafu)?;
+            }
+            Compression::Zstd { level } => {
+                zstd::stream::copy_encode(&raw[..], buf, level).context(CompressSnafu)?
+            }
+        }
+        Ok(Encoding::Binary)
+    }
+}
+
+struct Pending<In> {
+    items: Vec<In>,
+    since: Instant,
+}
+
+struct Batches<In, S> {
+    sink: S,
+    pending: HashMap<String, Pending<In>>,
+}
+
+// locked by exec and the flush thread
+struct Shared<In, S, F> {
+    batches: Mutex<Batches<In, S>>,
+    formater: CompressFormater<BatchFormater<F>>,
+    max_wait: Duration,
+}
+
+impl<In, S, F> Shared<In, S, F>
+where
+    In: Serialize,
+    S: SinkExt<Batch<In>>,
+    F: FormaterExt<In>,
+{
+    // sends the batches that waited max_wait at now, all of them without a now
+    fn flush(&self, now: Option<Instant>) -> Result<(), SinkError> {
+        let mut batches = self.batches.lock().unwrap();
+        let Batches { sink, pending } = &mut *batches;
+        let mut r = Ok(());
+        for (dest, p) in pending.iter_mut() {
+            let due = match now {
+                Some(now) => now.saturating_duration_since(p.since) >= self.max_wait,
+                None => true,
+            };
+            if p.items.is_empty() || !due {
+                continue;
+            }
+            let batch = Batch::new(dest, std::mem::take(&mut p.items));
+            if let Err(e) = sink.exec(&batch, &self.formater) {
+                if r.is_ok() {
+                    r = Err(e);
+                }
+            }
+        }
+        r
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        let batches = self.batches.lock().unwrap();
+        batches
+            .pending
+            .values()
+            .filter(|p| !p.items.is_empty())
+            .map(|p| p.since + self.max_wait)
+            .min()
+    }
+}
+
+// sends the open batches
+impl<In, S, F> Close for Shared<In, S, F>
+where
+    In: Serialize + Send,
+    S: SinkExt<Batch<In>> + Send,
+    F: FormaterExt<In> + Send + Sync,
+{
+    fn close(&self) -> Result<(), SinkError> {
+        self.flush(None)
+    }
+}
+
+/// Collects the messages per destination and sends them to the inner sink as one
+/// `Batch` when `max_messages` are in or the first waited `max_ms`, formated with
+/// `BatchFormater` and `CompressFormater` around `formater`. A flush thread sends the
+/// batches that wait too long, dropping the sink sends the open ones.
+pub struct BatchSink<In, S, F>
+where
+    In: Serialize,
+    S: SinkExt<Batch<In>>,
+    F: FormaterExt<In>,
+{
+    config: BatchConfig,
+    shared: Arc<Shared<In, S, F>>,
+    // dropped to stop the flush thread
+    stop: Option<Sender<()>>,
+    flusher: Option<JoinHandle<()>>,
+}
+
+impl<In, S, F> BatchSink<In, S, F>
+where
+    In: Serialize + Send + 'static,
+    S: SinkExt<Batch<In>> + Send + 'static,
+    F: FormaterExt<In> + Send + Sync + 'static,
+{
+    pub fn new(config: &BatchConfig, sink: S, formater: F) -> Self {
+        let shared = Arc::new(Shared {
+            batches: Mutex::new(Batches {
+                sink,
+                pending: HashMap::new(),
+            }),
+            formater: CompressFormater::new(
+                BatchFormater::new(formater, config.layout),
+                config.compression,
+            ),
+            max_wait: Duration::from_millis(config.max_ms),
+        });
+        let (stop, stopped) = bounded(0);
+        let flusher = {
+            let shared = shared.clone();
+            std::thread::spawn(move || run_flusher(&shared, &stopped))
+        };
+        Self {
+            config: config.clone(),
+            shared,
+            stop: Some(stop),
+            flusher: Some(flusher),
+        }
+    }
+
+    /// The open batches are sent with `shutdown`, before the files are closed.
+    pub fn with_shutdown(self, shutdown: &Shutdown) -> Self {
+        shutdown.add_batches(Arc::downgrade(&self.shared) as Weak<dyn Close>);
+        self
+    }
+}
+
+// wakes when the oldest open batch is due, or after max_wait when none is open since a
+// batch opened in between is due later
+fn run_flusher<In, S, F>(shared: &Shared<In, S, F>, stopped: &Receiver<()>)
+where
+    In: Serialize,
+    S: SinkExt<Batch<In>>,
+    F: FormaterExt<In>,
+{
+    let log_limit = RateLimiter::default();
+    loop {
+        let wait = match shared.next_due() {
+            Some(due) => due.saturating_duration_since(Instant::now()),
+            None => shared.max_wait,
+        };
+        match stopped.recv_timeout(wait.max(Duration::from_millis(1))) {
+            Err(RecvTimeoutError::Timeout) => {
+                if let Err(e) = shared.flush(Some(Instant::now())) {
+                    if log_limit.check() {
+                        error!("batch flush error: {}", e);
+                    }
+                }
+            }
+            _ => return,
+        }
+    }
+}
+
+impl<In, S, F> BatchSink<In, S, F>
+where
+    In: Serialize + Dest + Clone,
+    S: SinkExt<Batch<In>>,
+    F: FormaterExt<In>,
+{
+    /// Adds the message to the batch of its destination, sends the batch when it is full.
+    pub fn exec(&mut self, input: &In) -> Result<(), SinkError> {
+        let now = Instant::now();
+        let mut batches = self.shared.batches.lock().unwrap();
+        let Batches { sink, pending } = &mut *batches;
+        let dest = input.get_dest();
+        let p = match pending.get_mut(dest) {
+            Some(p) => p,
+            None => pending.entry(dest.to_string()).or_insert(Pending {
+                items: vec![],
+                since: now,
+            }),
+        };
+        if p.items.is_empty() {
+            p.since = now;
+        }
+        p.items.push(input.clone());
+        if p.items.len() >= self.config.max_messages
+            || now.saturating_duration_since(p.since) >= self.shared.max_wait
+        {
+            let batch = Batch::new(dest, std::mem::take(&mut p.items));
+            return sink.exec(&batch, &self.shared.formater);
+        }
+        Ok(())
+    }
+
+    /// Sends the open batches.
+    pub fn flush(&self) -> Result<(), SinkError> {
+        self.shared.flush(None)
+    }
+}
+
+impl<In, S, F> Drop for BatchSink<In, S, F>
+where
+    In: Serialize,
+    S: SinkExt<Batch<In>>,
+    F: FormaterExt<In>,
+{
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(flusher) = self.flusher.take() {
+            let _ = flusher.join();
+        }
+        if let Err(e) = self.shared.flush(None) {
+            error!("batch flush error: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formater::{JsonFormater, MessagePackFormater, TomlFormater};
+    use crate::test_util::{msg, Msg};
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+
+    type Sent = Arc<Mutex<Vec<(String, String, Vec<u8>)>>>;
+
+    struct Capture(Sent);
+
+    impl SinkExt<Batch<Msg>> for Capture {
+        fn build(_id: &str) -> Self {
+            Capture(Sent::default())
+        }
+
+        fn exec(
+            &mut self,
+            input: &Batch<Msg>,
+            formater: &impl FormaterExt<Batch<Msg>>,
+        ) -> Result<(), SinkError> {
+            let mut buf = vec![];
+            formater.format_into(input, &mut buf).unwrap();
+            let content_type = formater.content_type().to_string();
+            self.0
+                .lock()
+                .unwrap()
+                .push((input.get_dest().to_string(), content_type, buf));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_batch_sink_and_formaters() {
+        let sent = Sent::default();
+        let config = BatchConfig {
+            max_messages: 2,
+            max_ms: 60_000,
+            ..Default::default()
+        };
+        let shutdown = Shutdown::default();
+        let mut sink =
+            BatchSink::new(&config, Capture(sent.clone()), JsonFormater).with_shutdown(&shutdown);
+        for m in [msg("A", 1.0), msg("B", 2.0), msg("A", 3.0)] {
+            sink.exec(&m).unwrap();
+        }
+        // one topic per batch, B is still open
+        assert_eq!(
+            sent.lock().unwrap().clone(),
+            vec![(
+                "A".to_string(),
+                "json+array".to_string(),
+                br#"[{"dest":"A","close":1.0},{"dest":"A","close":3.0}]"#.to_vec()
+            )]
+        );
+        // shutdown sends B
+        shutdown.close().unwrap();
+        assert_eq!(sent.lock().unwrap()[1].0, "B");
+        let config = BatchConfig {
+            max_ms: 0,
+            ..Default::default()
+        };
+        let mut sink = BatchSink::new(&config, Capture(sent.clone()), JsonFormater);
+        sink.exec(&msg("C", 1.0)).unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 3);
+
+        let batch = Batch::new("A", vec![msg("A", 1.0), msg("A", 2.5)]);
+        let lines = BatchFormater::new(JsonFormater, BatchLayout::Lines);
+        match lines.format(&batch).unwrap() {
+            Formated::String(s) => assert_eq!(
+                s,
+                "{\"dest\":\"A\",\"close\":1.0}\n{\"dest\":\"A\",\"close\":2.5}\n"
+            ),
+            Formated::Bytes(_) => panic!("lines are text"),
+        }
+        let array = BatchFormater::new(MessagePackFormater, BatchLayout::Array);
+        let zstd = CompressFormater::new(&array, Compression::Zstd { level: 3 });
+        assert_eq!(
+            FormaterExt::<Batch<Msg>>::content_type(&zstd),
+            "msgpack+array+zstd"
+        );
+        let mut buf = vec![];
+        zstd.format_into(&batch, &mut buf).unwrap();
+        let raw = zstd::decode_all(&buf[..]).unwrap();
+        let items: Vec<Msg> = rmp_serde::from_slice(&raw).unwrap();
+        assert_eq!(items, batch.items);
+
+        let delimited = BatchFormater::new(MessagePackFormater, BatchLayout::Delimited);
+        let lz4 = CompressFormater::new(delimited, Compression::Lz4);
+        let mut buf = vec![];
+        lz4.format_into(&batch, &mut buf).unwrap();
+        let mut raw = vec![];
+        lz4_flex::frame::FrameDecoder::new(&buf[..])
+            .read_to_end(&mut raw)
+            .unwrap();
+        let len = raw[0] as usize;
+        assert_eq!(raw.len(), 2 * (1 + len));
+        assert_eq!(
+            rmp_serde::from_slice::<Msg>(&raw[1..=len]).unwrap(),
+            msg("A", 1.0)
+        );
+
+        let lines = BatchFormater::new(MessagePackFormater, BatchLayout::Lines);
+        assert!(lines.format(&batch).is_err());
+        assert!(BatchFormater::new(TomlFormater, BatchLayout::Array)
+            .format(&batch)
+            .is_err());
+    }
+
+    #[test]
+    fn test_batch_sink_flushes_without_messages() {
+        let sent = Sent::default();
+        let config = BatchConfig {
+            max_ms: 60_000,
+            ..Default::default()
+        };
+        let mut sink = BatchSink::new(&config, Capture(sent.clone()), JsonFormater);
+        sink.exec(&msg("A", 1.0)).unwrap();
+        let due = sink.shared.next_due().unwrap();
+        sink.shared
+            .flush(Some(due - Duration::from_millis(1)))
+            .unwrap();
+        assert!(sent.lock().unwrap().is_empty());
+        // what the flush thread does once A is due
+        sink.shared.flush(Some(due)).unwrap();
+        let dests: Vec<_> = sent.lock().unwrap().iter().map(|s| s.0.clone()).collect();
+        assert_eq!(dests, vec!["A"]);
+        assert_eq!(sink.shared.next_due(), None);
+        sink.exec(&msg("A", 2.0)).unwrap();
+        sink.flush().unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 2);
+
+        // dropping the sink sends the open batches
+        let config = BatchConfig {
+            max_ms: 60_000,
+            ..Default::default()
+        };
+        let mut sink = BatchSink::new(&config, Capture(sent.clone()), JsonFormater);
+        sink.exec(&msg("B", 2.0)).unwrap();
+        drop(sink);
+        let dests: Vec<_> = sent.lock().unwrap().iter().map(|s| s.0.clone()).collect();
+        assert_eq!(dests, vec!["A", "A", "B"]);
+    }
+}
@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+use snafu::{prelude::Snafu, ResultExt};
+use std::path::{Path, PathBuf};
+
+use super::convertor::nasdaq_basic::PriceFormat;
+use super::convertor::topic::TopicTemplates;
+use super::middleware::MiddlewareConfig;
+use super::sink::SinkConfig;
+use super::watchdog::WatchdogConfig;
+
+#[derive(Debug, Snafu)]
+pub enum ConfigError {
+    #[snafu(display("Config {} read Error: {}", path.display(), source))]
+    Read {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[snafu(display("Config Toml Error: {}", source))]
+    ParseToml { source: toml::de::Error },
+    #[snafu(display("Config Yaml Error: {}", source))]
+    ParseYaml { source: serde_yaml::Error },
+    #[snafu(display("Config Json Error: {}", source))]
+    ParseJson { source: serde_json::Error },
+}
+
+/// Pipeline settings loaded from a toml, yaml or json file.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PipelineConfig {
+    #[serde(default)]
+    pub middleware: Vec<MiddlewareConfig>,
+    /// fan out branches of every sink worker
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+    /// topic of each message type
+    #[serde(default)]
+    pub topics: TopicTemplates,
+    /// stale data alerts, off when unset
+    pub watchdog: Option<WatchdogConfig>,
+    /// fixed-point prices per source, f64 for the others
+    #[serde(default)]
+    pub prices: Vec<PriceFormat>,
+}
+
+impl PipelineConfig {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path).context(ReadSnafu { path })?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&content).context(ParseYamlSnafu),
+            Some("json") => serde_json::from_str(&content).context(ParseJsonSnafu),
+            _ => toml::from_str(&content).context(ParseTomlSnafu),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pipeline_config_toml() {
+        let config: PipelineConfig = toml::from_str(
+            r#"
+            [[middleware]]
+            type = "symbol_allow"
+            symbols = ["AAPL", "NVDA"]
+
+            [[middleware]]
+            type = "round"
+            fields = ["close"]
+            decimals = 2
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.middleware.len(), 2);
+        assert!(matches!(
+            &config.middleware[0],
+            MiddlewareConfig::SymbolAllow { symbols } if symbols == &["AAPL", "NVDA"]
+        ));
+        assert!(matches!(
+            &config.middleware[1],
+            MiddlewareConfig::Round { fields, decimals: 2 } if fields == &["close"]
+        ));
+        assert!(toml::from_str::<PipelineConfig>("")
+            .unwrap()
+            .middleware
+            .is_empty());
+    }
+}
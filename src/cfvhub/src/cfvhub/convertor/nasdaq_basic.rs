@@ -1,15 +1,28 @@
 use ahash::RandomState;
+use arrow_schema::{DataType, Field};
 use cfapi::binding::MessageEvent;
+use cfapi::decimal::{Decimal, DecimalRepr};
 use cfapi::event_reader::{EventReader, EventReaderSerConfig};
+use cfapi::from_event::{FromCfEvent, FromCfValue};
 use cfapi::value::CFValue;
 use dashmap::DashMap;
 
-use crate::sink::Dest;
+use crate::envelope::Meta;
+use crate::latency::SourceTs;
+use crate::middleware::FieldAccess;
+use crate::proto::{encode_into, pb, ProtoError, ProtoMessage};
+use crate::queue::{QueueError, SpillCodec, SpillDecodeSnafu, SpillEncodeSnafu};
+use crate::sink::row::{to_row, Row, RowError};
+use crate::sink::{Dest, ParquetMessage};
+use crate::watchdog::Watchdog;
+use super::topic::{TopicFields, TopicTemplates};
 use super::Convertor;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
 use tracing::{debug, info, warn};
 use std::convert::Into;
+use std::sync::Arc;
 use serde_repr::{Serialize_repr, Deserialize_repr};
 
 
@@ -28,6 +41,12 @@ impl Default for MarketPhase {
     }   
 }
 
+impl FromCfValue for MarketPhase {
+    fn from_cf_value(value: &CFValue) -> Option<Self> {
+        value.coerce_i64().map(i64::into)
+    }
+}
+
 impl Into<MarketPhase> for i64 {
     fn into(self) -> MarketPhase {
         match self {
@@ -66,26 +85,44 @@ impl Into<MarketPhase> for i64 {
 //update
 
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+// the tokens of the snapshot and of the updates, snapshot_only ones are kept on update
+#[derive(Debug, Default, Serialize, Deserialize, Clone, FromCfEvent)]
 pub struct DataNasdaqBasicState {
+    #[cf(token = 3240, default, snapshot_only)]
     exchange: String, // 3240
     // symbol: String, //
     code: String,      // 3170
+    #[cf(token = 16, default)]
     ts: f64,           // 16 utc time zone
+    #[cf(token = 55, default)]
     exchange_ts: i64,  // 55 exchange time zone
+    #[cf(token = 10, default)]
     ask_price: f64,    // 10
+    #[cf(token = 11, default)]
     ask_volume: i64,   // 11
+    #[cf(token = 12, default)]
     bid_price: f64,    // 12
+    #[cf(token = 13, default)]
     bid_volume: i64,   // 13
+    #[cf(token = 447, default)]
     close: f64,        // 447
+    #[cf(token = 448, default)]
     volume: i64,       // 448
+    #[cf(token = 463, default)]
     total_volume: i64, // 22 or use 463 for official vol
+    #[cf(token = 460, default, snapshot_only)]
     total_amount: i64, // 460
-    open: f64,
-    high: f64,
-    low: f64,
+    #[cf(token = 401, default, snapshot_only)]
+    open: f64, // 401 is official open not exise in pre market
+    #[cf(token = 389, default, snapshot_only)]
+    high: f64, // 389 is official high 388 is ice not exise in pre market
+    #[cf(token = 395, default, snapshot_only)]
+    low: f64, // 395 is official low 394 is ice not exise in pre market
+    #[cf(token = 1709, default = MarketPhase::Closed)]
     market_phase: MarketPhase, // 1709 
+    #[cf(token = 361, default)]
     price_chg: f64, // 361
+    #[cf(token = 362, default)]
     pct_chg: f64,   // 362
                     // bid_side_total_vol: i64,
                     // ask_side_total_vol: i64,
@@ -93,31 +130,87 @@ pub struct DataNasdaqBasicState {
                     // ask_side_total_cnt: i64,
 }
 
+/// A price as the source sent it, or fixed-point when the source has a [`PriceFormat`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Price {
+    Float(f64),
+    Decimal(Decimal),
+}
+
+impl Default for Price {
+    fn default() -> Self {
+        Price::Float(0.0)
+    }
+}
+
+impl From<f64> for Price {
+    fn from(v: f64) -> Self {
+        Price::Float(v)
+    }
+}
+
+impl Price {
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Price::Float(v) => *v,
+            Price::Decimal(v) => v.to_f64(),
+        }
+    }
+}
+
+/// Fixed-point prices of a source, so 167.78000000000003 goes out as 167.78. The
+/// repr is for the serde formats, protobuf has double fields and encodes the nearest f64.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriceFormat {
+    pub source: i32,
+    /// digits after the point
+    pub scale: u8,
+    #[serde(default)]
+    pub repr: DecimalRepr,
+}
+
+impl PriceFormat {
+    pub fn price(&self, value: f64) -> Price {
+        match Decimal::from_f64(value, self.scale) {
+            Some(v) => Price::Decimal(v.with_repr(self.repr)),
+            None => {
+                debug!("price {} does not fit scale {}", value, self.scale);
+                Price::Float(value)
+            }
+        }
+    }
+}
+
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct NBBidAsk {
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     _dest: String,
+    #[serde(skip)]
+    _meta: Meta,
     exchange: String,
     code: String,
     ts: f64,
-    ask_price: f64,  // f64[]
-    ask_volume: i64, // i64[]
-    bid_price: f64,  // f64[]
-    bid_volume: i64, // i64
+    ask_price: Price, // f64[]
+    ask_volume: i64,  // i64[]
+    bid_price: Price, // f64[]
+    bid_volume: i64,  // i64
     market_phase: MarketPhase,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct NBTick {
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     _dest: String,
+    #[serde(skip)]
+    _meta: Meta,
     exchange: String,
     code: String,
     ts: f64,
-    open: f64,
-    high: f64,
-    low: f64,
-    close: f64,
+    open: Price,
+    high: Price,
+    low: Price,
+    close: Price,
     amount: i64,
     total_amount: i64,
     volume: i64,
@@ -191,6 +284,9 @@ pub enum DataNasdaqBasicV1 {
 pub struct NasdaqBasicConvertorV1 {
     reader_config: EventReaderSerConfig,
     state: DashMap<String, DataNasdaqBasicState, RandomState>,
+    topics: TopicTemplates,
+    watchdog: Option<Arc<Watchdog>>,
+    price_formats: Vec<PriceFormat>,
 }
 
 impl NasdaqBasicConvertorV1 {
@@ -198,6 +294,33 @@ impl NasdaqBasicConvertorV1 {
         Self {
             reader_config,
             state: DashMap::with_hasher(RandomState::new()),
+            topics: TopicTemplates::default(),
+            watchdog: None,
+            price_formats: vec![],
+        }
+    }
+
+    pub fn with_topics(mut self, topics: TopicTemplates) -> Self {
+        self.topics = topics;
+        self
+    }
+
+    /// Every event of a symbol counts as an update for the watchdog.
+    pub fn with_watchdog(mut self, watchdog: Arc<Watchdog>) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    /// Sources without a format keep f64 prices.
+    pub fn with_price_formats(mut self, price_formats: Vec<PriceFormat>) -> Self {
+        self.price_formats = price_formats;
+        self
+    }
+
+    fn price(&self, src: i32, value: f64) -> Price {
+        match self.price_formats.iter().find(|format| format.source == src) {
+            Some(format) => format.price(value),
+            None => Price::Float(value),
         }
     }
 }
@@ -212,6 +335,217 @@ impl Default for NasdaqBasicConvertorV1 {
     }
 }
 
+impl DataNasdaqBasicV1 {
+    pub fn is_tick(&self) -> bool {
+        matches!(self, DataNasdaqBasicV1::Tick(_))
+    }
+
+    fn prices_mut(&mut self) -> Vec<&mut Price> {
+        match self {
+            DataNasdaqBasicV1::BidAsk(ba) => vec![&mut ba.ask_price, &mut ba.bid_price],
+            DataNasdaqBasicV1::Tick(t) => {
+                vec![&mut t.open, &mut t.high, &mut t.low, &mut t.close]
+            }
+        }
+    }
+
+    // prices of a message come from the same format
+    fn decimal_repr(&self) -> Option<DecimalRepr> {
+        let price = match self {
+            DataNasdaqBasicV1::BidAsk(ba) => ba.ask_price,
+            DataNasdaqBasicV1::Tick(t) => t.close,
+        };
+        match price {
+            Price::Decimal(v) => Some(v.repr()),
+            Price::Float(_) => None,
+        }
+    }
+
+    fn set_decimal_repr(&mut self, repr: DecimalRepr) {
+        for price in self.prices_mut() {
+            if let Price::Decimal(v) = price {
+                *v = v.with_repr(repr);
+            }
+        }
+    }
+}
+
+impl MarketPhase {
+    /// name used in topics
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MarketPhase::PreMarket => "pre_market",
+            MarketPhase::Trading => "trading",
+            MarketPhase::PostMarket => "post_market",
+            MarketPhase::Closed => "closed",
+        }
+    }
+
+    fn from_repr(v: i64) -> MarketPhase {
+        match v {
+            0 => MarketPhase::PreMarket,
+            1 => MarketPhase::Trading,
+            2 => MarketPhase::PostMarket,
+            _ => MarketPhase::Closed,
+        }
+    }
+}
+
+// the fields are fixed, so renames and new fields are not supported
+impl FieldAccess for DataNasdaqBasicV1 {
+    fn get_field(&self, name: &str) -> Option<CFValue> {
+        let value = match (name, self) {
+            ("_dest", DataNasdaqBasicV1::BidAsk(ba)) => CFValue::String(ba._dest.clone()),
+            ("_dest", DataNasdaqBasicV1::Tick(t)) => CFValue::String(t._dest.clone()),
+            ("exchange", DataNasdaqBasicV1::BidAsk(ba)) => CFValue::String(ba.exchange.clone()),
+            ("exchange", DataNasdaqBasicV1::Tick(t)) => CFValue::String(t.exchange.clone()),
+            ("code", DataNasdaqBasicV1::BidAsk(ba)) => CFValue::String(ba.code.clone()),
+            ("code", DataNasdaqBasicV1::Tick(t)) => CFValue::String(t.code.clone()),
+            ("ts", DataNasdaqBasicV1::BidAsk(ba)) => CFValue::Datetime(ba.ts),
+            ("ts", DataNasdaqBasicV1::Tick(t)) => CFValue::Datetime(t.ts),
+            ("market_phase", DataNasdaqBasicV1::BidAsk(ba)) => {
+                CFValue::Int(ba.market_phase.clone() as i64)
+            }
+            ("market_phase", DataNasdaqBasicV1::Tick(t)) => {
+                CFValue::Int(t.market_phase.clone() as i64)
+            }
+            ("ask_price", DataNasdaqBasicV1::BidAsk(ba)) => price_value(ba.ask_price),
+            ("ask_volume", DataNasdaqBasicV1::BidAsk(ba)) => CFValue::Int(ba.ask_volume),
+            ("bid_price", DataNasdaqBasicV1::BidAsk(ba)) => price_value(ba.bid_price),
+            ("bid_volume", DataNasdaqBasicV1::BidAsk(ba)) => CFValue::Int(ba.bid_volume),
+            ("open", DataNasdaqBasicV1::Tick(t)) => price_value(t.open),
+            ("high", DataNasdaqBasicV1::Tick(t)) => price_value(t.high),
+            ("low", DataNasdaqBasicV1::Tick(t)) => price_value(t.low),
+            ("close", DataNasdaqBasicV1::Tick(t)) => price_value(t.close),
+            ("amount", DataNasdaqBasicV1::Tick(t)) => CFValue::Int(t.amount),
+            ("total_amount", DataNasdaqBasicV1::Tick(t)) => CFValue::Int(t.total_amount),
+            ("volume", DataNasdaqBasicV1::Tick(t)) => CFValue::Int(t.volume),
+            ("total_volume", DataNasdaqBasicV1::Tick(t)) => CFValue::Int(t.total_volume),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn set_field(&mut self, name: &str, value: CFValue) -> bool {
+        match (name, self) {
+            ("_dest", DataNasdaqBasicV1::BidAsk(ba)) => update_string(&value, &mut ba._dest, name),
+            ("_dest", DataNasdaqBasicV1::Tick(t)) => update_string(&value, &mut t._dest, name),
+            ("market_phase", DataNasdaqBasicV1::BidAsk(ba)) => {
+                update_market_phase(&value, &mut ba.market_phase)
+            }
+            ("market_phase", DataNasdaqBasicV1::Tick(t)) => {
+                update_market_phase(&value, &mut t.market_phase)
+            }
+            ("ts", DataNasdaqBasicV1::BidAsk(ba)) => update_double(&value, &mut ba.ts, name),
+            ("ts", DataNasdaqBasicV1::Tick(t)) => update_double(&value, &mut t.ts, name),
+            ("ask_price", DataNasdaqBasicV1::BidAsk(ba)) => {
+                update_price(&value, &mut ba.ask_price, name)
+            }
+            ("ask_volume", DataNasdaqBasicV1::BidAsk(ba)) => {
+                update_int(&value, &mut ba.ask_volume, name)
+            }
+            ("bid_price", DataNasdaqBasicV1::BidAsk(ba)) => {
+                update_price(&value, &mut ba.bid_price, name)
+            }
+            ("bid_volume", DataNasdaqBasicV1::BidAsk(ba)) => {
+                update_int(&value, &mut ba.bid_volume, name)
+            }
+            ("open", DataNasdaqBasicV1::Tick(t)) => update_price(&value, &mut t.open, name),
+            ("high", DataNasdaqBasicV1::Tick(t)) => update_price(&value, &mut t.high, name),
+            ("low", DataNasdaqBasicV1::Tick(t)) => update_price(&value, &mut t.low, name),
+            ("close", DataNasdaqBasicV1::Tick(t)) => update_price(&value, &mut t.close, name),
+            ("amount", DataNasdaqBasicV1::Tick(t)) => update_int(&value, &mut t.amount, name),
+            ("total_amount", DataNasdaqBasicV1::Tick(t)) => {
+                update_int(&value, &mut t.total_amount, name)
+            }
+            ("volume", DataNasdaqBasicV1::Tick(t)) => update_int(&value, &mut t.volume, name),
+            ("total_volume", DataNasdaqBasicV1::Tick(t)) => {
+                update_int(&value, &mut t.total_volume, name)
+            }
+            _ => false,
+        }
+    }
+}
+
+// a blank or mistyped token keeps the previous value, so it is not mistaken for a zero
+fn update_double(value: &CFValue, field: &mut f64, name: &str) -> bool {
+    match value.coerce_f64() {
+        Some(v) => *field = v,
+        None => {
+            debug!("{} keeps {}, got {:?}", name, field, value);
+            return false;
+        }
+    }
+    true
+}
+
+// a decimal price keeps its scale and repr
+fn update_price(value: &CFValue, field: &mut Price, name: &str) -> bool {
+    let price = match field {
+        Price::Float(_) => value.coerce_f64().map(Price::Float),
+        Price::Decimal(v) => value
+            .coerce_decimal(v.scale())
+            .map(|new| Price::Decimal(new.with_repr(v.repr()))),
+    };
+    match price {
+        Some(price) => *field = price,
+        None => {
+            debug!("{} keeps {:?}, got {:?}", name, field, value);
+            return false;
+        }
+    }
+    true
+}
+
+fn price_value(price: Price) -> CFValue {
+    match price {
+        Price::Float(v) => CFValue::Double(v),
+        Price::Decimal(v) => CFValue::Decimal(v),
+    }
+}
+
+fn update_int(value: &CFValue, field: &mut i64, name: &str) -> bool {
+    match value.coerce_i64() {
+        Some(v) => *field = v,
+        None => {
+            debug!("{} keeps {}, got {:?}", name, field, value);
+            return false;
+        }
+    }
+    true
+}
+
+fn update_string(value: &CFValue, field: &mut String, name: &str) -> bool {
+    match value.as_str() {
+        Some(v) => *field = v.to_string(),
+        None => {
+            debug!("{} keeps {}, got {:?}", name, field, value);
+            return false;
+        }
+    }
+    true
+}
+
+fn update_market_phase(value: &CFValue, field: &mut MarketPhase) -> bool {
+    match value.coerce_i64() {
+        Some(v) => *field = MarketPhase::from_repr(v),
+        None => {
+            debug!("market_phase keeps {:?}, got {:?}", field, value);
+            return false;
+        }
+    }
+    true
+}
+
+impl SourceTs for DataNasdaqBasicV1 {
+    fn source_ts(&self) -> f64 {
+        match self {
+            DataNasdaqBasicV1::BidAsk(ba) => ba.ts,
+            DataNasdaqBasicV1::Tick(tick) => tick.ts,
+        }
+    }
+}
+
 impl Dest for DataNasdaqBasicV1 {
     fn get_dest(&self) -> &str {
         match self {
@@ -219,8 +553,323 @@ impl Dest for DataNasdaqBasicV1 {
             DataNasdaqBasicV1::Tick(tick) => &tick._dest,
         }
     }
+
+    // keep ticks and quotes of a symbol on the same worker
+    fn get_partition_key(&self) -> &str {
+        match self {
+            DataNasdaqBasicV1::BidAsk(ba) => &ba.code,
+            DataNasdaqBasicV1::Tick(tick) => &tick.code,
+        }
+    }
+
+    fn get_meta(&self) -> Option<&Meta> {
+        match self {
+            DataNasdaqBasicV1::BidAsk(ba) => Some(&ba._meta),
+            DataNasdaqBasicV1::Tick(tick) => Some(&tick._meta),
+        }
+    }
+
+    fn meta_mut(&mut self) -> Option<&mut Meta> {
+        match self {
+            DataNasdaqBasicV1::BidAsk(ba) => Some(&mut ba._meta),
+            DataNasdaqBasicV1::Tick(tick) => Some(&mut tick._meta),
+        }
+    }
+}
+
+impl From<&MarketPhase> for pb::MarketPhase {
+    fn from(market_phase: &MarketPhase) -> Self {
+        match market_phase {
+            MarketPhase::PreMarket => pb::MarketPhase::PreMarket,
+            MarketPhase::Trading => pb::MarketPhase::Trading,
+            MarketPhase::PostMarket => pb::MarketPhase::PostMarket,
+            MarketPhase::Closed => pb::MarketPhase::Closed,
+        }
+    }
 }
 
+// the structs are destructured, so a field added to one of them does not compile until
+// it is in `proto/nasdaq_basic.proto` as well
+impl From<&NBTick> for pb::NbTick {
+    fn from(tick: &NBTick) -> Self {
+        let NBTick {
+            _dest: _,
+            _meta: _,
+            exchange,
+            code,
+            ts,
+            open,
+            high,
+            low,
+            close,
+            amount,
+            total_amount,
+            volume,
+            total_volume,
+            market_phase,
+        } = tick;
+        Self {
+            exchange: exchange.clone(),
+            code: code.clone(),
+            ts: *ts,
+            open: open.to_f64(),
+            high: high.to_f64(),
+            low: low.to_f64(),
+            close: close.to_f64(),
+            amount: *amount,
+            total_amount: *total_amount,
+            volume: *volume,
+            total_volume: *total_volume,
+            market_phase: pb::MarketPhase::from(market_phase).into(),
+        }
+    }
+}
+
+impl From<&NBBidAsk> for pb::NbBidAsk {
+    fn from(bidask: &NBBidAsk) -> Self {
+        let NBBidAsk {
+            _dest: _,
+            _meta: _,
+            exchange,
+            code,
+            ts,
+            ask_price,
+            ask_volume,
+            bid_price,
+            bid_volume,
+            market_phase,
+        } = bidask;
+        Self {
+            exchange: exchange.clone(),
+            code: code.clone(),
+            ts: *ts,
+            ask_price: ask_price.to_f64(),
+            ask_volume: *ask_volume,
+            bid_price: bid_price.to_f64(),
+            bid_volume: *bid_volume,
+            market_phase: pb::MarketPhase::from(market_phase).into(),
+        }
+    }
+}
+
+impl From<&DataNasdaqBasicState> for pb::DataNasdaqBasicState {
+    fn from(state: &DataNasdaqBasicState) -> Self {
+        let DataNasdaqBasicState {
+            exchange,
+            code,
+            ts,
+            exchange_ts,
+            ask_price,
+            ask_volume,
+            bid_price,
+            bid_volume,
+            close,
+            volume,
+            total_volume,
+            total_amount,
+            open,
+            high,
+            low,
+            market_phase,
+            price_chg,
+            pct_chg,
+        } = state;
+        Self {
+            exchange: exchange.clone(),
+            code: code.clone(),
+            ts: *ts,
+            exchange_ts: *exchange_ts,
+            ask_price: *ask_price,
+            ask_volume: *ask_volume,
+            bid_price: *bid_price,
+            bid_volume: *bid_volume,
+            close: *close,
+            volume: *volume,
+            total_volume: *total_volume,
+            total_amount: *total_amount,
+            open: *open,
+            high: *high,
+            low: *low,
+            market_phase: pb::MarketPhase::from(market_phase).into(),
+            price_chg: *price_chg,
+            pct_chg: *pct_chg,
+        }
+    }
+}
+
+impl ProtoMessage for NBTick {
+    fn encode_proto(&self, buf: &mut Vec<u8>) -> Result<(), ProtoError> {
+        encode_into(&pb::NbTick::from(self), buf);
+        Ok(())
+    }
+
+    fn envelope_data(&self) -> Result<pb::envelope::Data, ProtoError> {
+        Ok(pb::envelope::Data::Tick(self.into()))
+    }
+}
+
+impl ProtoMessage for NBBidAsk {
+    fn encode_proto(&self, buf: &mut Vec<u8>) -> Result<(), ProtoError> {
+        encode_into(&pb::NbBidAsk::from(self), buf);
+        Ok(())
+    }
+
+    fn envelope_data(&self) -> Result<pb::envelope::Data, ProtoError> {
+        Ok(pb::envelope::Data::BidAsk(self.into()))
+    }
+}
+
+impl ProtoMessage for DataNasdaqBasicV1 {
+    fn encode_proto(&self, buf: &mut Vec<u8>) -> Result<(), ProtoError> {
+        match self {
+            DataNasdaqBasicV1::BidAsk(ba) => ba.encode_proto(buf),
+            DataNasdaqBasicV1::Tick(tick) => tick.encode_proto(buf),
+        }
+    }
+
+    fn envelope_data(&self) -> Result<pb::envelope::Data, ProtoError> {
+        match self {
+            DataNasdaqBasicV1::BidAsk(ba) => ba.envelope_data(),
+            DataNasdaqBasicV1::Tick(tick) => tick.envelope_data(),
+        }
+    }
+}
+
+fn nullable(fields: &[(&str, DataType)]) -> Vec<Field> {
+    fields
+        .iter()
+        .map(|(name, data_type)| Field::new(*name, data_type.clone(), true))
+        .collect()
+}
+
+impl ParquetMessage for DataNasdaqBasicV1 {
+    fn arrow_fields(name: &str) -> Option<Vec<Field>> {
+        use DataType::{Float64, Int64, Utf8};
+        match name {
+            "NBTick" => Some(nullable(&[
+                ("exchange", Utf8),
+                ("code", Utf8),
+                ("ts", Float64),
+                ("open", Float64),
+                ("high", Float64),
+                ("low", Float64),
+                ("close", Float64),
+                ("amount", Int64),
+                ("total_amount", Int64),
+                ("volume", Int64),
+                ("total_volume", Int64),
+                ("market_phase", Int64),
+            ])),
+            "NBBidAsk" => Some(nullable(&[
+                ("exchange", Utf8),
+                ("code", Utf8),
+                ("ts", Float64),
+                ("ask_price", Float64),
+                ("ask_volume", Int64),
+                ("bid_price", Float64),
+                ("bid_volume", Int64),
+                ("market_phase", Int64),
+            ])),
+            _ => None,
+        }
+    }
+
+    // doubles whatever the repr, like protobuf
+    fn parquet_row(&self) -> Result<Row, RowError> {
+        let mut data = self.clone();
+        for price in data.prices_mut() {
+            *price = Price::Float(price.to_f64());
+        }
+        to_row(&data)
+    }
+}
+
+// not carried by the envelope
+impl ProtoMessage for DataNasdaqBasicState {
+    fn encode_proto(&self, buf: &mut Vec<u8>) -> Result<(), ProtoError> {
+        encode_into(&pb::DataNasdaqBasicState::from(self), buf);
+        Ok(())
+    }
+}
+
+// _dest and _meta are not serialized with the data, so the spill record carries them
+// next to the variant. decimal prices are spilled as strings, which keep the scale, and
+// the repr they go out with is carried as well
+#[derive(Serialize)]
+enum SpillRecordRef<'a> {
+    BidAsk(&'a str, &'a Meta, &'a NBBidAsk),
+    Tick(&'a str, &'a Meta, &'a NBTick),
+    DecimalBidAsk(&'a str, &'a Meta, DecimalRepr, &'a NBBidAsk),
+    DecimalTick(&'a str, &'a Meta, DecimalRepr, &'a NBTick),
+}
+
+#[derive(Deserialize)]
+enum SpillRecord {
+    BidAsk(String, Meta, NBBidAsk),
+    Tick(String, Meta, NBTick),
+    DecimalBidAsk(String, Meta, DecimalRepr, NBBidAsk),
+    DecimalTick(String, Meta, DecimalRepr, NBTick),
+}
+
+impl SpillCodec for DataNasdaqBasicV1 {
+    fn encode(&self) -> Result<Vec<u8>, QueueError> {
+        let repr = match self.decimal_repr() {
+            Some(repr) => repr,
+            None => {
+                let record = match self {
+                    DataNasdaqBasicV1::BidAsk(ba) => {
+                        SpillRecordRef::BidAsk(&ba._dest, &ba._meta, ba)
+                    }
+                    DataNasdaqBasicV1::Tick(tick) => {
+                        SpillRecordRef::Tick(&tick._dest, &tick._meta, tick)
+                    }
+                };
+                return rmp_serde::to_vec_named(&record).context(SpillEncodeSnafu);
+            }
+        };
+        let mut data = self.clone();
+        data.set_decimal_repr(DecimalRepr::String);
+        let record = match &data {
+            DataNasdaqBasicV1::BidAsk(ba) => {
+                SpillRecordRef::DecimalBidAsk(&ba._dest, &ba._meta, repr, ba)
+            }
+            DataNasdaqBasicV1::Tick(tick) => {
+                SpillRecordRef::DecimalTick(&tick._dest, &tick._meta, repr, tick)
+            }
+        };
+        rmp_serde::to_vec_named(&record).context(SpillEncodeSnafu)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, QueueError> {
+        let record: SpillRecord = rmp_serde::from_slice(bytes).context(SpillDecodeSnafu)?;
+        Ok(match record {
+            SpillRecord::BidAsk(dest, meta, mut ba) => {
+                ba._dest = dest;
+                ba._meta = meta;
+                DataNasdaqBasicV1::BidAsk(ba)
+            }
+            SpillRecord::Tick(dest, meta, mut tick) => {
+                tick._dest = dest;
+                tick._meta = meta;
+                DataNasdaqBasicV1::Tick(tick)
+            }
+            SpillRecord::DecimalBidAsk(dest, meta, repr, mut ba) => {
+                ba._dest = dest;
+                ba._meta = meta;
+                let mut data = DataNasdaqBasicV1::BidAsk(ba);
+                data.set_decimal_repr(repr);
+                data
+            }
+            SpillRecord::DecimalTick(dest, meta, repr, mut tick) => {
+                tick._dest = dest;
+                tick._meta = meta;
+                let mut data = DataNasdaqBasicV1::Tick(tick);
+                data.set_decimal_repr(repr);
+                data
+            }
+        })
+    }
+}
 
 impl Convertor for NasdaqBasicConvertorV1 {
     type Out = DataNasdaqBasicV1;
@@ -245,51 +894,31 @@ impl Convertor for NasdaqBasicConvertorV1 {
 
         let data = match self.state.get_mut(&key) {
             Some(mut state) => {
-                let mut is_tick = false;
-                let mut is_bidask = false;
-                for (token, value) in reader.iter_with_token_number() {
-                    // TODO only update the field that has value
-                    match token {
-                        10 => {
-                            is_bidask = true;
-                            state.ask_price = value.to_f64()
-                        },
-                        11 => state.ask_volume = value.to_i64(),
-                        12 => {
-                            is_bidask = true;
-                            state.bid_price = value.to_f64()
-                        },
-                        13 => state.bid_volume = value.to_i64(),
-                        16 => state.ts = value.to_f64(),
-                        55 => state.exchange_ts = value.to_i64(),
-                        361 => state.price_chg = value.to_f64(),
-                        362 => state.pct_chg = value.to_f64(),
-                        447 => {
-                            is_tick = true;
-                            state.close = value.to_f64()
-                        },
-                        448 => state.volume = value.to_i64(),
-                        // 460 => state.total_amount = value.to_i64(),
-                        // 22 => state.amount = value.to_i64(),
-                        463 => state.total_volume = value.to_i64(),
-                        1709 => state.market_phase = value.to_i64().into(),
-                        // 23 => update_int(value, &mut state.total_volume, "total_volume"),
-                        _ => {
-                            debug!("token: {}, value: {:?}", token, value);
-                        }
-                    }
-                }
+                let updated = state.apply_update(&mut reader);
+                // a trade or quote token makes a message, even with a blank value
+                let is_tick = updated.received("close");
+                let is_bidask = updated.received("ask_price") || updated.received("bid_price");
                 // println!("updated state: {:?}", state.clone());
+                if let Some(watchdog) = &self.watchdog {
+                    watchdog.touch(src, &state.code, &state.market_phase);
+                }
+                let fields = TopicFields {
+                    source: src,
+                    exchange: &state.exchange,
+                    code: &state.code,
+                    market_phase: state.market_phase.as_str(),
+                };
                 let data = if is_tick {
                     Some(DataNasdaqBasicV1::Tick(NBTick {
-                        _dest: format!("api/V1/TIC/{}/{}", state.exchange, state.code),
+                        _dest: self.topics.tick.render(&fields),
+                        _meta: Meta::default(),
                         exchange: state.exchange.clone(),
                         code: state.code.clone(),
                         ts: state.ts,
-                        open: state.open,
-                        high: state.high,
-                        low: state.low,
-                        close: state.close,
+                        open: self.price(src, state.open),
+                        high: self.price(src, state.high),
+                        low: self.price(src, state.low),
+                        close: self.price(src, state.close),
                         amount: 0,
                         total_amount: state.total_amount,
                         volume: state.volume,
@@ -298,13 +927,14 @@ impl Convertor for NasdaqBasicConvertorV1 {
                     }))
                 } else if is_bidask {
                     Some(DataNasdaqBasicV1::BidAsk(NBBidAsk {
-                        _dest: format!("api/V1/QUO/{}/{}", state.exchange, state.code),
+                        _dest: self.topics.bidask.render(&fields),
+                        _meta: Meta::default(),
                         exchange: state.exchange.clone(),
                         code: state.code.clone(),
                         ts: state.ts,
-                        ask_price: state.ask_price,
+                        ask_price: self.price(src, state.ask_price),
                         ask_volume: state.ask_volume,
-                        bid_price: state.bid_price,
+                        bid_price: self.price(src, state.bid_price),
                         bid_volume: state.bid_volume,
                         market_phase: state.market_phase.clone(),
                     }))
@@ -319,28 +949,18 @@ impl Convertor for NasdaqBasicConvertorV1 {
                 // let mut r = EventReader::new(event, &self.reader_config);
                 // let m = r.to_map();
                 // println!("event map: {:?}", m);
-                let data = DataNasdaqBasicState {
-                    code: symbol.to_string(),
-                    ask_price: reader.find(10).unwrap_or(CFValue::Double(0.0)).to_f64(),
-                    ask_volume: reader.find(11).unwrap_or(CFValue::Double(0.0)).to_i64(),
-                    bid_price: reader.find(12).unwrap_or(CFValue::Double(0.0)).to_f64(),
-                    bid_volume: reader.find(13).unwrap_or(CFValue::Double(0.0)).to_i64(),
-                    ts: reader.find(16).unwrap_or(CFValue::Datetime(0.0)).to_f64(),
-                    // total_volume: reader.find(22).unwrap_or(CFValue::Int(0)).to_i64(),
-                    exchange_ts: reader.find(55).unwrap_or(CFValue::Int(0)).to_i64(),
-                    price_chg: reader.find(361).unwrap_or(CFValue::Double(0.0)).to_f64(),
-                    pct_chg: reader.find(362).unwrap_or(CFValue::Double(0.0)).to_f64(),
-                    high: reader.find(389).unwrap_or(CFValue::Double(0.0)).to_f64(), // 389 is official high 388 is ice not exise in pre market
-                    low: reader.find(395).unwrap_or(CFValue::Double(0.0)).to_f64(), // 395 is official low 394 is ice not exise in pre market
-                    open: reader.find(401).unwrap_or(CFValue::Double(0.0)).to_f64(),// 401 is official open not exise in pre market
-                    close: reader.find(447).unwrap_or(CFValue::Double(0.0)).to_f64(),
-                    volume: reader.find(448).unwrap_or(CFValue::Int(0)).to_i64(),
-                    total_amount: reader.find(460).unwrap_or(CFValue::Int(0)).to_i64(),
-                    total_volume: reader.find(463).unwrap_or(CFValue::Int(0)).to_i64(),
-                    market_phase: reader.find(1709).unwrap_or(CFValue::Int(1)).to_i64().into(),
-                    exchange: reader.find(3240).unwrap_or(CFValue::String("".into())).to_string(),
+                let mut data = match DataNasdaqBasicState::from_event_reader(&mut reader) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        warn!("snapshot of {} not decoded: {}", key, e);
+                        return None;
+                    }
                 };
+                data.code = symbol.to_string();
                 // println!("new data: {:?}", data);
+                if let Some(watchdog) = &self.watchdog {
+                    watchdog.touch(src, &data.code, &data.market_phase);
+                }
                 self.state.insert(key.clone(), data);
                 None
             }
@@ -353,6 +973,8 @@ impl Convertor for NasdaqBasicConvertorV1 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::envelope::{run_id, EnvelopeFormater};
+    use crate::formater::{Formated, FormaterExt, ProtobufFormater};
     use cfapi::value::CFValue;
     use std::collections::BTreeMap;
 
@@ -382,6 +1004,8 @@ mod tests {
         state.insert("TSE.2330", data.clone());
         let origin = state.get("TSE.2330").unwrap();
         assert_eq!(origin.ask_price, 594.0);
+        // the read guard locks the shard, get_mut on the same key below would deadlock
+        drop(origin);
         let _new_v = match state.get_mut("TSE.2330") {
             Some(mut v) => {
                 v.ask_price = 595.0;
@@ -394,6 +1018,350 @@ mod tests {
         assert_eq!(new_v.ask_price, 595.0);
     }
 
+    #[test]
+    fn test_set_field_keeps_mistyped() {
+        let mut tick = DataNasdaqBasicV1::Tick(NBTick {
+            close: 590.0.into(),
+            volume: 3,
+            ..Default::default()
+        });
+        assert!(!tick.set_field("close", CFValue::Null));
+        assert!(!tick.set_field("volume", CFValue::Double(1.5)));
+        assert!(tick.set_field("total_volume", CFValue::Double(7.0)));
+        assert!(!tick.set_field("ask_price", CFValue::Double(1.0)));
+        assert_eq!(tick.get_field("close"), Some(CFValue::Double(590.0)));
+        assert_eq!(tick.get_field("volume"), Some(CFValue::Int(3)));
+        assert_eq!(tick.get_field("total_volume"), Some(CFValue::Int(7)));
+    }
+
+    #[test]
+    fn test_middleware_rejects_field_changes() {
+        use crate::middleware::{build_chain, MiddlewareConfig};
+
+        let rename = MiddlewareConfig::Rename {
+            from: "close".to_string(),
+            to: "price".to_string(),
+        };
+        let enrich = MiddlewareConfig::Enrich {
+            field: "venue".to_string(),
+            value: CFValue::String("NASDAQ".to_string()),
+        };
+        let round = MiddlewareConfig::Round {
+            fields: vec!["close".to_string()],
+            decimals: 2,
+        };
+        assert!(build_chain::<DataNasdaqBasicV1>(&[rename]).is_err());
+        assert!(build_chain::<DataNasdaqBasicV1>(&[enrich]).is_err());
+        assert!(build_chain::<DataNasdaqBasicV1>(&[round]).is_ok());
+    }
+
+    #[test]
+    fn test_state_apply_update() {
+        let mut state = DataNasdaqBasicState {
+            close: 590.0,
+            volume: 3,
+            ..Default::default()
+        };
+        let updated = state.apply_tokens([
+            (447, CFValue::Null),
+            (448, CFValue::Int(5)),
+            (12, CFValue::Double(589.5)),
+            (1709, CFValue::Int(4)),
+            (22, CFValue::Int(9)),
+            (460, CFValue::Int(1000)),
+            (401, CFValue::Double(1.0)),
+            (3240, CFValue::from("X")),
+        ]);
+        assert_eq!(
+            updated.iter().collect::<Vec<_>>(),
+            vec!["bid_price", "volume", "market_phase"]
+        );
+        // the blank close still makes a tick, the snapshot fields are not updated
+        assert!(updated.received("close") && !updated.received("ask_price"));
+        assert_eq!((state.total_amount, state.open), (0, 0.0));
+        assert_eq!(state.exchange, "");
+        assert_eq!(
+            (state.close, state.volume, state.bid_price),
+            (590.0, 5, 589.5)
+        );
+        assert!(matches!(state.market_phase, MarketPhase::Trading));
+    }
+
+    #[test]
+    fn test_spill_codec_keeps_dest() {
+        let tick = DataNasdaqBasicV1::Tick(NBTick {
+            _dest: "api/V1/TIC/TSE/2330".into(),
+            _meta: Meta {
+                source: 533,
+                tag: 3,
+                ..Default::default()
+            },
+            exchange: "TSE".into(),
+            code: "2330".into(),
+            close: 590.0.into(),
+            total_volume: 347307,
+            ..Default::default()
+        });
+        let bidask = DataNasdaqBasicV1::BidAsk(NBBidAsk {
+            _dest: "api/V1/QUO/TSE/2330".into(),
+            exchange: "TSE".into(),
+            code: "2330".into(),
+            ask_price: 594.0.into(),
+            ..Default::default()
+        });
+        let format = PriceFormat {
+            source: 533,
+            scale: 2,
+            repr: DecimalRepr::Scaled,
+        };
+        let decimal = DataNasdaqBasicV1::Tick(NBTick {
+            _dest: "api/V1/TIC/Q/AAPL".into(),
+            close: format.price(167.78000000000003),
+            high: format.price(168.5),
+            ..Default::default()
+        });
+        let json = serde_json::to_value(&decimal).unwrap();
+        assert_eq!((json["close"].as_i64(), json["high"].as_i64()), (Some(16778), Some(16850)));
+        for data in [tick, bidask, decimal] {
+            let decoded = DataNasdaqBasicV1::decode(&data.encode().unwrap()).unwrap();
+            assert_eq!(decoded.get_dest(), data.get_dest());
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", data));
+        }
+    }
+
+    // (number, value) of each field, doubles as f64, varints as u64
+    fn proto_fields(bytes: &[u8]) -> Vec<(u64, String)> {
+        fn varint(bytes: &[u8], pos: &mut usize) -> u64 {
+            let mut v = 0;
+            for shift in (0..64).step_by(7) {
+                let b = bytes[*pos];
+                *pos += 1;
+                v |= ((b & 0x7f) as u64) << shift;
+                if b < 0x80 {
+                    break;
+                }
+            }
+            v
+        }
+        let mut fields = vec![];
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let tag = varint(bytes, &mut pos);
+            let value = match tag & 7 {
+                0 => varint(bytes, &mut pos).to_string(),
+                1 => {
+                    let v = f64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap());
+                    pos += 8;
+                    v.to_string()
+                }
+                2 => {
+                    let len = varint(bytes, &mut pos) as usize;
+                    pos += len;
+                    String::from_utf8_lossy(&bytes[pos - len..pos]).to_string()
+                }
+                wire => panic!("unexpected wire type {}", wire),
+            };
+            fields.push((tag >> 3, value));
+        }
+        fields
+    }
+
+    fn expected(fields: &[(u64, &str)]) -> Vec<(u64, String)> {
+        fields.iter().map(|(n, v)| (*n, v.to_string())).collect()
+    }
+
+    fn protobuf<T: Serialize + ProtoMessage>(data: &T) -> Vec<u8> {
+        let mut buf = vec![];
+        ProtobufFormater.format_into(data, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_protobuf_decimal_prices() {
+        use prost::Message;
+
+        let format = PriceFormat {
+            source: 533,
+            scale: 2,
+            repr: DecimalRepr::String,
+        };
+        let tick = NBTick {
+            close: format.price(167.78000000000003),
+            ..Default::default()
+        };
+        assert_eq!(serde_json::to_value(&tick).unwrap()["close"], "167.78");
+        let decoded = pb::NbTick::decode(&protobuf(&tick)[..]).unwrap();
+        assert_eq!(decoded.close, 167.78);
+    }
+
+    /// Every field has a value other than the default so all of them are encoded. A
+    /// rust field added does not compile in the conversions to `pb`, and a field
+    /// renumbered or retyped in `proto/nasdaq_basic.proto` fails here.
+    #[test]
+    fn test_protobuf_schema_compatible() {
+        use prost::Message;
+
+        let tick = NBTick {
+            _dest: "api/V1/TIC/Q/AAPL".into(),
+            _meta: Meta::default(),
+            exchange: "Q".into(),
+            code: "AAPL".into(),
+            ts: 1.5,
+            open: 2.5.into(),
+            high: 3.5.into(),
+            low: 4.5.into(),
+            close: 5.5.into(),
+            amount: 6,
+            total_amount: 7,
+            volume: 8,
+            total_volume: 9,
+            market_phase: MarketPhase::Trading,
+        };
+        let bytes = protobuf(&tick);
+        assert_eq!(
+            pb::NbTick::decode(&bytes[..]).unwrap(),
+            pb::NbTick {
+                exchange: "Q".into(),
+                code: "AAPL".into(),
+                ts: 1.5,
+                open: 2.5,
+                high: 3.5,
+                low: 4.5,
+                close: 5.5,
+                amount: 6,
+                total_amount: 7,
+                volume: 8,
+                total_volume: 9,
+                market_phase: pb::MarketPhase::Trading.into(),
+            }
+        );
+        assert_eq!(
+            proto_fields(&bytes),
+            expected(&[
+                (1, "Q"),
+                (2, "AAPL"),
+                (3, "1.5"),
+                (4, "2.5"),
+                (5, "3.5"),
+                (6, "4.5"),
+                (7, "5.5"),
+                (8, "6"),
+                (9, "7"),
+                (10, "8"),
+                (11, "9"),
+                (12, "1"),
+            ])
+        );
+        let bidask = NBBidAsk {
+            _dest: "api/V1/QUO/Q/AAPL".into(),
+            _meta: Meta::default(),
+            exchange: "Q".into(),
+            code: "AAPL".into(),
+            ts: 1.5,
+            ask_price: 2.5.into(),
+            ask_volume: 3,
+            bid_price: 4.5.into(),
+            bid_volume: 5,
+            market_phase: MarketPhase::PostMarket,
+        };
+        let bytes = protobuf(&DataNasdaqBasicV1::BidAsk(bidask));
+        assert_eq!(
+            pb::NbBidAsk::decode(&bytes[..]).unwrap(),
+            pb::NbBidAsk {
+                exchange: "Q".into(),
+                code: "AAPL".into(),
+                ts: 1.5,
+                ask_price: 2.5,
+                ask_volume: 3,
+                bid_price: 4.5,
+                bid_volume: 5,
+                market_phase: pb::MarketPhase::PostMarket.into(),
+            }
+        );
+        assert_eq!(
+            proto_fields(&bytes),
+            expected(&[
+                (1, "Q"),
+                (2, "AAPL"),
+                (3, "1.5"),
+                (4, "2.5"),
+                (5, "3"),
+                (6, "4.5"),
+                (7, "5"),
+                (8, "2"),
+            ])
+        );
+        let state = DataNasdaqBasicState {
+            exchange: "Q".into(),
+            code: "AAPL".into(),
+            ts: 1.5,
+            exchange_ts: 2,
+            ask_price: 3.5,
+            ask_volume: 4,
+            bid_price: 5.5,
+            bid_volume: 6,
+            close: 7.5,
+            volume: 8,
+            total_volume: 9,
+            total_amount: 10,
+            open: 11.5,
+            high: 12.5,
+            low: 13.5,
+            market_phase: MarketPhase::Trading,
+            price_chg: -3.0,
+            pct_chg: -0.5,
+        };
+        let bytes = protobuf(&state);
+        assert_eq!(
+            pb::DataNasdaqBasicState::decode(&bytes[..]).unwrap(),
+            pb::DataNasdaqBasicState::from(&state)
+        );
+        assert_eq!(
+            proto_fields(&bytes),
+            expected(&[
+                (1, "Q"),
+                (2, "AAPL"),
+                (3, "1.5"),
+                (4, "2"),
+                (5, "3.5"),
+                (6, "4"),
+                (7, "5.5"),
+                (8, "6"),
+                (9, "7.5"),
+                (10, "8"),
+                (11, "9"),
+                (12, "10"),
+                (13, "11.5"),
+                (14, "12.5"),
+                (15, "13.5"),
+                (16, "1"),
+                (17, "-3"),
+                (18, "-0.5"),
+            ])
+        );
+
+        // the envelope carries the message in its data oneof
+        let formater = EnvelopeFormater::new(ProtobufFormater, "hub-a/0");
+        let bytes = match formater.format(&DataNasdaqBasicV1::Tick(tick)).unwrap() {
+            Formated::Bytes(bytes) => bytes,
+            Formated::String(_) => panic!("protobuf is binary"),
+        };
+        let envelope = pb::Envelope::decode(&bytes[..]).unwrap();
+        assert_eq!(envelope.producer, "hub-a/0");
+        assert_eq!(envelope.run, run_id());
+        assert_eq!(envelope.event_type(), pb::EventType::Update);
+        assert_eq!(envelope.schema_version, 1);
+        match envelope.data {
+            Some(pb::envelope::Data::Tick(tick)) => assert_eq!(tick.code, "AAPL"),
+            data => panic!("expected a tick, got {:?}", data),
+        }
+        let fields = proto_fields(&bytes);
+        assert_eq!(fields[0], (2, "hub-a/0".to_string()));
+        let tags: Vec<u64> = fields.iter().map(|(tag, _)| *tag).collect();
+        assert_eq!(tags[tags.len() - 2..], [8, 10]);
+        assert_eq!(fields.last().unwrap().1, run_id().to_string());
+    }
+
     #[test]
     fn test_dashmap_usage() {
         let state = DashMap::with_hasher(RandomState::new());
@@ -0,0 +1,166 @@
+use serde::{Deserialize, Serialize};
+use snafu::prelude::Snafu;
+use std::fmt::Write;
+
+#[derive(Debug, Snafu)]
+pub enum TopicError {
+    #[snafu(display("Topic template {} has unknown placeholder {{{}}}", template, name))]
+    UnknownPlaceholder { template: String, name: String },
+    #[snafu(display("Topic template {} has an unclosed placeholder", template))]
+    Unclosed { template: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Placeholder {
+    Source,
+    Exchange,
+    Code,
+    MarketPhase,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Field(Placeholder),
+}
+
+/// Values of the placeholders of a message.
+#[derive(Debug, Clone, Copy)]
+pub struct TopicFields<'a> {
+    pub source: i32,
+    pub exchange: &'a str,
+    pub code: &'a str,
+    pub market_phase: &'a str,
+}
+
+/// A topic with `{source}`, `{exchange}`, `{code}` and `{market_phase}` placeholders,
+/// e.g. `api/V1/TIC/{exchange}/{code}`. Unknown placeholders fail when parsed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TopicTemplate {
+    template: String,
+    segments: Vec<Segment>,
+}
+
+impl TopicTemplate {
+    pub fn parse(template: &str) -> Result<Self, TopicError> {
+        let mut segments = vec![];
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| TopicError::Unclosed {
+                    template: template.to_string(),
+                })?;
+            let name = &rest[start + 1..start + end];
+            let placeholder = match name {
+                "source" => Placeholder::Source,
+                "exchange" => Placeholder::Exchange,
+                "code" => Placeholder::Code,
+                "market_phase" => Placeholder::MarketPhase,
+                _ => return UnknownPlaceholderSnafu { template, name }.fail(),
+            };
+            segments.push(Segment::Field(placeholder));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+        Ok(Self {
+            template: template.to_string(),
+            segments,
+        })
+    }
+
+    pub fn render(&self, fields: &TopicFields) -> String {
+        let mut topic = String::with_capacity(self.template.len() + 16);
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => topic.push_str(text),
+                Segment::Field(Placeholder::Source) => {
+                    let _ = write!(topic, "{}", fields.source);
+                }
+                Segment::Field(Placeholder::Exchange) => topic.push_str(fields.exchange),
+                Segment::Field(Placeholder::Code) => topic.push_str(fields.code),
+                Segment::Field(Placeholder::MarketPhase) => topic.push_str(fields.market_phase),
+            }
+        }
+        topic
+    }
+}
+
+impl TryFrom<String> for TopicTemplate {
+    type Error = TopicError;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        Self::parse(&template)
+    }
+}
+
+impl From<TopicTemplate> for String {
+    fn from(template: TopicTemplate) -> Self {
+        template.template
+    }
+}
+
+fn default_tick() -> TopicTemplate {
+    TopicTemplate::parse("api/V1/TIC/{exchange}/{code}").unwrap()
+}
+
+fn default_bidask() -> TopicTemplate {
+    TopicTemplate::parse("api/V1/QUO/{exchange}/{code}").unwrap()
+}
+
+/// Topic of each message type, e.g. in toml `[topics]` `tick = "md/{source}/{code}/tick"`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TopicTemplates {
+    #[serde(default = "default_tick")]
+    pub tick: TopicTemplate,
+    #[serde(default = "default_bidask")]
+    pub bidask: TopicTemplate,
+}
+
+impl Default for TopicTemplates {
+    fn default() -> Self {
+        Self {
+            tick: default_tick(),
+            bidask: default_bidask(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_template() {
+        let fields = TopicFields {
+            source: 533,
+            exchange: "Q",
+            code: "AAPL",
+            market_phase: "trading",
+        };
+        assert_eq!(
+            TopicTemplates::default().tick.render(&fields),
+            "api/V1/TIC/Q/AAPL"
+        );
+        let template = TopicTemplate::parse("md/{source}/{code}/{market_phase}").unwrap();
+        assert_eq!(template.render(&fields), "md/533/AAPL/trading");
+
+        assert!(matches!(
+            TopicTemplate::parse("md/{symbol}"),
+            Err(TopicError::UnknownPlaceholder { .. })
+        ));
+        assert!(matches!(
+            TopicTemplate::parse("md/{code"),
+            Err(TopicError::Unclosed { .. })
+        ));
+        let templates: TopicTemplates = toml::from_str(r#"tick = "md/{code}""#).unwrap();
+        assert_eq!(templates.tick.render(&fields), "md/AAPL");
+        assert_eq!(templates.bidask, TopicTemplates::default().bidask);
+    }
+}
@@ -0,0 +1,264 @@
+use ahash::RandomState;
+use cfapi::binding::{MessageEvent, MessageEvent_Types};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::formater::{Encoding, FormatError, Formated, FormaterExt};
+use super::latency::now_ns;
+use super::sink::Dest;
+
+/// Version of the envelope and message layout, bumped on incompatible changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum EventType {
+    ImageComplete,
+    ImagePart,
+    Refresh,
+    Status,
+    #[default]
+    Update,
+}
+
+impl From<MessageEvent_Types> for EventType {
+    fn from(event_type: MessageEvent_Types) -> Self {
+        match event_type {
+            MessageEvent_Types::IMAGE_COMPLETE => EventType::ImageComplete,
+            MessageEvent_Types::IMAGE_PART => EventType::ImagePart,
+            MessageEvent_Types::REFRESH => EventType::Refresh,
+            MessageEvent_Types::STATUS => EventType::Status,
+            MessageEvent_Types::UPDATE => EventType::Update,
+        }
+    }
+}
+
+/// Envelope fields a message carries through the pipeline. The origin is set after
+/// the convertor, the seq and produced_at before the message is queued for a sink.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Meta {
+    pub seq: u64,
+    pub source: i32,
+    pub event_type: EventType,
+    pub tag: i64,
+    /// epoch nanoseconds
+    pub produced_at: u64,
+}
+
+impl Meta {
+    pub fn from_event(event: &MessageEvent) -> Self {
+        Self {
+            source: i32::from(event.getSource()),
+            event_type: event.getType().into(),
+            tag: event.getTag(),
+            ..Default::default()
+        }
+    }
+
+    /// Sets the origin of the messages converted from one event, a message without
+    /// an envelope is left alone.
+    pub fn stamp_origin<T: Dest>(&self, out: &mut [T]) {
+        for data in out.iter_mut() {
+            if let Some(meta) = data.meta_mut() {
+                *meta = *self;
+            }
+        }
+    }
+}
+
+/// Sequence per destination, starting at 1.
+#[derive(Debug, Default)]
+pub struct DestSeq {
+    seqs: HashMap<String, u64, RandomState>,
+}
+
+impl DestSeq {
+    pub fn next(&mut self, dest: &str) -> u64 {
+        match self.seqs.get_mut(dest) {
+            Some(seq) => {
+                *seq += 1;
+                *seq
+            }
+            None => {
+                self.seqs.insert(dest.to_string(), 1);
+                1
+            }
+        }
+    }
+
+    /// Numbers a message with an envelope before it is queued, so a gap downstream is
+    /// a dropped or lost message. `produced_at` is in epoch nanoseconds.
+    pub fn stamp<T: Dest>(&mut self, data: &mut T, produced_at: u64) {
+        if data.get_meta().is_none() {
+            return;
+        }
+        let seq = self.next(data.get_dest());
+        if let Some(meta) = data.meta_mut() {
+            meta.seq = seq;
+            meta.produced_at = produced_at;
+        }
+    }
+}
+
+static RUN_ID: Lazy<u64> = Lazy::new(now_ns);
+
+/// Id of this hub process, the start time in epoch nanoseconds. The seq of a
+/// destination starts over at 1 when the run id changes.
+pub fn run_id() -> u64 {
+    *RUN_ID
+}
+
+fn default_instance() -> String {
+    dotenvy::var("CFVHUB_INSTANCE")
+        .or_else(|_| dotenvy::var("HOSTNAME"))
+        .unwrap_or_else(|_| "cfvhub".to_string())
+}
+
+/// Wraps the messages of a sink, e.g. in toml `[sinks.envelope]` `instance = "hub-a"`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EnvelopeConfig {
+    /// hub part of the producer id, `CFVHUB_INSTANCE` or `HOSTNAME` when unset
+    #[serde(default = "default_instance")]
+    pub instance: String,
+}
+
+impl Default for EnvelopeConfig {
+    fn default() -> Self {
+        Self {
+            instance: default_instance(),
+        }
+    }
+}
+
+impl EnvelopeConfig {
+    /// `{instance}/{sink id}`, the seq of a destination increases per producer.
+    pub fn producer(&self, id: &str) -> String {
+        format!("{}/{}", self.instance, id)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Envelope<'a, T> {
+    pub seq: u64,
+    pub producer: &'a str,
+    pub run: u64,
+    pub source: i32,
+    pub event_type: EventType,
+    pub tag: i64,
+    pub produced_at: u64,
+    pub schema_version: u32,
+    pub data: &'a T,
+}
+
+/// Formats `Envelope { .., data }` with the inner formater. Messages without
+/// `Dest::get_meta` get the default meta.
+#[derive(Debug)]
+pub struct EnvelopeFormater<F> {
+    inner: F,
+    producer: String,
+    run: u64,
+}
+
+impl<F> EnvelopeFormater<F> {
+    pub fn new(inner: F, producer: &str) -> Self {
+        Self {
+            inner,
+            producer: producer.to_string(),
+            run: run_id(),
+        }
+    }
+
+    fn envelope<'a, In: Dest>(&'a self, input: &'a In) -> Envelope<'a, In> {
+        let meta = input.get_meta().copied().unwrap_or_default();
+        Envelope {
+            seq: meta.seq,
+            producer: &self.producer,
+            run: self.run,
+            source: meta.source,
+            event_type: meta.event_type,
+            tag: meta.tag,
+            produced_at: meta.produced_at,
+            schema_version: SCHEMA_VERSION,
+            data: input,
+        }
+    }
+}
+
+impl<In, F> FormaterExt<In> for EnvelopeFormater<F>
+where
+    In: Serialize + Dest,
+    F: for<'a> FormaterExt<Envelope<'a, In>>,
+{
+    fn format(&self, input: &In) -> Result<Formated, FormatError> {
+        self.inner.format(&self.envelope(input))
+    }
+
+    fn content_type(&self) -> &str {
+        self.inner.content_type()
+    }
+
+    fn format_into(&self, input: &In, buf: &mut Vec<u8>) -> Result<Encoding, FormatError> {
+        self.inner.format_into(&self.envelope(input), buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formater::{JsonFormater, MessagePackFormater, TomlFormater, YamlFormater};
+    use crate::test_util::{msg, Msg};
+
+    fn text(formated: Formated) -> String {
+        match formated {
+            Formated::String(s) => s,
+            Formated::Bytes(b) => {
+                let value: serde_json::Value = rmp_serde::from_slice(&b).unwrap();
+                value.to_string()
+            }
+        }
+    }
+
+    #[test]
+    fn test_envelope_formater() {
+        let mut seqs = DestSeq::default();
+        let mut meta = Meta {
+            source: 533,
+            event_type: EventType::Update,
+            tag: 7,
+            ..Default::default()
+        };
+        assert_eq!(seqs.next("api/V1/TIC/Q/NVDA"), 1);
+        meta.seq = seqs.next("api/V1/TIC/Q/AAPL");
+        meta.seq = seqs.next("api/V1/TIC/Q/AAPL");
+        meta.produced_at = 1_700_000_000_000_000_000;
+        let msg = Msg {
+            meta: Some(meta),
+            ..msg("api/V1/TIC/Q/AAPL", 1.5)
+        };
+
+        let json = EnvelopeFormater::new(JsonFormater, "hub-a/0");
+        assert_eq!(
+            text(json.format(&msg).unwrap()),
+            format!(
+                r#"{{"seq":2,"producer":"hub-a/0","run":{},"source":533,"event_type":"UPDATE","tag":7,"produced_at":1700000000000000000,"schema_version":1,"data":{{"dest":"api/V1/TIC/Q/AAPL","close":1.5}}}}"#,
+                run_id()
+            )
+        );
+        assert_eq!(FormaterExt::<Msg>::content_type(&json), "json");
+
+        let msgpack = EnvelopeFormater::new(MessagePackFormater, "hub-a/0");
+        assert_eq!(
+            text(msgpack.format(&msg).unwrap()),
+            format!(
+                r#"[2,"hub-a/0",{},533,"UPDATE",7,1700000000000000000,1,["api/V1/TIC/Q/AAPL",1.5]]"#,
+                run_id()
+            )
+        );
+        let yaml = EnvelopeFormater::new(YamlFormater, "hub-a/0");
+        assert!(text(yaml.format(&msg).unwrap()).starts_with("seq: 2\nproducer: hub-a/0\n"));
+        let toml = EnvelopeFormater::new(TomlFormater, "hub-a/0");
+        assert!(text(toml.format(&msg).unwrap())
+            .contains("schema_version = 1\n\n[data]\ndest = \"api/V1/TIC/Q/AAPL\"\nclose = 1.5"));
+    }
+}
@@ -1,7 +1,10 @@
 use serde::Serialize;
 use snafu::{prelude::Snafu, ResultExt};
 
+use super::proto::{ProtoError, ProtoMessage};
+
 #[derive(Debug, Snafu)]
+#[snafu(visibility(pub))]
 pub enum FormatError {
     #[snafu(display("Error Eecode Json: {}", source))]
     Json { source: serde_json::Error },
@@ -11,6 +14,12 @@ pub enum FormatError {
     Toml{ source: toml::ser::Error },
     #[snafu(display("Error Eecode MessagePack: {}", source))]
     MessagePack{ source: rmp_serde::encode::Error },
+    #[snafu(display("Error Eecode Protobuf: {}", source))]
+    Protobuf{ source: ProtoError },
+    #[snafu(display("Error Batch: {} can not be batched as {}", content_type, layout))]
+    Batch{ content_type: String, layout: &'static str },
+    #[snafu(display("Error Compress: {}", source))]
+    Compress{ source: std::io::Error },
 }
 
 pub trait FormaterExt<In> 
@@ -19,6 +28,37 @@ where
 {
     fn format(&self, input: &In) -> Result<Formated, FormatError>;
     fn content_type(&self) -> &str;
+
+    /// Appends the formated input to `buf`, so a sink can keep one buffer for all its
+    /// messages. The content of `buf` is unspecified after an error.
+    fn format_into(&self, input: &In, buf: &mut Vec<u8>) -> Result<Encoding, FormatError> {
+        match self.format(input)? {
+            Formated::String(s) => {
+                buf.extend_from_slice(s.as_bytes());
+                Ok(Encoding::Text)
+            }
+            Formated::Bytes(b) => {
+                buf.extend_from_slice(&b);
+                Ok(Encoding::Binary)
+            }
+        }
+    }
+}
+
+impl<In, F> FormaterExt<In> for &F
+where
+    In: Serialize,
+    F: FormaterExt<In> + ?Sized,
+{
+    fn format(&self, input: &In) -> Result<Formated, FormatError> {
+        (**self).format(input)
+    }
+    fn content_type(&self) -> &str {
+        (**self).content_type()
+    }
+    fn format_into(&self, input: &In, buf: &mut Vec<u8>) -> Result<Encoding, FormatError> {
+        (**self).format_into(input, buf)
+    }
 }
 
 pub enum Formated {
@@ -26,6 +66,25 @@ pub enum Formated {
     Bytes(Vec<u8>),
 }
 
+impl Formated {
+    /// What `format_into` wrote as the `format` result.
+    pub fn from_encoded(buf: Vec<u8>, encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Text => String::from_utf8(buf)
+                .map(Formated::String)
+                .unwrap_or_else(|e| Formated::Bytes(e.into_bytes())),
+            Encoding::Binary => Formated::Bytes(buf),
+        }
+    }
+}
+
+/// What `format_into` wrote, utf8 text like `Formated::String` or bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Text,
+    Binary,
+}
+
 #[derive(Debug, Default)]
 pub struct JsonFormater;
 
@@ -39,6 +98,10 @@ where
     fn content_type(&self) -> &str {
         "json"
     }
+    fn format_into(&self, input: &I, buf: &mut Vec<u8>) -> Result<Encoding, FormatError> {
+        serde_json::to_writer(buf, input).context(JsonSnafu)?;
+        Ok(Encoding::Text)
+    }
 }
 
 
@@ -55,6 +118,10 @@ where
     fn content_type(&self) -> &str {
         "yaml"
     }
+    fn format_into(&self, input: &I, buf: &mut Vec<u8>) -> Result<Encoding, FormatError> {
+        serde_yaml::to_writer(buf, input).context(YamlSnafu)?;
+        Ok(Encoding::Text)
+    }
 }
 
 #[derive(Debug, Default)]
@@ -70,6 +137,20 @@ where
     fn content_type(&self) -> &str {
         "toml"
     }
+    fn format_into(&self, input: &I, buf: &mut Vec<u8>) -> Result<Encoding, FormatError> {
+        // toml only writes to a String, lend it the buffer when that holds text
+        let mut text = match String::from_utf8(std::mem::take(buf)) {
+            Ok(text) => text,
+            Err(e) => {
+                *buf = e.into_bytes();
+                buf.extend_from_slice(toml::to_string(input).context(TomlSnafu)?.as_bytes());
+                return Ok(Encoding::Text);
+            }
+        };
+        let r = input.serialize(toml::Serializer::new(&mut text)).context(TomlSnafu);
+        *buf = text.into_bytes();
+        r.map(|_| Encoding::Text)
+    }
 }
 
 #[derive(Debug, Default)]
@@ -85,4 +166,69 @@ where
     fn content_type(&self) -> &str {
         "msgpack"
     }
+    fn format_into(&self, input: &I, buf: &mut Vec<u8>) -> Result<Encoding, FormatError> {
+        rmp_serde::encode::write(buf, input).context(MessagePackSnafu)?;
+        Ok(Encoding::Binary)
+    }
+}
+
+/// Encodes the input as its message in `proto/`, see `ProtoMessage`.
+#[derive(Debug, Default)]
+pub struct ProtobufFormater;
+
+impl<I> FormaterExt<I> for ProtobufFormater
+where
+    I: Serialize + ProtoMessage,
+{
+    fn format(&self,input: &I) -> Result<Formated, FormatError> {
+        let mut buf = vec![];
+        input.encode_proto(&mut buf).context(ProtobufSnafu)?;
+        Ok(Formated::Bytes(buf))
+    }
+    fn content_type(&self) -> &str {
+        "protobuf"
+    }
+    fn format_into(&self, input: &I, buf: &mut Vec<u8>) -> Result<Encoding, FormatError> {
+        input.encode_proto(buf).context(ProtobufSnafu)?;
+        Ok(Encoding::Binary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{msg, Msg};
+
+    fn check<F: FormaterExt<Msg>>(formater: &F, msg: &Msg, encoding: Encoding) {
+        let expected = match formater.format(msg).unwrap() {
+            Formated::String(s) => s.into_bytes(),
+            Formated::Bytes(b) => b,
+        };
+        let mut buf = b"prefix".to_vec();
+        assert_eq!(formater.format_into(msg, &mut buf).unwrap(), encoding);
+        assert_eq!(buf[..6], *b"prefix");
+        assert_eq!(buf[6..], expected);
+    }
+
+    #[test]
+    fn test_format_into_same_as_format() {
+        let msg = msg("AAPL", 1.5);
+        check(&JsonFormater, &msg, Encoding::Text);
+        check(&YamlFormater, &msg, Encoding::Text);
+        check(&TomlFormater, &msg, Encoding::Text);
+        check(&MessagePackFormater, &msg, Encoding::Binary);
+
+        // the buffer keeps its allocation between messages
+        let mut buf = Vec::with_capacity(256);
+        let ptr = buf.as_ptr();
+        for _ in 0..3 {
+            buf.clear();
+            JsonFormater.format_into(&msg, &mut buf).unwrap();
+        }
+        assert_eq!(buf.as_ptr(), ptr);
+        // a buffer that is not text can not be lent to toml
+        let mut buf = vec![0xff];
+        TomlFormater.format_into(&msg, &mut buf).unwrap();
+        assert_eq!(buf[1..], *b"dest = \"AAPL\"\nclose = 1.5\n");
+    }
 }
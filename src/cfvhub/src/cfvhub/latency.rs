@@ -0,0 +1,311 @@
+use hdrhistogram::Histogram;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+use super::envelope::Meta;
+use super::metrics::METRICS;
+use super::queue::{QueueError, SpillCodec};
+use super::sink::Dest;
+
+pub static LATENCY: Lazy<LatencyRecorder> = Lazy::new(LatencyRecorder::new);
+
+/// Feed timestamp of a message in epoch seconds, 0 when unknown.
+pub trait SourceTs {
+    fn source_ts(&self) -> f64;
+}
+
+pub fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// Wall clock points a message passed through, in epoch nanoseconds.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stamps {
+    pub source_ns: u64,
+    pub callback_ns: u64,
+    pub convert_ns: u64,
+    pub dequeue_ns: u64,
+}
+
+/// A queued message with its stamps.
+#[derive(Debug)]
+pub struct Stamped<T> {
+    pub data: T,
+    pub stamps: Stamps,
+}
+
+impl<T: Dest> Dest for Stamped<T> {
+    fn get_dest(&self) -> &str {
+        self.data.get_dest()
+    }
+
+    fn get_partition_key(&self) -> &str {
+        self.data.get_partition_key()
+    }
+
+    fn get_meta(&self) -> Option<&Meta> {
+        self.data.get_meta()
+    }
+
+    fn meta_mut(&mut self) -> Option<&mut Meta> {
+        self.data.meta_mut()
+    }
+}
+
+impl<T: SpillCodec> SpillCodec for Stamped<T> {
+    fn encode(&self) -> Result<Vec<u8>, QueueError> {
+        let data = self.data.encode()?;
+        let mut buf = Vec::with_capacity(24 + data.len());
+        buf.extend_from_slice(&self.stamps.source_ns.to_le_bytes());
+        buf.extend_from_slice(&self.stamps.callback_ns.to_le_bytes());
+        buf.extend_from_slice(&self.stamps.convert_ns.to_le_bytes());
+        buf.extend_from_slice(&data);
+        Ok(buf)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, QueueError> {
+        let stamp = |i: usize| {
+            bytes
+                .get(i * 8..i * 8 + 8)
+                .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+                .unwrap_or(0)
+        };
+        Ok(Self {
+            data: T::decode(bytes.get(24..).unwrap_or_default())?,
+            stamps: Stamps {
+                source_ns: stamp(0),
+                callback_ns: stamp(1),
+                convert_ns: stamp(2),
+                dequeue_ns: 0,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// feed timestamp to CFAPI callback
+    Feed,
+    /// callback to convertor output
+    Convert,
+    /// convertor output to sink worker dequeue
+    Queue,
+    /// dequeue to sink send completion
+    Sink,
+    /// feed timestamp to sink send completion
+    Total,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 5] = [
+        Stage::Feed,
+        Stage::Convert,
+        Stage::Queue,
+        Stage::Sink,
+        Stage::Total,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Feed => "feed",
+            Stage::Convert => "convert",
+            Stage::Queue => "queue",
+            Stage::Sink => "sink",
+            Stage::Total => "total",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StageSummary {
+    pub stage: Stage,
+    pub count: u64,
+    pub p50_us: u64,
+    pub p99_us: u64,
+    pub p999_us: u64,
+    pub max_us: u64,
+}
+
+type StageHistograms = Arc<Mutex<Vec<Histogram<u64>>>>;
+
+fn stage_histograms() -> Vec<Histogram<u64>> {
+    // up to one hour at 3 significant figures
+    Stage::ALL
+        .iter()
+        .map(|_| Histogram::new_with_max(3_600_000_000, 3).unwrap())
+        .collect()
+}
+
+/// Per stage microsecond histograms, one set per sink worker so the workers never wait on
+/// each other. `summary` merges and resets them.
+pub struct LatencyRecorder {
+    workers: Mutex<Vec<StageHistograms>>,
+}
+
+/// The histograms of one sink worker, only contended while a summary reads them.
+pub struct WorkerLatency {
+    histograms: StageHistograms,
+}
+
+impl WorkerLatency {
+    pub fn record(&self, stamps: &Stamps, done_ns: u64) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let mut record = |stage: Stage, from: u64, to: u64| {
+            if from != 0 && to != 0 {
+                // a feed clock ahead of ours is recorded as 0
+                histograms[stage as usize].saturating_record(to.saturating_sub(from) / 1000);
+            }
+        };
+        record(Stage::Feed, stamps.source_ns, stamps.callback_ns);
+        record(Stage::Convert, stamps.callback_ns, stamps.convert_ns);
+        record(Stage::Queue, stamps.convert_ns, stamps.dequeue_ns);
+        record(Stage::Sink, stamps.dequeue_ns, done_ns);
+        record(Stage::Total, stamps.source_ns, done_ns);
+    }
+}
+
+impl LatencyRecorder {
+    pub fn new() -> Self {
+        Self {
+            workers: Mutex::new(vec![]),
+        }
+    }
+
+    /// Histograms for a new sink worker to record into.
+    pub fn worker(&self) -> WorkerLatency {
+        let histograms = Arc::new(Mutex::new(stage_histograms()));
+        self.workers.lock().unwrap().push(histograms.clone());
+        WorkerLatency { histograms }
+    }
+
+    /// Quantiles since the last summary, then start a new window.
+    pub fn summary(&self) -> Vec<StageSummary> {
+        let mut merged = stage_histograms();
+        let mut workers = self.workers.lock().unwrap();
+        for worker in workers.iter() {
+            let mut histograms = worker.lock().unwrap();
+            for (merged, h) in merged.iter_mut().zip(histograms.iter_mut()) {
+                // same bounds, so adding can not fail
+                merged.add(&*h).unwrap();
+                h.reset();
+            }
+        }
+        // the worker is gone
+        workers.retain(|worker| Arc::strong_count(worker) > 1);
+        Stage::ALL
+            .iter()
+            .map(|stage| {
+                let h = &merged[*stage as usize];
+                StageSummary {
+                    stage: *stage,
+                    count: h.len(),
+                    p50_us: h.value_at_quantile(0.5),
+                    p99_us: h.value_at_quantile(0.99),
+                    p999_us: h.value_at_quantile(0.999),
+                    max_us: h.max(),
+                }
+            })
+            .collect()
+    }
+}
+
+impl Default for LatencyRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Log the per stage quantiles every `interval` and publish them as metrics.
+pub fn spawn_summary(interval: Duration) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        for s in LATENCY.summary() {
+            if s.count == 0 {
+                continue;
+            }
+            info!(
+                "latency {}: count: {}, p50: {}us, p99: {}us, p999: {}us, max: {}us",
+                s.stage.name(),
+                s.count,
+                s.p50_us,
+                s.p99_us,
+                s.p999_us,
+                s.max_us
+            );
+            METRICS.set_latency(&s);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_summary() {
+        let recorder = LatencyRecorder::new();
+        let workers = [recorder.worker(), recorder.worker()];
+        for i in 1..=1000u64 {
+            let stamps = Stamps {
+                source_ns: 1_000_000_000,
+                callback_ns: 1_000_000_000 + i * 1000,
+                convert_ns: 1_000_000_000 + i * 1000 + 5_000,
+                dequeue_ns: 0,
+            };
+            workers[i as usize % 2].record(&stamps, 1_000_000_000 + i * 1000 + 10_000);
+        }
+        let summary = recorder.summary();
+        let feed = &summary[Stage::Feed as usize];
+        assert_eq!(feed.count, 1000);
+        assert_eq!(feed.p50_us, 500);
+        assert_eq!(feed.p99_us, 990);
+        assert_eq!(feed.max_us, 1000);
+        assert_eq!(summary[Stage::Convert as usize].p999_us, 5);
+        // dequeue was never stamped
+        assert_eq!(summary[Stage::Queue as usize].count, 0);
+        assert_eq!(summary[Stage::Sink as usize].count, 0);
+        assert_eq!(recorder.summary()[Stage::Feed as usize].count, 0);
+        drop(workers);
+        recorder.summary();
+        assert!(recorder.workers.lock().unwrap().is_empty());
+    }
+
+    impl SpillCodec for String {
+        fn encode(&self) -> Result<Vec<u8>, QueueError> {
+            Ok(self.as_bytes().to_vec())
+        }
+
+        fn decode(bytes: &[u8]) -> Result<Self, QueueError> {
+            Ok(String::from_utf8_lossy(bytes).to_string())
+        }
+    }
+
+    #[test]
+    fn test_stamped_spill_codec() {
+        let stamped = Stamped {
+            data: "abc".to_string(),
+            stamps: Stamps {
+                source_ns: 1,
+                callback_ns: 2,
+                convert_ns: 3,
+                dequeue_ns: 4,
+            },
+        };
+        let decoded = Stamped::<String>::decode(&stamped.encode().unwrap()).unwrap();
+        assert_eq!(decoded.data, "abc");
+        assert_eq!(
+            decoded.stamps,
+            Stamps {
+                dequeue_ns: 0,
+                ..stamped.stamps
+            }
+        );
+    }
+}
@@ -0,0 +1,439 @@
+use cfapi::binding::{SessionEvent, SessionEvent_Types, StatisticsEvent};
+use cfapi::session_event::{DefaultSessionEventHandler, SessionEventHandlerExt};
+use cfapi::stat_event::{StatisticsData, StatisticsEventHandlerExt};
+use once_cell::sync::Lazy;
+use prometheus::{IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use tracing::{error, info};
+
+use super::latency::StageSummary;
+use super::queue::QueueStatsSnapshot;
+
+pub static METRICS: Lazy<HubMetrics> = Lazy::new(HubMetrics::new);
+
+type Refresher = Box<dyn Fn(&HubMetrics) + Send + Sync>;
+
+pub struct HubMetrics {
+    registry: Registry,
+    pub cfapi_statistics: IntGaugeVec,
+    pub session_state: IntGaugeVec,
+    pub receive_queue_depth: IntGauge,
+    pub source_available: IntGaugeVec,
+    pub queue_depth: IntGaugeVec,
+    pub queue_messages: IntCounterVec,
+    pub convertor_messages: IntCounterVec,
+    pub sink_messages: IntCounterVec,
+    pub solace_state: IntGaugeVec,
+    pub stale_symbols: IntGaugeVec,
+    pub stale_alerts: IntCounterVec,
+    pub latency: IntGaugeVec,
+    pub latency_samples: IntCounterVec,
+    // pulled values, like queue depth, are updated right before each scrape
+    refreshers: Mutex<Vec<Refresher>>,
+}
+
+fn gauge_vec(name: &str, help: &str, labels: &[&str]) -> IntGaugeVec {
+    IntGaugeVec::new(Opts::new(name, help), labels).unwrap()
+}
+
+fn counter_vec(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    IntCounterVec::new(Opts::new(name, help), labels).unwrap()
+}
+
+impl HubMetrics {
+    pub fn new() -> Self {
+        let metrics = Self {
+            registry: Registry::new(),
+            cfapi_statistics: gauge_vec(
+                "cfapi_statistics",
+                "latest CFAPI statistics event by stat",
+                &["stat"],
+            ),
+            session_state: gauge_vec(
+                "cfapi_session_state",
+                "1 for the current CFAPI session state",
+                &["state"],
+            ),
+            receive_queue_depth: IntGauge::new(
+                "cfapi_receive_queue_depth",
+                "receive queue depth of the last threshold event",
+            )
+            .unwrap(),
+            source_available: gauge_vec(
+                "cfapi_source_available",
+                "1 when the source is available",
+                &["source"],
+            ),
+            queue_depth: gauge_vec(
+                "cfvhub_queue_depth",
+                "messages waiting in the pipe queue",
+                &["handler", "partition"],
+            ),
+            queue_messages: counter_vec(
+                "cfvhub_queue_messages_total",
+                "pipe queue messages by outcome",
+                &["handler", "outcome"],
+            ),
+            convertor_messages: counter_vec(
+                "cfvhub_convertor_messages_total",
+                "message events emitted or skipped by the convertor",
+                &["source", "outcome"],
+            ),
+            sink_messages: counter_vec(
+                "cfvhub_sink_messages_total",
+                "messages sent by the sinks",
+                &["sink", "id", "outcome"],
+            ),
+            solace_state: gauge_vec(
+                "cfvhub_solace_session_state",
+                "1 for the current session state of the solace sink",
+                &["id", "state"],
+            ),
+            stale_symbols: gauge_vec(
+                "cfvhub_stale_symbols",
+                "trading symbols without updates for the watchdog stale_secs",
+                &["source"],
+            ),
+            stale_alerts: counter_vec(
+                "cfvhub_stale_alerts_total",
+                "stale data alerts of the watchdog by symbol or whole source",
+                &["source", "scope"],
+            ),
+            latency: gauge_vec(
+                "cfvhub_latency_microseconds",
+                "latency quantiles of the last summary window by stage",
+                &["stage", "quantile"],
+            ),
+            latency_samples: counter_vec(
+                "cfvhub_latency_samples_total",
+                "messages measured by stage",
+                &["stage"],
+            ),
+            refreshers: Mutex::new(vec![]),
+        };
+        metrics
+            .registry
+            .register(Box::new(metrics.cfapi_statistics.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.session_state.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.receive_queue_depth.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.source_available.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.queue_depth.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.queue_messages.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.convertor_messages.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.sink_messages.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.solace_state.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.stale_symbols.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.stale_alerts.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.latency.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.latency_samples.clone()))
+            .unwrap();
+        metrics
+    }
+
+    pub fn add_refresher(&self, refresher: impl Fn(&HubMetrics) + Send + Sync + 'static) {
+        self.refreshers.lock().unwrap().push(Box::new(refresher));
+    }
+
+    /// Prometheus text exposition of every metric.
+    pub fn render(&self) -> String {
+        for refresher in self.refreshers.lock().unwrap().iter() {
+            refresher(self);
+        }
+        let mut out = String::new();
+        if let Err(e) = TextEncoder::new().encode_utf8(&self.registry.gather(), &mut out) {
+            error!("metrics encode error: {}", e);
+        }
+        out
+    }
+
+    pub fn set_queue_stats(&self, handler: &str, stats: &QueueStatsSnapshot) {
+        for (outcome, value) in [
+            ("pushed", stats.pushed),
+            ("blocked", stats.blocked),
+            ("dropped", stats.dropped),
+            ("conflated", stats.conflated),
+            ("spilled", stats.spilled),
+        ] {
+            set_counter(
+                &self.queue_messages.with_label_values(&[handler, outcome]),
+                value,
+            );
+        }
+    }
+
+    pub fn set_latency(&self, summary: &StageSummary) {
+        let stage = summary.stage.name();
+        for (quantile, value) in [
+            ("0.5", summary.p50_us),
+            ("0.99", summary.p99_us),
+            ("0.999", summary.p999_us),
+            ("1", summary.max_us),
+        ] {
+            self.latency
+                .with_label_values(&[stage, quantile])
+                .set(value as i64);
+        }
+        self.latency_samples
+            .with_label_values(&[stage])
+            .inc_by(summary.count);
+    }
+
+    /// Message counters of worker `id` of a sink, kept by the sink.
+    pub fn sink_counters(&self, sink: &str, id: &str) -> SinkCounters {
+        SinkCounters {
+            ok: self.sink_messages.with_label_values(&[sink, id, "ok"]),
+            error: self.sink_messages.with_label_values(&[sink, id, "error"]),
+            sink: sink.to_string(),
+            id: id.to_string(),
+            messages: self.sink_messages.clone(),
+            outcomes: HashMap::default(),
+        }
+    }
+
+    pub fn set_solace_state(&self, id: &str, state: &str) {
+        for s in SOLACE_STATES {
+            self.solace_state
+                .with_label_values(&[id, s])
+                .set((s == state) as i64);
+        }
+    }
+}
+
+impl Default for HubMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// the counter follows a cumulative value kept elsewhere
+fn set_counter(counter: &IntCounter, value: u64) {
+    counter.inc_by(value.saturating_sub(counter.get()));
+}
+
+/// Per source emit and skip counters, cached so the hot path does not format labels.
+#[derive(Default)]
+pub struct ConvertorCounters {
+    counters: HashMap<i32, (IntCounter, IntCounter), ahash::RandomState>,
+}
+
+impl ConvertorCounters {
+    pub fn record(&mut self, source: i32, emitted: bool) {
+        let (emit, skip) = self.counters.entry(source).or_insert_with(|| {
+            let source = source.to_string();
+            (
+                METRICS
+                    .convertor_messages
+                    .with_label_values(&[&source, "emit"]),
+                METRICS
+                    .convertor_messages
+                    .with_label_values(&[&source, "skip"]),
+            )
+        });
+        if emitted {
+            emit.inc();
+        } else {
+            skip.inc();
+        }
+    }
+}
+
+/// Per outcome sink counters, cached like `ConvertorCounters`.
+pub struct SinkCounters {
+    ok: IntCounter,
+    error: IntCounter,
+    sink: String,
+    id: String,
+    messages: IntCounterVec,
+    outcomes: HashMap<&'static str, IntCounter, ahash::RandomState>,
+}
+
+impl SinkCounters {
+    pub fn result(&self, ok: bool) {
+        match ok {
+            true => self.ok.inc(),
+            false => self.error.inc(),
+        }
+    }
+
+    /// Count a message outcome such as retry, dead_letter, lost or dropped.
+    pub fn outcome(&mut self, outcome: &'static str) {
+        self.outcomes
+            .entry(outcome)
+            .or_insert_with(|| {
+                self.messages
+                    .with_label_values(&[&self.sink, &self.id, outcome])
+            })
+            .inc();
+    }
+}
+
+/// Logs like the default handler and keeps the latest stats as gauges.
+pub struct MetricsStatisticsEventHandler;
+
+impl StatisticsEventHandlerExt for MetricsStatisticsEventHandler {
+    fn on_statistics_event(&mut self, event: &StatisticsEvent) {
+        let data: StatisticsData = event.into();
+        info!("statistics event: {:?}", data);
+        if let Ok(serde_json::Value::Object(stats)) = serde_json::to_value(&data) {
+            for (stat, value) in stats {
+                if let Some(value) = value.as_i64() {
+                    METRICS
+                        .cfapi_statistics
+                        .with_label_values(&[&stat])
+                        .set(value);
+                }
+            }
+        }
+    }
+}
+
+/// Logs like the default handler and tracks session state and source availability.
+#[derive(Default)]
+pub struct MetricsSessionEventHandler {
+    sources: BTreeSet<i32>,
+}
+
+const SESSION_STATES: [&str; 3] = ["unavailable", "established", "recovery"];
+const SOLACE_STATES: [&str; 4] = ["connecting", "up", "reconnecting", "down"];
+
+impl MetricsSessionEventHandler {
+    fn set_state(&self, state: &str) {
+        for s in SESSION_STATES {
+            METRICS
+                .session_state
+                .with_label_values(&[s])
+                .set((s == state) as i64);
+        }
+    }
+
+    fn set_source(&mut self, source: i32, available: bool) {
+        self.sources.insert(source);
+        METRICS
+            .source_available
+            .with_label_values(&[&source.to_string()])
+            .set(available as i64);
+    }
+}
+
+impl SessionEventHandlerExt for MetricsSessionEventHandler {
+    fn on_session_event(&mut self, event: &SessionEvent) {
+        DefaultSessionEventHandler.on_session_event(event);
+        match event.getType() {
+            SessionEvent_Types::CFAPI_SESSION_UNAVAILABLE => {
+                self.set_state("unavailable");
+                for source in self.sources.clone() {
+                    self.set_source(source, false);
+                }
+            }
+            SessionEvent_Types::CFAPI_SESSION_ESTABLISHED => self.set_state("established"),
+            SessionEvent_Types::CFAPI_SESSION_RECOVERY => self.set_state("recovery"),
+            SessionEvent_Types::CFAPI_SESSION_RECOVERY_SOURCES
+            | SessionEvent_Types::CFAPI_SESSION_SOURCE_REMOVED => {
+                self.set_source(i32::from(event.getSourceID()), false);
+            }
+            SessionEvent_Types::CFAPI_SESSION_AVAILABLE_ALLSOURCES
+            | SessionEvent_Types::CFAPI_SESSION_AVAILABLE_SOURCES
+            | SessionEvent_Types::CFAPI_SESSION_SOURCE_ADDED => {
+                self.set_source(i32::from(event.getSourceID()), true);
+            }
+            SessionEvent_Types::CFAPI_SESSION_RECEIVE_QUEUE_ABOVE_THRESHOLD
+            | SessionEvent_Types::CFAPI_SESSION_RECEIVE_QUEUE_BELOW_THRESHOLD => {
+                METRICS
+                    .receive_queue_depth
+                    .set(i32::from(event.getQueueDepth()) as i64);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Serve `METRICS` as Prometheus text on `addr`, any path, in a background thread.
+pub fn serve(addr: &str) -> Result<std::thread::JoinHandle<()>, std::io::Error> {
+    let server = tiny_http::Server::http(addr).map_err(std::io::Error::other)?;
+    info!("metrics listening on {}", addr);
+    let content_type =
+        tiny_http::Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap();
+    Ok(std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = tiny_http::Response::from_string(METRICS.render())
+                .with_header(content_type.clone());
+            if let Err(e) = request.respond(response) {
+                error!("metrics respond error: {}", e);
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_queue_and_sink() {
+        let metrics = HubMetrics::new();
+        let stats = QueueStatsSnapshot {
+            pushed: 10,
+            dropped: 2,
+            ..Default::default()
+        };
+        metrics.set_queue_stats("nasdaq", &stats);
+        metrics.set_queue_stats("nasdaq", &stats);
+        metrics.add_refresher(|m| m.queue_depth.with_label_values(&["nasdaq", "0"]).set(3));
+        let mut counters = metrics.sink_counters("solace", "0");
+        counters.result(true);
+        counters.result(false);
+        counters.outcome("retry");
+        counters.outcome("retry");
+        let text = metrics.render();
+        assert!(
+            text.contains(r#"cfvhub_queue_messages_total{handler="nasdaq",outcome="pushed"} 10"#)
+        );
+        assert!(
+            text.contains(r#"cfvhub_queue_messages_total{handler="nasdaq",outcome="dropped"} 2"#)
+        );
+        assert!(text.contains(r#"cfvhub_queue_depth{handler="nasdaq",partition="0"} 3"#));
+        assert!(
+            text.contains(r#"cfvhub_sink_messages_total{id="0",outcome="error",sink="solace"} 1"#)
+        );
+        assert!(
+            text.contains(r#"cfvhub_sink_messages_total{id="0",outcome="retry",sink="solace"} 2"#)
+        );
+    }
+}
@@ -0,0 +1,480 @@
+use cfapi::binding::{MessageEvent, MessageEvent_Types};
+use cfapi::value::CFValue;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use snafu::{prelude::Snafu, ResultExt};
+use std::collections::{BTreeMap, HashSet};
+use tracing::warn;
+
+use super::queue::RateLimiter;
+
+#[derive(Debug, Snafu)]
+pub enum MiddlewareError {
+    #[snafu(display("Middleware Regex Error: {}", source))]
+    Regex { source: regex::Error },
+    #[snafu(display("Middleware {} Error: the output fields are fixed", stage))]
+    FixedFields { stage: String },
+}
+
+/// What the stages know about the event an item was converted from.
+#[derive(Debug, Clone)]
+pub struct EventContext {
+    pub source: i32,
+    pub symbol: String,
+    pub event_type: &'static str,
+}
+
+impl EventContext {
+    pub fn from_event(event: &MessageEvent) -> Self {
+        let event_type = match event.getType() {
+            MessageEvent_Types::IMAGE_COMPLETE => "IMAGE_COMPLETE",
+            MessageEvent_Types::IMAGE_PART => "IMAGE_PART",
+            MessageEvent_Types::REFRESH => "REFRESH",
+            MessageEvent_Types::STATUS => "STATUS",
+            MessageEvent_Types::UPDATE => "UPDATE",
+        };
+        Self {
+            source: i32::from(event.getSource()),
+            symbol: event.getSymbol().to_string(),
+            event_type,
+        }
+    }
+}
+
+/// Named field access on convertor output, used by the transform stages.
+pub trait FieldAccess {
+    /// Whether an item can gain and lose fields, `rename` and `enrich` are rejected at
+    /// build when it can not.
+    const OPEN_FIELDS: bool = false;
+
+    fn get_field(&self, name: &str) -> Option<CFValue>;
+    /// Returns false when the item has no such field and can not gain one, or when the
+    /// value does not fit the type of the field, which then keeps its value.
+    fn set_field(&mut self, name: &str, value: CFValue) -> bool;
+    fn remove_field(&mut self, _name: &str) -> Option<CFValue> {
+        None
+    }
+}
+
+impl FieldAccess for BTreeMap<String, CFValue> {
+    const OPEN_FIELDS: bool = true;
+
+    fn get_field(&self, name: &str) -> Option<CFValue> {
+        self.get(name).cloned()
+    }
+
+    fn set_field(&mut self, name: &str, value: CFValue) -> bool {
+        self.insert(name.to_string(), value);
+        true
+    }
+
+    fn remove_field(&mut self, name: &str) -> Option<CFValue> {
+        self.remove(name)
+    }
+}
+
+pub trait Middleware<T>: Send + Sync {
+    fn name(&self) -> &str;
+    /// Push zero, one or more items to `out`.
+    fn process(&self, ctx: &EventContext, item: T, out: &mut Vec<T>);
+}
+
+/// Stages run in order between the convertor and the sink.
+pub struct MiddlewareChain<T> {
+    stages: Vec<Box<dyn Middleware<T>>>,
+    scratch: Vec<T>,
+}
+
+impl<T> Default for MiddlewareChain<T> {
+    fn default() -> Self {
+        Self {
+            stages: vec![],
+            scratch: vec![],
+        }
+    }
+}
+
+impl<T> MiddlewareChain<T> {
+    pub fn with_stage(mut self, stage: Box<dyn Middleware<T>>) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    pub fn apply(&mut self, ctx: &EventContext, item: T, out: &mut Vec<T>) {
+        out.push(item);
+        for stage in self.stages.iter() {
+            std::mem::swap(out, &mut self.scratch);
+            for item in self.scratch.drain(..) {
+                stage.process(ctx, item, out);
+            }
+            if out.is_empty() {
+                break;
+            }
+        }
+    }
+}
+
+/// Stage settings as written in the pipeline config, e.g. in toml
+/// `[[middleware]]` `type = "symbol_allow"` `symbols = ["AAPL", "NVDA"]`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MiddlewareConfig {
+    SymbolAllow {
+        symbols: Vec<String>,
+    },
+    SymbolDeny {
+        symbols: Vec<String>,
+    },
+    SymbolRegex {
+        pattern: String,
+    },
+    /// IMAGE_PART, IMAGE_COMPLETE, UPDATE, REFRESH or STATUS
+    EventType {
+        types: Vec<String>,
+    },
+    /// output market_phase values, 0 pre market, 1 trading, 2 post market, 3 closed
+    MarketPhase {
+        phases: Vec<i64>,
+    },
+    /// needs an output that can gain and lose fields, e.g. a map
+    Rename {
+        from: String,
+        to: String,
+    },
+    Round {
+        fields: Vec<String>,
+        decimals: u32,
+    },
+    /// needs an output that can gain fields, e.g. a map
+    Enrich {
+        field: String,
+        value: CFValue,
+    },
+    /// one copy per entry with `_dest` set to it, `{dest}` is the original destination
+    FanOut {
+        dests: Vec<String>,
+    },
+}
+
+impl MiddlewareConfig {
+    pub fn build<T>(&self) -> Result<Box<dyn Middleware<T>>, MiddlewareError>
+    where
+        T: FieldAccess + Clone + Send + Sync + 'static,
+    {
+        let stage = match self {
+            MiddlewareConfig::Rename { .. } => Some("rename"),
+            MiddlewareConfig::Enrich { .. } => Some("enrich"),
+            _ => None,
+        };
+        if let (Some(stage), false) = (stage, T::OPEN_FIELDS) {
+            return FixedFieldsSnafu { stage }.fail();
+        }
+        Ok(match self {
+            MiddlewareConfig::SymbolAllow { symbols } => Box::new(SymbolFilter {
+                symbols: symbols.iter().cloned().collect(),
+                allow: true,
+            }),
+            MiddlewareConfig::SymbolDeny { symbols } => Box::new(SymbolFilter {
+                symbols: symbols.iter().cloned().collect(),
+                allow: false,
+            }),
+            MiddlewareConfig::SymbolRegex { pattern } => Box::new(SymbolRegex {
+                regex: Regex::new(pattern).context(RegexSnafu)?,
+            }),
+            MiddlewareConfig::EventType { types } => Box::new(EventTypeFilter {
+                types: types.clone(),
+            }),
+            MiddlewareConfig::MarketPhase { phases } => Box::new(MarketPhaseFilter {
+                phases: phases.clone(),
+            }),
+            MiddlewareConfig::Rename { from, to } => Box::new(Rename {
+                from: from.clone(),
+                to: to.clone(),
+                log_limit: RateLimiter::default(),
+            }),
+            MiddlewareConfig::Round { fields, decimals } => Box::new(Round {
+                fields: fields.clone(),
+                decimals: *decimals,
+                factor: 10f64.powi(*decimals as i32),
+            }),
+            MiddlewareConfig::Enrich { field, value } => Box::new(Enrich {
+                field: field.clone(),
+                value: value.clone(),
+                log_limit: RateLimiter::default(),
+            }),
+            MiddlewareConfig::FanOut { dests } => Box::new(FanOut {
+                dests: dests.clone(),
+            }),
+        })
+    }
+}
+
+pub fn build_chain<T>(configs: &[MiddlewareConfig]) -> Result<MiddlewareChain<T>, MiddlewareError>
+where
+    T: FieldAccess + Clone + Send + Sync + 'static,
+{
+    let mut chain = MiddlewareChain::default();
+    for config in configs {
+        chain = chain.with_stage(config.build()?);
+    }
+    Ok(chain)
+}
+
+pub struct SymbolFilter {
+    symbols: HashSet<String>,
+    allow: bool,
+}
+
+impl<T> Middleware<T> for SymbolFilter {
+    fn name(&self) -> &str {
+        "symbol_filter"
+    }
+
+    fn process(&self, ctx: &EventContext, item: T, out: &mut Vec<T>) {
+        if self.symbols.contains(&ctx.symbol) == self.allow {
+            out.push(item);
+        }
+    }
+}
+
+pub struct SymbolRegex {
+    regex: Regex,
+}
+
+impl<T> Middleware<T> for SymbolRegex {
+    fn name(&self) -> &str {
+        "symbol_regex"
+    }
+
+    fn process(&self, ctx: &EventContext, item: T, out: &mut Vec<T>) {
+        if self.regex.is_match(&ctx.symbol) {
+            out.push(item);
+        }
+    }
+}
+
+pub struct EventTypeFilter {
+    types: Vec<String>,
+}
+
+impl<T> Middleware<T> for EventTypeFilter {
+    fn name(&self) -> &str {
+        "event_type"
+    }
+
+    fn process(&self, ctx: &EventContext, item: T, out: &mut Vec<T>) {
+        if self.types.iter().any(|t| t == ctx.event_type) {
+            out.push(item);
+        }
+    }
+}
+
+pub struct MarketPhaseFilter {
+    phases: Vec<i64>,
+}
+
+impl<T: FieldAccess> Middleware<T> for MarketPhaseFilter {
+    fn name(&self) -> &str {
+        "market_phase"
+    }
+
+    fn process(&self, _ctx: &EventContext, item: T, out: &mut Vec<T>) {
+        if let Some(CFValue::Int(phase)) = item.get_field("market_phase") {
+            if self.phases.contains(&phase) {
+                out.push(item);
+            }
+        }
+    }
+}
+
+pub struct Rename {
+    from: String,
+    to: String,
+    log_limit: RateLimiter,
+}
+
+impl<T: FieldAccess> Middleware<T> for Rename {
+    fn name(&self) -> &str {
+        "rename"
+    }
+
+    fn process(&self, _ctx: &EventContext, mut item: T, out: &mut Vec<T>) {
+        if let Some(value) = item.remove_field(&self.from) {
+            if !item.set_field(&self.to, value) && self.log_limit.check() {
+                warn!(
+                    "rename {} to {} does not fit the output",
+                    self.from, self.to
+                );
+            }
+        }
+        out.push(item);
+    }
+}
+
+pub struct Round {
+    fields: Vec<String>,
+    decimals: u32,
+    factor: f64,
+}
+
+impl<T: FieldAccess> Middleware<T> for Round {
+    fn name(&self) -> &str {
+        "round"
+    }
+
+    fn process(&self, _ctx: &EventContext, mut item: T, out: &mut Vec<T>) {
+        for field in self.fields.iter() {
+            match item.get_field(field) {
+                Some(CFValue::Double(v)) => {
+                    let v = (v * self.factor).round() / self.factor;
+                    item.set_field(field, CFValue::Double(v));
+                }
+                // a decimal with fewer digits is already rounded
+                Some(CFValue::Decimal(v)) if self.decimals < v.scale() as u32 => {
+                    if let Some(v) = v.rescale(self.decimals as u8) {
+                        item.set_field(field, CFValue::Decimal(v));
+                    }
+                }
+                _ => {}
+            }
+        }
+        out.push(item);
+    }
+}
+
+pub struct Enrich {
+    field: String,
+    value: CFValue,
+    log_limit: RateLimiter,
+}
+
+impl<T: FieldAccess> Middleware<T> for Enrich {
+    fn name(&self) -> &str {
+        "enrich"
+    }
+
+    fn process(&self, _ctx: &EventContext, mut item: T, out: &mut Vec<T>) {
+        if !item.set_field(&self.field, self.value.clone()) && self.log_limit.check() {
+            warn!("enrich {} with {:?} does not fit the output", self.field, self.value);
+        }
+        out.push(item);
+    }
+}
+
+pub struct FanOut {
+    dests: Vec<String>,
+}
+
+impl<T: FieldAccess + Clone> Middleware<T> for FanOut {
+    fn name(&self) -> &str {
+        "fan_out"
+    }
+
+    fn process(&self, _ctx: &EventContext, item: T, out: &mut Vec<T>) {
+        let dest = match item.get_field("_dest") {
+            Some(CFValue::String(dest)) => dest,
+            _ => String::new(),
+        };
+        for template in self.dests.iter() {
+            let mut copy = item.clone();
+            copy.set_field("_dest", CFValue::String(template.replace("{dest}", &dest)));
+            out.push(copy);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfapi::decimal::{Decimal, DecimalRepr};
+
+    fn ctx(symbol: &str) -> EventContext {
+        EventContext {
+            source: 533,
+            symbol: symbol.to_string(),
+            event_type: "UPDATE",
+        }
+    }
+
+    fn run(
+        chain: &mut MiddlewareChain<BTreeMap<String, CFValue>>,
+        symbol: &str,
+    ) -> Vec<BTreeMap<String, CFValue>> {
+        let mut item = BTreeMap::new();
+        item.insert("_dest".to_string(), CFValue::String("api/AAPL".into()));
+        item.insert("price".to_string(), CFValue::Double(1.23456));
+        let mut out = vec![];
+        chain.apply(&ctx(symbol), item, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_chain_from_config() {
+        let config: Vec<MiddlewareConfig> = serde_json::from_str(
+            r#"[
+                {"type": "symbol_regex", "pattern": "^A"},
+                {"type": "symbol_deny", "symbols": ["AMD"]},
+                {"type": "event_type", "types": ["UPDATE"]},
+                {"type": "round", "fields": ["price"], "decimals": 2},
+                {"type": "rename", "from": "price", "to": "px"},
+                {"type": "enrich", "field": "venue", "value": "NASDAQ"},
+                {"type": "fan_out", "dests": ["{dest}", "backup/{dest}"]}
+            ]"#,
+        )
+        .unwrap();
+        let mut chain = build_chain(&config).unwrap();
+        assert!(run(&mut chain, "NVDA").is_empty());
+        assert!(run(&mut chain, "AMD").is_empty());
+        let out = run(&mut chain, "AAPL");
+        assert_eq!(out.len(), 2);
+        assert!(matches!(out[0].get("px"), Some(CFValue::Double(v)) if *v == 1.23));
+        assert!(!out[0].contains_key("price"));
+        assert!(matches!(out[0].get("venue"), Some(CFValue::String(v)) if v == "NASDAQ"));
+        assert!(matches!(out[1].get("_dest"), Some(CFValue::String(v)) if v == "backup/api/AAPL"));
+    }
+
+    #[test]
+    fn test_round_decimal() {
+        let round = MiddlewareConfig::Round {
+            fields: vec!["price".to_string(), "close".to_string()],
+            decimals: 2,
+        };
+        let mut chain = build_chain(&[round]).unwrap();
+        let price = Decimal::new(123456, 4).with_repr(DecimalRepr::Float);
+        let mut item = BTreeMap::new();
+        item.insert("price".to_string(), CFValue::Decimal(price));
+        item.insert("close".to_string(), CFValue::Decimal(Decimal::new(-15, 1)));
+        let mut out = vec![];
+        chain.apply(&ctx("AAPL"), item, &mut out);
+        match out[0].get("price") {
+            Some(CFValue::Decimal(v)) => assert_eq!(
+                (v.units(), v.scale(), v.repr()),
+                (1235, 2, DecimalRepr::Float)
+            ),
+            v => panic!("price is {:?}", v),
+        }
+        // fewer digits than asked stay as they are
+        match out[0].get("close") {
+            Some(CFValue::Decimal(v)) => assert_eq!((v.units(), v.scale()), (-15, 1)),
+            v => panic!("close is {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_market_phase_filter() {
+        let mut chain = build_chain(&[MiddlewareConfig::MarketPhase { phases: vec![1] }]).unwrap();
+        let mut item = BTreeMap::new();
+        item.insert("market_phase".to_string(), CFValue::Int(1));
+        let mut out = vec![];
+        chain.apply(&ctx("AAPL"), item.clone(), &mut out);
+        assert_eq!(out.len(), 1);
+        out.clear();
+        item.insert("market_phase".to_string(), CFValue::Int(3));
+        chain.apply(&ctx("AAPL"), item, &mut out);
+        assert!(out.is_empty());
+    }
+}
@@ -1,5 +1,16 @@
+pub mod batch;
+pub mod config;
 pub mod convertor;
+pub mod envelope;
 pub mod formater;
+pub mod latency;
+pub mod metrics;
+pub mod middleware;
 pub mod sink;
 pub mod pipe;
-pub mod pipe_queue;
\ No newline at end of file
+pub mod proto;
+pub mod pipe_queue;
+pub mod queue;
+pub mod watchdog;
+#[cfg(test)]
+pub(crate) mod test_util;
\ No newline at end of file
@@ -1,26 +1,38 @@
+use super::envelope::{DestSeq, Meta};
 use super::formater::FormaterExt;
-use super::sink::SinkExt;
+use super::latency::now_ns;
+use super::metrics::ConvertorCounters;
+use super::middleware::{EventContext, MiddlewareChain};
+use super::queue::RateLimiter;
+use super::sink::{Dest, SinkError, SinkExt};
 use cfapi::binding::MessageEvent;
 
 use cfapi::message_event::MessageEventHandlerExt;
-use tracing::info;
+use tracing::{error, info};
 
 use super::convertor::Convertor;
 
 pub struct PipeMessageHandler<C, F, R>
 where
     C: Convertor,
+    C::Out: Dest,
     F: FormaterExt<C::Out>,
     R: SinkExt<C::Out>,
 {
     convertor: C,
     formater: F,
     sink: R,
+    counters: ConvertorCounters,
+    middleware: MiddlewareChain<C::Out>,
+    out: Vec<C::Out>,
+    seqs: DestSeq,
+    log_limit: RateLimiter,
 }
 
 impl<C, F, R> PipeMessageHandler<C, F, R>
 where
     C: Convertor,
+    C::Out: Dest,
     F: FormaterExt<C::Out>,
     R: SinkExt<C::Out>,
 {
@@ -29,6 +41,34 @@ where
             convertor,
             formater,
             sink,
+            counters: ConvertorCounters::default(),
+            middleware: MiddlewareChain::default(),
+            out: vec![],
+            seqs: DestSeq::default(),
+            log_limit: RateLimiter::default(),
+        }
+    }
+
+    pub fn with_middleware(mut self, middleware: MiddlewareChain<C::Out>) -> Self {
+        self.middleware = middleware;
+        self
+    }
+
+    // stamps the messages of one event like the queue workers do, then sends them
+    fn send(&mut self, meta: Meta, out: &mut Vec<C::Out>) {
+        meta.stamp_origin(out);
+        for mut data in out.drain(..) {
+            self.seqs.stamp(&mut data, now_ns());
+            let r = self.sink.exec(&data, &self.formater);
+            self.log_error(r);
+        }
+    }
+
+    fn log_error(&self, r: Result<(), SinkError>) {
+        if let Err(e) = r {
+            if self.log_limit.check() {
+                error!("sink error: {}", e);
+            }
         }
     }
 }
@@ -36,6 +76,7 @@ where
 impl<C, F, R> MessageEventHandlerExt for PipeMessageHandler<C, F, R>
 where
     C: Convertor,
+    C::Out: Dest,
     F: FormaterExt<C::Out>,
     R: SinkExt<C::Out>,
 {
@@ -48,13 +89,91 @@ where
             return;
         }
         let data = self.convertor.convert(event);
-        match data {
-            Some(data) => {
-                self.sink.exec(&data, &self.formater);
-            }
-            None => {}
-            
+        self.counters
+            .record(i32::from(event.getSource()), data.is_some());
+        let data = match data {
+            Some(data) => data,
+            None => return,
+        };
+        let mut out = std::mem::take(&mut self.out);
+        if self.middleware.is_empty() {
+            out.push(data);
+        } else {
+            self.middleware
+                .apply(&EventContext::from_event(event), data, &mut out);
         }
+        self.send(Meta::from_event(event), &mut out);
+        self.out = out;
         // std::thread::sleep(std::time::Duration::from_millis(100));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::EventType;
+    use crate::formater::JsonFormater;
+    use crate::test_util::{msg, Msg};
+    use std::sync::{Arc, Mutex};
+
+    struct NoConvertor;
+
+    impl Convertor for NoConvertor {
+        type Out = Msg;
+
+        fn convert(&self, _event: &MessageEvent) -> Option<Msg> {
+            None
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordSink(Arc<Mutex<Vec<Msg>>>);
+
+    impl SinkExt<Msg> for RecordSink {
+        fn build(_id: &str) -> Self {
+            Self::default()
+        }
+
+        fn exec(
+            &mut self,
+            input: &Msg,
+            _formater: &impl FormaterExt<Msg>,
+        ) -> Result<(), SinkError> {
+            self.0.lock().unwrap().push(input.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_send_stamps_meta() {
+        let sink = RecordSink::default();
+        let sent = sink.0.clone();
+        let mut handler = PipeMessageHandler::new(NoConvertor, JsonFormater, sink);
+        let enveloped = |dest| Msg {
+            meta: Some(Meta::default()),
+            ..msg(dest, 1.0)
+        };
+        let meta = Meta {
+            source: 533,
+            event_type: EventType::Refresh,
+            tag: 7,
+            ..Default::default()
+        };
+        let mut out = vec![enveloped("AAPL"), msg("NVDA", 1.0), enveloped("AAPL")];
+        handler.send(meta, &mut out);
+        handler.send(meta, &mut vec![enveloped("NVDA")]);
+
+        let sent = sent.lock().unwrap();
+        let metas: Vec<_> = sent
+            .iter()
+            .map(|m| m.meta.map(|m| (m.seq, m.source)))
+            .collect();
+        assert_eq!(
+            metas,
+            vec![Some((1, 533)), None, Some((2, 533)), Some((1, 533))]
+        );
+        let first = sent[0].meta.unwrap();
+        assert_eq!((first.event_type, first.tag), (EventType::Refresh, 7));
+        assert!(first.produced_at > 0 && first.produced_at <= sent[2].meta.unwrap().produced_at);
+    }
+}
@@ -1,42 +1,55 @@
-use std::{fmt::Debug, marker::PhantomData};
+use std::{fmt::Debug, marker::PhantomData, sync::Arc};
 
+use super::envelope::{DestSeq, Meta};
 use super::formater::FormaterExt;
-use super::sink::SinkExt;
+use super::latency::{now_ns, SourceTs, Stamped, Stamps, LATENCY};
+use super::metrics::{ConvertorCounters, METRICS};
+use super::middleware::{EventContext, MiddlewareChain};
+use super::queue::{
+    build_conflating_queue, build_queue, ChannelOverflow, ChannelQueue, ConflateFilter,
+    OverflowPolicy, PipeQueue, QueueError, QueueStatsSnapshot, RateLimiter, SpillCodec,
+};
+use super::sink::{Dest, SinkError, SinkExt};
 use cfapi::binding::MessageEvent;
 
 use cfapi::message_event::MessageEventHandlerExt;
-use crossbeam_queue::ArrayQueue;
 use tracing::{error, info};
 // use crossbeam_utils::thread::scope;
 use super::convertor::Convertor;
-use crossbeam_channel::{bounded, unbounded, TrySendError};
 
 pub struct PipeQueueMessageHandler<C, F, R>
 where
     C: Convertor + Send + Sync,
     F: FormaterExt<C::Out> + Send + Sync,
     R: SinkExt<C::Out> + Send + Sync,
-    C::Out: Send + Sync + Debug,
+    C::Out: Send + Sync + Debug + Dest + SpillCodec + SourceTs + 'static,
 {
     convertor: C,
     // formater: F,
     // sink: R,
-    // queue: &'a ArrayQueue<C::Out>,
-    recv: crossbeam_channel::Receiver<C::Out>,
-    send: crossbeam_channel::Sender<C::Out>,
-    recv_back: crossbeam_channel::Receiver<C::Out>,
-    send_back: crossbeam_channel::Sender<C::Out>,
+    // one queue per sink worker, messages are routed by `Dest::get_partition_key`
+    queues: Vec<Arc<dyn PipeQueue<Stamped<C::Out>>>>,
+    hasher: ahash::RandomState,
+    seqs: DestSeq,
+    counters: ConvertorCounters,
+    middleware: MiddlewareChain<C::Out>,
+    out: Vec<C::Out>,
+    size: usize,
     n: usize,
+    sink_builder: SinkBuilder<R>,
     _formater: PhantomData<F>,
     _sink: PhantomData<R>,
 }
 
+/// Builds the sink of each worker from its id.
+pub type SinkBuilder<R> = Arc<dyn Fn(&str) -> Result<R, SinkError> + Send + Sync>;
+
 impl<C, F, R> PipeQueueMessageHandler<C, F, R>
 where
     C: Convertor + Send + Sync,
     F: FormaterExt<C::Out> + Send + Sync + Default,
     R: SinkExt<C::Out> + Send + Sync + Default,
-    C::Out: Send + Sync + Debug,
+    C::Out: Send + Sync + Debug + Dest + SpillCodec + SourceTs + 'static,
 {
     // pub fn new(convertor: C, formater: F, sink: R, size: usize) -> Self {
     //     let (s, r) = bounded(size);
@@ -52,27 +65,72 @@ where
     //     }
     // }
 
+    /// `size` is the capacity of each of the `n` worker queues, which block the caller
+    /// when full, see `with_overflow_policy` to drop or spill instead.
     pub fn new(convertor: C, size: usize, n: usize) -> Self
     where
         F: FormaterExt<C::Out> + Send + Sync + Default,
         R: SinkExt<C::Out> + Send + Sync + Default,
     {
-        let (send, recv) = bounded(size);
-        let (send_back, recv_back) = unbounded();
+        let n = n.max(1);
+        let queues = (0..n)
+            .map(|_| {
+                Arc::new(ChannelQueue::new(size, ChannelOverflow::Block)) as Arc<dyn PipeQueue<_>>
+            })
+            .collect();
         Self {
             convertor,
             // formater,
             // sink,
-            recv,
-            send,
-            recv_back,
-            send_back,
+            queues,
+            hasher: ahash::RandomState::new(),
+            seqs: DestSeq::default(),
+            counters: ConvertorCounters::default(),
+            middleware: MiddlewareChain::default(),
+            out: vec![],
+            size,
             n,
+            sink_builder: Arc::new(|id: &str| Ok(R::build(id))),
             _formater: PhantomData,
             _sink: PhantomData,
         }
     }
 
+    pub fn with_overflow_policy(mut self, policy: &OverflowPolicy) -> Result<Self, QueueError> {
+        self.queues = (0..self.n)
+            .map(|i| build_queue(&policy.for_partition(i), self.size))
+            .collect::<Result<_, _>>()?;
+        Ok(self)
+    }
+
+    /// Keep only the latest pending message per destination, except those matching
+    /// `never_conflate`. Replaces the overflow policy, the producer blocks when full.
+    pub fn with_conflation(mut self, never_conflate: Option<ConflateFilter<C::Out>>) -> Self {
+        let never_conflate = never_conflate.map(|filter| {
+            Arc::new(move |stamped: &Stamped<C::Out>| filter(&stamped.data))
+                as ConflateFilter<Stamped<C::Out>>
+        });
+        self.queues = (0..self.n)
+            .map(|_| build_conflating_queue(self.size, never_conflate.clone()))
+            .collect();
+        self
+    }
+
+    /// Build the worker sinks with `builder` instead of `SinkExt::build`.
+    pub fn with_sink_builder(
+        mut self,
+        builder: impl Fn(&str) -> Result<R, SinkError> + Send + Sync + 'static,
+    ) -> Self {
+        self.sink_builder = Arc::new(builder);
+        self
+    }
+
+    /// Run every converted message through `middleware` before it is queued.
+    pub fn with_middleware(mut self, middleware: MiddlewareChain<C::Out>) -> Self {
+        self.middleware = middleware;
+        self
+    }
+
     // pub fn exec(&self) {
     //     match self.recv.recv() {
     //         Ok(data) => {
@@ -101,58 +159,87 @@ where
     //     }
     // }
 
-    pub fn exec_loop_th(&self)
+    /// Builds the sink of every worker, then starts the workers. Nothing is started
+    /// when a sink fails to build.
+    pub fn exec_loop_th(&self) -> Result<(), SinkError>
     where
         <C as Convertor>::Out: 'static,
         F: FormaterExt<C::Out> + Send + Sync + Default,
-        R: SinkExt<C::Out> + Send + Sync + Default,
+        R: SinkExt<C::Out> + Send + Sync + Default + 'static,
     {
-        for i in 0..self.n {
-            let recv = self.recv.clone();
+        let sinks = (0..self.queues.len())
+            .map(|i| (self.sink_builder)(&i.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        for ((i, queue), mut sink) in self.queues.iter().enumerate().zip(sinks) {
+            let queue = queue.clone();
             let id = i.to_string();
             std::thread::spawn(move || {
                 let formater = F::default();
-                let mut sink = R::build(&id);
-                // let recv = recv.clone();
-                loop {
-                    match recv.recv() {
-                        Ok(data) => {
-                            // info!("data: {:?}", data);
-                            // println!("data: {:?}", data);
-                            sink.exec(&data, &formater);
-                        }
-                        Err(_) => {
-                            error!("channel is empty");
-                        }
-                    }
-                }
-            });
-        }
-        for i in 0..self.n {
-            let recv = self.recv_back.clone();
-            let id = format!("b{}", i);
-            std::thread::spawn(move || {
-                let formater = F::default();
-                let mut sink = R::build(&id);
-                // let recv = recv.clone();
-                loop {
-                    match recv.recv() {
-                        Ok(data) => {
-                            // info!("data: {:?}", data);
-                            // println!("data: {:?}", data);
-                            sink.exec(&data, &formater);
-                        }
-                        Err(_) => {
-                            error!("channel is empty");
+                let log_limit = RateLimiter::default();
+                let latency = LATENCY.worker();
+                while let Some(mut stamped) = queue.pop() {
+                    stamped.stamps.dequeue_ns = now_ns();
+                    // info!("data: {:?}", data);
+                    if let Err(e) = sink.exec(&stamped.data, &formater) {
+                        if log_limit.check() {
+                            error!("sink {} error: {}", id, e);
                         }
                     }
+                    latency.record(&stamped.stamps, now_ns());
                 }
+                error!("queue is closed");
             });
         }
+        Ok(())
     }
 
     pub fn get_queue_size(&self) -> usize {
-        self.recv.len()
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    pub fn get_partition_sizes(&self) -> Vec<usize> {
+        self.queues.iter().map(|queue| queue.len()).collect()
+    }
+
+    /// Export queue depth per partition and overflow counts under the `handler` label.
+    pub fn register_metrics(&self, handler: &str) {
+        let queues = self.queues.clone();
+        let handler = handler.to_string();
+        METRICS.add_refresher(move |metrics| {
+            let mut stats = QueueStatsSnapshot::default();
+            for (i, queue) in queues.iter().enumerate() {
+                metrics
+                    .queue_depth
+                    .with_label_values(&[&handler, &i.to_string()])
+                    .set(queue.len() as i64);
+                stats += queue.stats().snapshot();
+            }
+            metrics.set_queue_stats(&handler, &stats);
+        });
+    }
+
+    pub fn get_queue_stats(&self) -> QueueStatsSnapshot {
+        let mut stats = QueueStatsSnapshot::default();
+        for queue in self.queues.iter() {
+            stats += queue.stats().snapshot();
+        }
+        stats
+    }
+}
+
+impl<C, F, R> PipeQueueMessageHandler<C, F, R>
+where
+    C: Convertor + Send + Sync,
+    F: FormaterExt<C::Out> + Send + Sync,
+    R: SinkExt<C::Out> + Send + Sync,
+    C::Out: Send + Sync + Debug + Dest + SpillCodec + SourceTs + 'static,
+{
+    // every message with the same partition key goes to one worker queue, in order.
+    // numbered before it is queued, so a dropped or conflated message leaves a gap
+    fn route(&mut self, mut data: C::Out, stamps: Stamps) {
+        self.seqs.stamp(&mut data, stamps.convert_ns);
+        let partition = self.hasher.hash_one(data.get_partition_key()) as usize % self.n;
+        self.queues[partition].push(Stamped { data, stamps });
     }
 }
 
@@ -161,35 +248,145 @@ where
     C: Convertor + Send + Sync,
     F: FormaterExt<C::Out> + Send + Sync,
     R: SinkExt<C::Out> + Send + Sync,
-    C::Out: Send + Sync + Debug,
+    C::Out: Send + Sync + Debug + Dest + SpillCodec + SourceTs + 'static,
 {
     fn on_message_event(&mut self, event: &MessageEvent) {
         if event.getSource() == autocxx::c_int(0) {
             return;
         }
+        let callback_ns = now_ns();
         let data = self.convertor.convert(event);
-        match data {
-            Some(data) => {
-                match self.send.try_send(data) {
-                    Ok(_) => {
-                        // info!("send");
-                    }
-                    Err(TrySendError::Full(data)) => {
-                        // send full notifiy
-                        match self.send_back.send(data) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                error!("backup channel error {:?}", e);
-                            }
-                        }
-                        error!("channel is full");
-                    }
-                    Err(TrySendError::Disconnected(_)) => {
-                        error!("channel is disconnected");
-                    }
+        self.counters
+            .record(i32::from(event.getSource()), data.is_some());
+        let data = match data {
+            Some(data) => data,
+            None => return,
+        };
+        let mut out = std::mem::take(&mut self.out);
+        if self.middleware.is_empty() {
+            out.push(data);
+        } else {
+            self.middleware
+                .apply(&EventContext::from_event(event), data, &mut out);
+        }
+        let convert_ns = now_ns();
+        Meta::from_event(event).stamp_origin(&mut out);
+        for data in out.drain(..) {
+            let stamps = Stamps {
+                source_ns: (data.source_ts() * 1e9) as u64,
+                callback_ns,
+                convert_ns,
+                dequeue_ns: 0,
+            };
+            self.route(data, stamps);
+        }
+        self.out = out;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formater::JsonFormater;
+    use crate::sink::SinkError;
+    use crate::test_util::{msg, Msg};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    struct NoConvertor;
+
+    impl Convertor for NoConvertor {
+        type Out = Msg;
+
+        fn convert(&self, _event: &MessageEvent) -> Option<Msg> {
+            None
+        }
+    }
+
+    type Seen = Arc<Mutex<Vec<(String, String, u32)>>>;
+
+    // records the worker id, dest and close of every message
+    #[derive(Default)]
+    struct RecordSink {
+        id: String,
+        seen: Seen,
+    }
+
+    impl SinkExt<Msg> for RecordSink {
+        fn exec(&mut self, input: &Msg, _: &impl FormaterExt<Msg>) -> Result<(), SinkError> {
+            let record = (self.id.clone(), input.dest.clone(), input.close as u32);
+            self.seen.lock().unwrap().push(record);
+            Ok(())
+        }
+
+        fn build(id: &str) -> Self {
+            RecordSink {
+                id: id.to_string(),
+                seen: Seen::default(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_partition_keeps_symbol_on_one_worker() {
+        let seen = Seen::default();
+        let mut handler: PipeQueueMessageHandler<NoConvertor, JsonFormater, RecordSink> =
+            PipeQueueMessageHandler::new(NoConvertor, 1024, 4).with_sink_builder({
+                let seen = seen.clone();
+                move |id| {
+                    Ok(RecordSink {
+                        id: id.to_string(),
+                        seen: seen.clone(),
+                    })
                 }
+            });
+        handler.exec_loop_th().unwrap();
+        for seq in 0..50 {
+            for code in 0..20 {
+                let code = format!("S{}", code);
+                handler.route(msg(&code, seq as f64), Stamps::default());
             }
-            None => {}
         }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while seen.lock().unwrap().len() < 1000 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1000);
+        let mut last: HashMap<&str, (&str, u32)> = HashMap::new();
+        for (id, code, seq) in seen.iter() {
+            match last.insert(code, (id, *seq)) {
+                Some((prev_id, prev_seq)) => {
+                    assert_eq!(prev_id, id, "{} moved worker", code);
+                    assert_eq!(prev_seq + 1, *seq, "{} out of order", code);
+                }
+                None => assert_eq!(*seq, 0),
+            }
+        }
+        let workers: std::collections::HashSet<_> = last.values().map(|(id, _)| *id).collect();
+        assert!(workers.len() > 1);
+    }
+
+    #[test]
+    fn test_seq_is_stamped_before_the_queue() {
+        let mut handler: PipeQueueMessageHandler<NoConvertor, JsonFormater, RecordSink> =
+            PipeQueueMessageHandler::new(NoConvertor, 2, 1)
+                .with_overflow_policy(&OverflowPolicy::DropOldest)
+                .unwrap();
+        for close in 0..4 {
+            let data = Msg {
+                meta: Some(Meta::default()),
+                ..msg("api/V1/TIC/Q/AAPL", close as f64)
+            };
+            handler.route(data, Stamps::default());
+        }
+        // the two oldest were dropped, the consumer sees the gap
+        let queue = &handler.queues[0];
+        let seqs: Vec<u64> = (0..queue.len())
+            .map(|_| queue.pop().unwrap().data.meta.unwrap().seq)
+            .collect();
+        assert_eq!(seqs, vec![3, 4]);
     }
 }
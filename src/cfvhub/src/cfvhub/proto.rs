@@ -0,0 +1,100 @@
+use prost::Message;
+use std::fmt::Display;
+
+use super::envelope::{Envelope, EventType};
+
+/// Types generated from the published schemas in `proto/`.
+pub mod pb {
+    include!(concat!(env!("OUT_DIR"), "/cfvhub.v1.rs"));
+}
+
+#[derive(Debug)]
+pub struct ProtoError(String);
+
+impl Display for ProtoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ProtoError {}
+
+fn no_schema<T: ?Sized, R>() -> Result<R, ProtoError> {
+    Err(ProtoError(format!(
+        "{} has no message in proto/",
+        std::any::type_name::<T>()
+    )))
+}
+
+/// A type with a message in `proto/`, encoded by the protobuf formater. Without the
+/// methods the type has no schema and formating it as protobuf fails.
+pub trait ProtoMessage {
+    /// Appends the encoded message to `buf`.
+    fn encode_proto(&self, _buf: &mut Vec<u8>) -> Result<(), ProtoError> {
+        no_schema::<Self, _>()
+    }
+
+    /// The message in the `data` oneof of the envelope.
+    fn envelope_data(&self) -> Result<pb::envelope::Data, ProtoError> {
+        no_schema::<Self, _>()
+    }
+}
+
+/// Appends a generated message, a `Vec` grows so this does not fail.
+pub fn encode_into(message: &impl Message, buf: &mut Vec<u8>) {
+    buf.reserve(message.encoded_len());
+    message.encode_raw(buf);
+}
+
+impl From<EventType> for pb::EventType {
+    fn from(event_type: EventType) -> Self {
+        match event_type {
+            EventType::ImageComplete => pb::EventType::ImageComplete,
+            EventType::ImagePart => pb::EventType::ImagePart,
+            EventType::Refresh => pb::EventType::Refresh,
+            EventType::Status => pb::EventType::Status,
+            EventType::Update => pb::EventType::Update,
+        }
+    }
+}
+
+impl<T: ProtoMessage> ProtoMessage for Envelope<'_, T> {
+    fn encode_proto(&self, buf: &mut Vec<u8>) -> Result<(), ProtoError> {
+        let envelope = pb::Envelope {
+            seq: self.seq,
+            producer: self.producer.to_string(),
+            run: self.run,
+            source: self.source,
+            event_type: pb::EventType::from(self.event_type).into(),
+            tag: self.tag,
+            produced_at: self.produced_at,
+            schema_version: self.schema_version,
+            data: Some(self.data.envelope_data()?),
+        };
+        encode_into(&envelope, buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_into_appends() {
+        let message = pb::NbBidAsk {
+            code: "AAPL".to_string(),
+            ask_volume: 300,
+            ..Default::default()
+        };
+        let mut buf = b"prefix".to_vec();
+        encode_into(&message, &mut buf);
+        assert_eq!(buf[..6], *b"prefix");
+        assert_eq!(buf[6..], message.encode_to_vec());
+        assert_eq!(pb::NbBidAsk::decode(&buf[6..]).unwrap(), message);
+
+        struct NoSchema;
+        impl ProtoMessage for NoSchema {}
+        assert!(NoSchema.encode_proto(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use super::{PipeQueue, QueueStats, RateLimiter};
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use std::sync::atomic::Ordering;
+use tracing::{error, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelOverflow {
+    Block,
+    DropNewest,
+    DropOldest,
+}
+
+/// Bounded crossbeam channel for the block and drop overflow policies.
+pub struct ChannelQueue<T> {
+    send: Sender<T>,
+    recv: Receiver<T>,
+    overflow: ChannelOverflow,
+    stats: QueueStats,
+    log_limit: RateLimiter,
+}
+
+impl<T> ChannelQueue<T> {
+    pub fn new(size: usize, overflow: ChannelOverflow) -> Self {
+        let (send, recv) = bounded(size);
+        Self {
+            send,
+            recv,
+            overflow,
+            stats: QueueStats::default(),
+            log_limit: RateLimiter::default(),
+        }
+    }
+
+    fn log_full(&self) {
+        if self.log_limit.check() {
+            warn!(
+                "pipe queue full, overflow: {:?}, stats: {:?}",
+                self.overflow,
+                self.stats.snapshot()
+            );
+        }
+    }
+}
+
+impl<T: Send + Sync> PipeQueue<T> for ChannelQueue<T> {
+    fn push(&self, item: T) {
+        self.stats.pushed.fetch_add(1, Ordering::Relaxed);
+        let mut item = item;
+        loop {
+            match self.send.try_send(item) {
+                Ok(_) => return,
+                Err(TrySendError::Full(data)) => match self.overflow {
+                    ChannelOverflow::Block => {
+                        self.stats.blocked.fetch_add(1, Ordering::Relaxed);
+                        self.log_full();
+                        if self.send.send(data).is_err() {
+                            error!("channel is disconnected");
+                        }
+                        return;
+                    }
+                    ChannelOverflow::DropNewest => {
+                        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                        self.log_full();
+                        return;
+                    }
+                    ChannelOverflow::DropOldest => {
+                        if self.recv.try_recv().is_ok() {
+                            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                            self.log_full();
+                        }
+                        item = data;
+                    }
+                },
+                Err(TrySendError::Disconnected(_)) => {
+                    error!("channel is disconnected");
+                    return;
+                }
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        self.recv.recv().ok()
+    }
+
+    fn len(&self) -> usize {
+        self.recv.len()
+    }
+
+    fn stats(&self) -> &QueueStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drop_newest() {
+        let queue = ChannelQueue::new(2, ChannelOverflow::DropNewest);
+        for i in 0..4 {
+            queue.push(i);
+        }
+        assert_eq!(queue.pop(), Some(0));
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.stats().snapshot().dropped, 2);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let queue = ChannelQueue::new(2, ChannelOverflow::DropOldest);
+        for i in 0..4 {
+            queue.push(i);
+        }
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.stats().snapshot().dropped, 2);
+    }
+}
@@ -0,0 +1,241 @@
+use super::{ConflateFilter, PipeQueue, QueueStats, RateLimiter};
+use crate::sink::Dest;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::Ordering;
+use std::sync::{Condvar, Mutex};
+use tracing::warn;
+
+struct ConflateState<T> {
+    items: VecDeque<T>,
+    head_seq: u64,
+    // dest -> seq of the newest queued message for it
+    latest: HashMap<String, u64, ahash::RandomState>,
+}
+
+/// FIFO queue keyed by `Dest`. A replaced message keeps the position of the one it replaces,
+/// so a busy destination can not push the others back.
+///
+/// Built with `new` it only conflates once full, and drops messages for a destination with
+/// nothing pending. Built with `conflating` it always keeps just the latest pending message per
+/// destination and blocks the producer when full of distinct destinations.
+pub struct ConflateQueue<T> {
+    size: usize,
+    always: bool,
+    never_conflate: Option<ConflateFilter<T>>,
+    state: Mutex<ConflateState<T>>,
+    ready: Condvar,
+    space: Condvar,
+    stats: QueueStats,
+    log_limit: RateLimiter,
+}
+
+impl<T> ConflateQueue<T> {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            always: false,
+            never_conflate: None,
+            state: Mutex::new(ConflateState {
+                items: VecDeque::with_capacity(size),
+                head_seq: 0,
+                latest: HashMap::default(),
+            }),
+            ready: Condvar::new(),
+            space: Condvar::new(),
+            stats: QueueStats::default(),
+            log_limit: RateLimiter::default(),
+        }
+    }
+
+    pub fn conflating(size: usize) -> Self {
+        Self {
+            always: true,
+            ..Self::new(size)
+        }
+    }
+
+    /// Messages matching the filter are always queued on their own and never replaced.
+    pub fn with_never_conflate(mut self, never_conflate: Option<ConflateFilter<T>>) -> Self {
+        self.never_conflate = never_conflate;
+        self
+    }
+
+    fn conflatable(&self, item: &T) -> bool {
+        match &self.never_conflate {
+            Some(filter) => !filter(item),
+            None => true,
+        }
+    }
+
+    fn log_full(&self) {
+        if self.log_limit.check() {
+            warn!(
+                "pipe queue full, overflow: Conflate, always: {}, stats: {:?}",
+                self.always,
+                self.stats.snapshot()
+            );
+        }
+    }
+}
+
+impl<T> ConflateState<T> {
+    fn replace(&mut self, key: &str, item: T) -> Result<(), T> {
+        match self.latest.get(key) {
+            Some(seq) => {
+                let idx = (seq - self.head_seq) as usize;
+                self.items[idx] = item;
+                Ok(())
+            }
+            None => Err(item),
+        }
+    }
+
+    fn push_back(&mut self, item: T, conflatable: bool, key: &str) {
+        if conflatable {
+            let seq = self.head_seq + self.items.len() as u64;
+            self.latest.insert(key.to_string(), seq);
+        }
+        self.items.push_back(item);
+    }
+}
+
+impl<T: Dest + Send + Sync> PipeQueue<T> for ConflateQueue<T> {
+    fn push(&self, item: T) {
+        self.stats.pushed.fetch_add(1, Ordering::Relaxed);
+        let conflatable = self.conflatable(&item);
+        let key = item.get_dest().to_string();
+        let mut state = self.state.lock().unwrap();
+        let mut item = item;
+        if self.always && conflatable {
+            match state.replace(&key, item) {
+                Ok(_) => {
+                    self.stats.conflated.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                Err(data) => item = data,
+            }
+        }
+        if state.items.len() < self.size {
+            state.push_back(item, conflatable, &key);
+            drop(state);
+            self.ready.notify_one();
+            return;
+        }
+        if self.always {
+            self.stats.blocked.fetch_add(1, Ordering::Relaxed);
+            self.log_full();
+            while state.items.len() >= self.size {
+                state = self.space.wait(state).unwrap();
+            }
+            state.push_back(item, conflatable, &key);
+            drop(state);
+            self.ready.notify_one();
+            return;
+        }
+        let replaced = if conflatable {
+            state.replace(&key, item).is_ok()
+        } else {
+            false
+        };
+        if replaced {
+            self.stats.conflated.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        drop(state);
+        self.log_full();
+    }
+
+    fn pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(item) = state.items.pop_front() {
+                let seq = state.head_seq;
+                state.head_seq += 1;
+                if state.latest.get(item.get_dest()) == Some(&seq) {
+                    state.latest.remove(item.get_dest());
+                }
+                drop(state);
+                self.space.notify_one();
+                return Some(item);
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.state.lock().unwrap().items.len()
+    }
+
+    fn stats(&self) -> &QueueStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{msg, Msg};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_conflate_when_full() {
+        let queue = ConflateQueue::new(2);
+        queue.push(msg("a", 1.0));
+        queue.push(msg("b", 1.0));
+        queue.push(msg("a", 2.0));
+        queue.push(msg("c", 1.0));
+        assert_eq!(queue.pop(), Some(msg("a", 2.0)));
+        queue.push(msg("a", 3.0));
+        assert_eq!(queue.pop(), Some(msg("b", 1.0)));
+        assert_eq!(queue.pop(), Some(msg("a", 3.0)));
+        let stats = queue.stats().snapshot();
+        assert_eq!(stats.conflated, 1);
+        assert_eq!(stats.dropped, 1);
+    }
+
+    #[test]
+    fn test_conflating_fair_fifo() {
+        let queue = ConflateQueue::conflating(8);
+        queue.push(msg("a", 1.0));
+        queue.push(msg("b", 1.0));
+        queue.push(msg("a", 2.0));
+        queue.push(msg("c", 1.0));
+        queue.push(msg("a", 3.0));
+        queue.push(msg("b", 2.0));
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.pop(), Some(msg("a", 3.0)));
+        queue.push(msg("a", 4.0));
+        assert_eq!(queue.pop(), Some(msg("b", 2.0)));
+        assert_eq!(queue.pop(), Some(msg("c", 1.0)));
+        assert_eq!(queue.pop(), Some(msg("a", 4.0)));
+        assert_eq!(queue.stats().snapshot().conflated, 3);
+    }
+
+    #[test]
+    fn test_conflating_never_conflate() {
+        let never: ConflateFilter<Msg> = Arc::new(|msg: &Msg| msg.dest.starts_with("tick"));
+        let queue = ConflateQueue::conflating(8).with_never_conflate(Some(never));
+        queue.push(msg("tick/a", 1.0));
+        queue.push(msg("quote/a", 1.0));
+        queue.push(msg("tick/a", 2.0));
+        queue.push(msg("quote/a", 2.0));
+        assert_eq!(queue.pop(), Some(msg("tick/a", 1.0)));
+        assert_eq!(queue.pop(), Some(msg("quote/a", 2.0)));
+        assert_eq!(queue.pop(), Some(msg("tick/a", 2.0)));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_conflating_blocks_when_full() {
+        let queue = Arc::new(ConflateQueue::conflating(1));
+        queue.push(msg("a", 1.0));
+        let producer = {
+            let queue = queue.clone();
+            std::thread::spawn(move || queue.push(msg("b", 1.0)))
+        };
+        assert_eq!(queue.pop(), Some(msg("a", 1.0)));
+        producer.join().unwrap();
+        assert_eq!(queue.pop(), Some(msg("b", 1.0)));
+    }
+}
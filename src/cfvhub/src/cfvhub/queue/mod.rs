@@ -0,0 +1,237 @@
+use serde::{Deserialize, Serialize};
+use snafu::prelude::Snafu;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::sink::Dest;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub))]
+pub enum QueueError {
+    #[snafu(display("Spill file {} open Error: {}", path.display(), source))]
+    SpillOpen {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[snafu(display("Spill file IO Error: {}", source))]
+    SpillIo { source: std::io::Error },
+    #[snafu(display("Spill Encode Error: {}", source))]
+    SpillEncode { source: rmp_serde::encode::Error },
+    #[snafu(display("Spill Decode Error: {}", source))]
+    SpillDecode { source: rmp_serde::decode::Error },
+}
+
+/// What `PipeQueueMessageHandler` does with a message when its queue is full.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum OverflowPolicy {
+    /// block the CFAPI callback thread until a sink worker frees a slot, nothing is lost
+    #[default]
+    Block,
+    /// drop the incoming message
+    DropNewest,
+    /// drop the oldest queued message to make room, counted in `QueueStats::dropped`
+    DropOldest,
+    /// replace the pending message with the same destination, drop when there is none
+    Conflate,
+    /// append to a disk-backed queue at the path and read it back in order
+    Spill(PathBuf),
+}
+
+impl OverflowPolicy {
+    /// Policy for one of several partitions, each spill partition gets its own file.
+    pub fn for_partition(&self, partition: usize) -> OverflowPolicy {
+        match self {
+            OverflowPolicy::Spill(path) => {
+                let mut path = path.clone().into_os_string();
+                path.push(format!(".{}", partition));
+                OverflowPolicy::Spill(path.into())
+            }
+            policy => policy.clone(),
+        }
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "conflate" => Ok(OverflowPolicy::Conflate),
+            _ => match s.strip_prefix("spill:") {
+                Some(path) if !path.is_empty() => Ok(OverflowPolicy::Spill(path.into())),
+                _ => Err(format!(
+                    "unknown overflow policy {}, expect block, drop-newest, drop-oldest, conflate or spill:<path>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Queue between the CFAPI callback thread and the sink workers.
+pub trait PipeQueue<T>: Send + Sync {
+    /// Enqueue from the CFAPI callback thread, applying the overflow policy.
+    fn push(&self, item: T);
+    /// Blocking dequeue for the sink workers, None when the queue is closed.
+    fn pop(&self) -> Option<T>;
+    fn len(&self) -> usize;
+    fn stats(&self) -> &QueueStats;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Returns true for messages a conflating queue must never replace.
+pub type ConflateFilter<T> = Arc<dyn Fn(&T) -> bool + Send + Sync>;
+
+/// Items that can be written to the spill file and read back.
+pub trait SpillCodec: Sized {
+    fn encode(&self) -> Result<Vec<u8>, QueueError>;
+    fn decode(bytes: &[u8]) -> Result<Self, QueueError>;
+}
+
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    pub pushed: AtomicU64,
+    pub blocked: AtomicU64,
+    pub dropped: AtomicU64,
+    pub conflated: AtomicU64,
+    pub spilled: AtomicU64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QueueStatsSnapshot {
+    pub pushed: u64,
+    pub blocked: u64,
+    pub dropped: u64,
+    pub conflated: u64,
+    pub spilled: u64,
+}
+
+impl std::ops::AddAssign for QueueStatsSnapshot {
+    fn add_assign(&mut self, other: Self) {
+        self.pushed += other.pushed;
+        self.blocked += other.blocked;
+        self.dropped += other.dropped;
+        self.conflated += other.conflated;
+        self.spilled += other.spilled;
+    }
+}
+
+impl QueueStats {
+    pub fn snapshot(&self) -> QueueStatsSnapshot {
+        QueueStatsSnapshot {
+            pushed: self.pushed.load(Ordering::Relaxed),
+            blocked: self.blocked.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            conflated: self.conflated.load(Ordering::Relaxed),
+            spilled: self.spilled.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Lets one log line through per interval, so a full queue does not log on every message.
+#[derive(Debug)]
+pub struct RateLimiter {
+    start: Instant,
+    interval_ms: u64,
+    next_ms: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            start: Instant::now(),
+            interval_ms: interval.as_millis() as u64,
+            next_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub fn check(&self) -> bool {
+        let now = self.start.elapsed().as_millis() as u64;
+        let next = self.next_ms.load(Ordering::Relaxed);
+        now >= next
+            && self
+                .next_ms
+                .compare_exchange(
+                    next,
+                    now + self.interval_ms,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(5))
+    }
+}
+
+pub fn build_queue<T>(
+    policy: &OverflowPolicy,
+    size: usize,
+) -> Result<Arc<dyn PipeQueue<T>>, QueueError>
+where
+    T: Dest + SpillCodec + Send + Sync + 'static,
+{
+    let queue: Arc<dyn PipeQueue<T>> = match policy {
+        OverflowPolicy::Block => Arc::new(ChannelQueue::new(size, ChannelOverflow::Block)),
+        OverflowPolicy::DropNewest => {
+            Arc::new(ChannelQueue::new(size, ChannelOverflow::DropNewest))
+        }
+        OverflowPolicy::DropOldest => {
+            Arc::new(ChannelQueue::new(size, ChannelOverflow::DropOldest))
+        }
+        OverflowPolicy::Conflate => Arc::new(ConflateQueue::new(size)),
+        OverflowPolicy::Spill(path) => Arc::new(SpillQueue::new(size, path)?),
+    };
+    Ok(queue)
+}
+
+pub fn build_conflating_queue<T>(
+    size: usize,
+    never_conflate: Option<ConflateFilter<T>>,
+) -> Arc<dyn PipeQueue<T>>
+where
+    T: Dest + Send + Sync + 'static,
+{
+    Arc::new(ConflateQueue::conflating(size).with_never_conflate(never_conflate))
+}
+
+pub mod channel;
+pub mod conflate;
+pub mod spill;
+pub use channel::{ChannelOverflow, ChannelQueue};
+pub use conflate::ConflateQueue;
+pub use spill::SpillQueue;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overflow_policy_from_str() {
+        assert_eq!("drop-oldest".parse(), Ok(OverflowPolicy::DropOldest));
+        assert_eq!(
+            "spill:/tmp/q"
+                .parse::<OverflowPolicy>()
+                .unwrap()
+                .for_partition(1),
+            OverflowPolicy::Spill("/tmp/q.1".into())
+        );
+        assert!("spill:".parse::<OverflowPolicy>().is_err());
+        assert_eq!(
+            OverflowPolicy::Block.for_partition(3),
+            OverflowPolicy::Block
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use super::{
+    PipeQueue, QueueError, QueueStats, RateLimiter, SpillCodec, SpillIoSnafu, SpillOpenSnafu,
+};
+use snafu::ResultExt;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Condvar, Mutex};
+use tracing::{error, warn};
+
+// records read from the spill file per flush of the writer
+const READ_BATCH: usize = 256;
+
+struct SpillState<T> {
+    mem: VecDeque<T>,
+    // length-prefixed records, appended by the producer
+    writer: BufWriter<File>,
+    // spilled and not yet popped, the read ahead ones included
+    pending: usize,
+}
+
+struct SpillReader<T> {
+    reader: BufReader<File>,
+    // read from the file and not yet popped
+    ahead: VecDeque<T>,
+}
+
+impl<T: SpillCodec> SpillReader<T> {
+    // Ok with the number of records that did not decode and the last decode error
+    fn read_batch(&mut self, n: usize) -> Result<(usize, Option<QueueError>), QueueError> {
+        let mut undecodable = (0, None);
+        for _ in 0..n {
+            let mut len = [0u8; 4];
+            self.reader.read_exact(&mut len).context(SpillIoSnafu)?;
+            let mut buf = vec![0u8; u32::from_le_bytes(len) as usize];
+            self.reader.read_exact(&mut buf).context(SpillIoSnafu)?;
+            match T::decode(&buf) {
+                Ok(item) => self.ahead.push_back(item),
+                Err(e) => undecodable = (undecodable.0 + 1, Some(e)),
+            }
+        }
+        Ok(undecodable)
+    }
+}
+
+// empties the file once the reader caught up or can no longer read it in sequence
+fn truncate<T>(state: &mut SpillState<T>, reader: &mut SpillReader<T>) -> Result<(), QueueError> {
+    state.writer.flush().context(SpillIoSnafu)?;
+    state.writer.get_ref().set_len(0).context(SpillIoSnafu)?;
+    state
+        .writer
+        .seek(SeekFrom::Start(0))
+        .context(SpillIoSnafu)?;
+    reader
+        .reader
+        .seek(SeekFrom::Start(0))
+        .context(SpillIoSnafu)?;
+    Ok(())
+}
+
+/// Keeps up to `size` messages in memory and appends the rest to a file.
+/// Once anything is spilled new messages also go to the file, so order is kept.
+/// The file is read back in batches outside the queue lock and truncated whenever the
+/// reader catches up.
+pub struct SpillQueue<T> {
+    size: usize,
+    state: Mutex<SpillState<T>>,
+    // taken by the sink workers only, before `state`
+    reader: Mutex<SpillReader<T>>,
+    ready: Condvar,
+    stats: QueueStats,
+    log_limit: RateLimiter,
+}
+
+impl<T> SpillQueue<T> {
+    pub fn new(size: usize, path: &Path) -> Result<Self, QueueError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .context(SpillOpenSnafu { path })?;
+        let reader = File::open(path).context(SpillOpenSnafu { path })?;
+        Ok(Self {
+            size,
+            state: Mutex::new(SpillState {
+                mem: VecDeque::with_capacity(size),
+                writer: BufWriter::new(file),
+                pending: 0,
+            }),
+            reader: Mutex::new(SpillReader {
+                reader: BufReader::new(reader),
+                ahead: VecDeque::new(),
+            }),
+            ready: Condvar::new(),
+            stats: QueueStats::default(),
+            log_limit: RateLimiter::default(),
+        })
+    }
+
+    // counts the spilled records that will never be popped
+    fn lost(
+        &self,
+        state: &mut SpillState<T>,
+        reader: &mut SpillReader<T>,
+        lost: usize,
+        e: QueueError,
+    ) {
+        self.stats.dropped.fetch_add(lost as u64, Ordering::Relaxed);
+        state.pending -= lost;
+        if self.log_limit.check() {
+            error!(
+                "spill read failed: {}, lost: {}, stats: {:?}",
+                e,
+                lost,
+                self.stats.snapshot()
+            );
+        }
+        if state.pending == reader.ahead.len() {
+            if let Err(e) = truncate(state, reader) {
+                error!("spill reset failed: {}", e);
+            }
+        }
+    }
+
+    fn write(state: &mut SpillState<T>, bytes: &[u8]) -> Result<(), QueueError> {
+        state
+            .writer
+            .write_all(&(bytes.len() as u32).to_le_bytes())
+            .context(SpillIoSnafu)?;
+        state.writer.write_all(bytes).context(SpillIoSnafu)?;
+        state.pending += 1;
+        Ok(())
+    }
+}
+
+impl<T: SpillCodec + Send + Sync> PipeQueue<T> for SpillQueue<T> {
+    fn push(&self, item: T) {
+        self.stats.pushed.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+        if state.pending == 0 && state.mem.len() < self.size {
+            state.mem.push_back(item);
+        } else {
+            match item
+                .encode()
+                .and_then(|bytes| Self::write(&mut state, &bytes))
+            {
+                Ok(_) => {
+                    self.stats.spilled.fetch_add(1, Ordering::Relaxed);
+                    if self.log_limit.check() {
+                        warn!(
+                            "pipe queue full, overflow: Spill, pending on disk: {}, stats: {:?}",
+                            state.pending,
+                            self.stats.snapshot()
+                        );
+                    }
+                }
+                Err(e) => {
+                    self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    if self.log_limit.check() {
+                        error!("spill failed: {}, stats: {:?}", e, self.stats.snapshot());
+                    }
+                    return;
+                }
+            }
+        }
+        drop(state);
+        self.ready.notify_one();
+    }
+
+    fn pop(&self) -> Option<T> {
+        let mut reader = self.reader.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        loop {
+            // everything in memory was queued before the first spilled record
+            if let Some(item) = state.mem.pop_front() {
+                return Some(item);
+            }
+            if let Some(item) = reader.ahead.pop_front() {
+                state.pending -= 1;
+                if state.pending == 0 {
+                    if let Err(e) = truncate(&mut state, &mut reader) {
+                        error!("spill reset failed: {}", e);
+                    }
+                }
+                return Some(item);
+            }
+            if state.pending == 0 {
+                state = self.ready.wait(state).unwrap();
+                continue;
+            }
+            let batch = state.pending.min(READ_BATCH);
+            if let Err(e) = state.writer.flush().context(SpillIoSnafu) {
+                let lost = state.pending;
+                self.lost(&mut state, &mut reader, lost, e);
+                continue;
+            }
+            drop(state);
+            let read = reader.read_batch(batch);
+            state = self.state.lock().unwrap();
+            match read {
+                Ok((_, None)) => {}
+                Ok((undecodable, Some(e))) => self.lost(&mut state, &mut reader, undecodable, e),
+                // the file is no longer readable in sequence, give up what is left
+                Err(e) => {
+                    let lost = state.pending - reader.ahead.len();
+                    self.lost(&mut state, &mut reader, lost, e);
+                }
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.mem.len() + state.pending
+    }
+
+    fn stats(&self) -> &QueueStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::SpillEncodeSnafu;
+
+    impl SpillCodec for u32 {
+        fn encode(&self) -> Result<Vec<u8>, QueueError> {
+            rmp_serde::to_vec(self).context(SpillEncodeSnafu)
+        }
+
+        fn decode(bytes: &[u8]) -> Result<Self, QueueError> {
+            rmp_serde::from_slice(bytes).context(crate::queue::SpillDecodeSnafu)
+        }
+    }
+
+    #[test]
+    fn test_spill_keeps_order() {
+        let path = std::env::temp_dir().join(format!("cfvhub-spill-{}", std::process::id()));
+        let queue = SpillQueue::new(2, &path).unwrap();
+        for i in 0..5u32 {
+            queue.push(i);
+        }
+        assert_eq!(queue.len(), 5);
+        assert_eq!(queue.stats().snapshot().spilled, 3);
+        assert_eq!(queue.pop(), Some(0));
+        assert_eq!(queue.pop(), Some(1));
+        queue.push(5);
+        for i in 2..6u32 {
+            assert_eq!(queue.pop(), Some(i));
+        }
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        queue.push(6);
+        assert_eq!(queue.pop(), Some(6));
+        // more than one read batch, pushed while it is read back
+        for i in 0..600u32 {
+            queue.push(i);
+        }
+        for i in 0..300u32 {
+            assert_eq!(queue.pop(), Some(i));
+        }
+        queue.push(600);
+        for i in 300..601u32 {
+            assert_eq!(queue.pop(), Some(i));
+        }
+        assert!(queue.is_empty());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
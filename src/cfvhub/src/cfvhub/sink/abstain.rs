@@ -1,4 +1,4 @@
-use super::{SinkExt, FormaterExt};
+use super::{SinkExt, FormaterExt, SinkError};
 use serde::Serialize;
 
 #[derive(Debug, Default)]
@@ -10,8 +10,9 @@ impl<In: Serialize> SinkExt<In> for DoNothingSink {
     fn build(_id: &str) -> Self {
         Self {}
     }
-    fn exec(&mut self, input: &In, formater: &impl FormaterExt<In>) {
+    fn exec(&mut self, input: &In, formater: &impl FormaterExt<In>) -> Result<(), SinkError> {
         let _formated = formater.format(input);
+        Ok(())
     }
 }
 
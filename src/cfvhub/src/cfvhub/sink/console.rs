@@ -1,29 +1,28 @@
-use super::{SinkExt, FormaterExt, Formated};
+use super::{SinkExt, FormaterExt, FormatSnafu, SinkError};
+use crate::formater::Encoding;
 use serde::Serialize;
+use snafu::ResultExt;
 
 #[derive(Debug, Default)]
-pub struct ConsoleSink {}
+pub struct ConsoleSink {
+    buf: Vec<u8>,
+}
 
 impl<In: Serialize> SinkExt<In> for ConsoleSink {
     fn build(_id: &str) -> Self {
-        Self {}
+        Self::default()
     }
 
-    fn exec(&mut self, input: &In, formater: &impl FormaterExt<In>) {
-        let formated = formater.format(input);
-
-        match formated {
-            Ok(formated) => match formated {
-                Formated::String(s) => {
-                    println!("{}", s);
-                }
-                Formated::Bytes(b) => {
-                    println!("{:?}", b);
-                }
-            },
-            Err(e) => {
-                eprintln!("Error: {}", e);
+    fn exec(&mut self, input: &In, formater: &impl FormaterExt<In>) -> Result<(), SinkError> {
+        self.buf.clear();
+        match formater.format_into(input, &mut self.buf).context(FormatSnafu)? {
+            Encoding::Text => {
+                println!("{}", String::from_utf8_lossy(&self.buf));
+            }
+            Encoding::Binary => {
+                println!("{:?}", self.buf);
             }
         }
+        Ok(())
     }
 }
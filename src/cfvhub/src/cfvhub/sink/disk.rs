@@ -1,68 +1,595 @@
-use super::{DiskSinkPathSnafu, DiskSinkReadFileSnafu, Formated, FormaterExt, SinkError, SinkExt};
-use serde::Serialize;
+use super::shutdown::{Close, Shutdown};
+use super::{
+    DiskSinkPathSnafu, DiskSinkReadFileSnafu, DiskSinkWriteSnafu, FormatSnafu, FormaterExt,
+    SinkError, SinkExt,
+};
+use crate::formater::Encoding;
+use crate::metrics::{SinkCounters, METRICS};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
-use std::fs::OpenOptions;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
 use std::io::prelude::Write;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use tracing::error;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How records are delimited in the file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    /// newline for json, which is one line per record, length prefix for the rest
+    #[default]
+    Auto,
+    /// one record per line, for single line formats like json
+    Newline,
+    /// u32 little endian length then the record
+    LengthPrefixed,
+}
+
+/// Who writes a file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiskFiles {
+    /// one file per sink worker, the worker id is added to the file name
+    #[default]
+    PerWorker,
+    /// all workers share one writer, records never interleave
+    Single,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Rotation {
+    #[default]
+    Never,
+    /// start a new file once this many bytes (before compression) are written
+    Size { bytes: u64 },
+    /// start a new file every `secs` seconds
+    Interval { secs: u64 },
+    /// one file per trading day, the day starts at `roll_hour` in `utc_offset_hours`
+    TradingDay {
+        utc_offset_hours: i32,
+        roll_hour: u32,
+    },
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd {
+        level: i32,
+    },
+    Gzip {
+        level: u32,
+    },
+}
+
+impl Compression {
+    fn extension(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Zstd { .. } => Some("zst"),
+            Compression::Gzip { .. } => Some("gz"),
+        }
+    }
+}
+
+/// When written records are forced to the disk.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FsyncPolicy {
+    /// leave it to the os, buffers are still flushed every second
+    #[default]
+    Never,
+    Always,
+    Interval {
+        ms: u64,
+    },
+    OnRotate,
+}
+
+fn default_path() -> String {
+    dotenvy::var("DISK_SINK_PATH").unwrap_or_else(|_| "disk_sink.log".to_string())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiskSinkConfig {
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(default)]
+    pub framing: Framing,
+    #[serde(default)]
+    pub files: DiskFiles,
+    #[serde(default)]
+    pub rotation: Rotation,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub fsync: FsyncPolicy,
+}
+
+impl Default for DiskSinkConfig {
+    fn default() -> Self {
+        Self {
+            path: default_path(),
+            framing: Framing::default(),
+            files: DiskFiles::default(),
+            rotation: Rotation::default(),
+            compression: Compression::default(),
+            fsync: FsyncPolicy::default(),
+        }
+    }
+}
+
+impl DiskSinkConfig {
+    /// File name before rotation and compression labels, e.g. `disk_sink.0.log`.
+    pub fn base_path(&self, id: &str) -> PathBuf {
+        match self.files {
+            DiskFiles::PerWorker => with_labels(Path::new(&self.path), &[id], None),
+            DiskFiles::Single => PathBuf::from(&self.path),
+        }
+    }
+}
+
+// `dir/stem.ext` to `dir/stem.label.ext[.extra]`
+fn with_labels(path: &Path, labels: &[&str], extra: Option<&str>) -> PathBuf {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let mut name = std::iter::once(stem)
+        .chain(labels.iter().copied())
+        .collect::<Vec<_>>()
+        .join(".");
+    if let Some(ext) = path.extension().and_then(|s| s.to_str()) {
+        name = format!("{}.{}", name, ext);
+    }
+    if let Some(extra) = extra {
+        name = format!("{}.{}", name, extra);
+    }
+    path.with_file_name(name)
+}
+
+// proleptic gregorian date of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+pub(crate) fn date_label(days: i64) -> String {
+    let (y, m, d) = civil_from_days(days);
+    format!("{:04}{:02}{:02}", y, m, d)
+}
+
+pub(crate) fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+impl Rotation {
+    /// Files are rotated when the period changes.
+    fn period(&self, secs: i64) -> i64 {
+        match self {
+            Rotation::Never | Rotation::Size { .. } => 0,
+            Rotation::Interval { secs: interval } => secs.div_euclid((*interval).max(1) as i64),
+            Rotation::TradingDay {
+                utc_offset_hours,
+                roll_hour,
+            } => (secs + *utc_offset_hours as i64 * 3600 - *roll_hour as i64 * 3600)
+                .div_euclid(86400),
+        }
+    }
+
+    fn file_path(&self, base: &Path, compression: &Compression, secs: i64) -> PathBuf {
+        let ext = compression.extension();
+        match self {
+            Rotation::Never => with_labels(base, &[], ext),
+            // appending to the day file after a restart, compressed streams concatenate
+            Rotation::TradingDay { .. } => {
+                with_labels(base, &[&date_label(self.period(secs))], ext)
+            }
+            Rotation::Size { .. } | Rotation::Interval { .. } => {
+                let days = secs.div_euclid(86400);
+                let tod = secs.rem_euclid(86400);
+                let time = format!(
+                    "{}-{:02}{:02}{:02}",
+                    date_label(days),
+                    tod / 3600,
+                    tod % 3600 / 60,
+                    tod % 60
+                );
+                (0..)
+                    .map(|seq| with_labels(base, &[&format!("{}-{:04}", time, seq)], ext))
+                    .find(|path| !path.exists())
+                    .unwrap()
+            }
+        }
+    }
+}
+
+struct OpenFile {
+    path: PathBuf,
+    writer: BufWriter<Box<dyn Write + Send>>,
+    // the same file for fsync, below any compression
+    file: File,
+    written: u64,
+    period: i64,
+}
+
+/// Framed, rotating and optionally compressed writer of one file series.
+pub struct DiskWriter {
+    config: DiskSinkConfig,
+    base: PathBuf,
+    open: Option<OpenFile>,
+    last_flush: Instant,
+    last_sync: Instant,
+}
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+impl DiskWriter {
+    pub fn new(config: DiskSinkConfig, base: PathBuf) -> Result<Self, SinkError> {
+        let mut writer = Self {
+            config,
+            base,
+            open: None,
+            last_flush: Instant::now(),
+            last_sync: Instant::now(),
+        };
+        writer.open_file(now_secs())?;
+        Ok(writer)
+    }
+
+    /// The file being written.
+    pub fn path(&self) -> Option<&Path> {
+        self.open.as_ref().map(|open| open.path.as_path())
+    }
+
+    fn open_file(&mut self, secs: i64) -> Result<(), SinkError> {
+        let rotation = self.config.rotation;
+        let path = rotation.file_path(&self.base, &self.config.compression, secs);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .context(DiskSinkReadFileSnafu)?;
+        let sync = file.try_clone().context(DiskSinkReadFileSnafu)?;
+        let inner: Box<dyn Write + Send> = match self.config.compression {
+            Compression::None => Box::new(file),
+            Compression::Zstd { level } => Box::new(
+                zstd::stream::write::Encoder::new(file, level)
+                    .context(DiskSinkReadFileSnafu)?
+                    .auto_finish(),
+            ),
+            Compression::Gzip { level } => Box::new(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::new(level),
+            )),
+        };
+        self.open = Some(OpenFile {
+            path,
+            writer: BufWriter::new(inner),
+            file: sync,
+            written: 0,
+            period: rotation.period(secs),
+        });
+        Ok(())
+    }
+
+    /// Finish the current file, the encoder writes its trailer on drop. The next record
+    /// opens a file again.
+    pub fn close_file(&mut self) -> Result<(), SinkError> {
+        if let Some(mut open) = self.open.take() {
+            open.writer.flush().context(DiskSinkWriteSnafu)?;
+            let file = open.file;
+            drop(open.writer);
+            if self.config.fsync != FsyncPolicy::Never {
+                file.sync_all().context(DiskSinkWriteSnafu)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn rotate(&mut self) -> Result<(), SinkError> {
+        self.close_file()?;
+        self.open_file(now_secs())
+    }
+
+    pub fn write_record(&mut self, record: &[u8], framing: Framing) -> Result<(), SinkError> {
+        let secs = now_secs();
+        let period = self.config.rotation.period(secs);
+        if !matches!(&self.open, Some(open) if open.period == period) {
+            self.close_file()?;
+            self.open_file(secs)?;
+        }
+        let open = self.open.as_mut().unwrap();
+        match framing {
+            Framing::LengthPrefixed => {
+                open.writer
+                    .write_all(&(record.len() as u32).to_le_bytes())
+                    .context(DiskSinkWriteSnafu)?;
+                open.writer.write_all(record).context(DiskSinkWriteSnafu)?;
+                open.written += 4 + record.len() as u64;
+            }
+            Framing::Newline | Framing::Auto => {
+                open.writer.write_all(record).context(DiskSinkWriteSnafu)?;
+                open.writer.write_all(b"\n").context(DiskSinkWriteSnafu)?;
+                open.written += 1 + record.len() as u64;
+            }
+        }
+        let sync = match self.config.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Interval { ms } => self.last_sync.elapsed() >= Duration::from_millis(ms),
+            FsyncPolicy::Never | FsyncPolicy::OnRotate => false,
+        };
+        if sync || self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            open.writer.flush().context(DiskSinkWriteSnafu)?;
+            self.last_flush = Instant::now();
+        }
+        if sync {
+            open.file.sync_data().context(DiskSinkWriteSnafu)?;
+            self.last_sync = Instant::now();
+        }
+        if let Rotation::Size { bytes } = self.config.rotation {
+            if open.written >= bytes {
+                self.rotate()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), SinkError> {
+        if let Some(open) = self.open.as_mut() {
+            open.writer.flush().context(DiskSinkWriteSnafu)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DiskWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.close_file() {
+            tracing::error!("close disk sink file error: {}", e);
+        }
+    }
+}
+
+// single writer files are shared by every worker in the process
+static SHARED_WRITERS: Lazy<Mutex<HashMap<PathBuf, Weak<Mutex<DiskWriter>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// flushes the file and writes the compression trailer, later writes open it again
+impl Close for Mutex<DiskWriter> {
+    fn close(&self) -> Result<(), SinkError> {
+        self.lock().unwrap().close_file()
+    }
+}
 
-#[derive(Debug)]
 pub struct DiskSink {
     pub path: std::path::PathBuf,
-    file: std::fs::File,
-    // file: Arc<Mutex<std::fs::File>>,
+    id: String,
+    framing: Framing,
+    writer: Arc<Mutex<DiskWriter>>,
+    counters: SinkCounters,
+    // reused for every record of this worker
+    buf: Vec<u8>,
 }
 
-// pub struct DiskSinkConfig {
-//     pub path: String,
-// }
+impl std::fmt::Debug for DiskSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DiskSink")
+            .field("path", &self.path)
+            .field("id", &self.id)
+            .finish()
+    }
+}
 
 impl Default for DiskSink {
     fn default() -> Self {
-        let path = dotenvy::var("DISK_SINK_PATH").unwrap_or_else(|_| "disk_sink.log".to_string());
-        Self::new(&path).unwrap()
+        Self::new(&default_path()).unwrap()
     }
 }
 
 impl DiskSink {
+    /// A single file at `path`, no rotation or compression.
     pub fn new(path: &str) -> Result<Self, SinkError> {
         let path = std::path::PathBuf::from_str(path).context(DiskSinkPathSnafu)?;
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(path.clone())
-            .context(DiskSinkReadFileSnafu)?;
-        // Ok(Self { path, file: Arc::new(Mutex::new(file)) })
-        // Ok(Self { path, file})
-        Ok(Self { path, file })
+        let config = DiskSinkConfig {
+            path: path.to_string_lossy().to_string(),
+            files: DiskFiles::Single,
+            ..Default::default()
+        };
+        Self::from_config(&config, "default")
+    }
+
+    pub fn from_config(config: &DiskSinkConfig, id: &str) -> Result<Self, SinkError> {
+        let base = config.base_path(id);
+        let writer = match config.files {
+            DiskFiles::PerWorker => {
+                Arc::new(Mutex::new(DiskWriter::new(config.clone(), base.clone())?))
+            }
+            DiskFiles::Single => {
+                let mut shared = SHARED_WRITERS.lock().unwrap();
+                match shared.get(&base).and_then(Weak::upgrade) {
+                    Some(writer) => writer,
+                    None => {
+                        let writer =
+                            Arc::new(Mutex::new(DiskWriter::new(config.clone(), base.clone())?));
+                        shared.insert(base.clone(), Arc::downgrade(&writer));
+                        writer
+                    }
+                }
+            }
+        };
+        Ok(Self {
+            path: base,
+            id: id.to_string(),
+            framing: config.framing,
+            counters: METRICS.sink_counters("disk", id),
+            writer,
+            buf: Vec::new(),
+        })
+    }
+
+    /// The file is closed with `shutdown`.
+    pub fn with_shutdown(self, shutdown: &Shutdown) -> Self {
+        shutdown.add_file(Arc::downgrade(&self.writer) as Weak<dyn Close>);
+        self
+    }
+
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.id = id.to_string();
+        self.counters = METRICS.sink_counters("disk", id);
+        self
+    }
+
+    pub fn flush(&self) -> Result<(), SinkError> {
+        self.writer.lock().unwrap().flush()
     }
 }
 
 impl<In: Serialize> SinkExt<In> for DiskSink {
+    /// Per worker files at `DISK_SINK_PATH`.
     fn build(id: &str) -> Self {
-        let path = dotenvy::var("DISK_SINK_PATH").unwrap_or_else(|_| "disk_sink.log".to_string());
-        Self::new(&path).unwrap()
+        Self::from_config(&DiskSinkConfig::default(), id).unwrap()
     }
 
-    fn exec(&mut self, input: &In, formater: &impl FormaterExt<In>) {
-        let formated = formater.format(input);
-        match formated {
-            Ok(formated) => match formated {
-                Formated::String(s) => {
-                    if let Err(e) = writeln!(self.file, "{}", s) {
-                        error!("write event to file error: {}", e)
-                    }
-                }
-                Formated::Bytes(b) => {
-                    if let Err(e) = self.file.write_all(&b) {
-                        error!("write event bytes to file error: {}", e)
+    fn exec(&mut self, input: &In, formater: &impl FormaterExt<In>) -> Result<(), SinkError> {
+        self.buf.clear();
+        let r = formater
+            .format_into(input, &mut self.buf)
+            .context(FormatSnafu)
+            .and_then(|encoding| {
+                let framing = match self.framing {
+                    // yaml, toml and batches span several lines
+                    Framing::Auto
+                        if encoding == Encoding::Text && formater.content_type() == "json" =>
+                    {
+                        Framing::Newline
                     }
-                }
-            },
-            Err(e) => {
-                eprintln!("Error: {}", e);
-            }
+                    Framing::Auto => Framing::LengthPrefixed,
+                    framing => framing,
+                };
+                self.writer.lock().unwrap().write_record(&self.buf, framing)
+            });
+        self.counters.result(r.is_ok());
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formater::{JsonFormater, MessagePackFormater, YamlFormater};
+    use crate::sink::DiskReader;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cfvhub-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_date_label() {
+        assert_eq!(date_label(0), "19700101");
+        assert_eq!(date_label(19912), "20240708");
+        assert_eq!(date_label(-1), "19691231");
+        let day = Rotation::TradingDay {
+            utc_offset_hours: -4,
+            roll_hour: 0,
+        };
+        // 2024-07-09 02:00 UTC is still the 8th in New York
+        assert_eq!(date_label(day.period(1720490400)), "20240708");
+    }
+
+    #[test]
+    fn test_rotate_compressed_and_read_back() {
+        let dir = temp_dir("disk-zstd");
+        let config = DiskSinkConfig {
+            path: dir.join("ticks.log").to_string_lossy().to_string(),
+            rotation: Rotation::Size { bytes: 64 },
+            compression: Compression::Zstd { level: 3 },
+            ..Default::default()
+        };
+        let mut sink = DiskSink::from_config(&config, "0").unwrap();
+        for i in 0..10u32 {
+            sink.exec(&vec![i; 8], &MessagePackFormater).unwrap();
         }
+        drop(sink);
+        let files = DiskReader::files(&config.base_path("0")).unwrap();
+        assert!(files.len() > 1);
+        assert!(files[0].to_string_lossy().ends_with(".log.zst"));
+        let records = files
+            .iter()
+            .flat_map(|path| DiskReader::open(path, Framing::LengthPrefixed).unwrap())
+            .map(|record| rmp_serde::from_slice::<Vec<u32>>(&record.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records, (0..10u32).map(|i| vec![i; 8]).collect::<Vec<_>>());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_single_writer_gzip() {
+        let dir = temp_dir("disk-gzip");
+        let config = DiskSinkConfig {
+            path: dir.join("audit.log").to_string_lossy().to_string(),
+            files: DiskFiles::Single,
+            compression: Compression::Gzip { level: 6 },
+            fsync: FsyncPolicy::Always,
+            ..Default::default()
+        };
+        let shutdown = Shutdown::default();
+        let mut a = DiskSink::from_config(&config, "0")
+            .unwrap()
+            .with_shutdown(&shutdown);
+        let mut b = DiskSink::from_config(&config, "1")
+            .unwrap()
+            .with_shutdown(&shutdown);
+        a.exec(&"a", &JsonFormater).unwrap();
+        b.exec(&"b", &JsonFormater).unwrap();
+        // the workers are still running at shutdown
+        shutdown.close().unwrap();
+        let path = dir.join("audit.log.gz");
+        let records = DiskReader::open(&path, Framing::Newline)
+            .unwrap()
+            .map(|record| String::from_utf8(record.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records, vec![r#""a""#, r#""b""#]);
+        drop((a, b));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_auto_framing_length_prefixes_yaml() {
+        let dir = temp_dir("disk-yaml");
+        let config = DiskSinkConfig {
+            path: dir.join("quotes.log").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut sink = DiskSink::from_config(&config, "0").unwrap();
+        let quote = HashMap::from([("bid", 1), ("ask", 2)]);
+        sink.exec(&quote, &YamlFormater).unwrap();
+        sink.exec(&quote, &YamlFormater).unwrap();
+        drop(sink);
+        let records = DiskReader::open(&config.base_path("0"), Framing::LengthPrefixed)
+            .unwrap()
+            .map(|record| serde_yaml::from_slice(&record.unwrap()).unwrap())
+            .collect::<Vec<HashMap<String, i32>>>();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1]["ask"], 2);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
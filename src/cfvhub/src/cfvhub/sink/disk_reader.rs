@@ -0,0 +1,130 @@
+use super::disk::Framing;
+use super::{DiskSinkReadFileSnafu, DiskSinkReadSnafu, SinkError};
+use snafu::ResultExt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+/// Iterates the records of one `DiskSink` file, decompressing by extension.
+pub struct DiskReader {
+    reader: Box<dyn BufRead>,
+    framing: Framing,
+    done: bool,
+}
+
+impl DiskReader {
+    /// `Framing::Auto` reads newline framing, which the sink only picks for json. Pass
+    /// `LengthPrefixed` for the other formaters.
+    pub fn open(path: &Path, framing: Framing) -> Result<Self, SinkError> {
+        let file = File::open(path).context(DiskSinkReadFileSnafu)?;
+        let reader: Box<dyn BufRead> = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("zst") => Box::new(BufReader::new(
+                zstd::stream::read::Decoder::new(file).context(DiskSinkReadFileSnafu)?,
+            )),
+            Some("gz") => Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(file))),
+            _ => Box::new(BufReader::new(file)),
+        };
+        Ok(Self {
+            reader,
+            framing,
+            done: false,
+        })
+    }
+
+    /// Files written for `base` (see `DiskSinkConfig::base_path`) in the order written.
+    pub fn files(base: &Path) -> Result<Vec<PathBuf>, SinkError> {
+        let dir = match base.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let stem = base.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+        let ext = base.extension().and_then(|s| s.to_str());
+        let name = base.file_name().and_then(|s| s.to_str()).unwrap_or("");
+        let mut files = std::fs::read_dir(dir)
+            .context(DiskSinkReadFileSnafu)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                let file_name = path.file_name().and_then(|s| s.to_str()).unwrap_or("");
+                let file_name = file_name
+                    .strip_suffix(".zst")
+                    .or_else(|| file_name.strip_suffix(".gz"))
+                    .unwrap_or(file_name);
+                if file_name == name {
+                    return true;
+                }
+                // stem.<label>.ext with a single label, other workers have one more
+                let label = match ext {
+                    Some(ext) => file_name
+                        .strip_prefix(stem)
+                        .and_then(|s| s.strip_suffix(ext))
+                        .and_then(|s| s.strip_prefix('.'))
+                        .and_then(|s| s.strip_suffix('.')),
+                    None => file_name
+                        .strip_prefix(stem)
+                        .and_then(|s| s.strip_prefix('.')),
+                };
+                label.is_some_and(|label| {
+                    !label.is_empty() && label.bytes().all(|b| b.is_ascii_digit() || b == b'-')
+                })
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        Ok(files)
+    }
+
+    fn read_length_prefixed(&mut self) -> Result<Option<Vec<u8>>, SinkError> {
+        let mut len = [0u8; 4];
+        match self.reader.read_exact(&mut len) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e).context(DiskSinkReadSnafu),
+        }
+        let mut record = vec![0u8; u32::from_le_bytes(len) as usize];
+        // a record cut short by a crash ends the file with an error
+        self.reader
+            .read_exact(&mut record)
+            .context(DiskSinkReadSnafu)?;
+        Ok(Some(record))
+    }
+
+    fn read_line(&mut self) -> Result<Option<Vec<u8>>, SinkError> {
+        let mut record = vec![];
+        if self
+            .reader
+            .read_until(b'\n', &mut record)
+            .context(DiskSinkReadSnafu)?
+            == 0
+        {
+            return Ok(None);
+        }
+        if record.last() == Some(&b'\n') {
+            record.pop();
+        }
+        Ok(Some(record))
+    }
+}
+
+impl Iterator for DiskReader {
+    type Item = Result<Vec<u8>, SinkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = match self.framing {
+            Framing::LengthPrefixed => self.read_length_prefixed(),
+            Framing::Newline | Framing::Auto => self.read_line(),
+        };
+        match record {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
@@ -0,0 +1,627 @@
+use crossbeam_channel::{bounded, Sender, TrySendError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use super::{
+    BatchLayoutSnafu, BehindSnafu, ConsoleSink, DeadLetterSink, Dest, DiskSink, DiskSinkConfig,
+    DoNothingSink, FormaterExt, MulticastSink, MulticastSinkConfig, PanickedSnafu, ParquetMessage,
+    ParquetSink, ParquetSinkConfig, RedisSink, RedisSinkConfig, RetryPolicy, RetrySink, Shutdown,
+    SinkError, SinkExt, SolaceSink, SolaceSinkConfig, WebSocketSink, WebSocketSinkConfig,
+};
+use crate::batch::{Batch, BatchConfig, BatchLayout, BatchSink};
+use crate::envelope::{Envelope, EnvelopeConfig, EnvelopeFormater};
+use crate::formater::{
+    JsonFormater, MessagePackFormater, ProtobufFormater, TomlFormater, YamlFormater,
+};
+use crate::metrics::{SinkCounters, METRICS};
+use crate::proto::ProtoMessage;
+use crate::queue::RateLimiter;
+
+/// Messages a branch accepts, everything when unset.
+pub type SinkFilter<In> = Arc<dyn Fn(&In) -> bool + Send + Sync>;
+
+type BranchExec<In> = Box<dyn FnMut(&In) -> Result<(), SinkError> + Send + Sync>;
+
+enum Target<In> {
+    Inline(BranchExec<In>),
+    // the worker thread owns the sink, it stops when the sender is dropped
+    Detached(Sender<In>),
+}
+
+/// One sink of a `FanOutSink` with its own formater and optional filter.
+pub struct SinkBranch<In> {
+    name: String,
+    filter: Option<SinkFilter<In>>,
+    target: Target<In>,
+    // created with the first dropped message, the fan out passes the id
+    counters: Option<SinkCounters>,
+    log_limit: RateLimiter,
+}
+
+impl<In: 'static> SinkBranch<In> {
+    pub fn new<S, F>(name: &str, mut sink: S, formater: F) -> Self
+    where
+        In: Serialize,
+        S: SinkExt<In> + Send + Sync + 'static,
+        F: FormaterExt<In> + Send + Sync + 'static,
+    {
+        Self::from_exec(name, move |input| sink.exec(input, &formater))
+    }
+
+    /// A branch around a sink that formats on its own, e.g. a `BatchSink`.
+    pub fn from_exec<E>(name: &str, exec: E) -> Self
+    where
+        E: FnMut(&In) -> Result<(), SinkError> + Send + Sync + 'static,
+    {
+        Self {
+            name: name.to_string(),
+            filter: None,
+            target: Target::Inline(Box::new(exec)),
+            counters: None,
+            log_limit: RateLimiter::default(),
+        }
+    }
+
+    pub fn with_filter(mut self, filter: SinkFilter<In>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Run the sink on its own thread behind a queue of `size` messages, so a slow sink
+    /// only delays itself. Messages are dropped while the queue is full.
+    pub fn detached(self, size: usize) -> Self
+    where
+        In: Clone + Send,
+    {
+        let mut exec = match self.target {
+            Target::Inline(exec) => exec,
+            Target::Detached(_) => return self,
+        };
+        let (send, recv) = bounded::<In>(size);
+        let name = self.name.clone();
+        std::thread::spawn(move || {
+            let log_limit = RateLimiter::default();
+            for input in recv.iter() {
+                if let Err(e) = exec_isolated(&name, &mut exec, &input) {
+                    if log_limit.check() {
+                        error!("fan out branch {} error: {}", name, e);
+                    }
+                }
+            }
+            info!("fan out branch {} stopped", name);
+        });
+        Self {
+            target: Target::Detached(send),
+            ..self
+        }
+    }
+
+    fn exec(&mut self, id: &str, input: &In) -> Result<(), SinkError>
+    where
+        In: Clone,
+    {
+        if let Some(filter) = &self.filter {
+            if !filter(input) {
+                return Ok(());
+            }
+        }
+        match &mut self.target {
+            Target::Inline(exec) => exec_isolated(&self.name, exec, input),
+            Target::Detached(send) => match send.try_send(input.clone()) {
+                Ok(_) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    self.counters
+                        .get_or_insert_with(|| METRICS.sink_counters(&self.name, id))
+                        .outcome("dropped");
+                    if self.log_limit.check() {
+                        warn!("fan out branch {} is behind, dropping messages", self.name);
+                    }
+                    BehindSnafu { name: &self.name }.fail()
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    if self.log_limit.check() {
+                        error!("fan out branch {} worker is gone", self.name);
+                    }
+                    PanickedSnafu { name: &self.name }.fail()
+                }
+            },
+        }
+    }
+}
+
+// a panicking sink must not take the other branches down with it
+fn exec_isolated<In>(name: &str, exec: &mut BranchExec<In>, input: &In) -> Result<(), SinkError> {
+    catch_unwind(AssertUnwindSafe(|| exec(input))).unwrap_or_else(|_| {
+        error!("fan out branch {} panicked", name);
+        PanickedSnafu { name }.fail()
+    })
+}
+
+/// Sends every message to each branch in turn. A failing branch does not stop the
+/// others, the first error is returned. Retry inside the branches, not around the
+/// fan out, or the healthy branches get duplicates.
+pub struct FanOutSink<In> {
+    id: String,
+    branches: Vec<SinkBranch<In>>,
+}
+
+impl<In> Default for FanOutSink<In> {
+    fn default() -> Self {
+        Self::new("default")
+    }
+}
+
+impl<In> FanOutSink<In> {
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            branches: vec![],
+        }
+    }
+
+    pub fn with_branch(mut self, branch: SinkBranch<In>) -> Self {
+        self.branches.push(branch);
+        self
+    }
+}
+
+impl<In> FanOutSink<In>
+where
+    In: Serialize + Dest + ProtoMessage + ParquetMessage + Clone + Send + 'static,
+{
+    /// The sinks that hold data until they are closed are added to `shutdown`.
+    pub fn from_config(
+        id: &str,
+        configs: &[SinkConfig],
+        shutdown: &Shutdown,
+    ) -> Result<Self, SinkError> {
+        configs.iter().try_fold(Self::new(id), |sink, config| {
+            Ok(sink.with_branch(config.build(id, shutdown)?))
+        })
+    }
+}
+
+// the branches carry their own formaters, the one given to exec is unused
+impl<In: Serialize + Clone + 'static> SinkExt<In> for FanOutSink<In> {
+    /// No branches, add them with `with_branch` or use `from_config`.
+    fn build(id: &str) -> Self {
+        Self::new(id)
+    }
+
+    fn exec(&mut self, input: &In, _formater: &impl FormaterExt<In>) -> Result<(), SinkError> {
+        let mut r = Ok(());
+        for branch in self.branches.iter_mut() {
+            if let Err(e) = branch.exec(&self.id, input) {
+                if r.is_ok() {
+                    r = Err(SinkError::Branch {
+                        name: branch.name.clone(),
+                        source: Box::new(e),
+                    });
+                }
+            }
+        }
+        r
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SinkKind {
+    Solace,
+    Disk,
+    Parquet,
+    #[serde(rename = "websocket")]
+    WebSocket,
+    Multicast,
+    Redis,
+    Console,
+    Nothing,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FormatKind {
+    #[default]
+    Json,
+    Yaml,
+    Toml,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    /// the schemas in `proto/`, the messages and the envelope must be in them
+    Protobuf,
+}
+
+impl FormatKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FormatKind::Json => "json",
+            FormatKind::Yaml => "yaml",
+            FormatKind::Toml => "toml",
+            FormatKind::MessagePack => "msgpack",
+            FormatKind::Protobuf => "protobuf",
+        }
+    }
+}
+
+/// A fan out branch as written in the pipeline config, e.g. in toml
+/// `[[sinks]]` `name = "audit"` `kind = "disk"` `queue = 65536` and `[sinks.disk]` `path = "audit.log"`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SinkConfig {
+    pub name: String,
+    pub kind: SinkKind,
+    #[serde(default)]
+    pub format: FormatKind,
+    /// wrap the messages with seq, producer, origin and schema version
+    pub envelope: Option<EnvelopeConfig>,
+    /// send the messages of a topic together, solace and disk only
+    pub batch: Option<BatchConfig>,
+    /// solace session, delivery mode and user properties, the `SOLACE_*` env when unset
+    pub solace: Option<SolaceSinkConfig>,
+    /// disk sink file, framing, rotation and compression, `DISK_SINK_PATH` when unset
+    pub disk: Option<DiskSinkConfig>,
+    /// parquet sink root, flush and date partition, `PARQUET_SINK_PATH` when unset
+    pub parquet: Option<ParquetSinkConfig>,
+    /// websocket server address and slow client handling, `WS_SINK_ADDR` when unset
+    pub websocket: Option<WebSocketSinkConfig>,
+    /// multicast group, heartbeat and retransmission, `MULTICAST_SINK_ADDR` when unset
+    pub multicast: Option<MulticastSinkConfig>,
+    /// redis address, key prefix and publish, `REDIS_SINK_ADDR` when unset
+    pub redis: Option<RedisSinkConfig>,
+    /// run on its own thread with a queue of this many messages
+    pub queue: Option<usize>,
+    /// only messages with these partition keys (symbols), all when empty
+    #[serde(default)]
+    pub keys: Vec<String>,
+    /// retry retryable errors with this backoff
+    pub retry: Option<RetryPolicy>,
+    /// json lines file for the messages that still fail, with `retry` or a redis sink
+    pub dead_letter: Option<String>,
+}
+
+impl SinkConfig {
+    /// Connects or opens the sink of worker `id`, a sink that can not start fails the build,
+    /// and so does a batch layout the format can not be written in.
+    pub fn build<In>(&self, id: &str, shutdown: &Shutdown) -> Result<SinkBranch<In>, SinkError>
+    where
+        In: Serialize + Dest + ProtoMessage + ParquetMessage + Clone + Send + 'static,
+    {
+        self.check_batch_layout()?;
+        let branch = match self.kind {
+            SinkKind::Solace => {
+                let config = match &self.solace {
+                    Some(config) => config.clone(),
+                    None => SolaceSinkConfig::from_env()?,
+                };
+                self.with_batch(id, SolaceSink::from_config(&config, id)?, shutdown)?
+            }
+            SinkKind::Disk => {
+                let config = self.disk.clone().unwrap_or_default();
+                let sink = DiskSink::from_config(&config, id)?.with_shutdown(shutdown);
+                self.with_batch(id, sink, shutdown)?
+            }
+            SinkKind::Parquet => {
+                let config = self.parquet.clone().unwrap_or_default();
+                self.with_retry(id, ParquetSink::new(&config, id).with_shutdown(shutdown))?
+            }
+            SinkKind::WebSocket => {
+                let config = self.websocket.clone().unwrap_or_default();
+                self.with_retry(id, WebSocketSink::from_config(&config, id)?)?
+            }
+            SinkKind::Multicast => {
+                let config = self.multicast.clone().unwrap_or_default();
+                self.with_retry(id, MulticastSink::from_config(&config, id)?)?
+            }
+            SinkKind::Redis => {
+                let config = self.redis.clone().unwrap_or_default();
+                let sink = RedisSink::new(&config, id);
+                // for the error replies read after the exec of their message
+                let sink = match self.dead_letter_sink(id)? {
+                    Some(dead_letter) => sink.with_dead_letter(dead_letter),
+                    None => sink,
+                };
+                self.with_retry(id, sink)?
+            }
+            SinkKind::Console => self.with_retry(id, ConsoleSink::default())?,
+            SinkKind::Nothing => self.with_retry(id, DoNothingSink {})?,
+        };
+        let branch = if self.keys.is_empty() {
+            branch
+        } else {
+            let keys: HashSet<String> = self.keys.iter().cloned().collect();
+            branch.with_filter(Arc::new(move |input: &In| {
+                keys.contains(input.get_partition_key())
+            }))
+        };
+        Ok(match self.queue {
+            Some(size) => branch.detached(size),
+            None => branch,
+        })
+    }
+
+    // the layouts BatchFormater writes the format in
+    fn check_batch_layout(&self) -> Result<(), SinkError> {
+        let layout = match &self.batch {
+            Some(config) => config.layout,
+            None => return Ok(()),
+        };
+        let fits = match layout {
+            BatchLayout::Array => matches!(self.format, FormatKind::Json | FormatKind::MessagePack),
+            BatchLayout::Lines => {
+                !matches!(self.format, FormatKind::MessagePack | FormatKind::Protobuf)
+            }
+            BatchLayout::Delimited => true,
+        };
+        match fits {
+            true => Ok(()),
+            false => BatchLayoutSnafu {
+                name: &self.name,
+                format: self.format.as_str(),
+                layout: layout.as_str(),
+            }
+            .fail(),
+        }
+    }
+
+    fn with_batch<In, S>(
+        &self,
+        id: &str,
+        sink: S,
+        shutdown: &Shutdown,
+    ) -> Result<SinkBranch<In>, SinkError>
+    where
+        In: Serialize + Dest + ProtoMessage + Clone + Send + 'static,
+        S: SinkExt<In> + SinkExt<Batch<In>> + Send + Sync + 'static,
+    {
+        let config = match &self.batch {
+            Some(config) => config,
+            None => return self.with_retry(id, sink),
+        };
+        // the batches are retried and dead lettered, not the messages
+        Ok(match &self.retry {
+            Some(policy) => {
+                let sink = self.retry_sink(id, sink, policy)?;
+                self.with_format(
+                    id,
+                    Batched {
+                        config,
+                        sink,
+                        shutdown,
+                    },
+                )
+            }
+            None => self.with_format(
+                id,
+                Batched {
+                    config,
+                    sink,
+                    shutdown,
+                },
+            ),
+        })
+    }
+
+    fn with_retry<In, S>(&self, id: &str, sink: S) -> Result<SinkBranch<In>, SinkError>
+    where
+        In: Serialize + Dest + ProtoMessage + 'static,
+        S: SinkExt<In> + Send + Sync + 'static,
+    {
+        Ok(match &self.retry {
+            Some(policy) => self.with_format(id, Plain(self.retry_sink(id, sink, policy)?)),
+            None => self.with_format(id, Plain(sink)),
+        })
+    }
+
+    fn retry_sink<S>(
+        &self,
+        id: &str,
+        sink: S,
+        policy: &RetryPolicy,
+    ) -> Result<RetrySink<S>, SinkError> {
+        let sink = RetrySink::new(&self.name, id, sink).with_policy(policy.clone());
+        Ok(match self.dead_letter_sink(id)? {
+            Some(dead_letter) => sink.with_dead_letter(dead_letter),
+            None => sink,
+        })
+    }
+
+    fn dead_letter_sink(&self, id: &str) -> Result<Option<DeadLetterSink>, SinkError> {
+        match &self.dead_letter {
+            Some(path) => {
+                // one file per worker, they write concurrently
+                let path = format!("{}.{}", path, id);
+                Ok(Some(DeadLetterSink::new(path.as_ref())?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn with_format<In, S>(&self, id: &str, sink: S) -> SinkBranch<In>
+    where
+        In: Serialize + Dest + ProtoMessage + 'static,
+        S: BranchSink<In>,
+    {
+        match self.format {
+            FormatKind::Json => self.with_envelope(id, sink, JsonFormater),
+            FormatKind::Yaml => self.with_envelope(id, sink, YamlFormater),
+            FormatKind::Toml => self.with_envelope(id, sink, TomlFormater),
+            FormatKind::MessagePack => self.with_envelope(id, sink, MessagePackFormater),
+            FormatKind::Protobuf => self.with_envelope(id, sink, ProtobufFormater),
+        }
+    }
+
+    fn with_envelope<In, S, F>(&self, id: &str, sink: S, formater: F) -> SinkBranch<In>
+    where
+        In: Serialize + Dest + ProtoMessage + 'static,
+        S: BranchSink<In>,
+        F: FormaterExt<In> + for<'a> FormaterExt<Envelope<'a, In>> + Send + Sync + 'static,
+    {
+        match &self.envelope {
+            Some(config) => {
+                let formater = EnvelopeFormater::new(formater, &config.producer(id));
+                sink.branch(&self.name, formater)
+            }
+            None => sink.branch(&self.name, formater),
+        }
+    }
+}
+
+// the sink of a branch, put together with the formater of the config
+trait BranchSink<In: Serialize> {
+    fn branch<F>(self, name: &str, formater: F) -> SinkBranch<In>
+    where
+        F: FormaterExt<In> + Send + Sync + 'static;
+}
+
+// formated message by message
+struct Plain<S>(S);
+
+impl<In, S> BranchSink<In> for Plain<S>
+where
+    In: Serialize + 'static,
+    S: SinkExt<In> + Send + Sync + 'static,
+{
+    fn branch<F>(self, name: &str, formater: F) -> SinkBranch<In>
+    where
+        F: FormaterExt<In> + Send + Sync + 'static,
+    {
+        SinkBranch::new(name, self.0, formater)
+    }
+}
+
+// the batch sink owns the formater, its flush thread formats the batches
+struct Batched<'a, S> {
+    config: &'a BatchConfig,
+    sink: S,
+    shutdown: &'a Shutdown,
+}
+
+impl<In, S> BranchSink<In> for Batched<'_, S>
+where
+    In: Serialize + Dest + Clone + Send + 'static,
+    S: SinkExt<Batch<In>> + Send + 'static,
+{
+    fn branch<F>(self, name: &str, formater: F) -> SinkBranch<In>
+    where
+        F: FormaterExt<In> + Send + Sync + 'static,
+    {
+        let mut sink =
+            BatchSink::new(self.config, self.sink, formater).with_shutdown(self.shutdown);
+        SinkBranch::from_exec(name, move |input| sink.exec(input))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formater::Formated;
+    use crate::test_util::{msg, Msg};
+    use std::sync::Mutex;
+
+    // records formated messages, sleeps or panics on request
+    struct RecordSink {
+        out: Arc<Mutex<Vec<String>>>,
+        delay: std::time::Duration,
+    }
+
+    impl SinkExt<Msg> for RecordSink {
+        fn build(_id: &str) -> Self {
+            unimplemented!()
+        }
+
+        fn exec(&mut self, input: &Msg, formater: &impl FormaterExt<Msg>) -> Result<(), SinkError> {
+            if input.dest == "panic" {
+                panic!("record sink panic");
+            }
+            std::thread::sleep(self.delay);
+            if let Ok(Formated::String(s)) = formater.format(input) {
+                self.out.lock().unwrap().push(s);
+            }
+            Ok(())
+        }
+    }
+
+    fn record(delay_ms: u64) -> (RecordSink, Arc<Mutex<Vec<String>>>) {
+        let out = Arc::new(Mutex::new(vec![]));
+        let sink = RecordSink {
+            out: out.clone(),
+            delay: std::time::Duration::from_millis(delay_ms),
+        };
+        (sink, out)
+    }
+
+    #[test]
+    fn test_fan_out_isolation() {
+        let (fast, fast_out) = record(0);
+        let (slow, slow_out) = record(200);
+        let (filtered, filtered_out) = record(0);
+        let mut sink = FanOutSink::new("0")
+            .with_branch(SinkBranch::new("fast", fast, YamlFormater))
+            .with_branch(SinkBranch::new("slow", slow, JsonFormater).detached(1))
+            .with_branch(
+                SinkBranch::new("filtered", filtered, JsonFormater)
+                    .with_filter(Arc::new(|m: &Msg| m.dest == "AAPL")),
+            );
+        let start = std::time::Instant::now();
+        let errors = ["AAPL", "panic", "NVDA", "AMD", "TSLA"]
+            .iter()
+            .filter_map(|key| sink.exec(&msg(key, 1.0), &JsonFormater).err())
+            .collect::<Vec<_>>();
+        assert!(errors.iter().any(|e| matches!(
+            e,
+            SinkError::Branch { name, source } if name == "fast" && !source.is_retryable()
+        )));
+        // the slow branch takes one message at a time and drops the rest
+        assert!(start.elapsed() < std::time::Duration::from_millis(200));
+        assert_eq!(fast_out.lock().unwrap().len(), 4);
+        assert_eq!(
+            *filtered_out.lock().unwrap(),
+            vec![r#"{"dest":"AAPL","close":1.0}"#]
+        );
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        assert!(slow_out.lock().unwrap().len() < 4);
+        assert_eq!(
+            slow_out.lock().unwrap()[0],
+            r#"{"dest":"AAPL","close":1.0}"#
+        );
+    }
+
+    #[test]
+    fn test_sink_config() {
+        let configs: Vec<SinkConfig> = serde_json::from_str(
+            r#"[
+                {"name": "console", "kind": "console", "format": "msgpack", "keys": ["AAPL"]},
+                {"name": "nothing", "kind": "nothing", "queue": 16}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(configs[0].format, FormatKind::MessagePack);
+        assert_eq!(configs[1].format, FormatKind::Json);
+        let shutdown = Shutdown::default();
+        let mut sink = FanOutSink::<Msg>::from_config("0", &configs, &shutdown).unwrap();
+        assert_eq!(sink.branches.len(), 2);
+        assert!(sink.exec(&msg("NVDA", 1.0), &JsonFormater).is_ok());
+
+        // a branch that can not start fails the whole fan out
+        let configs: Vec<SinkConfig> = serde_json::from_str(
+            r#"[
+                {"name": "console", "kind": "console"},
+                {"name": "ws", "kind": "websocket", "websocket": {"addr": "nowhere"}}
+            ]"#,
+        )
+        .unwrap();
+        assert!(FanOutSink::<Msg>::from_config("0", &configs, &shutdown).is_err());
+
+        // protobuf has no array to batch into
+        let configs: Vec<SinkConfig> = serde_json::from_str(
+            r#"[{"name": "audit", "kind": "nothing", "format": "protobuf", "batch": {}}]"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            FanOutSink::<Msg>::from_config("0", &configs, &shutdown),
+            Err(SinkError::BatchLayout { .. })
+        ));
+    }
+}
@@ -1,4 +1,6 @@
-use super::formater::{Formated, FormaterExt};
+use super::envelope::Meta;
+use super::formater::{FormatError, Formated, FormaterExt};
+use rsolace::types::SolClientReturnCode;
 use serde::Serialize;
 use snafu::prelude::Snafu;
 use std::io;
@@ -10,6 +12,94 @@ pub enum SinkError {
     DiskSinkReadFile { source: io::Error },
     #[snafu(display("DiskSink Path Error: {}", source))]
     DiskSinkPath { source: std::convert::Infallible },
+    #[snafu(display("DiskSink Read Error: {}", source))]
+    DiskSinkRead { source: io::Error },
+    #[snafu(display("DiskSink Write Error: {}", source))]
+    DiskSinkWrite { source: io::Error },
+    #[snafu(display("Sink Format Error: {}", source))]
+    Format { source: FormatError },
+    #[snafu(display("SolaceSink Send Error: {:?}", code))]
+    SolaceSend { code: SolClientReturnCode },
+    #[snafu(display("SolaceSink Message Error: {}", message))]
+    SolaceMsg { message: String },
+    #[snafu(display("SolaceSink Client Error: {}", message))]
+    SolaceClient { message: String },
+    #[snafu(display("SolaceSink Config {} has invalid value {}", name, value))]
+    SolaceConfig { name: String, value: String },
+    #[snafu(display("Sink {} Panicked", name))]
+    Panicked { name: String },
+    #[snafu(display("Sink {} is behind, message dropped", name))]
+    Behind { name: String },
+    #[snafu(display("Dead Letter Open Error {}: {}", path.display(), source))]
+    DeadLetterOpen {
+        path: std::path::PathBuf,
+        source: io::Error,
+    },
+    #[snafu(display("Dead Letter Encode Error: {}", source))]
+    DeadLetterEncode { source: serde_json::Error },
+    #[snafu(display("Sink {} can not batch {} messages as {}", name, format, layout))]
+    BatchLayout {
+        name: String,
+        format: String,
+        layout: String,
+    },
+    #[snafu(display("Sink {} Error: {}", name, source))]
+    Branch { name: String, source: Box<SinkError> },
+    #[snafu(display("Row Encode Error: {}", source))]
+    Row { source: row::RowError },
+    #[snafu(display("Arrow Error: {}", source))]
+    Arrow { source: arrow_schema::ArrowError },
+    #[snafu(display("Parquet Error: {}", source))]
+    Parquet { source: ::parquet::errors::ParquetError },
+    #[snafu(display("ParquetSink row does not match the schema of {}", path.display()))]
+    ParquetSchema { path: std::path::PathBuf },
+    #[snafu(display("ParquetSink column {} of {} can not hold {:?}", column, path.display(), cell))]
+    ParquetColumn {
+        path: std::path::PathBuf,
+        column: String,
+        cell: row::Cell,
+    },
+    #[snafu(display("WebSocketSink Bind Error: {}", source))]
+    WebSocketBind { source: io::Error },
+    #[snafu(display("WebSocketSink {} is already serving with other settings", addr))]
+    WebSocketConfig { addr: String },
+    #[snafu(display("MulticastSink Io Error: {}", source))]
+    Multicast { source: io::Error },
+    #[snafu(display("MulticastSink channel {} is taken, the workers need distinct ids", channel))]
+    MulticastChannel { channel: u16 },
+    #[snafu(display("MulticastSink datagram of {} bytes is too large", len))]
+    MulticastTooLarge { len: usize },
+    #[snafu(display("RedisSink Io Error: {}", source))]
+    Redis { source: io::Error },
+    #[snafu(display("RedisSink Reply Error: {}", message))]
+    RedisReply { message: String },
+}
+
+impl SinkError {
+    /// Whether sending the same message again may succeed, e.g. during a broker outage.
+    /// Format and setup errors are fatal.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            SinkError::DiskSinkWrite { source } => !matches!(
+                source.kind(),
+                io::ErrorKind::PermissionDenied | io::ErrorKind::InvalidInput
+            ),
+            SinkError::SolaceSend { code } => matches!(
+                code,
+                SolClientReturnCode::WouldBlock
+                    | SolClientReturnCode::NotReady
+                    | SolClientReturnCode::InProgress
+                    | SolClientReturnCode::Fail
+            ),
+            SinkError::Multicast { source } => source.kind() == io::ErrorKind::WouldBlock,
+            // reconnects on the next attempt
+            SinkError::Redis { .. } => true,
+            // e.g. WRONGTYPE, the same command gets the same reply
+            SinkError::RedisReply { .. } => false,
+            SinkError::Branch { source, .. } => source.is_retryable(),
+            _ => false,
+        }
+    }
 }
 
 // pub trait SinkConfig {
@@ -23,7 +113,7 @@ where
 {
     // type In;
     // type F;
-    fn exec(&mut self, input: &In, formater: &impl FormaterExt<In>);
+    fn exec(&mut self, input: &In, formater: &impl FormaterExt<In>) -> Result<(), SinkError>;
     // fn format(&self, input: &Self::In) -> Self::F;
     // fn build(config: &dyn SinkConfig) -> Self;
     fn build(id: &str) -> Self;
@@ -31,13 +121,43 @@ where
 
 pub trait Dest {
     fn get_dest(&self) -> &str;
+    /// Messages with the same key are handled by one sink worker in order.
+    fn get_partition_key(&self) -> &str {
+        self.get_dest()
+    }
+    /// Envelope fields, none when the message has no room for them.
+    fn get_meta(&self) -> Option<&Meta> {
+        None
+    }
+    fn meta_mut(&mut self) -> Option<&mut Meta> {
+        None
+    }
 }
 
 pub mod abstain;
 pub mod console;
 pub mod disk;
+pub mod disk_reader;
+pub mod fanout;
+pub mod multicast;
+pub mod multicast_reader;
+pub mod parquet;
+pub mod redis;
+pub mod retry;
+pub mod row;
+pub mod shutdown;
 pub mod solace;
+pub mod websocket;
 pub use abstain::DoNothingSink;
 pub use console::ConsoleSink;
-pub use disk::DiskSink;
-pub use solace::SolaceSink;
+pub use disk::{DiskSink, DiskSinkConfig};
+pub use disk_reader::DiskReader;
+pub use fanout::{FanOutSink, SinkBranch, SinkConfig};
+pub use multicast::{MulticastSink, MulticastSinkConfig};
+pub use multicast_reader::{MulticastReader, Received};
+pub use self::parquet::{ParquetMessage, ParquetSink, ParquetSinkConfig};
+pub use self::redis::{RedisSink, RedisSinkConfig};
+pub use retry::{DeadLetterSink, RetryPolicy, RetrySink};
+pub use shutdown::Shutdown;
+pub use solace::{SolaceSink, SolaceSinkConfig};
+pub use websocket::{WebSocketSink, WebSocketSinkConfig};
@@ -0,0 +1,491 @@
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use super::{
+    Dest, FormatSnafu, Formated, FormaterExt, MulticastChannelSnafu, MulticastSnafu,
+    MulticastTooLargeSnafu, SinkError, SinkExt,
+};
+use crate::latency::now_ns;
+use crate::metrics::{SinkCounters, METRICS};
+
+pub const MAGIC: u16 = 0xcf5a;
+pub const VERSION: u8 = 1;
+/// magic, version, kind, channel, session and seq
+pub const HEADER_LEN: usize = 18;
+/// largest udp payload over ipv4
+pub const MAX_DATAGRAM: usize = 65507;
+/// channel, session, first and last seq
+pub const RETRANSMIT_REQUEST_LEN: usize = 22;
+
+fn default_group() -> String {
+    dotenvy::var("MULTICAST_SINK_ADDR").unwrap_or_else(|_| "239.255.0.1:30001".to_string())
+}
+
+fn default_interface() -> Ipv4Addr {
+    Ipv4Addr::UNSPECIFIED
+}
+
+fn default_ttl() -> u32 {
+    1
+}
+
+fn default_heartbeat_ms() -> u64 {
+    1000
+}
+
+fn default_ring_size() -> usize {
+    65536
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MulticastSinkConfig {
+    /// multicast group and port, a unicast address works too
+    #[serde(default = "default_group")]
+    pub group: String,
+    #[serde(default = "default_interface")]
+    pub interface: Ipv4Addr,
+    #[serde(default = "default_ttl")]
+    pub ttl: u32,
+    /// a heartbeat with the last seq is sent when a channel is idle this long
+    #[serde(default = "default_heartbeat_ms")]
+    pub heartbeat_ms: u64,
+    /// datagrams per channel kept for retransmission
+    #[serde(default = "default_ring_size")]
+    pub ring_size: usize,
+    /// tcp address of the retransmission service, none disables it
+    pub retransmit: Option<String>,
+}
+
+impl Default for MulticastSinkConfig {
+    fn default() -> Self {
+        Self {
+            group: default_group(),
+            interface: default_interface(),
+            ttl: default_ttl(),
+            heartbeat_ms: default_heartbeat_ms(),
+            ring_size: default_ring_size(),
+            retransmit: dotenvy::var("MULTICAST_RETRANSMIT_ADDR").ok(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum DatagramKind {
+    Data = 0,
+    /// `seq` is the last data seq of the channel
+    Heartbeat = 1,
+}
+
+/// One datagram, little endian header followed by the topic and the formated data.
+/// `session` changes when the producer restarts and seq starts over at 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Datagram {
+    pub kind: DatagramKind,
+    pub channel: u16,
+    pub session: u32,
+    pub seq: u64,
+    pub topic: String,
+    pub data: Vec<u8>,
+}
+
+impl Datagram {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + 2 + self.topic.len() + self.data.len());
+        buf.extend_from_slice(&MAGIC.to_le_bytes());
+        buf.push(VERSION);
+        buf.push(self.kind as u8);
+        buf.extend_from_slice(&self.channel.to_le_bytes());
+        buf.extend_from_slice(&self.session.to_le_bytes());
+        buf.extend_from_slice(&self.seq.to_le_bytes());
+        if self.kind == DatagramKind::Data {
+            buf.extend_from_slice(&(self.topic.len() as u16).to_le_bytes());
+            buf.extend_from_slice(self.topic.as_bytes());
+            buf.extend_from_slice(&self.data);
+        }
+        buf
+    }
+
+    /// None for foreign or truncated datagrams.
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_LEN
+            || u16::from_le_bytes([buf[0], buf[1]]) != MAGIC
+            || buf[2] != VERSION
+        {
+            return None;
+        }
+        let kind = match buf[3] {
+            0 => DatagramKind::Data,
+            1 => DatagramKind::Heartbeat,
+            _ => return None,
+        };
+        let mut datagram = Self {
+            kind,
+            channel: u16::from_le_bytes(buf[4..6].try_into().ok()?),
+            session: u32::from_le_bytes(buf[6..10].try_into().ok()?),
+            seq: u64::from_le_bytes(buf[10..18].try_into().ok()?),
+            topic: String::new(),
+            data: vec![],
+        };
+        if kind == DatagramKind::Data {
+            let body = &buf[HEADER_LEN..];
+            let topic_len = u16::from_le_bytes(body.get(..2)?.try_into().ok()?) as usize;
+            datagram.topic = String::from_utf8(body.get(2..2 + topic_len)?.to_vec()).ok()?;
+            datagram.data = body[2 + topic_len..].to_vec();
+        }
+        Some(datagram)
+    }
+}
+
+/// The last `size` encoded datagrams of a channel.
+struct Ring {
+    session: u32,
+    size: usize,
+    frames: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl Ring {
+    fn push(&mut self, seq: u64, frame: Vec<u8>) {
+        if self.frames.len() >= self.size {
+            self.frames.pop_front();
+        }
+        self.frames.push_back((seq, frame));
+    }
+
+    fn range(&self, session: u32, from: u64, to: u64) -> Vec<Vec<u8>> {
+        if session != self.session {
+            return vec![];
+        }
+        self.frames
+            .iter()
+            .filter(|(seq, _)| (from..=to).contains(seq))
+            .map(|(_, frame)| frame.clone())
+            .collect()
+    }
+}
+
+/// Answers gap fill requests over tcp from the rings of every channel in the process.
+/// A request is channel u16, session u32, first and last seq u64, little endian.
+/// The reply is the datagrams still in the ring, each prefixed by its u32 length,
+/// then a zero length.
+pub struct RetransmitServer {
+    addr: SocketAddr,
+    rings: DashMap<u16, Arc<Mutex<Ring>>>,
+}
+
+impl RetransmitServer {
+    pub fn bind(addr: &str) -> Result<Arc<Self>, SinkError> {
+        let listener = TcpListener::bind(addr).context(MulticastSnafu)?;
+        let server = Arc::new(Self {
+            addr: listener.local_addr().context(MulticastSnafu)?,
+            rings: DashMap::new(),
+        });
+        info!("multicast retransmit listening on {}", server.addr);
+        let accept = server.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let server = accept.clone();
+                        std::thread::spawn(move || {
+                            if let Err(e) = server.serve(stream) {
+                                warn!("multicast retransmit error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("multicast retransmit accept error: {}", e),
+                }
+            }
+        });
+        Ok(server)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // a channel has one sink, the ring of a dropped one is only held here
+    fn ring(&self, channel: u16, session: u32, size: usize) -> Result<Arc<Mutex<Ring>>, SinkError> {
+        let ring = Arc::new(Mutex::new(Ring {
+            session,
+            size: size.max(1),
+            frames: VecDeque::new(),
+        }));
+        match self.rings.entry(channel) {
+            Entry::Occupied(taken) if Arc::strong_count(taken.get()) > 1 => {
+                MulticastChannelSnafu { channel }.fail()
+            }
+            Entry::Occupied(mut dropped) => {
+                dropped.insert(ring.clone());
+                Ok(ring)
+            }
+            Entry::Vacant(vacant) => {
+                vacant.insert(ring.clone());
+                Ok(ring)
+            }
+        }
+    }
+
+    fn serve(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        let mut request = [0u8; RETRANSMIT_REQUEST_LEN];
+        loop {
+            match stream.read_exact(&mut request) {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            let channel = u16::from_le_bytes([request[0], request[1]]);
+            let session = u32::from_le_bytes(request[2..6].try_into().unwrap());
+            let from = u64::from_le_bytes(request[6..14].try_into().unwrap());
+            let to = u64::from_le_bytes(request[14..22].try_into().unwrap());
+            let frames = match self.rings.get(&channel) {
+                Some(ring) => ring.lock().unwrap().range(session, from, to),
+                None => vec![],
+            };
+            let mut reply = vec![];
+            for frame in frames {
+                reply.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+                reply.extend_from_slice(&frame);
+            }
+            reply.extend_from_slice(&0u32.to_le_bytes());
+            stream.write_all(&reply)?;
+        }
+    }
+}
+
+// one retransmit service per address for every worker in the process
+static RETRANSMIT_SERVERS: Lazy<Mutex<HashMap<String, Arc<RetransmitServer>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct ChannelState {
+    seq: u64,
+    last_send: Instant,
+}
+
+/// Publishes each message as one datagram on its own channel. Workers with a numeric
+/// id use it as the channel, so a symbol stays on one channel in order.
+pub struct MulticastSink {
+    id: String,
+    channel: u16,
+    session: u32,
+    group: SocketAddr,
+    socket: Arc<UdpSocket>,
+    state: Arc<Mutex<ChannelState>>,
+    ring: Option<Arc<Mutex<Ring>>>,
+    retransmit: Option<Arc<RetransmitServer>>,
+    counters: SinkCounters,
+}
+
+impl std::fmt::Debug for MulticastSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MulticastSink")
+            .field("id", &self.id)
+            .field("channel", &self.channel)
+            .field("group", &self.group)
+            .finish()
+    }
+}
+
+pub(crate) fn resolve(addr: &str) -> Result<SocketAddr, SinkError> {
+    addr.to_socket_addrs()
+        .context(MulticastSnafu)?
+        .next()
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::AddrNotAvailable))
+        .context(MulticastSnafu)
+}
+
+fn channel_of(id: &str) -> u16 {
+    id.parse().unwrap_or_else(|_| {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        id.hash(&mut hasher);
+        hasher.finish() as u16
+    })
+}
+
+impl MulticastSink {
+    pub fn from_config(config: &MulticastSinkConfig, id: &str) -> Result<Self, SinkError> {
+        let group = resolve(&config.group)?;
+        let socket = UdpSocket::bind((config.interface, 0)).context(MulticastSnafu)?;
+        if group.ip().is_multicast() {
+            socket
+                .set_multicast_ttl_v4(config.ttl)
+                .context(MulticastSnafu)?;
+            socket.set_multicast_loop_v4(true).context(MulticastSnafu)?;
+        }
+        let channel = channel_of(id);
+        // nanoseconds, so a restart within the same second is a new session too
+        let session = now_ns() as u32;
+        let retransmit = match &config.retransmit {
+            Some(addr) => {
+                let mut servers = RETRANSMIT_SERVERS.lock().unwrap();
+                let server = match servers.get(addr) {
+                    Some(server) => server.clone(),
+                    None => {
+                        let server = RetransmitServer::bind(addr)?;
+                        servers.insert(addr.clone(), server.clone());
+                        server
+                    }
+                };
+                Some(server)
+            }
+            None => None,
+        };
+        let ring = match &retransmit {
+            Some(server) => Some(server.ring(channel, session, config.ring_size)?),
+            None => None,
+        };
+        let sink = Self {
+            id: id.to_string(),
+            counters: METRICS.sink_counters("multicast", id),
+            channel,
+            session,
+            group,
+            socket: Arc::new(socket),
+            state: Arc::new(Mutex::new(ChannelState {
+                seq: 0,
+                last_send: Instant::now(),
+            })),
+            ring,
+            retransmit,
+        };
+        sink.spawn_heartbeat(Duration::from_millis(config.heartbeat_ms.max(1)));
+        Ok(sink)
+    }
+
+    pub fn channel(&self) -> u16 {
+        self.channel
+    }
+
+    pub fn retransmit_addr(&self) -> Option<SocketAddr> {
+        self.retransmit.as_ref().map(|server| server.local_addr())
+    }
+
+    // stops with the sink
+    fn spawn_heartbeat(&self, interval: Duration) {
+        let state = Arc::downgrade(&self.state);
+        let socket = self.socket.clone();
+        let group = self.group;
+        let mut heartbeat = Datagram {
+            kind: DatagramKind::Heartbeat,
+            channel: self.channel,
+            session: self.session,
+            seq: 0,
+            topic: String::new(),
+            data: vec![],
+        };
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval / 2);
+            let state = match Weak::upgrade(&state) {
+                Some(state) => state,
+                None => return,
+            };
+            let mut state = state.lock().unwrap();
+            if state.last_send.elapsed() < interval {
+                continue;
+            }
+            heartbeat.seq = state.seq;
+            if let Err(e) = socket.send_to(&heartbeat.encode(), group) {
+                warn!("multicast heartbeat error: {}", e);
+            }
+            state.last_send = Instant::now();
+        });
+    }
+
+    fn send(&self, topic: &str, data: Vec<u8>) -> Result<(), SinkError> {
+        let mut state = self.state.lock().unwrap();
+        // a failed send keeps the seq, so a retry does not open a gap
+        let datagram = Datagram {
+            kind: DatagramKind::Data,
+            channel: self.channel,
+            session: self.session,
+            seq: state.seq + 1,
+            topic: topic.to_string(),
+            data,
+        };
+        let frame = datagram.encode();
+        if frame.len() > MAX_DATAGRAM {
+            return MulticastTooLargeSnafu { len: frame.len() }.fail();
+        }
+        self.socket
+            .send_to(&frame, self.group)
+            .context(MulticastSnafu)?;
+        state.seq = datagram.seq;
+        state.last_send = Instant::now();
+        if let Some(ring) = &self.ring {
+            ring.lock().unwrap().push(datagram.seq, frame);
+        }
+        Ok(())
+    }
+}
+
+impl<In: Serialize + Dest> SinkExt<In> for MulticastSink {
+    /// Publishes to `MULTICAST_SINK_ADDR`, retransmits on `MULTICAST_RETRANSMIT_ADDR`.
+    fn build(id: &str) -> Self {
+        Self::from_config(&MulticastSinkConfig::default(), id).unwrap()
+    }
+
+    fn exec(&mut self, input: &In, formater: &impl FormaterExt<In>) -> Result<(), SinkError> {
+        let r = formater
+            .format(input)
+            .context(FormatSnafu)
+            .and_then(|formated| {
+                let data = match formated {
+                    Formated::String(s) => s.into_bytes(),
+                    Formated::Bytes(b) => b,
+                };
+                self.send(input.get_dest(), data)
+            });
+        self.counters.result(r.is_ok());
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_datagram_roundtrip() {
+        let datagram = Datagram {
+            kind: DatagramKind::Data,
+            channel: 3,
+            session: 7,
+            seq: 42,
+            topic: "api/V1/TIC/Q/AAPL".to_string(),
+            data: b"{\"close\":1.0}".to_vec(),
+        };
+        let buf = datagram.encode();
+        assert_eq!(Datagram::decode(&buf), Some(datagram));
+        assert_eq!(Datagram::decode(&buf[..HEADER_LEN + 1]), None);
+        assert_eq!(Datagram::decode(b"not a datagram at all"), None);
+    }
+
+    #[test]
+    fn test_one_sink_per_channel() {
+        let config = MulticastSinkConfig {
+            group: "127.0.0.1:9".to_string(),
+            retransmit: Some("127.0.0.1:0".to_string()),
+            ..Default::default()
+        };
+        let sink = MulticastSink::from_config(&config, "9").unwrap();
+        assert!(matches!(
+            MulticastSink::from_config(&config, "9"),
+            Err(SinkError::MulticastChannel { channel: 9 })
+        ));
+        // a restarted worker takes the channel over with a new session
+        let session = sink.session;
+        drop(sink);
+        let sink = MulticastSink::from_config(&config, "9").unwrap();
+        assert_ne!(sink.session, session);
+    }
+}
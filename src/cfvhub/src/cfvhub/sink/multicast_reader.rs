@@ -0,0 +1,288 @@
+use super::multicast::{resolve, Datagram, DatagramKind, MAX_DATAGRAM, RETRANSMIT_REQUEST_LEN};
+use super::{MulticastSnafu, SinkError};
+use snafu::ResultExt;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+use tracing::warn;
+
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What a `MulticastReader` hands out, in seq order per channel.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Received {
+    Message(Datagram),
+    /// seqs that were neither received nor recovered
+    Lost {
+        channel: u16,
+        from: u64,
+        to: u64,
+    },
+}
+
+struct ChannelSeq {
+    session: u32,
+    expected: u64,
+}
+
+/// Consumer side of `MulticastSink`, detects gaps from the seq and heartbeats and
+/// fills them from the retransmit service when one is set.
+pub struct MulticastReader {
+    socket: UdpSocket,
+    retransmit: Option<SocketAddr>,
+    conn: Option<TcpStream>,
+    channels: HashMap<u16, ChannelSeq>,
+    ready: VecDeque<Received>,
+    buf: Vec<u8>,
+}
+
+impl MulticastReader {
+    /// Listens on the port of `addr` and joins the group on `interface` when it is multicast.
+    pub fn bind(addr: &str, interface: Ipv4Addr) -> Result<Self, SinkError> {
+        let addr = resolve(addr)?;
+        let socket = match addr {
+            SocketAddr::V4(v4) if v4.ip().is_multicast() => {
+                let socket =
+                    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, v4.port())).context(MulticastSnafu)?;
+                socket
+                    .join_multicast_v4(v4.ip(), &interface)
+                    .context(MulticastSnafu)?;
+                socket
+            }
+            addr => UdpSocket::bind(addr).context(MulticastSnafu)?,
+        };
+        Ok(Self {
+            socket,
+            retransmit: None,
+            conn: None,
+            channels: HashMap::new(),
+            ready: VecDeque::new(),
+            buf: vec![0u8; MAX_DATAGRAM],
+        })
+    }
+
+    pub fn with_retransmit(mut self, addr: &str) -> Result<Self, SinkError> {
+        self.retransmit = Some(resolve(addr)?);
+        Ok(self)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), SinkError> {
+        self.socket
+            .set_read_timeout(timeout)
+            .context(MulticastSnafu)
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, SinkError> {
+        self.socket.local_addr().context(MulticastSnafu)
+    }
+
+    /// Blocks for the next message or gap, a read timeout ends with an io error.
+    pub fn recv(&mut self) -> Result<Received, SinkError> {
+        loop {
+            if let Some(received) = self.ready.pop_front() {
+                return Ok(received);
+            }
+            let len = self.socket.recv(&mut self.buf).context(MulticastSnafu)?;
+            if let Some(datagram) = Datagram::decode(&self.buf[..len]) {
+                self.handle(datagram);
+            }
+        }
+    }
+
+    fn handle(&mut self, datagram: Datagram) {
+        // a new channel or producer session starts at the first datagram seen
+        let first = match datagram.kind {
+            DatagramKind::Data => datagram.seq,
+            DatagramKind::Heartbeat => datagram.seq + 1,
+        };
+        let channel = self.channels.entry(datagram.channel).or_insert(ChannelSeq {
+            session: datagram.session,
+            expected: first,
+        });
+        if channel.session != datagram.session {
+            channel.session = datagram.session;
+            channel.expected = first;
+        }
+        let expected = channel.expected;
+        match datagram.kind {
+            // late duplicates were handed out already
+            DatagramKind::Data if datagram.seq < expected => {}
+            DatagramKind::Data => {
+                if datagram.seq > expected {
+                    self.recover(
+                        datagram.channel,
+                        datagram.session,
+                        expected,
+                        datagram.seq - 1,
+                    );
+                }
+                self.set_expected(datagram.channel, datagram.seq + 1);
+                self.ready.push_back(Received::Message(datagram));
+            }
+            DatagramKind::Heartbeat if datagram.seq >= expected => {
+                self.recover(datagram.channel, datagram.session, expected, datagram.seq);
+                self.set_expected(datagram.channel, datagram.seq + 1);
+            }
+            DatagramKind::Heartbeat => {}
+        }
+    }
+
+    fn set_expected(&mut self, channel: u16, expected: u64) {
+        if let Some(channel) = self.channels.get_mut(&channel) {
+            channel.expected = expected;
+        }
+    }
+
+    fn recover(&mut self, channel: u16, session: u32, from: u64, to: u64) {
+        let recovered = match self.retransmit {
+            Some(addr) => self
+                .request(addr, channel, session, from, to)
+                .unwrap_or_else(|e| {
+                    warn!("multicast retransmit from {} error: {}", addr, e);
+                    self.conn = None;
+                    vec![]
+                }),
+            None => vec![],
+        };
+        let mut next = from;
+        for datagram in recovered {
+            if datagram.channel != channel || datagram.seq < next || datagram.seq > to {
+                continue;
+            }
+            if datagram.seq > next {
+                self.ready.push_back(Received::Lost {
+                    channel,
+                    from: next,
+                    to: datagram.seq - 1,
+                });
+            }
+            next = datagram.seq + 1;
+            self.ready.push_back(Received::Message(datagram));
+        }
+        if next <= to {
+            self.ready.push_back(Received::Lost {
+                channel,
+                from: next,
+                to,
+            });
+        }
+    }
+
+    fn request(
+        &mut self,
+        addr: SocketAddr,
+        channel: u16,
+        session: u32,
+        from: u64,
+        to: u64,
+    ) -> std::io::Result<Vec<Datagram>> {
+        if self.conn.is_none() {
+            let conn = TcpStream::connect_timeout(&addr, RETRANSMIT_TIMEOUT)?;
+            conn.set_read_timeout(Some(RETRANSMIT_TIMEOUT))?;
+            conn.set_nodelay(true)?;
+            self.conn = Some(conn);
+        }
+        let conn = self.conn.as_mut().unwrap();
+        let mut request = Vec::with_capacity(RETRANSMIT_REQUEST_LEN);
+        request.extend_from_slice(&channel.to_le_bytes());
+        request.extend_from_slice(&session.to_le_bytes());
+        request.extend_from_slice(&from.to_le_bytes());
+        request.extend_from_slice(&to.to_le_bytes());
+        conn.write_all(&request)?;
+        let mut datagrams = vec![];
+        loop {
+            let mut len = [0u8; 4];
+            conn.read_exact(&mut len)?;
+            let len = u32::from_le_bytes(len) as usize;
+            if len == 0 {
+                return Ok(datagrams);
+            }
+            let mut frame = vec![0u8; len];
+            conn.read_exact(&mut frame)?;
+            datagrams.extend(Datagram::decode(&frame));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formater::JsonFormater;
+    use crate::sink::multicast::{MulticastSink, MulticastSinkConfig};
+    use crate::sink::SinkExt;
+    use crate::test_util::msg;
+
+    fn message(received: Received) -> (u64, serde_json::Value) {
+        match received {
+            Received::Message(datagram) => (
+                datagram.seq,
+                serde_json::from_slice(&datagram.data).unwrap(),
+            ),
+            lost => panic!("expected a message, got {:?}", lost),
+        }
+    }
+
+    #[test]
+    fn test_gap_recovery_on_loopback() {
+        // the sink sends to a lossy relay that drops seq 2 and 6 on the way to the reader
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        relay
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let config = MulticastSinkConfig {
+            group: relay.local_addr().unwrap().to_string(),
+            heartbeat_ms: 50,
+            ring_size: 4,
+            retransmit: Some("127.0.0.1:0".to_string()),
+            ..Default::default()
+        };
+        let mut sink = MulticastSink::from_config(&config, "7").unwrap();
+        let mut reader = MulticastReader::bind("127.0.0.1:0", Ipv4Addr::UNSPECIFIED)
+            .unwrap()
+            .with_retransmit(&sink.retransmit_addr().unwrap().to_string())
+            .unwrap();
+        reader
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let reader_addr = reader.local_addr().unwrap();
+
+        for i in 1..=6 {
+            sink.exec(&msg("api/V1/TIC/Q/AAPL", i as f64), &JsonFormater)
+                .unwrap();
+        }
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let len = relay.recv(&mut buf).unwrap();
+            let datagram = Datagram::decode(&buf[..len]).unwrap();
+            assert_eq!(datagram.channel, 7);
+            match datagram.kind {
+                DatagramKind::Data if [2, 6].contains(&datagram.seq) => continue,
+                DatagramKind::Data => {}
+                // the idle channel tells the last seq
+                DatagramKind::Heartbeat => {
+                    assert_eq!(datagram.seq, 6);
+                    relay.send_to(&buf[..len], reader_addr).unwrap();
+                    break;
+                }
+            }
+            relay.send_to(&buf[..len], reader_addr).unwrap();
+        }
+
+        assert_eq!(message(reader.recv().unwrap()).0, 1);
+        // seq 2 is no longer in the ring of 4
+        assert_eq!(
+            reader.recv().unwrap(),
+            Received::Lost {
+                channel: 7,
+                from: 2,
+                to: 2
+            }
+        );
+        for seq in 3..=6 {
+            let (received, data) = message(reader.recv().unwrap());
+            assert_eq!(received, seq);
+            assert_eq!(data["close"].as_f64(), Some(seq as f64));
+        }
+    }
+}
@@ -0,0 +1,697 @@
+use arrow_array::builder::{
+    ArrayBuilder, BooleanBuilder, Float64Builder, Int64Builder, StringBuilder, UInt64Builder,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use super::disk::{date_label, now_secs};
+use super::row::{to_row, Cell, Row, RowError};
+use super::shutdown::{Close, Shutdown};
+use super::{
+    ArrowSnafu, DiskSinkReadFileSnafu, FormaterExt, ParquetSnafu, RowSnafu, SinkError, SinkExt,
+};
+use crate::latency::now_ns;
+use crate::metrics::{SinkCounters, METRICS};
+use crate::queue::RateLimiter;
+
+fn default_path() -> String {
+    dotenvy::var("PARQUET_SINK_PATH").unwrap_or_else(|_| "parquet".to_string())
+}
+
+fn default_flush_rows() -> usize {
+    10000
+}
+
+fn default_flush_secs() -> u64 {
+    60
+}
+
+/// Files go to `{path}/date=YYYYMMDD/type={struct name}/part-{worker}-{ns}.parquet`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParquetSinkConfig {
+    #[serde(default = "default_path")]
+    pub path: String,
+    /// rows buffered before a row group is written
+    #[serde(default = "default_flush_rows")]
+    pub flush_rows: usize,
+    /// oldest buffered row age before a row group is written, checked every second
+    #[serde(default = "default_flush_secs")]
+    pub flush_secs: u64,
+    /// the date partition is the local date of the write time
+    #[serde(default)]
+    pub utc_offset_hours: i32,
+}
+
+impl Default for ParquetSinkConfig {
+    fn default() -> Self {
+        Self {
+            path: default_path(),
+            flush_rows: default_flush_rows(),
+            flush_secs: default_flush_secs(),
+            utc_offset_hours: 0,
+        }
+    }
+}
+
+impl ParquetSinkConfig {
+    fn days(&self, secs: i64) -> i64 {
+        (secs + self.utc_offset_hours as i64 * 3600).div_euclid(86400)
+    }
+
+    pub fn partition_dir(&self, days: i64, name: &str) -> PathBuf {
+        Path::new(&self.path)
+            .join(format!("date={}", date_label(days)))
+            .join(format!("type={}", name))
+    }
+}
+
+/// Arrow columns of the structs a message serializes to, so their types do not depend on
+/// the values, e.g. a price that is null in the whole first row group.
+pub trait ParquetMessage: Serialize {
+    /// Fields of the struct `name` in serialization order. None types each column by its
+    /// first value, and a column without one by the first row group is written as strings.
+    fn arrow_fields(_name: &str) -> Option<Vec<Field>> {
+        None
+    }
+
+    /// The row written for the message, its cells have to fit the fields.
+    fn parquet_row(&self) -> Result<Row, RowError> {
+        to_row(self)
+    }
+}
+
+enum Column {
+    // only nulls so far, typed by the first value
+    Null(usize),
+    Bool(BooleanBuilder),
+    I64(Int64Builder),
+    U64(UInt64Builder),
+    F64(Float64Builder),
+    Str(StringBuilder),
+}
+
+// integers up to 2^53 are exact in an f64
+const MAX_EXACT_F64: u64 = 1 << 53;
+
+impl Column {
+    fn typed(data_type: &DataType) -> Option<Self> {
+        Some(match data_type {
+            DataType::Boolean => Column::Bool(BooleanBuilder::new()),
+            DataType::Int64 => Column::I64(Int64Builder::new()),
+            DataType::UInt64 => Column::U64(UInt64Builder::new()),
+            DataType::Float64 => Column::F64(Float64Builder::new()),
+            DataType::Utf8 => Column::Str(StringBuilder::new()),
+            _ => return None,
+        })
+    }
+
+    fn data_type(&self) -> DataType {
+        match self {
+            Column::Bool(_) => DataType::Boolean,
+            Column::I64(_) => DataType::Int64,
+            Column::U64(_) => DataType::UInt64,
+            Column::F64(_) => DataType::Float64,
+            Column::Null(_) | Column::Str(_) => DataType::Utf8,
+        }
+    }
+
+    /// Whether the cell fits the column type without loss.
+    fn accepts(&self, cell: &Cell) -> bool {
+        match (self, cell) {
+            (Column::Null(_), _) | (_, Cell::Null) => true,
+            (Column::Bool(_), Cell::Bool(_))
+            | (Column::I64(_), Cell::I64(_))
+            | (Column::U64(_), Cell::U64(_))
+            | (Column::F64(_), Cell::F64(_))
+            | (Column::Str(_), Cell::Str(_)) => true,
+            (Column::I64(_), Cell::U64(v)) => i64::try_from(*v).is_ok(),
+            (Column::U64(_), Cell::I64(v)) => *v >= 0,
+            (Column::F64(_), Cell::I64(v)) => v.unsigned_abs() <= MAX_EXACT_F64,
+            (Column::F64(_), Cell::U64(v)) => *v <= MAX_EXACT_F64,
+            _ => false,
+        }
+    }
+
+    // the cell is accepted
+    fn append(&mut self, cell: &Cell) {
+        if let Column::Null(nulls) = self {
+            let nulls = *nulls;
+            *self = match cell {
+                Cell::Null => Column::Null(nulls + 1),
+                Cell::Bool(_) => Column::Bool(BooleanBuilder::new()),
+                Cell::I64(_) => Column::I64(Int64Builder::new()),
+                Cell::U64(_) => Column::U64(UInt64Builder::new()),
+                Cell::F64(_) => Column::F64(Float64Builder::new()),
+                Cell::Str(_) => Column::Str(StringBuilder::new()),
+            };
+            if cell == &Cell::Null {
+                return;
+            }
+            self.append_nulls(nulls);
+        }
+        match (self, cell) {
+            (Column::Bool(b), Cell::Bool(v)) => b.append_value(*v),
+            (Column::I64(b), Cell::I64(v)) => b.append_value(*v),
+            (Column::I64(b), Cell::U64(v)) => b.append_value(*v as i64),
+            (Column::U64(b), Cell::U64(v)) => b.append_value(*v),
+            (Column::U64(b), Cell::I64(v)) => b.append_value(*v as u64),
+            (Column::F64(b), Cell::F64(v)) => b.append_value(*v),
+            (Column::F64(b), Cell::I64(v)) => b.append_value(*v as f64),
+            (Column::F64(b), Cell::U64(v)) => b.append_value(*v as f64),
+            (Column::Str(b), Cell::Str(v)) => b.append_value(v),
+            (column, _) => column.append_nulls(1),
+        }
+    }
+
+    fn append_nulls(&mut self, n: usize) {
+        match self {
+            Column::Null(nulls) => *nulls += n,
+            Column::Bool(b) => b.append_nulls(n),
+            Column::I64(b) => b.append_nulls(n),
+            Column::U64(b) => b.append_nulls(n),
+            Column::F64(b) => b.append_nulls(n),
+            Column::Str(b) => (0..n).for_each(|_| b.append_null()),
+        }
+    }
+
+    /// A column without any value by the first row group is written as strings.
+    fn resolve(&mut self) {
+        if let Column::Null(nulls) = *self {
+            *self = Column::Str(StringBuilder::new());
+            self.append_nulls(nulls);
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            Column::Null(_) => unreachable!("resolved before the first row group"),
+            Column::Bool(b) => Arc::new(b.finish()),
+            Column::I64(b) => Arc::new(b.finish()),
+            Column::U64(b) => Arc::new(b.finish()),
+            Column::F64(b) => Arc::new(b.finish()),
+            Column::Str(b) => Arc::new(b.finish()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Column::Null(nulls) => *nulls,
+            Column::Bool(b) => b.len(),
+            Column::I64(b) => b.len(),
+            Column::U64(b) => b.len(),
+            Column::F64(b) => b.len(),
+            Column::Str(b) => b.len(),
+        }
+    }
+}
+
+/// One open file of a message type. Column types come from `ParquetMessage::arrow_fields`,
+/// or else from the first value of each column. The schema is fixed when the first row
+/// group is written and a row that does not fit it is an error.
+struct PartitionWriter {
+    path: PathBuf,
+    names: Vec<&'static str>,
+    columns: Vec<Column>,
+    // created with the first row group, once the column types are known
+    writer: Option<(SchemaRef, ArrowWriter<File>)>,
+    buffered_since: Option<Instant>,
+}
+
+impl PartitionWriter {
+    fn open(
+        dir: &Path,
+        id: &str,
+        row: &Row,
+        fields: Option<Vec<Field>>,
+    ) -> Result<Self, SinkError> {
+        let path = dir.join(format!("part-{}-{}.parquet", id, now_ns()));
+        let names: Vec<_> = row.fields.iter().map(|(name, _)| *name).collect();
+        let columns = match fields {
+            Some(fields) => {
+                let typed = fields
+                    .iter()
+                    .zip(&names)
+                    .map(|(field, name)| match field.name() == name {
+                        true => Column::typed(field.data_type()),
+                        false => None,
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .filter(|columns| columns.len() == names.len());
+                match typed {
+                    Some(columns) => columns,
+                    None => return Err(SinkError::ParquetSchema { path }),
+                }
+            }
+            None => names.iter().map(|_| Column::Null(0)).collect(),
+        };
+        std::fs::create_dir_all(dir).context(DiskSinkReadFileSnafu)?;
+        Ok(Self {
+            path,
+            names,
+            columns,
+            writer: None,
+            buffered_since: None,
+        })
+    }
+
+    fn append(&mut self, row: &Row) -> Result<(), SinkError> {
+        if row.fields.len() != self.names.len()
+            || row
+                .fields
+                .iter()
+                .zip(&self.names)
+                .any(|((name, _), column)| column != name)
+        {
+            return Err(SinkError::ParquetSchema {
+                path: self.path.clone(),
+            });
+        }
+        // checked before any column is appended, so a rejected row leaves no trace
+        if let Some((name, cell)) = row
+            .fields
+            .iter()
+            .zip(&self.columns)
+            .find(|((_, cell), column)| !column.accepts(cell))
+            .map(|(field, _)| field)
+        {
+            return Err(SinkError::ParquetColumn {
+                path: self.path.clone(),
+                column: name.to_string(),
+                cell: cell.clone(),
+            });
+        }
+        for (column, (_, cell)) in self.columns.iter_mut().zip(&row.fields) {
+            column.append(cell);
+        }
+        self.buffered_since.get_or_insert_with(Instant::now);
+        Ok(())
+    }
+
+    fn buffered(&self) -> usize {
+        self.columns.first().map_or(0, Column::len)
+    }
+
+    fn due(&self, flush_after: Duration, now: Instant) -> bool {
+        self.buffered_since
+            .is_some_and(|since| now.saturating_duration_since(since) >= flush_after)
+    }
+
+    /// Writes the buffered rows as one row group.
+    fn flush(&mut self) -> Result<(), SinkError> {
+        if self.buffered() == 0 {
+            return Ok(());
+        }
+        if self.writer.is_none() {
+            self.columns.iter_mut().for_each(Column::resolve);
+            let fields: Vec<_> = self
+                .names
+                .iter()
+                .zip(&self.columns)
+                .map(|(name, column)| Field::new(*name, column.data_type(), true))
+                .collect();
+            let schema = Arc::new(Schema::new(fields));
+            let props = WriterProperties::builder()
+                .set_compression(Compression::ZSTD(ZstdLevel::default()))
+                .build();
+            let file = File::create(&self.path).context(DiskSinkReadFileSnafu)?;
+            let writer =
+                ArrowWriter::try_new(file, schema.clone(), Some(props)).context(ParquetSnafu)?;
+            self.writer = Some((schema, writer));
+        }
+        let (schema, writer) = self.writer.as_mut().unwrap();
+        let arrays = self.columns.iter_mut().map(Column::finish).collect();
+        let batch = RecordBatch::try_new(schema.clone(), arrays).context(ArrowSnafu)?;
+        self.buffered_since = None;
+        writer.write(&batch).context(ParquetSnafu)?;
+        writer.flush().context(ParquetSnafu)
+    }
+
+    /// Files are only readable after the footer is written here.
+    fn close(mut self) -> Result<PathBuf, SinkError> {
+        self.flush()?;
+        if let Some((_, writer)) = self.writer {
+            writer.close().context(ParquetSnafu)?;
+        }
+        Ok(self.path)
+    }
+}
+
+struct ParquetWriters {
+    config: ParquetSinkConfig,
+    id: String,
+    days: i64,
+    partitions: HashMap<&'static str, PartitionWriter>,
+}
+
+impl ParquetWriters {
+    fn write(
+        &mut self,
+        row: &Row,
+        fields: fn(&str) -> Option<Vec<Field>>,
+    ) -> Result<(), SinkError> {
+        let days = self.config.days(now_secs());
+        if days != self.days {
+            self.close()?;
+            self.days = days;
+        }
+        let partition = match self.partitions.get_mut(row.name) {
+            Some(partition) => partition,
+            None => {
+                let dir = self.config.partition_dir(days, row.name);
+                let partition = PartitionWriter::open(&dir, &self.id, row, fields(row.name))?;
+                self.partitions.entry(row.name).or_insert(partition)
+            }
+        };
+        partition.append(row)?;
+        let flush_after = Duration::from_secs(self.config.flush_secs);
+        if partition.buffered() >= self.config.flush_rows
+            || partition.due(flush_after, Instant::now())
+        {
+            partition.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the rows buffered for `flush_secs` by `now`, also when no row follows.
+    fn flush_due(&mut self, now: Instant) -> Result<(), SinkError> {
+        let flush_after = Duration::from_secs(self.config.flush_secs);
+        self.partitions
+            .values_mut()
+            .filter(|partition| partition.due(flush_after, now))
+            .try_for_each(PartitionWriter::flush)
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        self.partitions
+            .values_mut()
+            .try_for_each(PartitionWriter::flush)
+    }
+
+    fn close(&mut self) -> Result<(), SinkError> {
+        let mut r = Ok(());
+        for (_, partition) in self.partitions.drain() {
+            if let Err(e) = partition.close() {
+                tracing::error!("close parquet file error: {}", e);
+                r = Err(e);
+            }
+        }
+        r
+    }
+}
+
+impl Drop for ParquetWriters {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+// writes the footers, later writes start new files
+impl Close for Mutex<ParquetWriters> {
+    fn close(&self) -> Result<(), SinkError> {
+        self.lock().unwrap().close()
+    }
+}
+
+/// Buffers serialized structs into arrow columns and writes parquet files per date and
+/// struct name, e.g. `NBTick` and `NBBidAsk` of `DataNasdaqBasicV1`. The formater is unused.
+pub struct ParquetSink {
+    id: String,
+    writers: Arc<Mutex<ParquetWriters>>,
+    counters: SinkCounters,
+}
+
+impl std::fmt::Debug for ParquetSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ParquetSink").field("id", &self.id).finish()
+    }
+}
+
+impl ParquetSink {
+    pub fn new(config: &ParquetSinkConfig, id: &str) -> Self {
+        let writers = Arc::new(Mutex::new(ParquetWriters {
+            config: config.clone(),
+            id: id.to_string(),
+            days: config.days(now_secs()),
+            partitions: HashMap::new(),
+        }));
+        spawn_flusher(Arc::downgrade(&writers));
+        Self {
+            id: id.to_string(),
+            writers,
+            counters: METRICS.sink_counters("parquet", id),
+        }
+    }
+
+    /// The files are closed with `shutdown`.
+    pub fn with_shutdown(self, shutdown: &Shutdown) -> Self {
+        shutdown.add_file(Arc::downgrade(&self.writers) as Weak<dyn Close>);
+        self
+    }
+
+    /// Writes the buffered rows, the files stay open.
+    pub fn flush(&self) -> Result<(), SinkError> {
+        self.writers.lock().unwrap().flush()
+    }
+
+    pub fn close(&self) -> Result<(), SinkError> {
+        self.writers.lock().unwrap().close()
+    }
+}
+
+// checks the age of the buffered rows until the sink is dropped
+fn spawn_flusher(writers: Weak<Mutex<ParquetWriters>>) {
+    std::thread::spawn(move || {
+        let log_limit = RateLimiter::default();
+        loop {
+            std::thread::sleep(Duration::from_secs(1));
+            let writers = match writers.upgrade() {
+                Some(writers) => writers,
+                None => return,
+            };
+            let r = writers.lock().unwrap().flush_due(Instant::now());
+            if let Err(e) = r {
+                if log_limit.check() {
+                    tracing::error!("parquet flush error: {}", e);
+                }
+            }
+        }
+    });
+}
+
+impl<In: ParquetMessage> SinkExt<In> for ParquetSink {
+    /// Files under `PARQUET_SINK_PATH`.
+    fn build(id: &str) -> Self {
+        Self::new(&ParquetSinkConfig::default(), id)
+    }
+
+    fn exec(&mut self, input: &In, _formater: &impl FormaterExt<In>) -> Result<(), SinkError> {
+        let r = input
+            .parquet_row()
+            .context(RowSnafu)
+            .and_then(|row| self.writers.lock().unwrap().write(&row, In::arrow_fields));
+        self.counters.result(r.is_ok());
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formater::JsonFormater;
+    use arrow_array::{Array, Float64Array, StringArray};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    #[derive(Serialize)]
+    struct Tick {
+        code: String,
+        close: f64,
+        volume: i64,
+        bid: Option<f64>,
+    }
+
+    // a tick whose close drifted to a decimal string
+    #[derive(Serialize)]
+    #[serde(rename = "Tick")]
+    struct StrTick {
+        code: String,
+        close: String,
+        volume: i64,
+        bid: Option<f64>,
+    }
+
+    #[derive(Serialize)]
+    struct BidAsk {
+        code: String,
+        bid_price: f64,
+    }
+
+    #[derive(Serialize)]
+    #[serde(untagged)]
+    enum Data {
+        Tick(Tick),
+        BidAsk(BidAsk),
+    }
+
+    impl ParquetMessage for Data {}
+
+    impl ParquetMessage for StrTick {}
+
+    #[derive(Serialize)]
+    #[serde(rename = "Tick")]
+    struct TypedTick {
+        code: String,
+        bid: Option<f64>,
+    }
+
+    impl ParquetMessage for TypedTick {
+        fn arrow_fields(_name: &str) -> Option<Vec<Field>> {
+            Some(vec![
+                Field::new("code", DataType::Utf8, true),
+                Field::new("bid", DataType::Float64, true),
+            ])
+        }
+    }
+
+    fn read(dir: &Path) -> Vec<RecordBatch> {
+        let files = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(files.len(), 1);
+        ParquetRecordBatchReaderBuilder::try_new(File::open(&files[0]).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .map(|batch| batch.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_parquet_sink() {
+        let root = std::env::temp_dir().join(format!("cfvhub-parquet-{}", std::process::id()));
+        let config = ParquetSinkConfig {
+            path: root.to_string_lossy().to_string(),
+            flush_rows: 2,
+            ..Default::default()
+        };
+        let shutdown = Shutdown::default();
+        let mut sink = ParquetSink::new(&config, "0").with_shutdown(&shutdown);
+        for i in 0..3 {
+            let tick = Data::Tick(Tick {
+                code: "AAPL".to_string(),
+                close: 100.0 + i as f64,
+                volume: i,
+                bid: (i > 0).then_some(99.5),
+            });
+            sink.exec(&tick, &JsonFormater).unwrap();
+        }
+        let drifted = StrTick {
+            code: "AAPL".to_string(),
+            close: "103.00".to_string(),
+            volume: 3,
+            bid: None,
+        };
+        let err = sink.exec(&drifted, &JsonFormater).unwrap_err();
+        assert!(matches!(err, SinkError::ParquetColumn { column, .. } if column == "close"));
+        let quote = Data::BidAsk(BidAsk {
+            code: "NVDA".to_string(),
+            bid_price: 9.5,
+        });
+        sink.exec(&quote, &JsonFormater).unwrap();
+        shutdown.close().unwrap();
+
+        let days = config.days(now_secs());
+        let batches = read(&config.partition_dir(days, "Tick"));
+        // a row group at 2 rows and the rest on close
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 3);
+        let schema = batches[0].schema();
+        assert_eq!(schema.field(0).name(), "code");
+        assert_eq!(schema.field(1).data_type(), &DataType::Float64);
+        assert_eq!(schema.field(2).data_type(), &DataType::Int64);
+        // typed by the first value, not the null of the first row
+        assert_eq!(schema.field(3).data_type(), &DataType::Float64);
+        let close = batches[0]
+            .column(1)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert_eq!(close.value(1), 101.0);
+
+        let batches = read(&config.partition_dir(days, "BidAsk"));
+        let code = batches[0]
+            .column(0)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(code.value(0), "NVDA");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_parquet_declared_fields() {
+        let root =
+            std::env::temp_dir().join(format!("cfvhub-parquet-typed-{}", std::process::id()));
+        let config = ParquetSinkConfig {
+            path: root.to_string_lossy().to_string(),
+            flush_rows: 1,
+            ..Default::default()
+        };
+        let mut sink = ParquetSink::new(&config, "0");
+        for bid in [None, Some(9.5)] {
+            let tick = TypedTick {
+                code: "AAPL".to_string(),
+                bid,
+            };
+            sink.exec(&tick, &JsonFormater).unwrap();
+        }
+        sink.close().unwrap();
+
+        let batches = read(&config.partition_dir(config.days(now_secs()), "Tick"));
+        // the first row group has only a null bid
+        assert_eq!(batches[0].schema().field(1).data_type(), &DataType::Float64);
+        let bid = batches[0]
+            .column(1)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert!(bid.is_null(0));
+        assert_eq!(bid.value(1), 9.5);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_parquet_flush_due() {
+        let root = std::env::temp_dir().join(format!("cfvhub-parquet-due-{}", std::process::id()));
+        let config = ParquetSinkConfig {
+            path: root.to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut sink = ParquetSink::new(&config, "0");
+        let tick = TypedTick {
+            code: "AAPL".to_string(),
+            bid: Some(9.5),
+        };
+        sink.exec(&tick, &JsonFormater).unwrap();
+        let mut writers = sink.writers.lock().unwrap();
+        writers.flush_due(Instant::now()).unwrap();
+        assert_eq!(writers.partitions["Tick"].buffered(), 1);
+        // no write follows, the timer writes the row group
+        writers
+            .flush_due(Instant::now() + Duration::from_secs(61))
+            .unwrap();
+        assert_eq!(writers.partitions["Tick"].buffered(), 0);
+        drop(writers);
+        sink.close().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,581 @@
+use serde::{Deserialize, Serialize, Serializer};
+use snafu::ResultExt;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use tracing::{error, warn};
+
+use super::retry::DeadLetterSink;
+use super::{
+    Dest, FormatSnafu, Formated, FormaterExt, RedisReplySnafu, RedisSnafu, SinkError, SinkExt,
+};
+use crate::latency::now_ns;
+use crate::metrics::{SinkCounters, METRICS};
+use crate::queue::RateLimiter;
+
+fn default_addr() -> String {
+    dotenvy::var("REDIS_SINK_ADDR").unwrap_or_else(|_| "127.0.0.1:6379".to_string())
+}
+
+fn default_pipeline() -> usize {
+    256
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RedisSinkConfig {
+    #[serde(default = "default_addr")]
+    pub addr: String,
+    pub password: Option<String>,
+    pub db: Option<u32>,
+    /// prepended to the `Dest` of the hash key
+    #[serde(default)]
+    pub key_prefix: String,
+    /// also PUBLISH the data on a channel named like the hash key
+    #[serde(default)]
+    pub publish: bool,
+    /// commands sent before their replies are read
+    #[serde(default = "default_pipeline")]
+    pub pipeline: usize,
+}
+
+impl Default for RedisSinkConfig {
+    fn default() -> Self {
+        Self {
+            addr: default_addr(),
+            password: dotenvy::var("REDIS_SINK_PASSWORD").ok(),
+            db: None,
+            key_prefix: String::new(),
+            publish: false,
+            pipeline: default_pipeline(),
+        }
+    }
+}
+
+/// Writes a RESP array of bulk strings.
+pub fn write_command<W: Write>(w: &mut W, args: &[&[u8]]) -> std::io::Result<()> {
+    write!(w, "*{}\r\n", args.len())?;
+    for arg in args {
+        write!(w, "${}\r\n", arg.len())?;
+        w.write_all(arg)?;
+        w.write_all(b"\r\n")?;
+    }
+    Ok(())
+}
+
+/// Reads one reply, `Ok(Err(message))` for an error reply. Values are not kept.
+pub fn read_reply<R: BufRead>(r: &mut R) -> std::io::Result<Result<(), String>> {
+    let mut line = String::new();
+    if r.read_line(&mut line)? == 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    let line = line.trim_end_matches("\r\n");
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, line.to_string());
+    let (kind, rest) = match line.as_bytes().first() {
+        Some(kind) => (*kind, &line[1..]),
+        None => return Err(invalid()),
+    };
+    match kind {
+        b'+' | b':' => Ok(Ok(())),
+        b'-' => Ok(Err(rest.to_string())),
+        b'$' => {
+            let len: i64 = rest.parse().map_err(|_| invalid())?;
+            if len >= 0 {
+                let mut bulk = vec![0u8; len as usize + 2];
+                r.read_exact(&mut bulk)?;
+            }
+            Ok(Ok(()))
+        }
+        b'*' => {
+            let len: i64 = rest.parse().map_err(|_| invalid())?;
+            let mut reply = Ok(());
+            for _ in 0..len.max(0) {
+                if let Err(e) = read_reply(r)? {
+                    reply = Err(e);
+                }
+            }
+            Ok(reply)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+struct Connection {
+    writer: BufWriter<TcpStream>,
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    /// Connects, then authenticates and selects the db, waiting for each reply.
+    fn open(config: &RedisSinkConfig) -> Result<Self, SinkError> {
+        let connect = || -> std::io::Result<Self> {
+            let stream = TcpStream::connect(&config.addr)?;
+            stream.set_nodelay(true)?;
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+            Ok(Self {
+                writer: BufWriter::new(stream.try_clone()?),
+                reader: BufReader::new(stream),
+            })
+        };
+        let mut conn = connect().context(RedisSnafu)?;
+        if let Some(password) = &config.password {
+            conn.command(&[b"AUTH", password.as_bytes()])?;
+        }
+        if let Some(db) = config.db {
+            conn.command(&[b"SELECT", db.to_string().as_bytes()])?;
+        }
+        Ok(conn)
+    }
+
+    /// Sends one command and reads its reply.
+    fn command(&mut self, args: &[&[u8]]) -> Result<(), SinkError> {
+        let r = write_command(&mut self.writer, args)
+            .and_then(|_| self.writer.flush())
+            .and_then(|_| read_reply(&mut self.reader));
+        match r {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(message)) => RedisReplySnafu { message }.fail(),
+            Err(e) => Err(e).context(RedisSnafu),
+        }
+    }
+
+    fn write(&mut self, pending: &Pending, publish: bool) -> std::io::Result<()> {
+        let key = pending.key.as_bytes();
+        write_command(
+            &mut self.writer,
+            &[
+                b"HSET",
+                key,
+                b"data",
+                &pending.data,
+                b"ct",
+                pending.ct.as_bytes(),
+                b"ts",
+                pending.ts.as_bytes(),
+            ],
+        )?;
+        if publish {
+            write_command(&mut self.writer, &[b"PUBLISH", key, &pending.data])?;
+        }
+        Ok(())
+    }
+}
+
+// a message sent and not acknowledged yet, also what is dead lettered
+#[derive(Debug, Serialize)]
+struct Pending {
+    key: String,
+    ct: String,
+    ts: String,
+    #[serde(serialize_with = "serialize_data")]
+    data: Vec<u8>,
+}
+
+impl Dest for Pending {
+    fn get_dest(&self) -> &str {
+        &self.key
+    }
+}
+
+// text formats stay readable in the dead letter file
+fn serialize_data<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    match std::str::from_utf8(data) {
+        Ok(text) => serializer.serialize_str(text),
+        Err(_) => serializer.serialize_bytes(data),
+    }
+}
+
+/// Keeps the latest output of each `Dest` in a hash with the fields `data`, `ct` and `ts`
+/// and optionally publishes it. Commands are pipelined and the replies are matched to
+/// the messages in order. A message with an error reply fails its own exec when it is
+/// the one being sent, an earlier one goes to the dead letter sink, or is counted lost.
+/// The messages a lost connection did not acknowledge are sent again after reconnecting.
+pub struct RedisSink {
+    id: String,
+    config: RedisSinkConfig,
+    conn: Option<Connection>,
+    // in the order of their replies
+    pending: VecDeque<Pending>,
+    dead_letter: Option<DeadLetterSink>,
+    counters: SinkCounters,
+    log_limit: RateLimiter,
+}
+
+impl std::fmt::Debug for RedisSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisSink")
+            .field("id", &self.id)
+            .field("addr", &self.config.addr)
+            .finish()
+    }
+}
+
+impl RedisSink {
+    /// Connects on the first exec.
+    pub fn new(config: &RedisSinkConfig, id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            config: config.clone(),
+            conn: None,
+            pending: VecDeque::new(),
+            dead_letter: None,
+            counters: METRICS.sink_counters("redis", id),
+            log_limit: RateLimiter::default(),
+        }
+    }
+
+    /// Where the earlier messages with an error reply go.
+    pub fn with_dead_letter(mut self, dead_letter: DeadLetterSink) -> Self {
+        self.dead_letter = Some(dead_letter);
+        self
+    }
+
+    /// Sends the buffered commands and waits for their replies.
+    pub fn flush(&mut self) -> Result<(), SinkError> {
+        match self.drain(false) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.conn = None;
+                Err(e).context(RedisSnafu)
+            }
+        }
+    }
+
+    // reads the replies of the pending messages. One with an error reply is rejected,
+    // except the last when `own_last`, its error is returned for the exec that sent it
+    fn drain(&mut self, own_last: bool) -> std::io::Result<Option<String>> {
+        let conn = match self.conn.as_mut() {
+            Some(conn) => conn,
+            None => return Ok(None),
+        };
+        conn.writer.flush()?;
+        let commands = 1 + self.config.publish as usize;
+        while !self.pending.is_empty() {
+            let mut reply = Ok(());
+            for _ in 0..commands {
+                if let Err(message) = read_reply(&mut conn.reader)? {
+                    reply = Err(message);
+                }
+            }
+            let pending = self.pending.pop_front().unwrap();
+            match reply {
+                Ok(()) => {}
+                Err(message) if own_last && self.pending.is_empty() => return Ok(Some(message)),
+                Err(message) => {
+                    let e = SinkError::RedisReply { message };
+                    reject(
+                        &self.id,
+                        &mut self.counters,
+                        self.dead_letter.as_mut(),
+                        &pending,
+                        &e,
+                    );
+                    if self.log_limit.check() {
+                        warn!("redis sink {} rejected {}: {}", self.id, pending.key, e);
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // writes the message, on a new connection after the unacknowledged ones
+    fn write(&mut self, pending: &Pending) -> Result<(), SinkError> {
+        let publish = self.config.publish;
+        if self.conn.is_none() {
+            let mut conn = Connection::open(&self.config)?;
+            for sent in self.pending.iter() {
+                conn.write(sent, publish).context(RedisSnafu)?;
+            }
+            self.conn = Some(conn);
+        }
+        let r = self.conn.as_mut().unwrap().write(pending, publish);
+        if r.is_err() {
+            self.conn = None;
+        }
+        r.context(RedisSnafu)
+    }
+
+    fn send(&mut self, key: &str, data: &[u8], content_type: &str) -> Result<(), SinkError> {
+        let pending = Pending {
+            key: key.to_string(),
+            ct: content_type.to_string(),
+            ts: now_ns().to_string(),
+            data: data.to_vec(),
+        };
+        self.write(&pending)?;
+        self.pending.push_back(pending);
+        let commands = 1 + self.config.publish as usize;
+        let r = match self.conn.as_mut() {
+            Some(_) if self.pending.len() * commands >= self.config.pipeline => self.drain(true),
+            // hand the commands to redis now, the replies are read later
+            Some(conn) => conn.writer.flush().map(|_| None),
+            None => Ok(None),
+        };
+        match r {
+            Ok(None) => Ok(()),
+            Ok(Some(message)) => RedisReplySnafu { message }.fail(),
+            Err(e) => {
+                // the others are sent again on the next connection, this one is
+                // reported to the caller instead
+                self.conn = None;
+                self.pending.pop_back();
+                Err(e).context(RedisSnafu)
+            }
+        }
+    }
+}
+
+fn reject(
+    id: &str,
+    counters: &mut SinkCounters,
+    dead_letter: Option<&mut DeadLetterSink>,
+    pending: &Pending,
+    e: &SinkError,
+) {
+    match dead_letter.map(|dead_letter| dead_letter.record("redis", id, pending, e, 1)) {
+        Some(Ok(_)) => counters.outcome("dead_letter"),
+        Some(Err(e)) => {
+            counters.outcome("lost");
+            error!("dead letter write error: {}", e);
+        }
+        None => counters.outcome("lost"),
+    }
+}
+
+impl<In: Serialize + Dest> SinkExt<In> for RedisSink {
+    /// Connects to `REDIS_SINK_ADDR`.
+    fn build(id: &str) -> Self {
+        Self::new(&RedisSinkConfig::default(), id)
+    }
+
+    fn exec(&mut self, input: &In, formater: &impl FormaterExt<In>) -> Result<(), SinkError> {
+        let r = formater
+            .format(input)
+            .context(FormatSnafu)
+            .and_then(|formated| {
+                let key = format!("{}{}", self.config.key_prefix, input.get_dest());
+                match formated {
+                    Formated::String(s) => self.send(&key, s.as_bytes(), formater.content_type()),
+                    Formated::Bytes(b) => self.send(&key, &b, formater.content_type()),
+                }
+            });
+        self.counters.result(r.is_ok());
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formater::JsonFormater;
+    use crate::test_util::msg;
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::process::{Command, Stdio};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    fn read_command<R: BufRead>(r: &mut R) -> Option<Vec<String>> {
+        let mut line = String::new();
+        r.read_line(&mut line).ok().filter(|n| *n > 0)?;
+        let len: usize = line.trim_end()[1..].parse().ok()?;
+        (0..len)
+            .map(|_| {
+                let mut line = String::new();
+                r.read_line(&mut line).ok()?;
+                let len: usize = line.trim_end()[1..].parse().ok()?;
+                let mut arg = vec![0u8; len + 2];
+                r.read_exact(&mut arg).ok()?;
+                arg.truncate(len);
+                String::from_utf8(arg).ok()
+            })
+            .collect()
+    }
+
+    type Hashes = Arc<Mutex<HashMap<String, HashMap<String, String>>>>;
+
+    // enough of redis for HSET and PUBLISH, SELECT 9 and HSET of a LIST key are refused
+    fn fake_redis() -> (String, Hashes, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let hashes: Hashes = Default::default();
+        let published: Arc<Mutex<Vec<String>>> = Default::default();
+        let (h, p) = (hashes.clone(), published.clone());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let (h, p) = (h.clone(), p.clone());
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    while let Some(command) = read_command(&mut reader) {
+                        let reply = match command[0].as_str() {
+                            "HSET" if command[1].ends_with("LIST") => {
+                                "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+                            }
+                            "HSET" => {
+                                let mut hashes = h.lock().unwrap();
+                                let hash = hashes.entry(command[1].clone()).or_default();
+                                for kv in command[2..].chunks(2) {
+                                    hash.insert(kv[0].clone(), kv[1].clone());
+                                }
+                                ":3\r\n"
+                            }
+                            "PUBLISH" => {
+                                p.lock().unwrap().push(command[1].clone());
+                                ":0\r\n"
+                            }
+                            "SELECT" if command[1] == "9" => "-ERR DB index is out of range\r\n",
+                            _ => "+OK\r\n",
+                        };
+                        if stream.write_all(reply.as_bytes()).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        (addr, hashes, published)
+    }
+
+    #[test]
+    fn test_redis_sink() {
+        let (addr, hashes, published) = fake_redis();
+        let config = RedisSinkConfig {
+            addr,
+            key_prefix: "cf:".to_string(),
+            publish: true,
+            pipeline: 4,
+            ..Default::default()
+        };
+        let mut sink = RedisSink::new(&config, "0");
+        for (code, close) in [("AAPL", 1.0), ("NVDA", 2.0), ("AAPL", 3.0)] {
+            let msg = msg(&format!("api/V1/TIC/Q/{}", code), close);
+            sink.exec(&msg, &JsonFormater).unwrap();
+        }
+        sink.flush().unwrap();
+        assert!(sink.pending.is_empty());
+        let hashes = hashes.lock().unwrap();
+        let aapl = &hashes["cf:api/V1/TIC/Q/AAPL"];
+        assert_eq!(aapl["data"], r#"{"dest":"api/V1/TIC/Q/AAPL","close":3.0}"#);
+        assert_eq!(aapl["ct"], "json");
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(published.lock().unwrap().len(), 3);
+        drop(hashes);
+
+        let config = RedisSinkConfig {
+            db: Some(9),
+            ..config
+        };
+        let mut sink = RedisSink::new(&config, "0");
+        // the refused SELECT fails the exec that opens the connection
+        assert!(matches!(
+            sink.exec(&msg("api/V1/TIC/Q/AAPL", 1.0), &JsonFormater),
+            Err(SinkError::RedisReply { .. })
+        ));
+        assert!(sink.conn.is_none());
+    }
+
+    fn letters(path: &std::path::Path) -> Vec<serde_json::Value> {
+        let content = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    // LIST holds a list, so its HSET gets a WRONGTYPE reply
+    fn check_error_replies(addr: String, name: &str) {
+        let config = RedisSinkConfig {
+            addr,
+            key_prefix: "cf:".to_string(),
+            pipeline: 4,
+            ..Default::default()
+        };
+        let path = std::env::temp_dir().join(format!("cfvhub-{}-{}", name, std::process::id()));
+        let mut sink =
+            RedisSink::new(&config, "0").with_dead_letter(DeadLetterSink::new(&path).unwrap());
+        for code in ["AAPL", "LIST", "NVDA"] {
+            let msg = msg(&format!("api/V1/TIC/Q/{}", code), 1.0);
+            sink.exec(&msg, &JsonFormater).unwrap();
+        }
+        // the reply of LIST is read after its exec returned, it is dead lettered
+        sink.flush().unwrap();
+        let letters = letters(&path);
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0]["dest"], "cf:api/V1/TIC/Q/LIST");
+        assert_eq!(
+            letters[0]["data"]["data"],
+            r#"{"dest":"api/V1/TIC/Q/LIST","close":1.0}"#
+        );
+        assert!(letters[0]["error"].as_str().unwrap().contains("WRONGTYPE"));
+
+        // the exec that reads its own error reply fails, and is not retried
+        let config = RedisSinkConfig {
+            pipeline: 1,
+            ..config
+        };
+        let mut sink = RedisSink::new(&config, "0");
+        let e = sink
+            .exec(&msg("api/V1/TIC/Q/LIST", 1.0), &JsonFormater)
+            .unwrap_err();
+        assert!(matches!(e, SinkError::RedisReply { .. }));
+        assert!(!e.is_retryable());
+        // the connection is still in step with the replies
+        sink.exec(&msg("api/V1/TIC/Q/AAPL", 1.0), &JsonFormater)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_error_reply_matches_its_message() {
+        let (addr, _, _) = fake_redis();
+        check_error_replies(addr, "redis-fake");
+    }
+
+    // needs redis-server on the path, skipped without it
+    #[test]
+    fn test_redis_server() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server = Command::new("redis-server")
+            .args([
+                "--port",
+                &port.to_string(),
+                "--save",
+                "",
+                "--appendonly",
+                "no",
+            ])
+            .stdout(Stdio::null())
+            .spawn();
+        let mut server = match server {
+            Ok(server) => server,
+            Err(e) => {
+                eprintln!("redis-server not started, skipped: {}", e);
+                return;
+            }
+        };
+        let addr = format!("127.0.0.1:{}", port);
+        let config = RedisSinkConfig {
+            addr: addr.clone(),
+            ..Default::default()
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut conn = loop {
+            match Connection::open(&config) {
+                Ok(conn) => break conn,
+                Err(e) if Instant::now() > deadline => panic!("redis-server not up: {}", e),
+                Err(_) => std::thread::sleep(Duration::from_millis(20)),
+            }
+        };
+        conn.command(&[b"RPUSH", b"cf:api/V1/TIC/Q/LIST", b"x"])
+            .unwrap();
+        check_error_replies(addr, "redis-server");
+        server.kill().unwrap();
+        server.wait().unwrap();
+    }
+}
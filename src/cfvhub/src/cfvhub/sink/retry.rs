@@ -0,0 +1,292 @@
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{error, warn};
+
+use super::{
+    DeadLetterEncodeSnafu, DeadLetterOpenSnafu, Dest, DiskSinkWriteSnafu, FormaterExt, SinkError,
+    SinkExt,
+};
+use crate::latency::now_ns;
+use crate::metrics::{SinkCounters, METRICS};
+use crate::queue::RateLimiter;
+
+/// Exponential backoff between attempts of a retryable error.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// attempts after the first one
+    pub max_retries: u32,
+    pub initial_ms: u64,
+    pub max_ms: u64,
+    pub multiplier: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_ms: 10,
+            max_ms: 1000,
+            multiplier: 2.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before retry number `retry`, starting at 0.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let ms = self.initial_ms as f64 * self.multiplier.powi(retry as i32);
+        Duration::from_millis(ms.min(self.max_ms as f64) as u64)
+    }
+}
+
+#[derive(Serialize)]
+struct DeadLetter<'a, In> {
+    ts: u64,
+    sink: &'a str,
+    id: &'a str,
+    dest: &'a str,
+    attempts: u32,
+    error: String,
+    data: &'a In,
+}
+
+/// Json lines of the messages a sink gave up on, with the error and attempts.
+#[derive(Debug)]
+pub struct DeadLetterSink {
+    pub path: PathBuf,
+    file: File,
+}
+
+impl DeadLetterSink {
+    pub fn new(path: &Path) -> Result<Self, SinkError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(DeadLetterOpenSnafu { path })?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    pub fn record<In: Serialize + Dest>(
+        &mut self,
+        sink: &str,
+        id: &str,
+        input: &In,
+        error: &SinkError,
+        attempts: u32,
+    ) -> Result<(), SinkError> {
+        let letter = DeadLetter {
+            ts: now_ns(),
+            sink,
+            id,
+            dest: input.get_dest(),
+            attempts,
+            error: error.to_string(),
+            data: input,
+        };
+        let line = serde_json::to_string(&letter).context(DeadLetterEncodeSnafu)?;
+        writeln!(self.file, "{}", line).context(DiskSinkWriteSnafu)
+    }
+}
+
+/// Retries retryable errors of the inner sink with backoff, then hands the message
+/// to the dead-letter sink. Blocks the calling worker while backing off.
+pub struct RetrySink<S> {
+    name: String,
+    id: String,
+    sink: S,
+    policy: RetryPolicy,
+    dead_letter: Option<DeadLetterSink>,
+    counters: SinkCounters,
+    log_limit: RateLimiter,
+}
+
+impl<S> RetrySink<S> {
+    pub fn new(name: &str, id: &str, sink: S) -> Self {
+        Self {
+            name: name.to_string(),
+            id: id.to_string(),
+            sink,
+            policy: RetryPolicy::default(),
+            dead_letter: None,
+            counters: METRICS.sink_counters(name, id),
+            log_limit: RateLimiter::default(),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_dead_letter(mut self, dead_letter: DeadLetterSink) -> Self {
+        self.dead_letter = Some(dead_letter);
+        self
+    }
+}
+
+impl<In, S> SinkExt<In> for RetrySink<S>
+where
+    In: Serialize + Dest,
+    S: SinkExt<In>,
+{
+    /// Dead letters go to `DEAD_LETTER_PATH` when set and the file opens, or else the
+    /// messages given up on are counted lost.
+    fn build(id: &str) -> Self {
+        let sink = Self::new("retry", id, S::build(id));
+        let path = match dotenvy::var("DEAD_LETTER_PATH") {
+            Ok(path) => path,
+            Err(_) => return sink,
+        };
+        match DeadLetterSink::new(path.as_ref()) {
+            Ok(dead_letter) => sink.with_dead_letter(dead_letter),
+            Err(e) => {
+                error!("retry sink {} without dead letters: {}", id, e);
+                sink
+            }
+        }
+    }
+
+    fn exec(&mut self, input: &In, formater: &impl FormaterExt<In>) -> Result<(), SinkError> {
+        let mut retry = 0;
+        let e = loop {
+            match self.sink.exec(input, formater) {
+                Ok(_) => return Ok(()),
+                Err(e) if e.is_retryable() && retry < self.policy.max_retries => {
+                    self.counters.outcome("retry");
+                    std::thread::sleep(self.policy.backoff(retry));
+                    retry += 1;
+                }
+                Err(e) => break e,
+            }
+        };
+        if self.log_limit.check() {
+            warn!(
+                "sink {} {} gave up after {} attempts: {}",
+                self.name,
+                self.id,
+                retry + 1,
+                e
+            );
+        }
+        if let Some(dead_letter) = self.dead_letter.as_mut() {
+            match dead_letter.record(&self.name, &self.id, input, &e, retry + 1) {
+                Ok(_) => self.counters.outcome("dead_letter"),
+                Err(e) => {
+                    self.counters.outcome("lost");
+                    error!("dead letter write error: {}", e);
+                }
+            }
+        } else {
+            self.counters.outcome("lost");
+        }
+        Err(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formater::JsonFormater;
+    use crate::test_util::{msg, Msg};
+    use rsolace::types::SolClientReturnCode;
+
+    // fails the first `fail` attempts, with a fatal error when `fatal`
+    struct FlakySink {
+        fail: u32,
+        attempts: u32,
+        fatal: bool,
+    }
+
+    impl SinkExt<Msg> for FlakySink {
+        fn build(_id: &str) -> Self {
+            unimplemented!()
+        }
+
+        fn exec(
+            &mut self,
+            _input: &Msg,
+            _formater: &impl FormaterExt<Msg>,
+        ) -> Result<(), SinkError> {
+            self.attempts += 1;
+            if self.attempts > self.fail {
+                Ok(())
+            } else if self.fatal {
+                Err(SinkError::Panicked {
+                    name: "flaky".to_string(),
+                })
+            } else {
+                Err(SinkError::SolaceSend {
+                    code: SolClientReturnCode::NotReady,
+                })
+            }
+        }
+    }
+
+    fn flaky(fail: u32, fatal: bool) -> FlakySink {
+        FlakySink {
+            fail,
+            attempts: 0,
+            fatal,
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(0), Duration::from_millis(10));
+        assert_eq!(policy.backoff(2), Duration::from_millis(40));
+        assert_eq!(policy.backoff(10), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_retry_then_dead_letter() {
+        let policy = RetryPolicy {
+            initial_ms: 1,
+            ..Default::default()
+        };
+        let msg = msg("api/AAPL", 1.0);
+        let mut sink = RetrySink::new("flaky", "0", flaky(3, false)).with_policy(policy.clone());
+        assert!(sink.exec(&msg, &JsonFormater).is_ok());
+        assert_eq!(sink.sink.attempts, 4);
+
+        let path = std::env::temp_dir().join(format!("cfvhub-dead-letter-{}", std::process::id()));
+        let mut sink = RetrySink::new("flaky", "0", flaky(4, false))
+            .with_policy(policy.clone())
+            .with_dead_letter(DeadLetterSink::new(&path).unwrap());
+        assert!(sink.exec(&msg, &JsonFormater).unwrap_err().is_retryable());
+        assert_eq!(sink.sink.attempts, 4);
+
+        let mut sink = RetrySink::new("flaky", "0", flaky(1, true))
+            .with_policy(policy)
+            .with_dead_letter(DeadLetterSink::new(&path).unwrap());
+        assert!(sink.exec(&msg, &JsonFormater).is_err());
+        assert_eq!(sink.sink.attempts, 1);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let letters: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(letters.len(), 2);
+        assert_eq!(letters[0]["attempts"], 4);
+        assert_eq!(letters[0]["dest"], "api/AAPL");
+        assert_eq!(letters[0]["data"]["dest"], "api/AAPL");
+        assert_eq!(letters[1]["attempts"], 1);
+        std::fs::remove_file(&path).unwrap();
+
+        let missing = path.join("no-such-dir").join("dead-letter");
+        assert!(matches!(
+            DeadLetterSink::new(&missing),
+            Err(SinkError::DeadLetterOpen { path, .. }) if path == missing
+        ));
+    }
+}
@@ -0,0 +1,403 @@
+use serde::ser::{self, Impossible, Serialize, SerializeStruct, Serializer};
+use std::fmt::Display;
+
+/// A scalar field value of a serialized row.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cell {
+    Null,
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Str(String),
+}
+
+#[derive(Debug)]
+pub struct RowError(String);
+
+impl Display for RowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for RowError {}
+
+impl ser::Error for RowError {
+    fn custom<T: Display>(msg: T) -> Self {
+        RowError(msg.to_string())
+    }
+}
+
+fn unsupported<T>(what: &str) -> Result<T, RowError> {
+    Err(RowError(format!("{} is not a row", what)))
+}
+
+/// A serialized struct, `name` is the serde name of the struct, e.g. `NBTick`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub name: &'static str,
+    pub fields: Vec<(&'static str, Cell)>,
+}
+
+/// Fields of a serialized struct in declaration order, nested values become json.
+/// Newtype wrappers and untagged enum variants are looked through.
+pub fn to_row<T: Serialize + ?Sized>(value: &T) -> Result<Row, RowError> {
+    value.serialize(RowSerializer)
+}
+
+struct RowSerializer;
+
+pub struct RowStruct {
+    name: &'static str,
+    fields: Vec<(&'static str, Cell)>,
+}
+
+impl SerializeStruct for RowStruct {
+    type Ok = Row;
+    type Error = RowError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), RowError> {
+        let cell = match value.serialize(CellSerializer) {
+            Ok(cell) => cell,
+            Err(_) => Cell::Str(serde_json::to_string(value).map_err(ser::Error::custom)?),
+        };
+        self.fields.push((key, cell));
+        Ok(())
+    }
+
+    fn skip_field(&mut self, _key: &'static str) -> Result<(), RowError> {
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, RowError> {
+        Ok(Row {
+            name: self.name,
+            fields: self.fields,
+        })
+    }
+}
+
+macro_rules! not_a_row {
+    ($($method:ident($($arg:ty),*)),* $(,)?) => {
+        $(fn $method(self, $(_: $arg),*) -> Result<Self::Ok, RowError> {
+            unsupported(stringify!($method))
+        })*
+    };
+}
+
+impl Serializer for RowSerializer {
+    type Ok = Row;
+    type Error = RowError;
+    type SerializeSeq = Impossible<Self::Ok, RowError>;
+    type SerializeTuple = Impossible<Self::Ok, RowError>;
+    type SerializeTupleStruct = Impossible<Self::Ok, RowError>;
+    type SerializeTupleVariant = Impossible<Self::Ok, RowError>;
+    type SerializeMap = Impossible<Self::Ok, RowError>;
+    type SerializeStruct = RowStruct;
+    type SerializeStructVariant = Impossible<Self::Ok, RowError>;
+
+    not_a_row!(
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+        serialize_bytes(&[u8]),
+        serialize_none(),
+        serialize_unit(),
+        serialize_unit_struct(&'static str),
+        serialize_unit_variant(&'static str, u32, &'static str),
+    );
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, RowError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, RowError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, RowError> {
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, RowError> {
+        unsupported("seq")
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, RowError> {
+        unsupported("tuple")
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, RowError> {
+        unsupported("tuple struct")
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, RowError> {
+        unsupported("tuple variant")
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, RowError> {
+        unsupported("map")
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, RowError> {
+        Ok(RowStruct {
+            name,
+            fields: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, RowError> {
+        unsupported("struct variant")
+    }
+}
+
+struct CellSerializer;
+
+impl Serializer for CellSerializer {
+    type Ok = Cell;
+    type Error = RowError;
+    type SerializeSeq = Impossible<Cell, RowError>;
+    type SerializeTuple = Impossible<Cell, RowError>;
+    type SerializeTupleStruct = Impossible<Cell, RowError>;
+    type SerializeTupleVariant = Impossible<Cell, RowError>;
+    type SerializeMap = Impossible<Cell, RowError>;
+    type SerializeStruct = Impossible<Cell, RowError>;
+    type SerializeStructVariant = Impossible<Cell, RowError>;
+
+    fn serialize_bool(self, v: bool) -> Result<Cell, RowError> {
+        Ok(Cell::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Cell, RowError> {
+        Ok(Cell::I64(v.into()))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Cell, RowError> {
+        Ok(Cell::I64(v.into()))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Cell, RowError> {
+        Ok(Cell::I64(v.into()))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Cell, RowError> {
+        Ok(Cell::I64(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Cell, RowError> {
+        Ok(Cell::U64(v.into()))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Cell, RowError> {
+        Ok(Cell::U64(v.into()))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Cell, RowError> {
+        Ok(Cell::U64(v.into()))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Cell, RowError> {
+        Ok(Cell::U64(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Cell, RowError> {
+        Ok(Cell::F64(v.into()))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Cell, RowError> {
+        Ok(Cell::F64(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Cell, RowError> {
+        Ok(Cell::Str(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Cell, RowError> {
+        Ok(Cell::Str(v.to_string()))
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<Cell, RowError> {
+        unsupported("bytes")
+    }
+
+    fn serialize_none(self) -> Result<Cell, RowError> {
+        Ok(Cell::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Cell, RowError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Cell, RowError> {
+        Ok(Cell::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Cell, RowError> {
+        Ok(Cell::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Cell, RowError> {
+        Ok(Cell::Str(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Cell, RowError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Cell, RowError> {
+        unsupported("newtype variant")
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, RowError> {
+        unsupported("seq")
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, RowError> {
+        unsupported("tuple")
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, RowError> {
+        unsupported("tuple struct")
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, RowError> {
+        unsupported("tuple variant")
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, RowError> {
+        unsupported("map")
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, RowError> {
+        unsupported("struct")
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, RowError> {
+        unsupported("struct variant")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct Quote {
+        code: String,
+        price: f64,
+        volume: i64,
+        phase: Option<u8>,
+        levels: Vec<f64>,
+    }
+
+    #[derive(Serialize)]
+    #[serde(untagged)]
+    enum Data {
+        Quote(Quote),
+    }
+
+    #[test]
+    fn test_to_row() {
+        let data = Data::Quote(Quote {
+            code: "AAPL".to_string(),
+            price: 1.5,
+            volume: 10,
+            phase: None,
+            levels: vec![1.0, 2.0],
+        });
+        let row = to_row(&data).unwrap();
+        assert_eq!(row.name, "Quote");
+        assert_eq!(
+            row.fields,
+            vec![
+                ("code", Cell::Str("AAPL".to_string())),
+                ("price", Cell::F64(1.5)),
+                ("volume", Cell::I64(10)),
+                ("phase", Cell::Null),
+                ("levels", Cell::Str("[1.0,2.0]".to_string())),
+            ]
+        );
+        assert!(to_row(&1.5).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use std::sync::{Arc, Mutex, Weak};
+
+use super::SinkError;
+
+/// Data a sink holds until it is closed, e.g. the open batches or a parquet footer.
+pub trait Close: Send + Sync {
+    fn close(&self) -> Result<(), SinkError>;
+}
+
+#[derive(Default)]
+struct Registered {
+    batches: Vec<Weak<dyn Close>>,
+    files: Vec<Weak<dyn Close>>,
+}
+
+/// The sinks built with it, closed before the process exits since sink workers never
+/// return. Clones share the sinks, dropped sinks are skipped.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    registered: Arc<Mutex<Registered>>,
+}
+
+impl std::fmt::Debug for Shutdown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let registered = self.registered.lock().unwrap();
+        f.debug_struct("Shutdown")
+            .field("batches", &registered.batches.len())
+            .field("files", &registered.files.len())
+            .finish()
+    }
+}
+
+impl Shutdown {
+    pub fn add_batches(&self, batches: Weak<dyn Close>) {
+        self.registered.lock().unwrap().batches.push(batches);
+    }
+
+    pub fn add_file(&self, file: Weak<dyn Close>) {
+        self.registered.lock().unwrap().files.push(file);
+    }
+
+    /// Sends the open batches, then closes the files they may go to. Returns the last
+    /// error, the rest are still closed. Later writes start new files.
+    pub fn close(&self) -> Result<(), SinkError> {
+        let mut registered = self.registered.lock().unwrap();
+        let Registered { batches, files } = &mut *registered;
+        let mut r = Ok(());
+        for open in [batches, files] {
+            open.retain(|close| close.strong_count() > 0);
+            for close in open.iter().filter_map(Weak::upgrade) {
+                if let Err(e) = close.close() {
+                    r = Err(e);
+                }
+            }
+        }
+        r
+    }
+}
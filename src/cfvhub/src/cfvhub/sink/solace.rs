@@ -1,10 +1,203 @@
 use rsolace::solclient::{SessionProps, SolClient};
 use rsolace::solmsg::SolMsg;
-use rsolace::types::{SolClientLogLevel, SolClientReturnCode};
-use serde::Serialize;
-use tracing::{error, info};
+use rsolace::types::{
+    SolClientDeliveryMode, SolClientLogLevel, SolClientReturnCode, SolClientSessionEvent,
+};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use tracing::{error, info, warn};
 
-use super::{Dest, Formated, FormaterExt, SinkError, SinkExt};
+use super::{
+    Dest, FormatSnafu, FormaterExt, SinkError, SinkExt, SolaceConfigSnafu, SolaceSendSnafu,
+};
+use crate::metrics::{SinkCounters, METRICS};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryMode {
+    #[default]
+    Direct,
+    Persistent,
+    NonPersistent,
+}
+
+impl From<DeliveryMode> for SolClientDeliveryMode {
+    fn from(mode: DeliveryMode) -> Self {
+        match mode {
+            DeliveryMode::Direct => SolClientDeliveryMode::Direct,
+            DeliveryMode::Persistent => SolClientDeliveryMode::Persistent,
+            DeliveryMode::NonPersistent => SolClientDeliveryMode::NonPersistent,
+        }
+    }
+}
+
+impl FromStr for DeliveryMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(DeliveryMode::Direct),
+            "persistent" => Ok(DeliveryMode::Persistent),
+            "non_persistent" => Ok(DeliveryMode::NonPersistent),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+/// Session and publish settings, the `SOLACE_*` env variables override them in `with_env`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SolaceSinkConfig {
+    pub host: String,
+    pub vpn: String,
+    pub username: String,
+    pub password: String,
+    pub reapply_subscriptions: bool,
+    pub connect_retries: u32,
+    pub reconnect_retries: u32,
+    pub connect_timeout_ms: u32,
+    pub compression_level: u32,
+    pub delivery_mode: DeliveryMode,
+    /// sent with every message next to `ct`
+    pub user_properties: BTreeMap<String, String>,
+}
+
+impl Default for SolaceSinkConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            vpn: "default".to_string(),
+            username: "default".to_string(),
+            password: "default".to_string(),
+            reapply_subscriptions: true,
+            connect_retries: 3,
+            reconnect_retries: 3,
+            connect_timeout_ms: 3000,
+            compression_level: 5,
+            delivery_mode: DeliveryMode::default(),
+            user_properties: BTreeMap::new(),
+        }
+    }
+}
+
+fn env_string(name: &str, target: &mut String) {
+    if let Ok(value) = dotenvy::var(name) {
+        *target = value;
+    }
+}
+
+fn env_parse<T: FromStr>(name: &str, target: &mut T) -> Result<(), SinkError> {
+    if let Ok(value) = dotenvy::var(name) {
+        *target = value
+            .parse()
+            .map_err(|_| SolaceConfigSnafu { name, value }.build())?;
+    }
+    Ok(())
+}
+
+impl SolaceSinkConfig {
+    pub fn from_env() -> Result<Self, SinkError> {
+        Self::default().with_env()
+    }
+
+    /// `SOLACE_USER_PROPERTIES` is `key=value` pairs separated by commas.
+    pub fn with_env(mut self) -> Result<Self, SinkError> {
+        env_string("SOLACE_HOST", &mut self.host);
+        env_string("SOLACE_VPN", &mut self.vpn);
+        env_string("SOLACE_USERNAME", &mut self.username);
+        env_string("SOLACE_PASSWORD", &mut self.password);
+        env_parse(
+            "SOLACE_REAPPLY_SUBSCRIPTIONS",
+            &mut self.reapply_subscriptions,
+        )?;
+        env_parse("SOLACE_CONNECT_RETRIES", &mut self.connect_retries)?;
+        env_parse("SOLACE_RECONNECT_RETRIES", &mut self.reconnect_retries)?;
+        env_parse("SOLACE_CONNECT_TIMEOUT_MS", &mut self.connect_timeout_ms)?;
+        env_parse("SOLACE_COMPRESSION_LEVEL", &mut self.compression_level)?;
+        env_parse("SOLACE_DELIVERY_MODE", &mut self.delivery_mode)?;
+        if let Ok(value) = dotenvy::var("SOLACE_USER_PROPERTIES") {
+            for pair in value.split(',').filter(|pair| !pair.trim().is_empty()) {
+                let (key, prop) = pair.split_once('=').ok_or_else(|| {
+                    SolaceConfigSnafu {
+                        name: "SOLACE_USER_PROPERTIES",
+                        value: value.clone(),
+                    }
+                    .build()
+                })?;
+                self.user_properties
+                    .insert(key.trim().to_string(), prop.trim().to_string());
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn session_props(&self) -> SessionProps {
+        SessionProps::default()
+            .host(&self.host)
+            .vpn(&self.vpn)
+            .username(&self.username)
+            .password(&self.password)
+            // .client_name(
+            // &dotenvy::var("SOLACE_CLIENT_NAME").unwrap_or_else(|_| "default".to_string()),
+            // ) maybe use ap and thread id
+            .reapply_subscriptions(self.reapply_subscriptions)
+            .connect_retries(self.connect_retries)
+            .reconnect_retries(self.reconnect_retries)
+            .connect_timeout_ms(self.connect_timeout_ms)
+            .compression_level(self.compression_level)
+    }
+}
+
+pub fn load_session_props_from_dotenv() -> Result<SessionProps, SinkError> {
+    SolaceSinkConfig::from_env().map(|config| config.session_props())
+}
+
+/// Session state from the solace session events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SolaceState {
+    Connecting = 0,
+    Up = 1,
+    Reconnecting = 2,
+    Down = 3,
+}
+
+impl SolaceState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SolaceState::Connecting => "connecting",
+            SolaceState::Up => "up",
+            SolaceState::Reconnecting => "reconnecting",
+            SolaceState::Down => "down",
+        }
+    }
+
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => SolaceState::Up,
+            2 => SolaceState::Reconnecting,
+            3 => SolaceState::Down,
+            _ => SolaceState::Connecting,
+        }
+    }
+
+    fn from_event(event: SolClientSessionEvent) -> Option<Self> {
+        match event {
+            SolClientSessionEvent::UpNotice | SolClientSessionEvent::ReconnectedNotice => {
+                Some(SolaceState::Up)
+            }
+            SolClientSessionEvent::ReconnectingNotice => Some(SolaceState::Reconnecting),
+            SolClientSessionEvent::DownError | SolClientSessionEvent::ConnectFailedError => {
+                Some(SolaceState::Down)
+            }
+            _ => None,
+        }
+    }
+}
 
 // #[derive(Debug)]
 #[derive(Serialize)]
@@ -12,131 +205,178 @@ pub struct SolaceSink {
     #[serde(skip)]
     solclient: SolClient,
     id: String,
-    // props: SessionProps,
-}
-
-fn load_session_props_from_dotenv() -> SessionProps {
-    SessionProps::default()
-        .host(&dotenvy::var("SOLACE_HOST").unwrap_or_else(|_| "localhost".to_string()))
-        .vpn(&dotenvy::var("SOLACE_VPN").unwrap_or_else(|_| "default".to_string()))
-        .username(&dotenvy::var("SOLACE_USERNAME").unwrap_or_else(|_| "default".to_string()))
-        .password(&dotenvy::var("SOLACE_PASSWORD").unwrap_or_else(|_| "default".to_string()))
-        // .client_name(
-        // &dotenvy::var("SOLACE_CLIENT_NAME").unwrap_or_else(|_| "default".to_string()),
-        // ) maybe use ap and thread id
-        .reapply_subscriptions(
-            dotenvy::var("SOLACE_REAPPLY_SUBSCRIPTIONS")
-                .unwrap_or_else(|_| "true".to_string())
-                .parse::<bool>()
-                .unwrap(),
-        )
-        .connect_retries(
-            dotenvy::var("SOLACE_CONNECT_RETRIES")
-                .unwrap_or_else(|_| "3".to_string())
-                .parse::<u32>()
-                .unwrap(),
-        )
-        .connect_timeout_ms(
-            dotenvy::var("SOLACE_CONNECT_TIMEOUT_MS")
-                .unwrap_or_else(|_| "3000".to_string())
-                .parse::<u32>()
-                .unwrap(),
-        )
-        .compression_level(
-            dotenvy::var("SOLACE_COMPRESSION_LEVEL")
-                .unwrap_or_else(|_| "5".to_string())
-                .parse::<u32>()
-                .unwrap(),
-        )
+    #[serde(skip)]
+    state: Arc<AtomicU8>,
+    #[serde(skip)]
+    delivery_mode: DeliveryMode,
+    #[serde(skip)]
+    user_properties: Vec<(String, String)>,
+    // the attachment of every message, reused
+    #[serde(skip)]
+    buf: Vec<u8>,
+    #[serde(skip)]
+    counters: SinkCounters,
 }
 
+/// Like `SinkExt::build`, panics when the `SOLACE_*` settings are invalid or the session
+/// does not connect, use `SolaceSink::from_env` to get the error.
 impl Default for SolaceSink {
     fn default() -> Self {
-        let props = load_session_props_from_dotenv();
-        Self::new(props, "default")
+        Self::from_env("default")
+            .unwrap_or_else(|e| panic!("SolaceSink default build error: {}", e))
     }
 }
 
 impl SolaceSink {
-    pub fn new(props: SessionProps, id: &str) -> Self {
-        let mut solclient = SolClient::new(SolClientLogLevel::Warning).unwrap();
-        // info!("SolaceSink created: {:?}", props);
-        let r = solclient.connect(props);
-        if r {
-            info!("SolaceSink {} connected", id);
-        } else {
-            error!("SolaceSink {} connect error", id);
-        }
+    pub fn new(props: SessionProps, id: &str) -> Result<Self, SinkError> {
+        Self::connect(props, id)
+    }
+
+    /// Settings from the `SOLACE_*` env variables.
+    pub fn from_env(id: &str) -> Result<Self, SinkError> {
+        SolaceSinkConfig::from_env().and_then(|config| Self::from_config(&config, id))
+    }
+
+    pub fn from_config(config: &SolaceSinkConfig, id: &str) -> Result<Self, SinkError> {
+        let mut sink = Self::connect(config.session_props(), id)?;
+        sink.delivery_mode = config.delivery_mode;
+        sink.user_properties = config
+            .user_properties
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        Ok(sink)
+    }
+
+    fn connect(props: SessionProps, id: &str) -> Result<Self, SinkError> {
+        let mut solclient =
+            SolClient::new(SolClientLogLevel::Warning).map_err(|e| SinkError::SolaceClient {
+                message: format!("{:?}", e),
+            })?;
+        let state = Arc::new(AtomicU8::new(SolaceState::Connecting as u8));
+        // events are handled before connect returns, so the up notice is not missed
         let event_recv = solclient.get_event_receiver();
         let id_th = id.to_string();
+        let state_th = state.clone();
         let _th_event = std::thread::spawn(move || loop {
             match event_recv.recv() {
                 Ok(event) => {
-                    tracing::info!("SolaceSink {} {:?}", id_th, event);
+                    let next = SolaceState::from_event(event.session_event);
+                    match next {
+                        Some(SolaceState::Reconnecting) | Some(SolaceState::Down) => {
+                            warn!("SolaceSink {} {:?}", id_th, event)
+                        }
+                        _ => info!("SolaceSink {} {:?}", id_th, event),
+                    }
+                    if let Some(next) = next {
+                        state_th.store(next as u8, Ordering::Release);
+                        METRICS.set_solace_state(&id_th, next.as_str());
+                    }
                 }
                 Err(e) => {
-                    tracing::error!("SolaceSink {} recv event error: {:?}", id_th, e);
+                    error!("SolaceSink {} recv event error: {:?}", id_th, e);
                     break;
                 }
             }
         });
-        // TODO event handle
-
-        Self {
+        // info!("SolaceSink created: {:?}", props);
+        if !solclient.connect(props) {
+            return Err(SinkError::SolaceClient {
+                message: format!("session of {} did not connect", id),
+            });
+        }
+        info!("SolaceSink {} connected", id);
+        Ok(Self {
             solclient,
             id: id.to_string(),
+            state,
+            delivery_mode: DeliveryMode::default(),
+            user_properties: vec![],
+            buf: Vec::new(),
+            counters: METRICS.sink_counters("solace", id),
+        })
+    }
+
+    pub fn state(&self) -> SolaceState {
+        SolaceState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn send<In: Serialize + Dest>(
+        &mut self,
+        input: &In,
+        formater: &impl FormaterExt<In>,
+    ) -> Result<(), SinkError> {
+        // fail fast while the session is away, the retry sink waits for the reconnect
+        if matches!(self.state(), SolaceState::Reconnecting | SolaceState::Down) {
+            return SolaceSendSnafu {
+                code: SolClientReturnCode::NotReady,
+            }
+            .fail();
+        }
+        let dest = input.get_dest();
+        let content_type = formater.content_type();
+        self.buf.clear();
+        formater.format_into(input, &mut self.buf).context(FormatSnafu)?;
+        let mut msg = SolMsg::new().map_err(|e| SinkError::SolaceMsg {
+            message: format!("{:?}", e),
+        })?;
+        msg.set_topic(dest);
+        msg.set_delivery_mode(self.delivery_mode.into());
+        msg.set_user_prop("ct", content_type, 20);
+        for (key, value) in &self.user_properties {
+            msg.set_user_prop(key, value, 20);
+        }
+        msg.set_binary_attachment(&self.buf);
+        let r = self.solclient.send_msg(&msg);
+        match r {
+            SolClientReturnCode::Ok => Ok(()),
+            code => SolaceSendSnafu { code }.fail(),
         }
     }
 }
 
 impl<In: Serialize + Dest> SinkExt<In> for SolaceSink {
+    /// Settings from the `SOLACE_*` env variables, use `SolaceSink::from_env` to get the
+    /// error instead of a panic.
     fn build(id: &str) -> Self {
-        let props = load_session_props_from_dotenv();
-        Self::new(props, id)
+        Self::from_env(id).unwrap_or_else(|e| panic!("SolaceSink {} build error: {}", id, e))
     }
 
-    fn exec(&mut self, input: &In, formater: &impl FormaterExt<In>) {
-        let dest = input.get_dest();
-        let content_type = formater.content_type();
-        let formated = formater.format(input);
-        match formated {
-            Ok(formated) => {
-                let mut msg = SolMsg::new().unwrap();
-                msg.set_topic(dest);
-                msg.set_user_prop("ct", content_type, 20);
-                let r = match formated {
-                    Formated::String(s) => {
-                        println!("topic: {} data: {}", dest, s);
-                        msg.set_binary_attachment(s.as_bytes());
-                        self.solclient.send_msg(&msg)
-                    }
-                    Formated::Bytes(b) => {
-                        // println!("topic: {} data: {:?}", dest, b);
-                        msg.set_binary_attachment(&b);
-                        self.solclient.send_msg(&msg)
-                    }
-                };
-                match r {
-                    SolClientReturnCode::Ok => {
-                        // TODO to check queue
-                        // info!("SolaceSink send message success");
-                    }
-                    _ => {
-                        error!("SolaceSink send message error: {:?}", r);
-                    }
-                }
-            }
-            Err(e) => {
-                eprintln!("Format Error: {}", e);
-            }
-        }
-        // let json = serde_json::to_string(input).unwrap();
-        // let topic = dotenvy::var("SOLACE_TOPIC").unwrap_or_else(|_| "default".to_string());
-        // let r = self.solclient.send_message(&topic, &json);
-        // if r {
-        //     info!("SolaceSink send message success");
-        // } else {
-        //     error!("SolaceSink send message error");
-        // }
+    fn exec(&mut self, input: &In, formater: &impl FormaterExt<In>) -> Result<(), SinkError> {
+        let r = self.send(input, formater);
+        self.counters.result(r.is_ok());
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_solace_sink_config() {
+        let config: SolaceSinkConfig = toml::from_str(
+            r#"
+            host = "tcp://broker:55555"
+            delivery_mode = "persistent"
+            [user_properties]
+            source = "cfvhub"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.host, "tcp://broker:55555");
+        assert_eq!(config.vpn, "default");
+        assert_eq!(config.delivery_mode, DeliveryMode::Persistent);
+        assert_eq!(config.user_properties["source"], "cfvhub");
+
+        let mut retries = 0u32;
+        std::env::set_var("CFVHUB_TEST_SOLACE_RETRIES", "three");
+        assert!(matches!(
+            env_parse("CFVHUB_TEST_SOLACE_RETRIES", &mut retries),
+            Err(SinkError::SolaceConfig { .. })
+        ));
+        std::env::set_var("CFVHUB_TEST_SOLACE_RETRIES", "5");
+        env_parse("CFVHUB_TEST_SOLACE_RETRIES", &mut retries).unwrap();
+        assert_eq!(retries, 5);
     }
 }
@@ -0,0 +1,567 @@
+use crossbeam_channel::{bounded, Receiver, Sender};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::{Message, WebSocket};
+
+use super::{
+    Dest, FormatSnafu, Formated, FormaterExt, SinkError, SinkExt, WebSocketBindSnafu,
+    WebSocketConfigSnafu,
+};
+use crate::metrics::{SinkCounters, METRICS};
+
+fn default_addr() -> String {
+    dotenvy::var("WS_SINK_ADDR").unwrap_or_else(|_| "0.0.0.0:8765".to_string())
+}
+
+fn default_max_pending() -> usize {
+    10000
+}
+
+/// What happens to a client that reads slower than messages arrive.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowClient {
+    /// keep only the latest unsent frame per topic
+    #[default]
+    Conflate,
+    /// close the connection once `max_pending` frames are queued
+    Disconnect,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebSocketSinkConfig {
+    #[serde(default = "default_addr")]
+    pub addr: String,
+    #[serde(default)]
+    pub slow_client: SlowClient,
+    #[serde(default = "default_max_pending")]
+    pub max_pending: usize,
+}
+
+impl Default for WebSocketSinkConfig {
+    fn default() -> Self {
+        Self {
+            addr: default_addr(),
+            slow_client: SlowClient::default(),
+            max_pending: default_max_pending(),
+        }
+    }
+}
+
+/// A subscription on the `Dest` topic levels, e.g. `api/V1/TIC/*/AAPL` or `api/V1/QUO/>`.
+/// `*` matches one level, `A*` a level starting with `A` and a trailing `>` one or more levels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicPattern {
+    levels: Vec<String>,
+}
+
+impl TopicPattern {
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            levels: pattern.split('/').map(|level| level.to_string()).collect(),
+        }
+    }
+
+    pub fn matches(&self, topic: &str) -> bool {
+        let mut levels = topic.split('/');
+        for (i, pattern) in self.levels.iter().enumerate() {
+            if pattern == ">" && i == self.levels.len() - 1 {
+                return levels.next().is_some();
+            }
+            let level = match levels.next() {
+                Some(level) => level,
+                None => return false,
+            };
+            let matched = match pattern.strip_suffix('*') {
+                Some(prefix) => level.starts_with(prefix),
+                None => pattern == level,
+            };
+            if !matched {
+                return false;
+            }
+        }
+        levels.next().is_none()
+    }
+}
+
+// a client's unsent frames
+enum Pending {
+    Conflate {
+        order: VecDeque<String>,
+        frames: HashMap<String, Message>,
+    },
+    Queue(VecDeque<Message>),
+}
+
+impl Pending {
+    fn new(slow_client: SlowClient) -> Self {
+        match slow_client {
+            SlowClient::Conflate => Pending::Conflate {
+                order: VecDeque::new(),
+                frames: HashMap::new(),
+            },
+            SlowClient::Disconnect => Pending::Queue(VecDeque::new()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Pending::Conflate { order, .. } => order.len(),
+            Pending::Queue(queue) => queue.len(),
+        }
+    }
+
+    /// Whether an unsent frame was replaced.
+    fn push(&mut self, topic: &str, frame: Message) -> bool {
+        match self {
+            Pending::Conflate { order, frames } => {
+                if frames.insert(topic.to_string(), frame).is_some() {
+                    return true;
+                }
+                order.push_back(topic.to_string());
+                false
+            }
+            Pending::Queue(queue) => {
+                queue.push_back(frame);
+                false
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<Message> {
+        match self {
+            Pending::Conflate { order, frames } => {
+                order.pop_front().and_then(|topic| frames.remove(&topic))
+            }
+            Pending::Queue(queue) => queue.pop_front(),
+        }
+    }
+}
+
+struct ClientState {
+    patterns: Vec<TopicPattern>,
+    pending: Pending,
+    closed: bool,
+}
+
+struct Client {
+    id: u64,
+    state: Mutex<ClientState>,
+    notify: Sender<()>,
+}
+
+impl Client {
+    fn matches(state: &ClientState, topic: &str) -> bool {
+        state.patterns.iter().any(|pattern| pattern.matches(topic))
+    }
+
+    fn wake(&self) {
+        let _ = self.notify.try_send(());
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Command {
+    #[serde(default)]
+    subscribe: Vec<String>,
+    #[serde(default)]
+    unsubscribe: Vec<String>,
+}
+
+// wait for new frames, the socket and commands are polled at least this often
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Embedded websocket server shared by the sink workers of one address.
+/// Clients send `{"subscribe": ["api/V1/TIC/*/AAPL"]}` or `{"unsubscribe": [...]}`,
+/// get the latest frame of every matching topic and then the live frames.
+pub struct WebSocketServer {
+    addr: SocketAddr,
+    config: WebSocketSinkConfig,
+    latest: DashMap<String, Message>,
+    clients: RwLock<Vec<Arc<Client>>>,
+    next_id: AtomicU64,
+}
+
+impl WebSocketServer {
+    pub fn bind(config: &WebSocketSinkConfig) -> Result<Arc<Self>, SinkError> {
+        let listener = TcpListener::bind(&config.addr).context(WebSocketBindSnafu)?;
+        let server = Arc::new(Self {
+            addr: listener.local_addr().context(WebSocketBindSnafu)?,
+            config: config.clone(),
+            latest: DashMap::new(),
+            clients: RwLock::new(vec![]),
+            next_id: AtomicU64::new(0),
+        });
+        info!("websocket sink listening on {}", server.addr);
+        let accept = server.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let server = accept.clone();
+                        std::thread::spawn(move || server.serve(stream));
+                    }
+                    Err(e) => warn!("websocket accept error: {}", e),
+                }
+            }
+        });
+        Ok(server)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn clients(&self) -> usize {
+        self.clients.read().unwrap().len()
+    }
+
+    /// Keeps the frame as the topic snapshot and queues it for the subscribed clients.
+    pub fn publish(&self, counters: &mut SinkCounters, topic: &str, frame: Message) {
+        self.latest.insert(topic.to_string(), frame.clone());
+        for client in self.clients.read().unwrap().iter() {
+            let mut state = client.state.lock().unwrap();
+            if state.closed || !Client::matches(&state, topic) {
+                continue;
+            }
+            if state.pending.push(topic, frame.clone()) {
+                counters.outcome("conflated");
+            } else if state.pending.len() > self.config.max_pending {
+                state.closed = true;
+                counters.outcome("disconnect");
+            }
+            drop(state);
+            client.wake();
+        }
+    }
+
+    // the snapshot is queued under the client lock, so a live frame is never followed
+    // by an older snapshot of the same topic
+    fn subscribe(&self, client: &Client, patterns: &[String]) {
+        let mut state = client.state.lock().unwrap();
+        let patterns = patterns
+            .iter()
+            .map(|pattern| TopicPattern::new(pattern))
+            .collect::<Vec<_>>();
+        for entry in self.latest.iter() {
+            if patterns.iter().any(|pattern| pattern.matches(entry.key()))
+                && !Client::matches(&state, entry.key())
+            {
+                state.pending.push(entry.key(), entry.value().clone());
+            }
+        }
+        state.patterns.extend(patterns);
+        drop(state);
+        client.wake();
+    }
+
+    fn unsubscribe(&self, client: &Client, patterns: &[String]) {
+        let mut state = client.state.lock().unwrap();
+        state
+            .patterns
+            .retain(|pattern| !patterns.iter().any(|p| *pattern == TopicPattern::new(p)));
+    }
+
+    fn command(&self, client: &Client, text: &str) -> Option<Message> {
+        match serde_json::from_str::<Command>(text) {
+            Ok(command) => {
+                self.unsubscribe(client, &command.unsubscribe);
+                self.subscribe(client, &command.subscribe);
+                None
+            }
+            Err(e) => Some(Message::Text(
+                serde_json::json!({ "error": e.to_string() }).to_string(),
+            )),
+        }
+    }
+
+    fn serve(self: Arc<Self>, stream: TcpStream) {
+        let peer = stream.peer_addr().ok();
+        let _ = stream.set_nodelay(true);
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+        let ws_config = WebSocketConfig {
+            max_write_buffer_size: 16 << 20,
+            ..Default::default()
+        };
+        let mut ws = match tungstenite::accept_with_config(stream, Some(ws_config)) {
+            Ok(ws) => ws,
+            Err(e) => {
+                warn!("websocket handshake with {:?} error: {}", peer, e);
+                return;
+            }
+        };
+        if let Err(e) = ws.get_mut().set_nonblocking(true) {
+            warn!("websocket {:?} error: {}", peer, e);
+            return;
+        }
+        let (notify, wake) = bounded(1);
+        let client = Arc::new(Client {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            state: Mutex::new(ClientState {
+                patterns: vec![],
+                pending: Pending::new(self.config.slow_client),
+                closed: false,
+            }),
+            notify,
+        });
+        self.clients.write().unwrap().push(client.clone());
+        info!("websocket client {:?} connected", peer);
+        if let Err(e) = self.client_loop(&client, &mut ws, &wake) {
+            info!("websocket client {:?} closed: {}", peer, e);
+        }
+        self.clients.write().unwrap().retain(|c| c.id != client.id);
+    }
+
+    fn client_loop(
+        &self,
+        client: &Client,
+        ws: &mut WebSocket<TcpStream>,
+        wake: &Receiver<()>,
+    ) -> Result<(), Box<tungstenite::Error>> {
+        // frames are only handed over while the socket keeps up, the rest wait in pending
+        let mut writable = true;
+        loop {
+            let _ = wake.recv_timeout(POLL_INTERVAL);
+            loop {
+                match ws.read() {
+                    Ok(Message::Text(text)) => {
+                        if let Some(reply) = self.command(client, &text) {
+                            would_block(ws.write(reply))?;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        would_block(Err(e))?;
+                        break;
+                    }
+                }
+            }
+            if client.state.lock().unwrap().closed {
+                let _ = ws.close(None);
+                let _ = ws.flush();
+                return Err(Box::new(tungstenite::Error::ConnectionClosed));
+            }
+            while writable {
+                let frame = client.state.lock().unwrap().pending.pop();
+                match frame {
+                    Some(frame) => writable = would_block(ws.write(frame))?,
+                    None => break,
+                }
+            }
+            writable = would_block(ws.flush())?;
+        }
+    }
+}
+
+// Ok(false) when the socket would block, the frame stays in the write buffer
+fn would_block(r: Result<(), tungstenite::Error>) -> Result<bool, Box<tungstenite::Error>> {
+    match r {
+        Ok(_) => Ok(true),
+        Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+// one server per address for every worker in the process
+static SERVERS: Lazy<Mutex<HashMap<String, Arc<WebSocketServer>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Json formaters send text frames `{"topic": ..., "data": ...}`, msgpack binary
+/// frames `[topic, data]`, other formats carry the data as a string or binary.
+pub fn frame(topic: &str, formated: Formated, content_type: &str) -> Message {
+    match formated {
+        Formated::String(s) if content_type == "json" => {
+            let topic = serde_json::to_string(topic).unwrap_or_default();
+            Message::Text(format!("{{\"topic\":{},\"data\":{}}}", topic, s))
+        }
+        Formated::String(s) => {
+            Message::Text(serde_json::json!({ "topic": topic, "data": s }).to_string())
+        }
+        Formated::Bytes(b) => {
+            let mut buf = Vec::with_capacity(b.len() + topic.len() + 16);
+            // fixarray of 2, then the topic str
+            buf.push(0x92);
+            msgpack_header(&mut buf, topic.len(), Some(0xa0), [0xd9, 0xda, 0xdb]);
+            buf.extend_from_slice(topic.as_bytes());
+            if content_type != "msgpack" {
+                msgpack_header(&mut buf, b.len(), None, [0xc4, 0xc5, 0xc6]);
+            }
+            buf.extend_from_slice(&b);
+            Message::Binary(buf)
+        }
+    }
+}
+
+// str and bin length markers, only str has a fix form below 32
+fn msgpack_header(buf: &mut Vec<u8>, len: usize, fix: Option<u8>, markers: [u8; 3]) {
+    if let Some(fix) = fix.filter(|_| len < 32) {
+        buf.push(fix | len as u8);
+    } else if len <= u8::MAX as usize {
+        buf.push(markers[0]);
+        buf.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        buf.push(markers[1]);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buf.push(markers[2]);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+pub struct WebSocketSink {
+    id: String,
+    server: Arc<WebSocketServer>,
+    counters: SinkCounters,
+}
+
+impl std::fmt::Debug for WebSocketSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSocketSink")
+            .field("id", &self.id)
+            .field("addr", &self.server.addr)
+            .finish()
+    }
+}
+
+impl WebSocketSink {
+    /// Starts the server of `config.addr` or joins the one already running, which fails
+    /// when that one was started with other settings.
+    pub fn from_config(config: &WebSocketSinkConfig, id: &str) -> Result<Self, SinkError> {
+        let mut servers = SERVERS.lock().unwrap();
+        let server = match servers.get(&config.addr) {
+            Some(server) if server.config != *config => {
+                return WebSocketConfigSnafu { addr: &config.addr }.fail()
+            }
+            Some(server) => server.clone(),
+            None => {
+                let server = WebSocketServer::bind(config)?;
+                servers.insert(config.addr.clone(), server.clone());
+                server
+            }
+        };
+        Ok(Self {
+            id: id.to_string(),
+            server,
+            counters: METRICS.sink_counters("websocket", id),
+        })
+    }
+
+    pub fn server(&self) -> &Arc<WebSocketServer> {
+        &self.server
+    }
+}
+
+impl<In: Serialize + Dest> SinkExt<In> for WebSocketSink {
+    /// Serves on `WS_SINK_ADDR`.
+    fn build(id: &str) -> Self {
+        Self::from_config(&WebSocketSinkConfig::default(), id).unwrap()
+    }
+
+    fn exec(&mut self, input: &In, formater: &impl FormaterExt<In>) -> Result<(), SinkError> {
+        let r = formater.format(input).context(FormatSnafu).map(|formated| {
+            let topic = input.get_dest();
+            let frame = frame(topic, formated, formater.content_type());
+            self.server.publish(&mut self.counters, topic, frame);
+        });
+        self.counters.result(r.is_ok());
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formater::JsonFormater;
+    use crate::test_util::msg;
+
+    fn recv(
+        client: &mut WebSocket<tungstenite::stream::MaybeTlsStream<TcpStream>>,
+    ) -> serde_json::Value {
+        loop {
+            if let Message::Text(text) = client.read().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_topic_pattern() {
+        assert!(TopicPattern::new("api/V1/TIC/*/AAPL").matches("api/V1/TIC/Q/AAPL"));
+        assert!(!TopicPattern::new("api/V1/TIC/*/AAPL").matches("api/V1/QUO/Q/AAPL"));
+        assert!(TopicPattern::new("api/V1/TIC/Q/A*").matches("api/V1/TIC/Q/AMD"));
+        assert!(!TopicPattern::new("api/V1/TIC/Q/A*").matches("api/V1/TIC/Q/NVDA"));
+        assert!(TopicPattern::new("api/V1/>").matches("api/V1/QUO/Q/NVDA"));
+        assert!(!TopicPattern::new("api/V1/>").matches("api/V1"));
+        assert!(!TopicPattern::new("api/V1/TIC").matches("api/V1/TIC/Q/AAPL"));
+    }
+
+    #[test]
+    fn test_snapshot_then_live() {
+        let config = WebSocketSinkConfig {
+            addr: "127.0.0.1:0".to_string(),
+            ..Default::default()
+        };
+        let mut sink = WebSocketSink::from_config(&config, "0").unwrap();
+        sink.exec(&msg("api/V1/TIC/Q/AAPL", 1.0), &JsonFormater)
+            .unwrap();
+        sink.exec(&msg("api/V1/TIC/Q/AAPL", 2.0), &JsonFormater)
+            .unwrap();
+        sink.exec(&msg("api/V1/QUO/Q/AAPL", 3.0), &JsonFormater)
+            .unwrap();
+
+        // the workers of one address share the server and its settings
+        let other = WebSocketSinkConfig {
+            max_pending: 1,
+            ..config.clone()
+        };
+        assert!(WebSocketSink::from_config(&config, "1").is_ok());
+        assert!(matches!(
+            WebSocketSink::from_config(&other, "1"),
+            Err(SinkError::WebSocketConfig { .. })
+        ));
+
+        let url = format!("ws://{}", sink.server().local_addr());
+        let (mut client, _) = tungstenite::connect(url).unwrap();
+        client
+            .send(Message::Text(
+                r#"{"subscribe": ["api/V1/TIC/*/>"]}"#.to_string(),
+            ))
+            .unwrap();
+        let snapshot = recv(&mut client);
+        assert_eq!(snapshot["topic"], "api/V1/TIC/Q/AAPL");
+        assert_eq!(snapshot["data"]["close"], 2.0);
+
+        sink.exec(&msg("api/V1/QUO/Q/AAPL", 4.0), &JsonFormater)
+            .unwrap();
+        sink.exec(&msg("api/V1/TIC/Q/NVDA", 5.0), &JsonFormater)
+            .unwrap();
+        let live = recv(&mut client);
+        assert_eq!(live["topic"], "api/V1/TIC/Q/NVDA");
+        assert_eq!(live["data"]["close"], 5.0);
+
+        client.send(Message::Text("nope".to_string())).unwrap();
+        assert!(recv(&mut client)["error"].is_string());
+    }
+
+    #[test]
+    fn test_pending_conflate() {
+        let mut pending = Pending::new(SlowClient::Conflate);
+        assert!(!pending.push("a", Message::Text("1".to_string())));
+        assert!(!pending.push("b", Message::Text("2".to_string())));
+        assert!(pending.push("a", Message::Text("3".to_string())));
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending.pop(), Some(Message::Text("3".to_string())));
+        assert_eq!(pending.pop(), Some(Message::Text("2".to_string())));
+        assert_eq!(pending.pop(), None);
+    }
+}
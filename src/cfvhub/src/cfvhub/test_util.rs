@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+
+use super::envelope::Meta;
+use super::latency::SourceTs;
+use super::proto::ProtoMessage;
+use super::queue::{QueueError, SpillCodec, SpillDecodeSnafu, SpillEncodeSnafu};
+use super::sink::{Dest, ParquetMessage};
+use snafu::ResultExt;
+
+/// Test message, routed by `dest` and serialized with `dest` and `close`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Msg {
+    pub dest: String,
+    pub close: f64,
+    // envelope fields, none unless a test sets them
+    #[serde(skip)]
+    pub meta: Option<Meta>,
+}
+
+pub fn msg(dest: &str, close: f64) -> Msg {
+    Msg {
+        dest: dest.to_string(),
+        close,
+        meta: None,
+    }
+}
+
+impl Dest for Msg {
+    fn get_dest(&self) -> &str {
+        &self.dest
+    }
+
+    fn get_meta(&self) -> Option<&Meta> {
+        self.meta.as_ref()
+    }
+
+    fn meta_mut(&mut self) -> Option<&mut Meta> {
+        self.meta.as_mut()
+    }
+}
+
+// no message in proto/
+impl ProtoMessage for Msg {}
+
+impl ParquetMessage for Msg {}
+
+impl SourceTs for Msg {
+    fn source_ts(&self) -> f64 {
+        0.0
+    }
+}
+
+impl SpillCodec for Msg {
+    fn encode(&self) -> Result<Vec<u8>, QueueError> {
+        rmp_serde::to_vec(self).context(SpillEncodeSnafu)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, QueueError> {
+        rmp_serde::from_slice(bytes).context(SpillDecodeSnafu)
+    }
+}
@@ -0,0 +1,414 @@
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::convertor::nasdaq_basic::MarketPhase;
+use crate::convertor::topic::{TopicFields, TopicTemplate};
+use crate::formater::JsonFormater;
+use crate::metrics::METRICS;
+use crate::proto::ProtoMessage;
+use crate::sink::{Dest, FanOutSink, ParquetMessage, Shutdown, SinkConfig, SinkError, SinkExt};
+
+fn default_stale_secs() -> u64 {
+    60
+}
+
+fn default_check_secs() -> u64 {
+    5
+}
+
+fn default_refresh_secs() -> u64 {
+    300
+}
+
+fn default_topic() -> TopicTemplate {
+    TopicTemplate::parse("cfvhub/stale/{source}/{code}").unwrap()
+}
+
+/// Stale data alerts, e.g. in toml `[watchdog]` `stale_secs = 30` `refresh = true`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatchdogConfig {
+    /// a trading symbol without updates for this long is stale
+    #[serde(default = "default_stale_secs")]
+    pub stale_secs: u64,
+    #[serde(default = "default_check_secs")]
+    pub check_secs: u64,
+    /// request QUERYSNAPANDSUBSCRIBE for a stale symbol
+    #[serde(default)]
+    pub refresh: bool,
+    /// at most one refresh per symbol in this many seconds
+    #[serde(default = "default_refresh_secs")]
+    pub refresh_secs: u64,
+    /// also send the alerts to these sinks
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+    /// topic of the alerts, `{code}` is empty for a whole source
+    #[serde(default = "default_topic")]
+    pub topic: TopicTemplate,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            stale_secs: default_stale_secs(),
+            check_secs: default_check_secs(),
+            refresh: false,
+            refresh_secs: default_refresh_secs(),
+            sinks: vec![],
+            topic: default_topic(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StaleAlert {
+    #[serde(skip)]
+    dest: String,
+    pub source: i32,
+    /// empty when no symbol of the source updates
+    pub code: String,
+    pub market_phase: MarketPhase,
+    /// since the last update
+    pub stale_secs: f64,
+    pub ts: f64,
+    pub refresh: bool,
+}
+
+impl Dest for StaleAlert {
+    fn get_dest(&self) -> &str {
+        &self.dest
+    }
+
+    fn get_partition_key(&self) -> &str {
+        &self.code
+    }
+}
+
+// alerts have no message in proto/
+impl ProtoMessage for StaleAlert {}
+
+impl ParquetMessage for StaleAlert {}
+
+/// A symbol to QUERYSNAPANDSUBSCRIBE again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Refresh {
+    pub source: i32,
+    pub symbol: String,
+}
+
+// timestamps are nanoseconds since `Watchdog::epoch`
+const NEVER: u64 = u64::MAX;
+
+struct SymbolEntry {
+    last: AtomicU64,
+    stale: AtomicBool,
+    refreshed: AtomicU64,
+}
+
+impl SymbolEntry {
+    fn new(now: u64) -> Self {
+        Self {
+            last: AtomicU64::new(now),
+            stale: AtomicBool::new(false),
+            refreshed: AtomicU64::new(NEVER),
+        }
+    }
+
+    /// True when the symbol was stale.
+    fn touch(&self, now: u64) -> bool {
+        self.last.store(now, Ordering::Relaxed);
+        // only written on a change, the check is the usual writer
+        self.stale.load(Ordering::Relaxed) && self.stale.swap(false, Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+struct SourceEntry {
+    stale: AtomicBool,
+    // phase of the most recent update of any symbol
+    trading: AtomicBool,
+    symbols: DashMap<String, SymbolEntry, ahash::RandomState>,
+}
+
+/// Last update per source and symbol from the convertor. Symbols go stale while the
+/// most recent update of their source is in the trading phase, so a symbol silent since
+/// the pre market is flagged once the others trade, and a quiet pre market or a closed
+/// source is fine.
+/// An update of a known symbol takes read locks and stores atomics.
+pub struct Watchdog {
+    config: WatchdogConfig,
+    epoch: Instant,
+    sources: DashMap<i32, SourceEntry>,
+}
+
+impl Watchdog {
+    pub fn new(config: WatchdogConfig) -> Self {
+        Self {
+            config,
+            epoch: Instant::now(),
+            sources: DashMap::new(),
+        }
+    }
+
+    fn nanos(&self, at: Instant) -> u64 {
+        at.saturating_duration_since(self.epoch).as_nanos() as u64
+    }
+
+    pub fn touch(&self, source: i32, symbol: &str, market_phase: &MarketPhase) {
+        self.touch_at(source, symbol, market_phase, Instant::now());
+    }
+
+    fn touch_at(&self, source: i32, symbol: &str, market_phase: &MarketPhase, now: Instant) {
+        let now = self.nanos(now);
+        let trading = matches!(market_phase, MarketPhase::Trading);
+        let entry = match self.sources.get(&source) {
+            Some(entry) => entry,
+            None => self.sources.entry(source).or_default().downgrade(),
+        };
+        entry.trading.store(trading, Ordering::Relaxed);
+        // the read guard is dropped before a new symbol takes the write lock
+        let was_stale = entry.symbols.get(symbol).map(|s| s.touch(now));
+        match was_stale {
+            Some(true) => info!("watchdog {}.{} updates again", source, symbol),
+            Some(false) => {}
+            None => {
+                entry
+                    .symbols
+                    .entry(symbol.to_string())
+                    .or_insert_with(|| SymbolEntry::new(now));
+            }
+        }
+    }
+
+    /// Alerts for the symbols and sources that went stale since the last check, and for
+    /// the stale symbols refreshed again after `refresh_secs`.
+    pub fn check(&self) -> Vec<StaleAlert> {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&self, now: Instant) -> Vec<StaleAlert> {
+        let stale_after = Duration::from_secs(self.config.stale_secs).as_nanos() as u64;
+        let refresh_every = Duration::from_secs(self.config.refresh_secs).as_nanos() as u64;
+        let now = self.nanos(now);
+        let ts = crate::latency::now_ns() as f64 / 1e9;
+        let mut alerts = vec![];
+        for entry in self.sources.iter() {
+            let source = *entry.key();
+            let mut stale_symbols = 0;
+            let trading = entry.trading.load(Ordering::Relaxed);
+            let mut source_last = 0;
+            for s in entry.symbols.iter() {
+                let last = s.last.load(Ordering::Relaxed);
+                source_last = source_last.max(last);
+                if !trading {
+                    s.stale.store(false, Ordering::Relaxed);
+                    continue;
+                }
+                let age = now.saturating_sub(last);
+                if age < stale_after {
+                    continue;
+                }
+                stale_symbols += 1;
+                let newly_stale = !s.stale.swap(true, Ordering::Relaxed);
+                // requested again every refresh_secs while it stays stale
+                let refreshed = s.refreshed.load(Ordering::Relaxed);
+                let refresh = self.config.refresh
+                    && (refreshed == NEVER || now.saturating_sub(refreshed) >= refresh_every);
+                if refresh {
+                    s.refreshed.store(now, Ordering::Relaxed);
+                }
+                if newly_stale || refresh {
+                    let age = Duration::from_nanos(age);
+                    alerts.push(self.alert(
+                        source,
+                        s.key(),
+                        &MarketPhase::Trading,
+                        age,
+                        ts,
+                        refresh,
+                    ));
+                }
+            }
+            METRICS
+                .stale_symbols
+                .with_label_values(&[&source.to_string()])
+                .set(stale_symbols);
+            let age = now.saturating_sub(source_last);
+            if trading && age >= stale_after {
+                if !entry.stale.swap(true, Ordering::Relaxed) {
+                    let age = Duration::from_nanos(age);
+                    alerts.push(self.alert(source, "", &MarketPhase::Trading, age, ts, false));
+                }
+            } else if entry.stale.swap(false, Ordering::Relaxed) {
+                info!("watchdog source {} updates again", source);
+            }
+        }
+        alerts
+    }
+
+    fn alert(
+        &self,
+        source: i32,
+        code: &str,
+        market_phase: &MarketPhase,
+        age: Duration,
+        ts: f64,
+        refresh: bool,
+    ) -> StaleAlert {
+        let fields = TopicFields {
+            source,
+            exchange: "",
+            code,
+            market_phase: market_phase.as_str(),
+        };
+        StaleAlert {
+            dest: self.config.topic.render(&fields),
+            source,
+            code: code.to_string(),
+            market_phase: market_phase.clone(),
+            stale_secs: age.as_secs_f64(),
+            ts,
+            refresh,
+        }
+    }
+
+    /// Checks every `check_secs` on its own thread. The symbols to refresh are sent on
+    /// the returned channel, the owner of the CFAPI sends the requests. Fails when an
+    /// alert sink can not be built.
+    pub fn spawn(self: &Arc<Self>, shutdown: &Shutdown) -> Result<Receiver<Refresh>, SinkError> {
+        let sink = FanOutSink::<StaleAlert>::from_config("watchdog", &self.config.sinks, shutdown)?;
+        let (send, recv) = bounded(1024);
+        let watchdog = self.clone();
+        std::thread::spawn(move || watchdog.run(sink, send));
+        Ok(recv)
+    }
+
+    fn run(&self, mut sink: FanOutSink<StaleAlert>, refresh: Sender<Refresh>) {
+        let every = Duration::from_secs(self.config.check_secs.max(1));
+        loop {
+            std::thread::sleep(every);
+            for alert in self.check() {
+                let scope = if alert.code.is_empty() {
+                    "source"
+                } else {
+                    "symbol"
+                };
+                METRICS
+                    .stale_alerts
+                    .with_label_values(&[&alert.source.to_string(), scope])
+                    .inc();
+                if alert.code.is_empty() {
+                    warn!(
+                        "watchdog source {} has no updates for {:.0}s",
+                        alert.source, alert.stale_secs
+                    );
+                } else {
+                    warn!(
+                        "watchdog {}.{} has no updates for {:.0}s while trading",
+                        alert.source, alert.code, alert.stale_secs
+                    );
+                }
+                if let Err(e) = sink.exec(&alert, &JsonFormater) {
+                    error!("watchdog alert sink error: {}", e);
+                }
+                if !alert.refresh {
+                    continue;
+                }
+                let request = Refresh {
+                    source: alert.source,
+                    symbol: alert.code,
+                };
+                match refresh.try_send(request) {
+                    Ok(_) => {}
+                    Err(TrySendError::Full(request)) => {
+                        warn!("watchdog refresh of {:?} dropped, queue is full", request)
+                    }
+                    // nobody sends the requests
+                    Err(TrySendError::Disconnected(_)) => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_symbols() {
+        let watchdog = Watchdog::new(WatchdogConfig {
+            stale_secs: 10,
+            refresh: true,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        watchdog.touch_at(533, "AAPL", &MarketPhase::Trading, at(0));
+        watchdog.touch_at(533, "NVDA", &MarketPhase::Trading, at(0));
+        assert!(watchdog.check_at(at(5)).is_empty());
+
+        watchdog.touch_at(533, "NVDA", &MarketPhase::Trading, at(8));
+        let alerts = watchdog.check_at(at(12));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].code, "AAPL");
+        assert_eq!(alerts[0].get_dest(), "cfvhub/stale/533/AAPL");
+        assert!(alerts[0].refresh);
+        // alerted once until it updates again or is refreshed again
+        assert!(watchdog.check_at(at(14)).is_empty());
+        watchdog.touch_at(533, "AAPL", &MarketPhase::Trading, at(15));
+        assert!(watchdog.check_at(at(16)).is_empty());
+
+        // the whole source stops, the refresh of AAPL waits for refresh_secs
+        let alerts = watchdog.check_at(at(30));
+        let codes: Vec<_> = alerts
+            .iter()
+            .map(|a| (a.code.as_str(), a.refresh))
+            .collect();
+        assert_eq!(codes.len(), 3);
+        assert!(codes.contains(&("AAPL", false)));
+        assert!(codes.contains(&("NVDA", true)));
+        assert!(codes.contains(&("", false)));
+
+        // still stale, AAPL is requested again refresh_secs after its last refresh
+        assert!(watchdog.check_at(at(311)).is_empty());
+        let alerts = watchdog.check_at(at(312));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!((alerts[0].code.as_str(), alerts[0].refresh), ("AAPL", true));
+        let alerts = watchdog.check_at(at(330));
+        assert_eq!((alerts[0].code.as_str(), alerts[0].refresh), ("NVDA", true));
+        assert!(watchdog.check_at(at(331)).is_empty());
+    }
+
+    #[test]
+    fn test_phase_of_the_source() {
+        let watchdog = Watchdog::new(WatchdogConfig {
+            stale_secs: 10,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        watchdog.touch_at(533, "TSLA", &MarketPhase::PreMarket, at(0));
+        watchdog.touch_at(533, "AAPL", &MarketPhase::PreMarket, at(0));
+        assert!(watchdog.check_at(at(12)).is_empty());
+
+        // TSLA has been silent since the pre market
+        watchdog.touch_at(533, "AAPL", &MarketPhase::Trading, at(15));
+        let alerts = watchdog.check_at(at(20));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].code, "TSLA");
+
+        // the source closed, nothing is stale
+        watchdog.touch_at(533, "AAPL", &MarketPhase::Closed, at(21));
+        assert!(watchdog.check_at(at(60)).is_empty());
+        // trading again, AAPL has been silent since the close
+        watchdog.touch_at(533, "TSLA", &MarketPhase::Trading, at(61));
+        let alerts = watchdog.check_at(at(62));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].code, "AAPL");
+    }
+}
@@ -2,6 +2,17 @@ pub mod cfvhub;
 
 pub use self::cfvhub::pipe;
 pub use self::cfvhub::pipe_queue;
+pub use self::cfvhub::queue;
 pub use self::cfvhub::sink;
 pub use self::cfvhub::formater;
-pub use self::cfvhub::convertor;
\ No newline at end of file
+pub use self::cfvhub::proto;
+pub use self::cfvhub::convertor;
+pub use self::cfvhub::envelope;
+pub use self::cfvhub::batch;
+pub use self::cfvhub::latency;
+pub use self::cfvhub::metrics;
+pub use self::cfvhub::middleware;
+pub use self::cfvhub::config;
+pub use self::cfvhub::watchdog;
+#[cfg(test)]
+pub(crate) use self::cfvhub::test_util;
\ No newline at end of file
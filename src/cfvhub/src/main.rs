@@ -1,14 +1,29 @@
+use cfapi::admin::AdminError;
 use cfapi::api::{CFAPIConfig, ConnectionConfig, SessionConfig, CFAPI};
 use cfapi::binding::Commands;
-use cfvhub::convertor::nasdaq_basic::NasdaqBasicConvertorV1;
+use cfapi::message_event::MessageEventHandlerExt;
+use cfapi::session_event::SessionEventHandlerExt;
+use cfapi::stat_event::StatisticsEventHandlerExt;
+use cfvhub::config::PipelineConfig;
+use cfvhub::convertor::nasdaq_basic::{DataNasdaqBasicV1, NasdaqBasicConvertorV1};
 use cfvhub::formater::{JsonFormater, MessagePackFormater};
+use cfvhub::metrics::{MetricsSessionEventHandler, MetricsStatisticsEventHandler};
 use cfvhub::pipe::PipeMessageHandler;
 use cfvhub::pipe_queue::PipeQueueMessageHandler;
-use cfvhub::sink::{ConsoleSink, DiskSink, DoNothingSink, SolaceSink};
-use clap::Parser;
-use tracing::{info, Level};
+use cfvhub::queue::{ConflateFilter, OverflowPolicy};
+use cfvhub::sink::{ConsoleSink, DiskSink, DoNothingSink, FanOutSink, Shutdown, SolaceSink};
+use cfvhub::sink::fanout::{FormatKind, SinkConfig, SinkKind};
+use cfvhub::sink::RetryPolicy;
+use cfvhub::watchdog::Watchdog;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, Level};
 use tracing_subscriber;
 
+const USERNAME: &str = "SINOPACNB";
+
 #[derive(Parser, Debug)]
 #[command(version, author, about)]
 struct Args {
@@ -21,6 +36,116 @@ struct Args {
     // sink thread number
     #[arg(short = 't', long, default_value_t = 2)]
     sink_thread: usize,
+    // queue full policy: block, spill:<path>, drop-oldest, drop-newest or conflate,
+    // block stalls the CFAPI callback thread while a sink is slow, the drops lose messages
+    #[arg(long, default_value = "block")]
+    overflow: OverflowPolicy,
+    // keep only the latest pending message per topic, overrides --overflow
+    #[arg(long)]
+    conflate: bool,
+    // with --conflate, also conflate ticks instead of sending every one
+    #[arg(long)]
+    conflate_ticks: bool,
+    // serve prometheus metrics on this address, e.g. 0.0.0.0:9100
+    #[arg(long)]
+    metrics_addr: Option<String>,
+    // seconds between latency summaries in the log
+    #[arg(long, default_value_t = 60)]
+    latency_log_secs: u64,
+    // pipeline config file (toml, yaml or json) with the middleware stages
+    #[arg(long)]
+    pipeline_config: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the response of an administrative or listing command as json
+    List {
+        #[arg(value_enum)]
+        kind: ListKind,
+        // source id for subscribed-symbols, enumeration, administration-info and exchange-info
+        #[arg(long, default_value_t = String::from("533"))]
+        src: String,
+        // user for user-permission, default to the login user
+        #[arg(long)]
+        user: Option<String>,
+        // seconds to wait for the response
+        #[arg(long, default_value_t = 30)]
+        timeout: u64,
+    },
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum ListKind {
+    SubscribedSymbols,
+    UserPermission,
+    ActiveClients,
+    AllUsers,
+    Enumeration,
+    AdministrationInfo,
+    ExchangeInfo,
+}
+
+fn new_api(
+    app_name: &str,
+    session_event_handlers: Vec<Box<dyn SessionEventHandlerExt>>,
+    message_event_handlers: Vec<Box<dyn MessageEventHandlerExt>>,
+    statistics_event_handlers: Vec<Box<dyn StatisticsEventHandlerExt>>,
+) -> CFAPI {
+    let config = CFAPIConfig::default()
+        .with_app_name(app_name)
+        .with_app_version("1.0")
+        .with_username(USERNAME)
+        .with_password("s1nopac")
+        .with_statistics_interval(60);
+    let session_config = SessionConfig::default()
+        .with_multi_threaded_api_connections(true)
+        .with_max_csp_threads(12)
+        .with_max_user_threads(12)
+        .with_queue_depth_threshold_percent(5);
+    let main_connection_config = ConnectionConfig::default();
+    // let backup_connection_config = ConnectionConfig::default().with_backup(true);
+    let mut api = CFAPI::new(
+        config,
+        vec![],
+        session_event_handlers,
+        message_event_handlers,
+        statistics_event_handlers,
+    );
+    api.set_session_config(&session_config);
+    api.set_connection_config("216.221.213.14:7022", &main_connection_config);
+    // api.set_connection_config("216.221.213.14:7022", &backup_connection_config);
+    api
+}
+
+fn print_response<T: Serialize>(response: Result<T, AdminError>) {
+    match response {
+        Ok(response) => match serde_json::to_string_pretty(&response) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error!("json encode error: {}", e),
+        },
+        Err(e) => error!("{}", e),
+    }
+}
+
+fn list(app_name: &str, kind: ListKind, src: &str, user: Option<String>, timeout: u64) {
+    let mut api = new_api(app_name, vec![], vec![], vec![]);
+    api.start();
+    let timeout = Duration::from_secs(timeout);
+    match kind {
+        ListKind::SubscribedSymbols => print_response(api.list_subscribed_symbols(src, timeout)),
+        ListKind::UserPermission => {
+            let user = user.unwrap_or_else(|| USERNAME.to_string());
+            print_response(api.list_user_permission(&user, timeout))
+        }
+        ListKind::ActiveClients => print_response(api.get_active_clients_info(timeout)),
+        ListKind::AllUsers => print_response(api.get_all_users(timeout)),
+        ListKind::Enumeration => print_response(api.list_enumeration(src, timeout)),
+        ListKind::AdministrationInfo => print_response(api.list_administration_info(src, timeout)),
+        ListKind::ExchangeInfo => print_response(api.list_extended_exchange_info(src, timeout)),
+    }
 }
 
 fn main() {
@@ -41,13 +166,84 @@ fn main() {
     // .init();
     tracing::subscriber::set_global_default(subscriber).unwrap();
 
+    let app_name = format!("CFVHUB-{}", args.sub);
+    if let Some(Command::List {
+        kind,
+        src,
+        user,
+        timeout,
+    }) = args.command
+    {
+        list(&app_name, kind, &src, user, timeout);
+        return;
+    }
+
     info!("CFVHUB Start mode: {}", args.mode);
+    let (shutdown_send, shutdown) = crossbeam_channel::bounded(1);
+    if let Err(e) = ctrlc::set_handler(move || {
+        let _ = shutdown_send.try_send(());
+    }) {
+        error!("set signal handler error: {}", e);
+        return;
+    }
+    let pipeline_config = match &args.pipeline_config {
+        Some(path) => match PipelineConfig::load(path.as_ref()) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        },
+        None => PipelineConfig::default(),
+    };
+    // publish to solace only unless the config lists the sinks
+    let sinks = if pipeline_config.sinks.is_empty() {
+        vec![SinkConfig {
+            name: "solace".to_string(),
+            kind: SinkKind::Solace,
+            format: FormatKind::MessagePack,
+            envelope: None,
+            batch: None,
+            solace: None,
+            disk: None,
+            parquet: None,
+            websocket: None,
+            multicast: None,
+            redis: None,
+            queue: None,
+            keys: vec![],
+            retry: Some(RetryPolicy::default()),
+            dead_letter: dotenvy::var("DEAD_LETTER_PATH").ok(),
+        }]
+    } else {
+        pipeline_config.sinks.clone()
+    };
+    let watchdog = pipeline_config
+        .watchdog
+        .clone()
+        .map(|config| Arc::new(Watchdog::new(config)));
+    let convertor = NasdaqBasicConvertorV1::default()
+        .with_topics(pipeline_config.topics.clone())
+        .with_price_formats(pipeline_config.prices.clone());
+    let convertor = match &watchdog {
+        Some(watchdog) => convertor.with_watchdog(watchdog.clone()),
+        None => convertor,
+    };
+    let middleware =
+        match cfvhub::middleware::build_chain::<DataNasdaqBasicV1>(&pipeline_config.middleware) {
+            Ok(middleware) => middleware,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+    let sink_shutdown = Shutdown::default();
     let pipe_queue_message_handler: PipeQueueMessageHandler<
         NasdaqBasicConvertorV1,
         MessagePackFormater,
-        SolaceSink,
-    > = PipeQueueMessageHandler::new(
-        NasdaqBasicConvertorV1::default(),
+        FanOutSink<DataNasdaqBasicV1>,
+    > = match PipeQueueMessageHandler::new(
+        convertor,
         // JsonFormater {},
         // MessagePackFormater {},
         // DiskSink::new("record.json".into()).unwrap(),
@@ -55,33 +251,69 @@ fn main() {
         // ConsoleSink {},
         1024,
         args.sink_thread,
-    );
-    pipe_queue_message_handler.exec_loop_th();
-    let app_name = format!("CFVHUB-{}", args.sub);
-    let config = CFAPIConfig::default()
-        .with_app_name(&app_name)
-        .with_app_version("1.0")
-        .with_username("SINOPACNB")
-        .with_password("s1nopac")
-        .with_statistics_interval(60);
-    let session_config = SessionConfig::default()
-        .with_multi_threaded_api_connections(true)
-        .with_max_csp_threads(12)
-        .with_max_user_threads(12)
-        .with_queue_depth_threshold_percent(5);
-    let main_connection_config = ConnectionConfig::default();
-    // let backup_connection_config = ConnectionConfig::default().with_backup(true);
-    let mut api = CFAPI::new(
-        config,
-        vec![],
-        vec![],
+    )
+    .with_overflow_policy(&args.overflow)
+    {
+        Ok(handler) => handler
+            .with_middleware(middleware)
+            .with_sink_builder({
+                let sink_shutdown = sink_shutdown.clone();
+                move |id| FanOutSink::from_config(id, &sinks, &sink_shutdown)
+            }),
+        Err(e) => {
+            error!("build queue error: {}", e);
+            return;
+        }
+    };
+    let pipe_queue_message_handler = if args.conflate {
+        let never_conflate: Option<ConflateFilter<DataNasdaqBasicV1>> = if args.conflate_ticks {
+            None
+        } else {
+            Some(Arc::new(DataNasdaqBasicV1::is_tick))
+        };
+        pipe_queue_message_handler.with_conflation(never_conflate)
+    } else {
+        pipe_queue_message_handler
+    };
+    // every sink is connected or opened here, before the api starts
+    if let Err(e) = pipe_queue_message_handler.exec_loop_th() {
+        error!("build sinks error: {}", e);
+        return;
+    }
+    let mut refresh = match &watchdog {
+        // the api stays on this thread, so the watchdog refreshes are requested here
+        Some(watchdog) => match watchdog.spawn(&sink_shutdown) {
+            Ok(refresh) => refresh,
+            Err(e) => {
+                error!("build watchdog sinks error: {}", e);
+                return;
+            }
+        },
+        None => crossbeam_channel::never(),
+    };
+    cfvhub::latency::spawn_summary(Duration::from_secs(args.latency_log_secs));
+    let (session_event_handlers, statistics_event_handlers) = match &args.metrics_addr {
+        Some(addr) => {
+            pipe_queue_message_handler.register_metrics("nasdaq_basic");
+            // the feed matters more than its metrics, e.g. when the port is taken
+            if let Err(e) = cfvhub::metrics::serve(addr) {
+                error!("metrics serve on {} error: {}", addr, e);
+            }
+            let session: Vec<Box<dyn SessionEventHandlerExt>> =
+                vec![Box::new(MetricsSessionEventHandler::default())];
+            let stat: Vec<Box<dyn StatisticsEventHandlerExt>> =
+                vec![Box::new(MetricsStatisticsEventHandler)];
+            (session, stat)
+        }
+        None => (vec![], vec![]),
+    };
+    let mut api = new_api(
+        &app_name,
+        session_event_handlers,
         // vec![Box::new(pipe_message_handler)],
         vec![Box::new(pipe_queue_message_handler)],
-        vec![],
+        statistics_event_handlers,
     );
-    api.set_session_config(&session_config);
-    api.set_connection_config("216.221.213.14:7022", &main_connection_config);
-    // api.set_connection_config("216.221.213.14:7022", &backup_connection_config);
     api.start();
     if args.sub.chars().count() > 1 {
         let start_char = args.sub.chars().nth(0).unwrap();
@@ -108,5 +340,30 @@ fn main() {
     // api.request("533", "*", Commands::QUERYSNAPANDSUBSCRIBEWILDCARD);
     // api.request("533", "NVDA", Commands::QUERYSNAPANDSUBSCRIBE);
     // api.request("533", "TLSA", Commands::QUERYSNAPANDSUBSCRIBE);
-    std::thread::sleep(std::time::Duration::from_secs(12 * 60 * 60));
+    // runs for 12 hours or until SIGINT/SIGTERM, then finishes the files
+    let deadline = Instant::now() + Duration::from_secs(12 * 60 * 60);
+    loop {
+        crossbeam_channel::select! {
+            recv(refresh) -> r => match r {
+                Ok(r) => {
+                    info!("refresh stale symbol {}.{}", r.source, r.symbol);
+                    api.request(
+                        &r.source.to_string(),
+                        &r.symbol,
+                        Commands::QUERYSNAPANDSUBSCRIBE,
+                    );
+                }
+                Err(_) => refresh = crossbeam_channel::never(),
+            },
+            recv(shutdown) -> _ => {
+                info!("shutdown signal received");
+                break;
+            }
+            default(deadline.saturating_duration_since(Instant::now())) => break,
+        }
+    }
+    // the sink workers are still running, the batches are sent before the files close
+    if let Err(e) = sink_shutdown.close() {
+        error!("close sinks error: {}", e);
+    }
 }